@@ -0,0 +1,392 @@
+use crate::{
+    alias, auth_failures, ban, cluster,
+    common::{get_arg, get_arg_or, listen_tcp, now},
+    connection_log, expiry, id_norm, ip_filter, last_error,
+    peer::{PeerInfo, PeerMap},
+    search, snapshot, strict,
+};
+use axum::{
+    extract::{ConnectInfo, Extension, Path, Query},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+        HeaderMap, Request, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use hbb_common::{log, tokio, ResultType};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+static TOKEN: OnceCell<String> = OnceCell::new();
+const UI: &str = include_str!("admin_ui.html");
+
+/// HTTP API on `ADMIN_API_PORT` for operational tooling, json in and out:
+/// `GET /peers[?inactive_days=<n>]`, `GET /peers/<id>`, `DELETE /peers/<id>[?reason=<text>]`,
+/// `POST /peers/<id>/expire-pk`, `GET`/`PUT /ip-filter`, `GET /online`,
+/// `GET /presence`, `GET /attempts`, `GET /attempts/search`, `GET
+/// /aliases[?since=<version>]`, `PUT`/`DELETE /aliases/<alias>`, `GET /bans`
+/// and `PUT`/`DELETE /bans/<id|ip>`. Requests need `Authorization: Bearer <ADMIN_API_TOKEN>`,
+/// without a token, or with `ADMIN_API_LOOPBACK=Y`, it only listens on
+/// loopback. `GET /ui` is a page showing the online peers and the attempts,
+/// it asks for the token itself. Peers come with the instance they are homed
+/// on, see cluster.
+pub(crate) async fn start(bind_addr: Option<IpAddr>, pm: PeerMap) -> ResultType<()> {
+    let port = get_arg("ADMIN_API_PORT").parse::<u16>().unwrap_or(0);
+    if port == 0 {
+        return Ok(());
+    }
+    let token = get_arg("ADMIN_API_TOKEN");
+    if token.is_empty() && strict::is_on() {
+        log::error!("ADMIN_API_PORT is off with STRICT unless ADMIN_API_TOKEN is set");
+        return Ok(());
+    }
+    let loopback = get_arg_or("ADMIN_API_LOOPBACK", "N".to_owned()).to_uppercase() == "Y";
+    let bind_addr = if token.is_empty() || loopback {
+        Some(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    } else {
+        bind_addr
+    };
+    TOKEN.set(token).ok();
+    let listener = listen_tcp(bind_addr, port).await?;
+    log::info!("Listening on tcp {} for the admin api", listener.local_addr()?);
+    let app = Router::new()
+        .route("/peers", get(list_peers))
+        .route("/peers/:id", get(get_peer).delete(delete_peer))
+        .route("/peers/:id/expire-pk", post(expire_pk))
+        .route("/ip-filter", get(get_ip_filter).put(put_ip_filter))
+        .route("/online", get(list_online))
+        .route("/presence", get(get_presence))
+        .route("/attempts", get(list_attempts))
+        .route("/attempts/search", get(search_attempts))
+        .route("/aliases", get(list_aliases))
+        .route("/aliases/:alias", put(put_alias).delete(delete_alias))
+        .route("/bans", get(list_bans))
+        .route("/bans/:target", put(put_ban).delete(delete_ban))
+        .layer(Extension(pm))
+        .layer(middleware::from_fn(authorize))
+        // no data in the page, after the authorization layer
+        .route("/ui", get(|| async { Html(UI) }));
+    let server = axum::Server::from_tcp(listener.into_std()?)?
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            log::error!("admin api failed: {}", err);
+        }
+    });
+    Ok(())
+}
+
+async fn authorize<B>(req: Request<B>, next: Next<B>) -> Response {
+    let header = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok());
+    if !is_authorized(header, TOKEN.get().map(|x| x.as_str()).unwrap_or_default()) {
+        return error(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+    next.run(req).await
+}
+
+fn is_authorized(header: Option<&str>, token: &str) -> bool {
+    token.is_empty()
+        || header
+            .and_then(|x| x.strip_prefix("Bearer "))
+            .is_some_and(|x| sodiumoxide::utils::memcmp(x.as_bytes(), token.as_bytes()))
+}
+
+fn error(status: StatusCode, err: &str) -> Response {
+    (status, Json(serde_json::json!({ "error": err }))).into_response()
+}
+
+// `body` with `etag`, or 304 if the client has it already
+fn with_etag(headers: &HeaderMap, etag: &str, body: serde_json::Value) -> Response {
+    let etag = format!("\"{}\"", etag);
+    let cached = headers
+        .get(IF_NONE_MATCH)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.split(',').any(|x| x.trim() == etag || x.trim() == "*"));
+    if cached {
+        return (StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response();
+    }
+    (
+        [(ETAG, etag), (CONTENT_TYPE, "application/json".to_owned())],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+// for bodies without a version of their own
+fn content_etag(body: &serde_json::Value) -> String {
+    let hash = sodiumoxide::crypto::hash::sha256::hash(body.to_string().as_bytes());
+    hash.0[..8].iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// All registered ids, from the database. `?inactive_days=<n>` only lists
+// those which didn't register for n days, or not since they are counted, see
+// peer_stats, to find installations gone dead.
+async fn list_peers(
+    Query(query): Query<HashMap<String, String>>,
+    Extension(pm): Extension<PeerMap>,
+) -> Response {
+    let before = match query.get("inactive_days").map(|x| x.parse::<u64>()) {
+        None => None,
+        Some(Ok(days)) => Some(now().saturating_sub(days * 24 * 3600)),
+        Some(Err(_)) => return error(StatusCode::BAD_REQUEST, "invalid inactive_days"),
+    };
+    let records = match pm.storage.get_peer_records().await {
+        Ok(records) => records,
+        Err(err) => return error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    };
+    let mut res = Vec::new();
+    for x in records {
+        let last_online = PeerInfo::parse(&x.info).ok().and_then(|x| x.last_online);
+        let online = expiry::is_online(&x.id);
+        if let Some(before) = before {
+            if online || last_online.is_some_and(|x| x >= before) {
+                continue;
+            }
+        }
+        res.push(serde_json::json!({ "id": x.id, "online": online, "last_online": last_online }));
+    }
+    Json(res).into_response()
+}
+
+async fn get_peer(Path(id): Path<String>, Extension(pm): Extension<PeerMap>) -> Response {
+    let id = id_norm::normalize(&id);
+    let Some(peer) = pm.get(&id).await else {
+        return error(StatusCode::NOT_FOUND, "not found");
+    };
+    let peer = peer.read().await;
+    Json(serde_json::json!({
+        "id": id,
+        "online": expiry::is_online(&id),
+        "ip": peer.info.ip,
+        "pk": base64::encode(&peer.pk),
+        "last_online": peer.info.last_online,
+        "registrations": peer.info.registrations,
+        "last_punch": peer.info.last_punch,
+        "instance": cluster::home(peer.socket_addr),
+        "last_error": last_error::to_json(&id, &peer.info.ip),
+    }))
+    .into_response()
+}
+
+// The tombstone names the caller by its address, not by anything it sends.
+async fn delete_peer(
+    Path(id): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(pm): Extension<PeerMap>,
+) -> Response {
+    let id = id_norm::normalize(&id);
+    let reason = query.get("reason").map(|x| x.as_str()).unwrap_or("deleted");
+    let operator = format!("admin-api {}", addr.ip());
+    match pm.remove(&id, reason, &operator, true).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => error(StatusCode::NOT_FOUND, "not found"),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+async fn expire_pk(Path(id): Path<String>, Extension(pm): Extension<PeerMap>) -> Response {
+    let id = id_norm::normalize(&id);
+    match pm.expire_pk(&id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => error(StatusCode::NOT_FOUND, "not found"),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+// from memory, only the peers online right now
+async fn list_online(Extension(pm): Extension<PeerMap>) -> Response {
+    Json(
+        pm.online()
+            .await
+            .into_iter()
+            .map(|(id, addr, elapsed)| {
+                let ip = addr.ip().to_string();
+                serde_json::json!({
+                    "id": id,
+                    "ip": ip,
+                    "addr": addr.to_string(),
+                    "last_reg": elapsed / 1000,
+                    "banned": auth_failures::is_banned(&ip),
+                    "instance": cluster::home(addr),
+                })
+            })
+            .collect::<Vec<_>>(),
+    )
+    .into_response()
+}
+
+// for the instance taking over from this one, see snapshot::warm
+async fn get_presence(Extension(pm): Extension<PeerMap>) -> Response {
+    Json(snapshot::export(&pm).await).into_response()
+}
+
+async fn list_attempts() -> Response {
+    Json(connection_log::recent()).into_response()
+}
+
+// the stored attempts, see search::Query for the parameters
+async fn search_attempts(Query(query): Query<HashMap<String, String>>) -> Response {
+    let query = match search::Query::parse(query.iter().map(|(k, v)| (k.as_str(), v.as_str()))) {
+        Ok(x) => x,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &err),
+    };
+    match search::search(&query).await {
+        Ok(rows) => Json(
+            rows.into_iter()
+                .map(|(tm, id, ip, outcome)| {
+                    serde_json::json!({ "time": tm, "id": id, "ip": ip, "outcome": outcome })
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(err) => error(StatusCode::SERVICE_UNAVAILABLE, &err.to_string()),
+    }
+}
+
+// The ETag is the version of the aliases, `?since=<version>` gets the
+// changes after it, `{"version", "changes": [{"alias", "id"}]}` with a null id
+// for a removed alias, or 410 if they are too old to be known.
+async fn list_aliases(
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    let version = alias::version();
+    if let Some(since) = query.get("since") {
+        let Some(changes) = alias::changes_since(since) else {
+            return error(StatusCode::GONE, "unknown version, get all aliases");
+        };
+        return Json(serde_json::json!({
+            "version": version,
+            "changes": changes
+                .into_iter()
+                .map(|(alias, id)| serde_json::json!({ "alias": alias, "id": id }))
+                .collect::<Vec<_>>(),
+        }))
+        .into_response();
+    }
+    let aliases = alias::list()
+        .into_iter()
+        .map(|(alias, id)| serde_json::json!({ "alias": alias, "id": id }))
+        .collect();
+    with_etag(&headers, &version, serde_json::Value::Array(aliases))
+}
+
+// `{"id": <id>}`, the device the alias stands for from now on
+async fn put_alias(
+    Path(name): Path<String>,
+    Extension(pm): Extension<PeerMap>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let Some(id) = body["id"].as_str() else {
+        return error(StatusCode::BAD_REQUEST, "id is required");
+    };
+    let (name, id) = (id_norm::normalize(&name), id_norm::normalize(id));
+    match alias::set(&pm.db, &*pm.storage, &name, &id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => error(StatusCode::BAD_REQUEST, &err.to_string()),
+    }
+}
+
+async fn delete_alias(Path(name): Path<String>, Extension(pm): Extension<PeerMap>) -> Response {
+    match alias::remove(&pm.db, &id_norm::normalize(&name)).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => error(StatusCode::NOT_FOUND, "not found"),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+async fn list_bans() -> Response {
+    Json(
+        ban::list()
+            .into_iter()
+            .map(|(target, reason, operator, tm, until)| {
+                serde_json::json!({
+                    "target": target,
+                    "reason": reason,
+                    "operator": operator,
+                    "time": tm,
+                    "until": until,
+                })
+            })
+            .collect::<Vec<_>>(),
+    )
+    .into_response()
+}
+
+// `{"reason": <text>, "minutes": <number>}`, both optional, no minutes is
+// for good
+async fn put_ban(
+    Path(target): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(pm): Extension<PeerMap>,
+    Json(body): Json<serde_json::Value>,
+) -> Response {
+    let reason = body["reason"].as_str().unwrap_or("banned");
+    let minutes = body["minutes"].as_u64().unwrap_or(0);
+    let operator = format!("admin-api {}", addr.ip());
+    match ban::add(&pm.db, &target, reason, &operator, minutes).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => error(StatusCode::BAD_REQUEST, &err.to_string()),
+    }
+}
+
+async fn delete_ban(Path(target): Path<String>, Extension(pm): Extension<PeerMap>) -> Response {
+    match ban::remove(&pm.db, &target).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => error(StatusCode::NOT_FOUND, "not found"),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+async fn get_ip_filter(headers: HeaderMap) -> Response {
+    let (allow, deny) = ip_filter::get();
+    let body = serde_json::json!({ "allow": allow, "deny": deny });
+    with_etag(&headers, &content_etag(&body), body)
+}
+
+// `{"allow": [<cidr>...], "deny": [<cidr>...]}`, a list left out is kept
+async fn put_ip_filter(Json(body): Json<serde_json::Value>) -> Response {
+    let list = |name: &str| -> Result<Option<String>, String> {
+        match &body[name] {
+            serde_json::Value::Null => Ok(None),
+            serde_json::Value::Array(nets) => nets
+                .iter()
+                .map(|x| x.as_str().ok_or(format!("{name} has to be strings")))
+                .collect::<Result<Vec<_>, _>>()
+                .map(|x| Some(x.join(","))),
+            _ => Err(format!("{name} has to be a list")),
+        }
+    };
+    let res = list("allow").and_then(|allow| {
+        let deny = list("deny")?;
+        ip_filter::set(allow.as_deref(), deny.as_deref())
+    });
+    match res {
+        Ok(_) => get_ip_filter(HeaderMap::new()).await,
+        Err(err) => error(StatusCode::BAD_REQUEST, &err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_bearer_token() {
+        assert!(is_authorized(None, ""));
+        assert!(is_authorized(Some("Bearer s3cret"), "s3cret"));
+        assert!(!is_authorized(Some("Bearer wrong"), "s3cret"));
+        assert!(!is_authorized(Some("s3cret"), "s3cret"));
+        assert!(!is_authorized(None, "s3cret"));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{common::get_arg, dispatch::Transport};
+use hbb_common::log;
+use once_cell::sync::OnceCell;
+use std::fmt::Write as _;
+
+#[derive(Debug, Default, PartialEq)]
+struct Addrs {
+    all: Option<String>,
+    by_transport: [Option<String>; 3], // indexed by Transport
+}
+
+static ADDRS: OnceCell<Addrs> = OnceCell::new();
+
+/// `ADVERTISE_ADDR` is the `host[:port]` clients reach hbbs at when that
+/// isn't what it sees itself, e.g. behind a NAT or a proxy. A comma
+/// separated list of one address for all listeners and/or
+/// `<udp|tcp|ws>=<host[:port]>` for one, e.g.
+/// `rd.example.com,ws=rd.example.com:443`.
+pub(crate) fn init() {
+    let v = get_arg("ADVERTISE_ADDR");
+    if v.is_empty() {
+        return;
+    }
+    log::info!("ADVERTISE_ADDR={}", v);
+    ADDRS.set(parse(&v)).ok();
+}
+
+fn parse(v: &str) -> Addrs {
+    let mut res = Addrs::default();
+    for x in v.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let (transport, addr) = match x.split_once('=') {
+            Some(("udp", addr)) => (Some(Transport::Udp), addr),
+            Some(("tcp", addr)) => (Some(Transport::Tcp), addr),
+            Some(("ws", addr)) => (Some(Transport::Ws), addr),
+            Some(_) => {
+                log::error!("Invalid advertised address {}, expected <udp|tcp|ws>=<host>", x);
+                continue;
+            }
+            None => (None, x),
+        };
+        let addr = Some(addr.trim().to_owned()).filter(|x| !x.is_empty());
+        match transport {
+            Some(t) => res.by_transport[t as usize] = addr,
+            None => res.all = addr,
+        }
+    }
+    res
+}
+
+/// The address advertised to clients which came over `transport`.
+pub(crate) fn get(transport: Transport) -> Option<&'static str> {
+    let addrs = ADDRS.get()?;
+    addrs.by_transport[transport as usize]
+        .as_deref()
+        .or(addrs.all.as_deref())
+}
+
+/// The rendezvous servers of a configuration update, the address advertised
+/// for `transport` first.
+pub(crate) fn rendezvous_servers(transport: Transport, configured: &[String]) -> Vec<String> {
+    let Some(addr) = get(transport) else {
+        return configured.to_vec();
+    };
+    let mut res = vec![addr.to_owned()];
+    res.extend(configured.iter().filter(|x| *x != addr).cloned());
+    res
+}
+
+pub(crate) fn status() -> String {
+    if ADDRS.get().is_none() {
+        return "off, set ADVERTISE_ADDR\n".to_owned();
+    }
+    let mut res = String::new();
+    for t in [Transport::Udp, Transport::Tcp, Transport::Ws] {
+        let _ = writeln!(res, "{:?}: {}", t, get(t).unwrap_or("-"));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_per_transport() {
+        let addrs = parse("rd.example.com, ws=rd.example.com:443, quic=x, tcp=");
+        assert_eq!(addrs.all.as_deref(), Some("rd.example.com"));
+        assert_eq!(
+            addrs.by_transport[Transport::Ws as usize].as_deref(),
+            Some("rd.example.com:443")
+        );
+        assert_eq!(addrs.by_transport[Transport::Tcp as usize], None);
+        ADDRS.set(addrs).ok();
+        let configured = vec!["rd2.example.com".to_owned(), "rd.example.com".to_owned()];
+        assert_eq!(
+            rendezvous_servers(Transport::Udp, &configured),
+            vec!["rd.example.com".to_owned(), "rd2.example.com".to_owned()]
+        );
+    }
+}
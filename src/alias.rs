@@ -0,0 +1,215 @@
+use crate::{
+    database::{Database, PeerStorage},
+    log_id,
+};
+use hbb_common::{
+    log,
+    rendezvous_proto::{rendezvous_message::Union, RendezvousMessage},
+    ResultType,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    sync::Mutex,
+};
+
+const MAX_ALIASES: usize = 100_000;
+const MAX_CHANGES: usize = 10_000;
+
+#[derive(Default)]
+struct Changes {
+    seq: u64,
+    // (seq, alias, the new id or None if removed), the last MAX_CHANGES
+    log: VecDeque<(u64, String, Option<String>)>,
+}
+
+lazy_static::lazy_static! {
+    // alias -> the id it stands for now
+    static ref ALIASES: Mutex<HashMap<String, String>> = Default::default();
+    static ref CHANGES: Mutex<Changes> = Default::default();
+    // a version of an earlier run is never taken for one of this run
+    static ref EPOCH: u32 = u32::from_le_bytes(
+        sodiumoxide::randombytes::randombytes(4)
+            .try_into()
+            .unwrap_or_default()
+    );
+}
+
+/// Aliases let controllers connect to a name, e.g. `kiosk-lobby`, which is
+/// assigned to one device at a time through the admin API, so swapping the
+/// device doesn't change what the controllers have saved. Kept in the
+/// `peer_alias` table.
+pub(crate) async fn load(db: &Database) {
+    match db.get_aliases().await {
+        Ok(aliases) => {
+            let n = aliases.len();
+            if let Ok(mut map) = ALIASES.lock() {
+                map.extend(aliases);
+            }
+            if n > 0 {
+                log::info!("{} aliases", n);
+            }
+        }
+        Err(err) => log::error!("Failed to load aliases: {}", err),
+    }
+}
+
+/// Assign `alias` to `id`, which mustn't be an id itself.
+pub(crate) async fn set(
+    db: &Database,
+    storage: &dyn PeerStorage,
+    alias: &str,
+    id: &str,
+) -> ResultType<()> {
+    if alias.is_empty() || id.is_empty() || alias == id {
+        hbb_common::bail!("alias and id are required and must differ");
+    }
+    if storage.get_peer(alias).await?.is_some() {
+        hbb_common::bail!("{} is a registered id", alias);
+    }
+    if ALIASES.lock().map_or(0, |x| x.len()) >= MAX_ALIASES {
+        hbb_common::bail!("too many aliases");
+    }
+    db.set_alias(alias, id).await?;
+    if let Ok(mut map) = ALIASES.lock() {
+        map.insert(alias.to_owned(), id.to_owned());
+    }
+    record(alias, Some(id));
+    log::info!("alias {} assigned to {}", alias, log_id::id(id));
+    Ok(())
+}
+
+pub(crate) async fn remove(db: &Database, alias: &str) -> ResultType<bool> {
+    let removed = db.remove_alias(alias).await?;
+    if let Ok(mut map) = ALIASES.lock() {
+        map.remove(alias);
+    }
+    if removed {
+        record(alias, None);
+    }
+    Ok(removed)
+}
+
+fn record(alias: &str, id: Option<&str>) {
+    if let Ok(mut changes) = CHANGES.lock() {
+        changes.seq += 1;
+        let seq = changes.seq;
+        if changes.log.len() >= MAX_CHANGES {
+            changes.log.pop_front();
+        }
+        changes
+            .log
+            .push_back((seq, alias.to_owned(), id.map(str::to_owned)));
+    }
+}
+
+/// The version of the aliases, which changes with every change, for clients
+/// syncing them.
+pub(crate) fn version() -> String {
+    let seq = CHANGES.lock().map_or(0, |x| x.seq);
+    format!("{:08x}-{}", *EPOCH, seq)
+}
+
+/// The changes after `version`, the new id or None for a removed alias, each
+/// alias once. None if they are no longer known, the client has to fetch all
+/// aliases again.
+pub(crate) fn changes_since(version: &str) -> Option<Vec<(String, Option<String>)>> {
+    let (epoch, seq) = version.split_once('-')?;
+    if u32::from_str_radix(epoch, 16).ok()? != *EPOCH {
+        return None;
+    }
+    let seq = seq.parse::<u64>().ok()?;
+    let changes = CHANGES.lock().ok()?;
+    let oldest = changes.log.front().map_or(changes.seq + 1, |x| x.0);
+    if seq > changes.seq || seq + 1 < oldest {
+        return None;
+    }
+    let mut latest: HashMap<&str, &Option<String>> = HashMap::new();
+    for (_, alias, id) in changes.log.iter().filter(|x| x.0 > seq) {
+        latest.insert(alias, id);
+    }
+    let mut res: Vec<_> = latest
+        .into_iter()
+        .map(|(alias, id)| (alias.to_owned(), id.clone()))
+        .collect();
+    res.sort();
+    Some(res)
+}
+
+pub(crate) fn list() -> Vec<(String, String)> {
+    let mut res: Vec<_> = ALIASES
+        .lock()
+        .map(|x| x.iter().map(|(a, b)| (a.clone(), b.clone())).collect())
+        .unwrap_or_default();
+    res.sort();
+    res
+}
+
+/// Replace an alias among the ids a controller looks up by its device.
+pub(crate) fn apply(msg: &mut RendezvousMessage) {
+    let Ok(map) = ALIASES.lock() else {
+        return;
+    };
+    if map.is_empty() {
+        return;
+    }
+    let resolve = |id: &mut String| {
+        if let Some(x) = map.get(id.as_str()) {
+            *id = x.clone();
+        }
+    };
+    match msg.union.as_mut() {
+        Some(Union::PunchHoleRequest(x)) => resolve(&mut x.id),
+        Some(Union::RequestRelay(x)) => resolve(&mut x.id),
+        Some(Union::OnlineRequest(x)) => x.peers.iter_mut().for_each(resolve),
+        _ => {}
+    }
+}
+
+pub(crate) fn status() -> String {
+    let mut res = String::new();
+    for (alias, id) in list() {
+        let _ = writeln!(res, "{} {}", alias, id);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::rendezvous_proto::{OnlineRequest, PunchHoleRequest};
+
+    #[test]
+    fn resolves_aliases_of_lookups() {
+        if let Ok(mut map) = ALIASES.lock() {
+            map.insert("kiosk-lobby".to_owned(), "123456789".to_owned());
+        }
+        let mut msg = RendezvousMessage::new();
+        msg.set_punch_hole_request(PunchHoleRequest {
+            id: "kiosk-lobby".to_owned(),
+            ..Default::default()
+        });
+        apply(&mut msg);
+        assert_eq!(msg.punch_hole_request().id, "123456789");
+        msg.set_online_request(OnlineRequest {
+            peers: vec!["kiosk-lobby".to_owned(), "987654321".to_owned()],
+            ..Default::default()
+        });
+        apply(&mut msg);
+        assert_eq!(msg.online_request().peers, ["123456789", "987654321"]);
+    }
+
+    #[test]
+    fn syncs_changes_since_a_version() {
+        let version = version();
+        record("kiosk-hall", Some("111111111"));
+        record("kiosk-hall", Some("222222222"));
+        record("kiosk-door", None);
+        let changes = changes_since(&version).unwrap();
+        assert!(changes.contains(&("kiosk-hall".to_owned(), Some("222222222".to_owned()))));
+        assert!(changes.contains(&("kiosk-door".to_owned(), None)));
+        assert!(changes_since(&version()).unwrap().is_empty());
+        assert!(changes_since(&format!("{:08x}-0", EPOCH.wrapping_add(1))).is_none());
+        assert!(changes_since("garbage").is_none());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::common::{get_arg, get_arg_or};
+use hbb_common::log;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// `PeerDiscovery.cmd` of a device reporting that a brokered session failed
+/// password authentication, sent over udp from its registered address.
+pub(crate) const CMD: &str = "auth-failed";
+// how long after a punch hole request a report is attributed to its requester
+pub(crate) const REPORT_DUR: u64 = 600; // in seconds
+
+const DEFAULT_WINDOW: u64 = 3600; // in seconds
+const DEFAULT_BAN_MINUTES: u64 = 60;
+const MAX_SOURCES: usize = 100_000;
+
+static BAN_THRESHOLD: AtomicUsize = AtomicUsize::new(0); // 0 means only aggregate
+static WINDOW: AtomicU64 = AtomicU64::new(DEFAULT_WINDOW);
+static BAN_MINUTES: AtomicU64 = AtomicU64::new(DEFAULT_BAN_MINUTES);
+static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+struct Source {
+    window: Option<Instant>,
+    failures: usize,
+    devices: HashSet<String>,
+    reported: HashSet<(String, Instant)>, // (device, punch request), once each
+    banned_until: Option<Instant>,
+}
+
+lazy_static::lazy_static! {
+    // controller ip -> failures reported by devices
+    static ref SOURCES: Mutex<HashMap<String, Source>> = Default::default();
+}
+
+pub(crate) fn init() {
+    BAN_THRESHOLD.store(get_arg("AUTH_FAIL_BAN").parse().unwrap_or(0), Ordering::SeqCst);
+    WINDOW.store(
+        get_arg_or("AUTH_FAIL_WINDOW", DEFAULT_WINDOW.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_WINDOW),
+        Ordering::SeqCst,
+    );
+    BAN_MINUTES.store(
+        get_arg_or("AUTH_FAIL_BAN_MINUTES", DEFAULT_BAN_MINUTES.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_BAN_MINUTES),
+        Ordering::SeqCst,
+    );
+    if BAN_THRESHOLD.load(Ordering::SeqCst) > 0 {
+        log::info!(
+            "AUTH_FAIL_BAN={}, AUTH_FAIL_WINDOW={}s, AUTH_FAIL_BAN_MINUTES={}",
+            BAN_THRESHOLD.load(Ordering::SeqCst),
+            WINDOW.load(Ordering::SeqCst),
+            BAN_MINUTES.load(Ordering::SeqCst)
+        );
+    }
+}
+
+/// Record a failed authentication of the controller at `ip` reported by
+/// `device` for the punch hole request made at `request`.
+pub(crate) fn report(ip: &str, device: &str, request: Instant) {
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+    let Ok(mut sources) = SOURCES.lock() else {
+        return;
+    };
+    let window = WINDOW.load(Ordering::Relaxed);
+    if sources.len() >= MAX_SOURCES && !sources.contains_key(ip) {
+        sources.retain(|_, s| !is_idle(s, window));
+        if sources.len() >= MAX_SOURCES {
+            return;
+        }
+    }
+    let source = sources.entry(ip.to_owned()).or_default();
+    let threshold = BAN_THRESHOLD.load(Ordering::Relaxed);
+    let minutes = BAN_MINUTES.load(Ordering::Relaxed);
+    if add_failure(source, device, request, window, threshold, minutes) {
+        log::warn!(
+            "Banned {} for {} minutes after {} failed authentications on {} devices",
+            ip,
+            minutes,
+            source.failures,
+            source.devices.len()
+        );
+    }
+}
+
+// Returns true if the source just got banned, by as many devices as the
+// threshold, so one device or anyone spoofing it can't ban a controller.
+fn add_failure(
+    source: &mut Source,
+    device: &str,
+    request: Instant,
+    window: u64,
+    threshold: usize,
+    minutes: u64,
+) -> bool {
+    if source.window.is_none_or(|x| x.elapsed().as_secs() >= window) {
+        source.window = Some(Instant::now());
+        source.failures = 0;
+        source.devices.clear();
+        source.reported.clear();
+    }
+    if !source.reported.insert((device.to_owned(), request)) {
+        return false;
+    }
+    source.failures += 1;
+    source.devices.insert(device.to_owned());
+    if threshold == 0 || source.devices.len() < threshold || is_banned_source(source).is_some() {
+        return false;
+    }
+    source.banned_until = Some(Instant::now() + Duration::from_secs(minutes * 60));
+    true
+}
+
+#[inline]
+fn is_banned_source(source: &Source) -> Option<u64> {
+    let until = source.banned_until?;
+    let now = Instant::now();
+    (until > now).then(|| (until - now).as_secs() / 60 + 1)
+}
+
+#[inline]
+fn is_idle(source: &Source, window: u64) -> bool {
+    is_banned_source(source).is_none()
+        && source.window.is_none_or(|x| x.elapsed().as_secs() >= window)
+}
+
+/// Remaining minutes if requests from `ip` are refused.
+pub(crate) fn is_banned(ip: &str) -> Option<u64> {
+    is_banned_source(SOURCES.lock().ok()?.get(ip)?)
+}
+
+pub(crate) fn unban(ip: &str) {
+    if let Ok(mut sources) = SOURCES.lock() {
+        sources.remove(ip);
+    }
+}
+
+/// Sources with the most failures first.
+pub(crate) fn status(n: usize) -> String {
+    let mut res = format!(
+        "ban: {}/{}s for {} minutes\nreports: {}\n",
+        BAN_THRESHOLD.load(Ordering::SeqCst),
+        WINDOW.load(Ordering::SeqCst),
+        BAN_MINUTES.load(Ordering::SeqCst),
+        REPORTS.load(Ordering::Relaxed)
+    );
+    let Ok(sources) = SOURCES.lock() else {
+        return res;
+    };
+    let mut v: Vec<_> = sources.iter().collect();
+    v.sort_by(|a, b| b.1.failures.cmp(&a.1.failures));
+    for (ip, s) in v.into_iter().take(n) {
+        let _ = write!(res, "{}: failures={} devices={}", ip, s.failures, s.devices.len());
+        if let Some(minutes) = is_banned_source(s) {
+            let _ = write!(res, " banned for {} minutes", minutes);
+        }
+        res.push('\n');
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_after_threshold() {
+        let (r1, r2) = (Instant::now(), Instant::now() + Duration::from_secs(1));
+        let mut source = Source::default();
+        assert!(!add_failure(&mut source, "1", r1, 60, 3, 10));
+        assert!(!add_failure(&mut source, "2", r1, 60, 3, 10));
+        assert!(add_failure(&mut source, "3", r1, 60, 3, 10));
+        assert_eq!(source.devices.len(), 3);
+        assert_eq!(is_banned_source(&source), Some(10));
+        // already banned
+        assert!(!add_failure(&mut source, "4", r1, 60, 3, 10));
+        let mut source = Source::default();
+        for _ in 0..10 {
+            assert!(!add_failure(&mut source, "1", r1, 60, 0, 10));
+        }
+        assert_eq!(is_banned_source(&source), None);
+        // one device reporting again and again
+        let mut source = Source::default();
+        for _ in 0..10 {
+            assert!(!add_failure(&mut source, "1", r1, 60, 3, 10));
+            assert!(!add_failure(&mut source, "1", r2, 60, 3, 10));
+        }
+        assert_eq!((source.failures, is_banned_source(&source)), (2, None));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::{common::now, database::Database, id_norm, log_id};
+use hbb_common::{log, try_into_v4, ResultType};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+const MAX_BANS: usize = 100_000;
+
+static REFUSED: AtomicUsize = AtomicUsize::new(0);
+
+struct Ban {
+    reason: String,
+    operator: String,
+    created_at: u64,
+    until: u64, // 0 is for good
+}
+
+lazy_static::lazy_static! {
+    // banned id or ip -> the ban
+    static ref BANS: Mutex<HashMap<String, Ban>> = Default::default();
+}
+
+/// Bans keep an id, or a source ip, from registering and an ip from
+/// requesting punch holes, e.g. while it tries passwords against well-known
+/// ids. Set by operators through the console or the admin API, kept in the
+/// `ban` table.
+pub(crate) async fn load(db: &Database) {
+    match db.get_bans(now() as i64).await {
+        Ok(bans) => {
+            let n = bans.len();
+            if let Ok(mut map) = BANS.lock() {
+                for (target, reason, operator, created_at, until) in bans {
+                    map.insert(
+                        target,
+                        Ban {
+                            reason,
+                            operator,
+                            created_at: created_at as u64,
+                            until: until as u64,
+                        },
+                    );
+                }
+            }
+            if n > 0 {
+                log::info!("{} bans", n);
+            }
+        }
+        Err(err) => log::error!("Failed to load bans: {}", err),
+    }
+}
+
+// ips in one form, whichever way they were typed
+fn normalize(target: &str) -> String {
+    match target.trim().parse::<IpAddr>() {
+        Ok(ip) => try_into_v4(SocketAddr::new(ip, 0)).ip().to_string(),
+        Err(_) => id_norm::normalize(target),
+    }
+}
+
+/// Ban an id or an ip for `minutes`, 0 for good.
+pub(crate) async fn add(
+    db: &Database,
+    target: &str,
+    reason: &str,
+    operator: &str,
+    minutes: u64,
+) -> ResultType<()> {
+    let target = normalize(target);
+    if target.is_empty() {
+        hbb_common::bail!("id or ip is required");
+    }
+    let until = if minutes == 0 { 0 } else { now() + minutes * 60 };
+    {
+        let Ok(bans) = BANS.lock() else {
+            hbb_common::bail!("bans unavailable");
+        };
+        if bans.len() >= MAX_BANS && !bans.contains_key(&target) {
+            hbb_common::bail!("too many bans");
+        }
+    }
+    db.set_ban(&target, reason, operator, until as _).await?;
+    if let Ok(mut bans) = BANS.lock() {
+        bans.insert(
+            target.clone(),
+            Ban {
+                reason: reason.to_owned(),
+                operator: operator.to_owned(),
+                created_at: now(),
+                until,
+            },
+        );
+    }
+    log::info!("{} banned by {}: {}", log_id::id(&target), operator, reason);
+    Ok(())
+}
+
+pub(crate) async fn remove(db: &Database, target: &str) -> ResultType<bool> {
+    let target = normalize(target);
+    let removed = db.remove_ban(&target).await?;
+    if let Ok(mut bans) = BANS.lock() {
+        bans.remove(&target);
+    }
+    if removed {
+        log::info!("{} unbanned", log_id::id(&target));
+    }
+    Ok(removed)
+}
+
+fn is_banned(bans: &mut HashMap<String, Ban>, target: &str) -> bool {
+    match bans.get(target) {
+        Some(ban) if ban.until == 0 || ban.until > now() => true,
+        Some(_) => {
+            bans.remove(target);
+            false
+        }
+        None => false,
+    }
+}
+
+/// Whether `id`, if any, or the ip of `addr` is banned, counted if so.
+pub(crate) fn refuses(id: &str, addr: SocketAddr) -> bool {
+    let Ok(mut bans) = BANS.lock() else {
+        return false;
+    };
+    if bans.is_empty() {
+        return false;
+    }
+    let ip = try_into_v4(addr).ip().to_string();
+    let banned = (!id.is_empty() && is_banned(&mut bans, id)) || is_banned(&mut bans, &ip);
+    if banned {
+        REFUSED.fetch_add(1, Ordering::Relaxed);
+    }
+    banned
+}
+
+/// (target, reason, operator, created_at, until) of the running bans.
+pub(crate) fn list() -> Vec<(String, String, String, u64, u64)> {
+    let now = now();
+    let mut res: Vec<_> = BANS
+        .lock()
+        .map(|x| {
+            x.iter()
+                .filter(|(_, ban)| ban.until == 0 || ban.until > now)
+                .map(|(target, ban)| {
+                    (
+                        target.clone(),
+                        ban.reason.clone(),
+                        ban.operator.clone(),
+                        ban.created_at,
+                        ban.until,
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    res.sort();
+    res
+}
+
+/// The running bans as csv.
+pub(crate) fn status() -> String {
+    let mut res = format!(
+        "refused: {}\ntime,target,reason,operator,until\n",
+        REFUSED.load(Ordering::Relaxed)
+    );
+    for (target, reason, operator, tm, until) in list() {
+        let _ = writeln!(res, "{},{},{},{},{}", tm, target, reason, operator, until);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_banned_ids_and_ips() {
+        if let Ok(mut bans) = BANS.lock() {
+            let ban = |until| Ban {
+                reason: "brute force".to_owned(),
+                operator: "console".to_owned(),
+                created_at: now(),
+                until,
+            };
+            bans.insert("123456789".to_owned(), ban(0));
+            bans.insert(normalize("::ffff:10.0.0.9"), ban(now() + 60));
+            bans.insert("987654321".to_owned(), ban(now() - 1));
+        }
+        let (addr, banned_addr): (SocketAddr, SocketAddr) =
+            ("10.0.0.1:1".parse().unwrap(), "10.0.0.9:1".parse().unwrap());
+        assert!(refuses("123456789", addr));
+        assert!(refuses("", banned_addr));
+        assert!(!refuses("987654321", addr));
+        assert!(!refuses("", addr));
+        assert_eq!(list().len(), 2);
+    }
+}
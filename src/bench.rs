@@ -0,0 +1,326 @@
+use crate::common::get_arg;
+use hbb_common::{
+    bail,
+    config::RENDEZVOUS_PORT,
+    protobuf::Message as _,
+    rendezvous_proto::*,
+    tcp::FramedStream,
+    tokio::{
+        self,
+        time::{interval_at, sleep, Instant},
+    },
+    udp::FramedSocket,
+    AddrMangle, ResultType,
+};
+use sodiumoxide::crypto::{hash::sha256, sign};
+use std::{
+    fmt::Write as _,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::Mutex,
+    time::Duration,
+};
+
+const USAGE: &str = "Usage: hbbs bench [<host[:port]>] [<peers>] [<requests/s>] [<seconds>]";
+const REG_INTERVAL: u64 = 12_000; // in ms, as clients
+const TIMEOUT: u64 = 3_000; // in ms
+const MAX_PK_SKIP: u32 = 15; // in registrations, about 3 minutes
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    server: String,
+    peers: usize,
+    rate: u64, // punch hole requests per second
+    secs: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    sent: usize,
+    failed: usize,       // refused, or answered late or never
+    latencies: Vec<u64>, // in µs
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTER: Mutex<Stats> = Default::default();
+    static ref REGISTER_PK: Mutex<Stats> = Default::default();
+    static ref PUNCH: Mutex<Stats> = Default::default();
+}
+
+/// `hbbs bench` simulates peers registering with an hbbs over UDP every 12
+/// seconds, as clients do, and connection requests to them over TCP, which
+/// the peers answer, to measure how fast hbbs handles them under load before
+/// a change is deployed. Peers are `bench000000` and up, their keys derived
+/// from their ids, so they keep their records across runs; use a database
+/// of its own. They send their key when hbbs asks for it, backing off while
+/// it's refused, e.g. by the limits per IP address. Each peer takes a UDP
+/// socket, raise `ulimit -n` for thousands.
+pub fn main(args: &[String]) -> ResultType<()> {
+    let opts = match parse(args) {
+        Ok(opts) => opts,
+        Err(err) => bail!("{}\n{}", err, USAGE),
+    };
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(opts))
+}
+
+fn parse(args: &[String]) -> Result<Options, &'static str> {
+    let arg = |i: usize| args.get(i).map(|x| x.as_str());
+    if args.len() > 4 {
+        return Err("too many arguments");
+    }
+    let server = match arg(0) {
+        Some(x) if x.contains(':') => x.to_owned(),
+        Some(x) => format!("{x}:{RENDEZVOUS_PORT}"),
+        None => format!("127.0.0.1:{RENDEZVOUS_PORT}"),
+    };
+    let number = |i: usize, default: u64| match arg(i) {
+        Some(x) => x.parse::<u64>().ok().filter(|x| *x > 0),
+        None => Some(default),
+    };
+    match (number(1, 1_000), number(2, 100), number(3, 30)) {
+        (Some(peers), Some(rate), Some(secs)) => Ok(Options {
+            server,
+            peers: peers as _,
+            rate,
+            secs,
+        }),
+        _ => Err("peers, requests/s and seconds are positive numbers"),
+    }
+}
+
+async fn run(opts: Options) -> ResultType<()> {
+    let Some(server) = opts.server.to_socket_addrs()?.next() else {
+        bail!("Failed to resolve {}", opts.server);
+    };
+    let ip = match server.ip() {
+        IpAddr::V4(x) if x.is_loopback() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(x) if x.is_loopback() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    println!("Registering {} peers with {}", opts.peers, server);
+    for i in 0..opts.peers {
+        let socket = match FramedSocket::new(SocketAddr::new(ip, 0)).await {
+            Ok(socket) => socket,
+            Err(err) => bail!("Socket of peer {}: {}, raise ulimit -n", i, err),
+        };
+        // spread over the interval, as clients start at different times
+        let offset = i as u64 * REG_INTERVAL / opts.peers as u64;
+        let start = Instant::now() + Duration::from_millis(offset);
+        tokio::spawn(peer(id_of(i), socket, server, start));
+    }
+    sleep(Duration::from_millis(REG_INTERVAL + TIMEOUT)).await;
+    println!("Requesting {} connections/s for {}s", opts.rate, opts.secs);
+    let key = get_arg("key");
+    let mut timer = tokio::time::interval(Duration::from_micros((1_000_000 / opts.rate).max(1)));
+    for n in 0..opts.rate * opts.secs {
+        timer.tick().await;
+        // spread over the peers
+        let id = id_of((n as usize).wrapping_mul(7919) % opts.peers);
+        tokio::spawn(punch(id, server, key.clone()));
+    }
+    sleep(Duration::from_millis(TIMEOUT)).await;
+    print!("{}", report("registrations", &REGISTER));
+    print!("{}", report("keys", &REGISTER_PK));
+    print!("{}", report("punch holes", &PUNCH));
+    Ok(())
+}
+
+fn id_of(i: usize) -> String {
+    format!("bench{i:06}")
+}
+
+async fn peer(id: String, mut socket: FramedSocket, server: SocketAddr, start: Instant) {
+    let seed = sha256::hash(id.as_bytes());
+    let (pk, _) = sign::keypair_from_seed(&sign::Seed(seed.0));
+    let mut register_pk = RendezvousMessage::new();
+    register_pk.set_register_pk(RegisterPk {
+        id: id.clone(),
+        uuid: seed.0[..16].to_vec().into(),
+        pk: pk.0.to_vec().into(),
+        ..Default::default()
+    });
+    let local_addr = socket.local_addr().unwrap_or(server);
+    let mut timer = interval_at(start, Duration::from_millis(REG_INTERVAL));
+    let mut sent: Option<Instant> = None;
+    let mut pk_sent: Option<Instant> = None;
+    // registrations to let pass before sending the key again, doubling while refused
+    let (mut pk_skip, mut pk_wait) = (0, 0);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                if sent.is_some() {
+                    update(&REGISTER, |x| x.failed += 1);
+                }
+                let mut msg_out = RendezvousMessage::new();
+                msg_out.set_register_peer(RegisterPeer {
+                    id: id.clone(),
+                    ..Default::default()
+                });
+                if socket.send(&msg_out, server).await.is_ok() {
+                    sent = Some(Instant::now());
+                    update(&REGISTER, |x| x.sent += 1);
+                }
+            }
+            Some(Ok((bytes, _))) = socket.next() => {
+                let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) else {
+                    continue;
+                };
+                match msg_in.union {
+                    Some(rendezvous_message::Union::RegisterPeerResponse(rpr)) => {
+                        if let Some(sent) = sent.take() {
+                            let us = sent.elapsed().as_micros() as u64;
+                            update(&REGISTER, |x| x.latencies.push(us));
+                        }
+                        if !rpr.request_pk {
+                            continue;
+                        }
+                        if pk_wait > 0 {
+                            pk_wait -= 1;
+                            continue;
+                        }
+                        if socket.send(&register_pk, server).await.is_ok() {
+                            pk_sent = Some(Instant::now());
+                            update(&REGISTER_PK, |x| x.sent += 1);
+                        }
+                        pk_wait = pk_skip;
+                        pk_skip = (pk_skip * 2 + 1).min(MAX_PK_SKIP);
+                    }
+                    Some(rendezvous_message::Union::RegisterPkResponse(res)) => {
+                        let Some(pk_sent) = pk_sent.take() else {
+                            continue;
+                        };
+                        if res.result.enum_value() == Ok(register_pk_response::Result::OK) {
+                            let us = pk_sent.elapsed().as_micros() as u64;
+                            update(&REGISTER_PK, |x| x.latencies.push(us));
+                            (pk_skip, pk_wait) = (0, 0);
+                        } else {
+                            update(&REGISTER_PK, |x| x.failed += 1);
+                        }
+                    }
+                    // answered over tcp, as clients do
+                    Some(rendezvous_message::Union::PunchHole(ph)) => {
+                        let mut msg_out = RendezvousMessage::new();
+                        msg_out.set_punch_hole_sent(PunchHoleSent {
+                            socket_addr: ph.socket_addr,
+                            id: id.clone(),
+                            relay_server: ph.relay_server,
+                            nat_type: NatType::ASYMMETRIC.into(),
+                            ..Default::default()
+                        });
+                        tokio::spawn(send_tcp(server, msg_out));
+                    }
+                    Some(rendezvous_message::Union::FetchLocalAddr(fla)) => {
+                        let mut msg_out = RendezvousMessage::new();
+                        msg_out.set_local_addr(LocalAddr {
+                            socket_addr: fla.socket_addr,
+                            local_addr: AddrMangle::encode(local_addr).into(),
+                            relay_server: fla.relay_server,
+                            id: id.clone(),
+                            ..Default::default()
+                        });
+                        tokio::spawn(send_tcp(server, msg_out));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+async fn send_tcp(server: SocketAddr, msg_out: RendezvousMessage) {
+    if let Ok(mut stream) = FramedStream::new(server, None, TIMEOUT).await {
+        stream.send(&msg_out).await.ok();
+    }
+}
+
+// A connection request to `id` until its answer came back through hbbs.
+async fn punch(id: String, server: SocketAddr, key: String) {
+    let start = Instant::now();
+    update(&PUNCH, |x| x.sent += 1);
+    match request(id, server, key).await {
+        Ok(true) => {
+            let us = start.elapsed().as_micros() as u64;
+            update(&PUNCH, |x| x.latencies.push(us));
+        }
+        _ => update(&PUNCH, |x| x.failed += 1),
+    }
+}
+
+async fn request(id: String, server: SocketAddr, key: String) -> ResultType<bool> {
+    let mut stream = FramedStream::new(server, None, TIMEOUT).await?;
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_punch_hole_request(PunchHoleRequest {
+        id,
+        nat_type: NatType::ASYMMETRIC.into(),
+        licence_key: key,
+        ..Default::default()
+    });
+    stream.send(&msg_out).await?;
+    let Some(Ok(bytes)) = stream.next_timeout(TIMEOUT).await else {
+        return Ok(false);
+    };
+    match RendezvousMessage::parse_from_bytes(&bytes)?.union {
+        Some(rendezvous_message::Union::PunchHoleResponse(res)) => {
+            Ok(res.other_failure.is_empty() && !res.socket_addr.is_empty())
+        }
+        _ => Ok(false),
+    }
+}
+
+fn update(stats: &Mutex<Stats>, f: impl FnOnce(&mut Stats)) {
+    if let Ok(mut stats) = stats.lock() {
+        f(&mut stats);
+    }
+}
+
+fn report(name: &str, stats: &Mutex<Stats>) -> String {
+    let Ok(mut stats) = stats.lock() else {
+        return "".to_owned();
+    };
+    stats.latencies.sort_unstable();
+    let mut res = String::new();
+    let _ = writeln!(
+        res,
+        "{}: sent {}, answered {}, failed {} ({:.2}%), p50 {:.2}ms, p99 {:.2}ms",
+        name,
+        stats.sent,
+        stats.latencies.len(),
+        stats.failed,
+        (stats.failed * 100) as f64 / stats.sent.max(1) as f64,
+        percentile(&stats.latencies, 50) as f64 / 1000.,
+        percentile(&stats.latencies, 99) as f64 / 1000.
+    );
+    res
+}
+
+// of sorted values, 0 if there are none
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    sorted[((sorted.len() * p).div_ceil(100)).clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_options_and_percentiles() {
+        let args = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let opts = parse(&args(&["hbbs.example.com", "5000", "200"])).unwrap();
+        assert_eq!(opts.server, format!("hbbs.example.com:{RENDEZVOUS_PORT}"));
+        assert_eq!((opts.peers, opts.rate, opts.secs), (5000, 200, 30));
+        assert_eq!(parse(&args(&[])).unwrap().server, format!("127.0.0.1:{RENDEZVOUS_PORT}"));
+        assert!(parse(&args(&["127.0.0.1:21116", "0"])).is_err());
+        assert!(parse(&args(&["127.0.0.1:21116", "x"])).is_err());
+        let sorted: Vec<u64> = (1..=200).collect();
+        assert_eq!(percentile(&sorted, 50), 100);
+        assert_eq!(percentile(&sorted, 99), 198);
+        assert_eq!(percentile(&[7], 99), 7);
+        assert_eq!(percentile(&[], 50), 0);
+    }
+}
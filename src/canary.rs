@@ -0,0 +1,127 @@
+use crate::common::get_arg;
+use hbb_common::log;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+static PERCENT: AtomicUsize = AtomicUsize::new(0);
+static REG_TIMEOUT: AtomicI64 = AtomicI64::new(0); // in ms, 0 means same as stable
+static PUNCH_REQUESTS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+static OFFLINE: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// Which policy version applies to a peer, chosen by a stable hash of its id
+/// so a peer stays in the same cohort across restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Cohort {
+    Stable = 0,
+    Canary = 1,
+}
+
+pub(crate) fn init() {
+    set_percent(get_arg("CANARY_PERCENT").parse().unwrap_or(0));
+    let v = get_arg("CANARY_REG_TIMEOUT").parse::<i64>().unwrap_or(0);
+    REG_TIMEOUT.store(v.max(0), Ordering::SeqCst);
+    if percent() > 0 {
+        log::info!(
+            "CANARY_PERCENT={}, CANARY_REG_TIMEOUT={}ms",
+            percent(),
+            REG_TIMEOUT.load(Ordering::SeqCst)
+        );
+    }
+}
+
+#[inline]
+pub(crate) fn percent() -> usize {
+    PERCENT.load(Ordering::SeqCst)
+}
+
+#[inline]
+pub(crate) fn set_percent(v: usize) {
+    PERCENT.store(v.min(100), Ordering::SeqCst);
+}
+
+impl Cohort {
+    #[inline]
+    pub(crate) fn of(id: &str) -> Self {
+        if is_canary(id, percent()) {
+            Cohort::Canary
+        } else {
+            Cohort::Stable
+        }
+    }
+
+    #[inline]
+    pub(crate) fn reg_timeout(self, default: i64) -> i64 {
+        match self {
+            Cohort::Canary => match REG_TIMEOUT.load(Ordering::Relaxed) {
+                0 => default,
+                v => v,
+            },
+            Cohort::Stable => default,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn on_punch_request(self) {
+        PUNCH_REQUESTS[self as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn on_offline(self) {
+        OFFLINE[self as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn status() -> String {
+    use std::fmt::Write as _;
+
+    let mut res = format!(
+        "percent: {}\nreg_timeout: {}ms\n",
+        percent(),
+        REG_TIMEOUT.load(Ordering::SeqCst)
+    );
+    for (name, c) in [("stable", Cohort::Stable), ("canary", Cohort::Canary)] {
+        let _ = writeln!(
+            res,
+            "{}: punch_requests={} offline={}",
+            name,
+            PUNCH_REQUESTS[c as usize].load(Ordering::Relaxed),
+            OFFLINE[c as usize].load(Ordering::Relaxed)
+        );
+    }
+    res
+}
+
+// FNV-1a, std's hasher isn't guaranteed stable across releases
+fn hash_id(id: &str) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in id.as_bytes() {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+#[inline]
+fn is_canary(id: &str, percent: usize) -> bool {
+    (hash_id(id) % 100) < percent as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cohort_is_stable_and_proportional() {
+        assert_eq!(hash_id(""), 0xcbf29ce484222325);
+        assert_eq!(hash_id("a"), 0xaf63dc4c8601ec8c);
+        let ids: Vec<String> = (0..10_000).map(|i| (100_000_000 + i).to_string()).collect();
+        assert!(!ids.iter().any(|id| is_canary(id, 0)));
+        assert!(ids.iter().all(|id| is_canary(id, 100)));
+        let n = ids.iter().filter(|id| is_canary(id, 10)).count();
+        assert!((800..1200).contains(&n), "{n}");
+        // raising the percentage only adds peers to the canary cohort
+        assert!(ids
+            .iter()
+            .filter(|id| is_canary(id, 10))
+            .all(|id| is_canary(id, 20)));
+    }
+}
@@ -0,0 +1,149 @@
+use hbb_common::{log, rendezvous_proto::*, try_into_v4};
+use std::{
+    fmt::Write as _,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+pub(crate) const DEFAULT_DURATION: u64 = 600; // in seconds
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref CAPTURE: Mutex<Option<Capture>> = Default::default();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Id(String),
+    Ip(IpAddr),
+}
+
+struct Capture {
+    target: Target,
+    until: Instant,
+}
+
+impl Target {
+    fn parse(s: &str) -> Self {
+        match s.parse::<IpAddr>() {
+            Ok(ip) => Target::Ip(ip),
+            Err(_) => Target::Id(s.to_owned()),
+        }
+    }
+
+    fn matches(&self, addr: SocketAddr, msg: &RendezvousMessage) -> bool {
+        match self {
+            Target::Ip(ip) => try_into_v4(addr).ip() == *ip,
+            Target::Id(id) => get_msg_id(msg) == Some(id.as_str()),
+        }
+    }
+}
+
+pub(crate) fn start(target: &str, secs: u64) {
+    if let Ok(mut lock) = CAPTURE.lock() {
+        let target = Target::parse(target);
+        log::info!("Capture of {:?} started for {}s", target, secs);
+        *lock = Some(Capture {
+            target,
+            until: Instant::now() + Duration::from_secs(secs),
+        });
+        ACTIVE.store(true, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn stop() {
+    if let Ok(mut lock) = CAPTURE.lock() {
+        if let Some(c) = lock.take() {
+            log::info!("Capture of {:?} stopped", c.target);
+        }
+        ACTIVE.store(false, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn status() -> String {
+    let lock = CAPTURE.lock();
+    let capture = match &lock {
+        Ok(x) => Option::as_ref(x),
+        Err(_) => None,
+    };
+    match capture {
+        Some(c) => format!(
+            "{:?}, {}s left\n",
+            c.target,
+            c.until.saturating_duration_since(Instant::now()).as_secs()
+        ),
+        None => "off\n".to_owned(),
+    }
+}
+
+/// Log the raw and decoded message if it involves the captured peer id or ip.
+#[inline]
+pub(crate) fn log_msg(tag: &str, addr: SocketAddr, bytes: &[u8], msg: &RendezvousMessage) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let Ok(mut lock) = CAPTURE.lock() else {
+        return;
+    };
+    let matched = match lock.as_ref() {
+        Some(c) if c.until <= Instant::now() => {
+            log::info!("Capture of {:?} expired", c.target);
+            *lock = None;
+            ACTIVE.store(false, Ordering::SeqCst);
+            false
+        }
+        Some(c) => c.target.matches(addr, msg),
+        None => false,
+    };
+    drop(lock);
+    if matched {
+        log::info!("[capture] {} {}: {} {:?}", tag, addr, to_hex(bytes), msg);
+    }
+}
+
+pub(crate) fn get_msg_id(msg: &RendezvousMessage) -> Option<&str> {
+    use rendezvous_message::Union;
+    match msg.union.as_ref()? {
+        Union::RegisterPeer(x) => Some(&x.id),
+        Union::RegisterPk(x) => Some(&x.id),
+        Union::PunchHoleRequest(x) => Some(&x.id),
+        Union::PunchHoleSent(x) => Some(&x.id),
+        Union::LocalAddr(x) => Some(&x.id),
+        Union::RequestRelay(x) => Some(&x.id),
+        Union::OnlineRequest(x) => Some(&x.id),
+        _ => None,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_matches_ip_or_id() {
+        let mut msg = RendezvousMessage::new();
+        msg.set_register_peer(RegisterPeer {
+            id: "123456789".to_owned(),
+            ..Default::default()
+        });
+        let addr: SocketAddr = "[::ffff:10.0.0.1]:21116".parse().unwrap();
+        assert!(Target::parse("10.0.0.1").matches(addr, &msg));
+        assert!(Target::parse("123456789").matches(addr, &msg));
+        assert!(!Target::parse("10.0.0.2").matches(addr, &msg));
+        assert!(!Target::parse("987654321").matches(addr, &msg));
+        assert_eq!(to_hex(&[0x0b, 0xff]), "0bff");
+    }
+}
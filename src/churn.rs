@@ -0,0 +1,190 @@
+use crate::common::{get_arg, now};
+use hbb_common::log;
+use ipnetwork::IpNetwork;
+use once_cell::sync::OnceCell;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    net::IpAddr,
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+const MAX_HOURS: usize = 7 * 24;
+const OTHER: &str = "other";
+
+// (site name, networks), the last one is OTHER
+static SITES: OnceCell<Vec<(String, Vec<IpNetwork>)>> = OnceCell::new();
+
+#[derive(Default)]
+struct Hour {
+    hour: u64, // since the epoch
+    online: u32,
+    offline: u32,
+}
+
+#[derive(Default)]
+struct Churn {
+    // id -> site of the online peers
+    online: HashMap<String, usize>,
+    // per site, oldest first
+    hours: Vec<VecDeque<Hour>>,
+}
+
+lazy_static::lazy_static! {
+    static ref CHURN: Mutex<Churn> = Default::default();
+}
+
+/// `CHURN_SITES` is a comma separated list of `name=cidr`, a site may have
+/// several networks. Peers in none of them count as `other`. Empty is off.
+pub(crate) fn init() {
+    let v = get_arg("CHURN_SITES");
+    if v.is_empty() {
+        return;
+    }
+    let sites = parse_sites(&v);
+    log::info!(
+        "CHURN_SITES={}",
+        sites.iter().map(|x| x.0.as_str()).collect::<Vec<_>>().join(",")
+    );
+    if let Ok(mut churn) = CHURN.lock() {
+        churn.hours = sites.iter().map(|_| VecDeque::new()).collect();
+    }
+    SITES.set(sites).ok();
+}
+
+fn parse_sites(v: &str) -> Vec<(String, Vec<IpNetwork>)> {
+    let mut sites: Vec<(String, Vec<IpNetwork>)> = Vec::new();
+    for x in v.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let Some((name, net)) = x.split_once('=') else {
+            log::error!("Invalid churn site {}, expected <name>=<cidr>", x);
+            continue;
+        };
+        let Ok(net) = net.trim().parse::<IpNetwork>() else {
+            log::error!("Invalid network of churn site {}", x);
+            continue;
+        };
+        let name = name.trim();
+        match sites.iter_mut().find(|s| s.0 == name) {
+            Some(site) => site.1.push(net),
+            None => sites.push((name.to_owned(), vec![net])),
+        }
+    }
+    sites.push((OTHER.to_owned(), Vec::new()));
+    sites
+}
+
+fn site_of(sites: &[(String, Vec<IpNetwork>)], ip: IpAddr) -> usize {
+    sites
+        .iter()
+        .position(|s| s.1.iter().any(|net| net.contains(ip)))
+        .unwrap_or(sites.len() - 1)
+}
+
+#[inline]
+fn current_hour() -> u64 {
+    now() / 3600
+}
+
+impl Churn {
+    fn hour(&mut self, site: usize, hour: u64) -> Option<&mut Hour> {
+        let hours = self.hours.get_mut(site)?;
+        if hours.back().is_none_or(|x| x.hour != hour) {
+            hours.push_back(Hour {
+                hour,
+                ..Default::default()
+            });
+            if hours.len() > MAX_HOURS {
+                hours.pop_front();
+            }
+        }
+        hours.back_mut()
+    }
+}
+
+/// A peer came online from `ip`, see expiry.
+pub(crate) fn on_online(id: &str, ip: IpAddr) {
+    let Some(sites) = SITES.get() else {
+        return;
+    };
+    let site = site_of(sites, ip);
+    let Ok(mut churn) = CHURN.lock() else {
+        return;
+    };
+    if churn.online.insert(id.to_owned(), site).is_none() {
+        if let Some(h) = churn.hour(site, current_hour()) {
+            h.online += 1;
+        }
+    }
+}
+
+/// A peer's registration expired.
+pub(crate) fn on_offline(id: &str) {
+    if SITES.get().is_none() {
+        return;
+    }
+    let Ok(mut churn) = CHURN.lock() else {
+        return;
+    };
+    if let Some(site) = churn.online.remove(id) {
+        if let Some(h) = churn.hour(site, current_hour()) {
+            h.offline += 1;
+        }
+    }
+}
+
+/// Online/offline transitions per site and hour, as csv for a heatmap.
+pub(crate) fn status(site: Option<&str>) -> String {
+    let Some(sites) = SITES.get() else {
+        return "off, set CHURN_SITES\n".to_owned();
+    };
+    let Ok(churn) = CHURN.lock() else {
+        return "".to_owned();
+    };
+    let mut res = "site,hour,online,offline\n".to_owned();
+    for (i, (name, _)) in sites.iter().enumerate() {
+        if site.is_some_and(|x| x != name) {
+            continue;
+        }
+        for h in churn.hours.get(i).into_iter().flatten() {
+            let tm = UNIX_EPOCH + std::time::Duration::from_secs(h.hour * 3600);
+            let _ = writeln!(
+                res,
+                "{},{},{},{}",
+                name,
+                chrono::DateTime::<chrono::Utc>::from(tm)
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                h.online,
+                h.offline
+            );
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_map_to_sites() {
+        let sites = parse_sites("hq=10.1.0.0/16, branch=192.168.5.0/24,hq=2001:db8::/32,bad");
+        assert_eq!(sites.len(), 3);
+        assert_eq!(sites[0].1.len(), 2);
+        assert_eq!(site_of(&sites, "10.1.2.3".parse().unwrap()), 0);
+        assert_eq!(site_of(&sites, "2001:db8::1".parse().unwrap()), 0);
+        assert_eq!(site_of(&sites, "192.168.5.9".parse().unwrap()), 1);
+        assert_eq!(site_of(&sites, "8.8.8.8".parse().unwrap()), 2);
+        let mut churn = Churn {
+            hours: vec![VecDeque::new()],
+            ..Default::default()
+        };
+        for hour in 0..(MAX_HOURS as u64 + 5) {
+            if let Some(h) = churn.hour(0, hour) {
+                h.online += 1;
+            }
+        }
+        assert_eq!(churn.hours[0].len(), MAX_HOURS);
+        assert_eq!(churn.hours[0].front().map(|h| h.hour), Some(5));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{advertise, common::get_arg, dispatch::Transport};
+use flate2::{write::ZlibEncoder, Compression, Crc};
+use hbb_common::{log, ResultType};
+use once_cell::sync::OnceCell;
+use qrcode::{Color, QrCode};
+use std::io::Write as _;
+
+const MODULE_PIXELS: usize = 8;
+const QUIET_ZONE: usize = 4; // in modules, scanners need it
+
+// (host, relay, key) of the running server, host may be empty
+static SERVER: OnceCell<(String, String, String)> = OnceCell::new();
+
+/// The server settings clients import as one string, the way the client's
+/// "Export server config" writes them: the json, url-safe base64, reversed.
+/// `relay` may be empty, clients then use the relay next to `host`.
+pub fn encode(host: &str, relay: &str, key: &str) -> String {
+    let json = serde_json::json!({
+        "host": host,
+        "relay": relay,
+        "api": "",
+        "key": key,
+    });
+    base64::encode_config(json.to_string(), base64::URL_SAFE)
+        .chars()
+        .rev()
+        .collect()
+}
+
+/// What the mobile client scans to import `config`.
+#[inline]
+pub fn qr_payload(config: &str) -> String {
+    format!("config={config}")
+}
+
+/// Opens the client and imports `config`, `rustdesk --config <config>` does
+/// the same on the command line.
+#[inline]
+pub fn deep_link(config: &str) -> String {
+    format!("rustdesk://config/{config}")
+}
+
+/// A black on white PNG of the QR code of `data`.
+pub fn qr_png(data: &str) -> ResultType<Vec<u8>> {
+    let code = QrCode::new(data.as_bytes())?;
+    let width = code.width();
+    let colors = code.to_colors();
+    let size = (width + QUIET_ZONE * 2) * MODULE_PIXELS;
+    // 8-bit grayscale rows, each led by filter type 0
+    let mut raw = Vec::with_capacity((size + 1) * size);
+    for y in 0..size {
+        raw.push(0);
+        for x in 0..size {
+            let (mx, my) = (x / MODULE_PIXELS, y / MODULE_PIXELS);
+            let dark = mx >= QUIET_ZONE
+                && my >= QUIET_ZONE
+                && mx - QUIET_ZONE < width
+                && my - QUIET_ZONE < width
+                && colors[(my - QUIET_ZONE) * width + mx - QUIET_ZONE] == Color::Dark;
+            raw.push(if dark { 0 } else { 255 });
+        }
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&raw)?;
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend((size as u32).to_be_bytes());
+    ihdr.extend((size as u32).to_be_bytes());
+    ihdr.extend([8, 0, 0, 0, 0]); // bit depth, grayscale, compression, filter, no interlace
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut png, b"IHDR", &ihdr);
+    png_chunk(&mut png, b"IDAT", &encoder.finish()?);
+    png_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(data);
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend(crc.sum().to_be_bytes());
+}
+
+/// `CLIENT_CONFIG_HOST` is the address clients reach hbbs at, the relay is the
+/// first of `RELAY_SERVERS` and the key the one hbbs runs with, so the
+/// configuration handed out always matches the running server.
+pub(crate) fn init(key: &str) {
+    let host = get_arg("CLIENT_CONFIG_HOST");
+    let relay = get_arg("relay-servers")
+        .split(',')
+        .map(str::trim)
+        .find(|x| !x.is_empty())
+        .unwrap_or_default()
+        .to_owned();
+    if !host.is_empty() {
+        log::info!("CLIENT_CONFIG_HOST={}", host);
+    }
+    SERVER.set((host, relay, key.to_owned())).ok();
+}
+
+/// The configuration string of the running server, the address advertised
+/// over TCP is used if CLIENT_CONFIG_HOST isn't set, then `host`, None if
+/// there is none.
+pub(crate) fn current(host: &str) -> Option<String> {
+    let (configured, relay, key) = SERVER.get()?;
+    let host = if configured.is_empty() {
+        advertise::get(Transport::Tcp).unwrap_or(host)
+    } else {
+        configured
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(encode(host, relay, key))
+}
+
+/// The QR code of current() for the mobile client to scan.
+pub(crate) fn current_png(host: &str) -> Option<Vec<u8>> {
+    match qr_png(&qr_payload(&current(host)?)) {
+        Ok(png) => Some(png),
+        Err(err) => {
+            log::error!("Failed to encode the client config QR code: {}", err);
+            None
+        }
+    }
+}
+
+pub(crate) fn status(host: &str) -> String {
+    match current(host) {
+        Some(config) => format!(
+            "{}\n{}\n{}\n",
+            config,
+            qr_payload(&config),
+            deep_link(&config)
+        ),
+        None => "set CLIENT_CONFIG_HOST or give the host clients reach hbbs at\n".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_like_the_client() {
+        let config = encode("hbbs.example.com", "", "AQID");
+        let reversed: String = config.chars().rev().collect();
+        let json: serde_json::Value =
+            serde_json::from_slice(&base64::decode_config(reversed, base64::URL_SAFE).unwrap())
+                .unwrap();
+        assert_eq!(json["host"], "hbbs.example.com");
+        assert_eq!(json["relay"], "");
+        assert_eq!(json["key"], "AQID");
+        assert_eq!(qr_payload("x"), "config=x");
+        let png = qr_png(&qr_payload(&config)).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert!(png.ends_with(&[0xae, 0x42, 0x60, 0x82])); // crc of an empty IEND
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{
+    common::{get_arg, now},
+    instance, relay_report,
+};
+use hbb_common::{
+    bytes::Bytes, log, protobuf::Message as _, rendezvous_proto::RendezvousMessage,
+    tokio::net::UdpSocket, try_into_v4, ResultType,
+};
+use once_cell::sync::OnceCell;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const ANNOUNCE_INTERVAL: u64 = 30; // in seconds, unless the address or key changed
+const REMOTE_TIMEOUT: u64 = 90; // in seconds without an announcement
+const MAX_SKEW: u64 = 30; // in seconds, of a message's time
+const MAX_PEERS: usize = 100_000;
+pub(crate) const MAX_DATAGRAM: usize = 64 * 1024;
+
+struct Cluster {
+    socket: Arc<UdpSocket>,
+    nodes: Vec<SocketAddr>,
+    secret: String,
+    sent: AtomicUsize,
+    received: AtomicUsize,
+}
+
+static CLUSTER: OnceCell<Cluster> = OnceCell::new();
+
+lazy_static::lazy_static! {
+    // id -> (address, hash of the pk, announced at), of peers registered here
+    static ref ANNOUNCED: Mutex<HashMap<String, (SocketAddr, u64, Instant)>> = Default::default();
+    // address -> (node, its instance id, announced at), of peers registered on
+    // another node
+    static ref REMOTE: Mutex<HashMap<SocketAddr, (SocketAddr, String, Instant)>> =
+        Default::default();
+}
+
+/// Between the nodes, the json of Envelope after its base64 HMAC with
+/// `CLUSTER_SECRET` and a newline.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    ts: u64, // in seconds since the epoch, against replay
+    msg: Msg,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Msg {
+    /// A peer registered on the sending node.
+    Register {
+        id: String,
+        addr: SocketAddr,
+        pk: String, // base64
+        #[serde(default)] // not sent by older nodes
+        instance: String,
+    },
+    /// For a peer registered on the receiving node.
+    Peer { addr: SocketAddr, msg: String },
+    /// For whichever node holds the tcp connection of `addr`.
+    Session { addr: SocketAddr, msg: String },
+}
+
+/// `CLUSTER_PORT` is the udp port the nodes of a cluster exchange peer
+/// registrations on, `CLUSTER_NODES` the comma separated `host:port` of the
+/// other nodes, and `CLUSTER_SECRET` the key they share. Returns the socket
+/// to receive on.
+pub(crate) async fn init(bind_addr: Option<IpAddr>) -> ResultType<Option<Arc<UdpSocket>>> {
+    let port = get_arg("CLUSTER_PORT").parse::<u16>().unwrap_or(0);
+    if port == 0 {
+        return Ok(None);
+    }
+    let secret = get_arg("CLUSTER_SECRET");
+    if secret.is_empty() {
+        log::error!("CLUSTER_PORT is off without CLUSTER_SECRET");
+        return Ok(None);
+    }
+    let mut nodes = Vec::new();
+    for x in get_arg("CLUSTER_NODES").split(',').map(str::trim) {
+        if x.is_empty() {
+            continue;
+        }
+        match x.to_socket_addrs().map(|mut x| x.next()) {
+            Ok(Some(addr)) => nodes.push(addr),
+            _ => log::error!("Can't resolve cluster node {}", x),
+        }
+    }
+    let addr = SocketAddr::new(
+        bind_addr.unwrap_or(IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED)),
+        port,
+    );
+    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    log::info!(
+        "Listening on udp {} for the cluster, CLUSTER_NODES={:?}",
+        socket.local_addr()?,
+        nodes
+    );
+    CLUSTER
+        .set(Cluster {
+            socket: socket.clone(),
+            nodes,
+            secret,
+            sent: AtomicUsize::new(0),
+            received: AtomicUsize::new(0),
+        })
+        .ok();
+    Ok(Some(socket))
+}
+
+#[inline]
+pub(crate) fn is_on() -> bool {
+    CLUSTER.get().is_some()
+}
+
+/// A peer registered here, tell the other nodes if it's news to them.
+pub(crate) fn on_register(id: &str, addr: SocketAddr, pk: &[u8]) {
+    let Some(c) = CLUSTER.get() else {
+        return;
+    };
+    let addr = try_into_v4(addr);
+    if let Ok(mut remote) = REMOTE.lock() {
+        remote.remove(&addr);
+    }
+    let pk_hash = hash(pk);
+    {
+        let Ok(mut announced) = ANNOUNCED.lock() else {
+            return;
+        };
+        if let Some(x) = announced.get(id) {
+            if x.0 == addr && x.1 == pk_hash && x.2.elapsed().as_secs() < ANNOUNCE_INTERVAL {
+                return;
+            }
+        }
+        if announced.len() >= MAX_PEERS && !announced.contains_key(id) {
+            announced.retain(|_, x| x.2.elapsed().as_secs() < REMOTE_TIMEOUT);
+            if announced.len() >= MAX_PEERS {
+                return;
+            }
+        }
+        announced.insert(id.to_owned(), (addr, pk_hash, Instant::now()));
+    }
+    let msg = Msg::Register {
+        id: id.to_owned(),
+        addr,
+        pk: base64::encode(pk),
+        instance: instance::id().to_owned(),
+    };
+    for node in c.nodes.iter() {
+        send(c, &msg, *node);
+    }
+}
+
+/// Recorded when a registration from `node` was applied.
+pub(crate) fn on_remote_register(addr: SocketAddr, node: SocketAddr, instance: String) {
+    let Ok(mut remote) = REMOTE.lock() else {
+        return;
+    };
+    if remote.len() >= MAX_PEERS {
+        remote.retain(|_, x| x.2.elapsed().as_secs() < REMOTE_TIMEOUT);
+        if remote.len() >= MAX_PEERS {
+            return;
+        }
+    }
+    remote.insert(try_into_v4(addr), (node, instance, Instant::now()));
+}
+
+/// The instance `addr` is registered on, this one unless another node
+/// announced it, or its address if it didn't tell its instance id.
+pub(crate) fn home(addr: SocketAddr) -> String {
+    let remote = REMOTE.lock().ok().and_then(|remote| {
+        remote
+            .get(&try_into_v4(addr))
+            .filter(|x| x.2.elapsed().as_secs() < REMOTE_TIMEOUT)
+            .map(|x| node_name(&x.0, &x.1))
+    });
+    remote.unwrap_or_else(|| instance::id().to_owned())
+}
+
+// what a node is called, its address if it's older than instance ids
+fn node_name(node: &SocketAddr, instance: &str) -> String {
+    if instance.is_empty() {
+        node.to_string()
+    } else {
+        instance.to_owned()
+    }
+}
+
+/// Hand `msg` to the node `addr` is registered on, false if that's here.
+pub(crate) fn forward(msg: &RendezvousMessage, addr: SocketAddr) -> bool {
+    let Some(c) = CLUSTER.get() else {
+        return false;
+    };
+    let node = REMOTE.lock().ok().and_then(|remote| {
+        remote
+            .get(&try_into_v4(addr))
+            .filter(|x| x.2.elapsed().as_secs() < REMOTE_TIMEOUT)
+            .map(|x| x.0)
+    });
+    let Some(node) = node else {
+        return false;
+    };
+    if let Ok(bytes) = msg.write_to_bytes() {
+        let msg = Msg::Peer {
+            addr,
+            msg: base64::encode(bytes),
+        };
+        send(c, &msg, node);
+    }
+    true
+}
+
+/// The tcp connection of `addr` isn't here, hand `msg` to all nodes.
+pub(crate) fn broadcast_session(msg: &RendezvousMessage, addr: SocketAddr) {
+    let Some(c) = CLUSTER.get() else {
+        return;
+    };
+    let Ok(bytes) = msg.write_to_bytes() else {
+        return;
+    };
+    let msg = Msg::Session {
+        addr,
+        msg: base64::encode(bytes),
+    };
+    for node in c.nodes.iter() {
+        send(c, &msg, *node);
+    }
+}
+
+// never block, a lost datagram is like a lost registration
+fn send(c: &Cluster, msg: &Msg, node: SocketAddr) {
+    let Ok(data) = seal(msg, &c.secret, now()) else {
+        return;
+    };
+    if c.socket.try_send_to(data.as_bytes(), node).is_ok() {
+        c.sent.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The message in a datagram from another node, None if it isn't one.
+pub(crate) fn open(data: &[u8]) -> Option<Msg> {
+    let c = CLUSTER.get()?;
+    let msg = unseal(data, &c.secret, now())?;
+    c.received.fetch_add(1, Ordering::Relaxed);
+    Some(msg)
+}
+
+pub(crate) fn decode_pk(pk: &str) -> Option<Bytes> {
+    base64::decode(pk).ok().map(Bytes::from)
+}
+
+pub(crate) fn decode_msg(msg: &str) -> Option<RendezvousMessage> {
+    let bytes = base64::decode(msg).ok()?;
+    RendezvousMessage::parse_from_bytes(&bytes).ok()
+}
+
+fn seal(msg: &Msg, secret: &str, ts: u64) -> ResultType<String> {
+    let json = serde_json::to_string(&Envelope { ts, msg })?;
+    Ok(format!("{}\n{}", relay_report::mac(&json, secret), json))
+}
+
+fn unseal(data: &[u8], secret: &str, now: u64) -> Option<Msg> {
+    let (mac, json) = std::str::from_utf8(data).ok()?.split_once('\n')?;
+    let expected = relay_report::mac(json, secret);
+    if !sodiumoxide::utils::memcmp(expected.as_bytes(), mac.as_bytes()) {
+        return None;
+    }
+    let envelope: Envelope = serde_json::from_str(json).ok()?;
+    (envelope.ts.abs_diff(now) <= MAX_SKEW).then_some(envelope.msg)
+}
+
+#[inline]
+fn hash(pk: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    pk.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub(crate) fn status() -> String {
+    let Some(c) = CLUSTER.get() else {
+        return "off\n".to_owned();
+    };
+    // remote peers by the instance they are homed on
+    let mut homes: BTreeMap<String, usize> = BTreeMap::new();
+    if let Ok(remote) = REMOTE.lock() {
+        for (node, instance, tm) in remote.values() {
+            if tm.elapsed() < Duration::from_secs(REMOTE_TIMEOUT) {
+                *homes.entry(node_name(node, instance)).or_default() += 1;
+            }
+        }
+    }
+    let mut res = format!(
+        "instance: {}\nnodes: {:?}\nsent: {}\nreceived: {}\nremote peers: {}\n",
+        instance::id(),
+        c.nodes,
+        c.sent.load(Ordering::Relaxed),
+        c.received.load(Ordering::Relaxed),
+        homes.values().sum::<usize>()
+    );
+    for (home, n) in homes {
+        let _ = writeln!(res, "  {} {}", home, n);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_opens_fresh_messages_of_the_cluster() {
+        let msg = Msg::Register {
+            id: "123456789".to_owned(),
+            addr: "10.0.0.1:1".parse().unwrap(),
+            pk: "AQID".to_owned(),
+            instance: "hbbs-1".to_owned(),
+        };
+        let data = seal(&msg, "secret", 1000).unwrap();
+        assert!(matches!(
+            unseal(data.as_bytes(), "secret", 1010),
+            Some(Msg::Register { id, .. }) if id == "123456789"
+        ));
+        assert!(unseal(data.as_bytes(), "other", 1010).is_none());
+        assert!(unseal(data.as_bytes(), "secret", 1000 + MAX_SKEW + 1).is_none());
+        let tampered = data.replace("10.0.0.1", "10.0.0.2");
+        assert!(unseal(tampered.as_bytes(), "secret", 1010).is_none());
+    }
+
+    #[test]
+    fn tells_the_instance_a_peer_is_homed_on() {
+        let addr: SocketAddr = "10.0.0.2:1".parse().unwrap();
+        let node: SocketAddr = "10.1.0.1:21119".parse().unwrap();
+        assert_eq!(home(addr), instance::id());
+        on_remote_register(addr, node, "hbbs-2".to_owned());
+        assert_eq!(home(addr), "hbbs-2");
+        on_remote_register(addr, node, String::new());
+        assert_eq!(home(addr), "10.1.0.1:21119");
+        // from a node before instance ids
+        let json = r#"{"Register":{"id":"1","addr":"10.0.0.1:1","pk":""}}"#;
+        assert!(matches!(
+            serde_json::from_str::<Msg>(json),
+            Ok(Msg::Register { instance, .. }) if instance.is_empty()
+        ));
+    }
+}
@@ -1,7 +1,8 @@
-use clap::App;
+use clap::{App, ArgMatches};
 use hbb_common::{
-    allow_err, anyhow::{Context, Result}, get_version_number, log, tokio, ResultType
+    anyhow::{Context, Result}, get_version_number, log, tokio, ResultType
 };
+use crate::errors::{self, Site};
 use ini::Ini;
 use sodiumoxide::crypto::sign;
 use std::{
@@ -81,6 +82,58 @@ pub fn set_arg(name: &str, value: &str) {
     std::env::set_var(arg_name(name), value);
 }
 
+// Defaults of `--strict`, for locked-down private servers. Each can still
+// be set.
+const STRICT_PROFILE: &[(&str, &str)] = &[
+    ("REQUIRE_REGISTERED", "Y"),
+    ("HIDE_ID_EXISTENCE", "Y"),
+    ("ADMIN_API_LOOPBACK", "Y"),
+];
+
+// Defaults of `--public`, for community servers. Each can still be set.
+const PUBLIC_PROFILE: &[(&str, &str)] = &[
+    ("MSG_RATE_LIMIT", "20"),
+    ("COOLDOWN_ATTEMPTS", "10"),
+    ("AUTH_FAIL_BAN", "20"),
+    ("HIDE_ID_EXISTENCE", "Y"),
+    ("ADMIN_API_LOOPBACK", "Y"),
+    ("LOG_ID_MODE", "hash"),
+];
+
+// Documents every variable, --print-config lists them from its tables.
+const DOCS: &str = include_str!("../docs/environment-variables.md");
+
+lazy_static::lazy_static! {
+    // arg name -> where its value was set from, the inherited environment if missing
+    static ref SOURCES: std::sync::Mutex<std::collections::HashMap<String, String>> =
+        Default::default();
+}
+
+static CONFIG_FILE: once_cell::sync::OnceCell<String> = once_cell::sync::OnceCell::new();
+
+#[allow(dead_code)]
+pub fn set_arg_from(name: &str, value: &str, source: &str) {
+    set_arg(name, value);
+    if let Ok(mut sources) = SOURCES.lock() {
+        sources.insert(arg_name(name), source.to_owned());
+    }
+}
+
+/// Apply the command line, including the defaults of flags not given.
+#[allow(dead_code)]
+pub fn set_args_from(matches: &ArgMatches) {
+    for (k, v) in matches.args.iter() {
+        if let Some(v) = v.vals.first() {
+            let source = if matches.occurrences_of(k) > 0 {
+                "flag"
+            } else {
+                "default"
+            };
+            set_arg_from(k, &v.to_string_lossy(), source);
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub fn init_args(args: &str, name: &str, about: &str) {
     let matches = App::new(name)
@@ -93,23 +146,158 @@ pub fn init_args(args: &str, name: &str, about: &str) {
         if let Some(section) = v.section(None::<String>) {
             section
                 .iter()
-                .for_each(|(k, v)| set_arg(k, v));
+                .for_each(|(k, v)| set_arg_from(k, v, ".env"));
         }
     }
     if let Some(config) = matches.value_of("config") {
+        CONFIG_FILE.set(config.to_owned()).ok();
         if let Ok(v) = Ini::load_from_file(config) {
             if let Some(section) = v.section(None::<String>) {
+                let source = format!("config {config}");
                 section
                     .iter()
-                    .for_each(|(k, v)| set_arg(k, v));
+                    .for_each(|(k, v)| set_arg_from(k, v, &source));
             }
         }
     }
-    for (k, v) in matches.args {
-        if let Some(v) = v.vals.first() {
-            set_arg(k, &v.to_string_lossy());
+    set_args_from(&matches);
+    for profile in ["public", "strict"] {
+        if matches.is_present(profile) {
+            set_arg_from(profile, "Y", "flag");
+        }
+    }
+    apply_profile("PUBLIC", PUBLIC_PROFILE);
+    apply_profile("STRICT", STRICT_PROFILE);
+    if matches.is_present("print-config") {
+        print!("{}", effective_config(name));
+        std::process::exit(0);
+    }
+}
+
+/// Read .env and the config file again, for a reload. Flags keep their
+/// value, variables removed from a file are unset.
+#[allow(dead_code)]
+pub fn reload_args() {
+    let mut files = vec![(".env".to_owned(), ".env".to_owned())];
+    if let Some(config) = CONFIG_FILE.get() {
+        files.push((config.clone(), format!("config {config}")));
+    }
+    for (file, source) in files {
+        let v = match Ini::load_from_file(&file) {
+            Ok(v) => v,
+            Err(err) => {
+                if file != ".env" {
+                    log::error!("Failed to read {}: {}", file, err);
+                }
+                continue;
+            }
+        };
+        let Some(section) = v.section(None::<String>) else {
+            continue;
+        };
+        let in_file: Vec<String> = section.iter().map(|x| arg_name(x.0)).collect();
+        let Ok(sources) = SOURCES.lock().map(|x| x.clone()) else {
+            continue;
+        };
+        let stale = sources
+            .iter()
+            .filter(|(k, v)| **v == source && !in_file.contains(k))
+            .map(|x| x.0);
+        for k in stale {
+            std::env::remove_var(k);
+            if let Ok(mut sources) = SOURCES.lock() {
+                sources.remove(k);
+            }
+        }
+        section
+            .iter()
+            .filter(|x| sources.get(&arg_name(x.0)).map(String::as_str) != Some("flag"))
+            .for_each(|(k, v)| set_arg_from(k, v, &source));
+    }
+}
+
+/// With `<name>=Y` the variables of `profile` which aren't set get the
+/// profile's value.
+fn apply_profile(name: &str, profile: &[(&str, &str)]) {
+    if get_arg(name).to_uppercase() != "Y" {
+        return;
+    }
+    let source = format!("{} profile", name.to_lowercase());
+    for (k, v) in profile {
+        if get_arg_opt(k).is_none() {
+            set_arg_from(k, v, &source);
+        }
+    }
+    log::info!("{}", source);
+}
+
+/// Every documented variable of `binary` with the value it runs with and
+/// where that comes from, in .env syntax.
+#[allow(dead_code)]
+pub fn effective_config(binary: &str) -> String {
+    let mut res = format!(
+        "# {binary} configuration, by precedence: flag, config file, .env, environment, default\n"
+    );
+    let Ok(sources) = SOURCES.lock() else {
+        return res;
+    };
+    for (name, default) in documented(binary) {
+        let line = match get_arg_opt(&name) {
+            Some(value) => {
+                let value = if is_secret(&name) && value.len() > 1 {
+                    "(hidden)".to_owned()
+                } else {
+                    value
+                };
+                let source = sources
+                    .get(&arg_name(&name))
+                    .map(String::as_str)
+                    .unwrap_or("environment");
+                format!("{name}={value} # {source}\n")
+            }
+            None => format!("{name}= # unset, default: {default}\n"),
+        };
+        res.push_str(&line);
+    }
+    res
+}
+
+#[inline]
+fn is_secret(name: &str) -> bool {
+    ["KEY", "KEYS", "SECRET", "SALT", "TOKEN", "TOKENS"]
+        .iter()
+        .any(|x| name.ends_with(x))
+}
+
+// (name, default) from the tables of the section of `binary` in DOCS
+fn documented(binary: &str) -> Vec<(String, String)> {
+    let heading = format!("## `{binary}`");
+    let mut res = Vec::new();
+    let mut default_col = None;
+    for line in DOCS
+        .lines()
+        .skip_while(|x| !x.starts_with(&heading))
+        .skip(1)
+        .take_while(|x| !x.starts_with("## "))
+    {
+        let cells: Vec<&str> = line.split('|').map(str::trim).collect();
+        if cells.get(1) == Some(&"Variable") {
+            default_col = cells.iter().position(|x| *x == "Default");
+            continue;
+        }
+        let (Some(name), Some(default)) = (
+            cells.get(1).and_then(|x| x.strip_prefix('`')?.split('`').next()),
+            default_col.and_then(|i| cells.get(i)),
+        ) else {
+            continue;
+        };
+        // e.g. REFUSAL_MESSAGE_<REASON>
+        if name.contains('<') || res.iter().any(|x: &(String, String)| x.0 == name) {
+            continue;
         }
+        res.push((name.to_owned(), default.replace(['`', '*'], "")));
     }
+    res
 }
 
 #[allow(dead_code)]
@@ -158,6 +346,62 @@ pub fn now() -> u64 {
         .unwrap_or_default()
 }
 
+#[allow(dead_code)]
+#[inline]
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// `LOG_FORMAT=json` in the process environment, like `RUST_LOG`, logs one
+/// JSON object per line for log shippers.
+pub fn log_format() -> flexi_logger::FormatFunction {
+    match std::env::var("LOG_FORMAT") {
+        Ok(x) if x.eq_ignore_ascii_case("json") => json_log_format,
+        _ => flexi_logger::opt_format,
+    }
+}
+
+/// What log lines of a punch hole exchange start with, followed by the
+/// trace id and a space.
+pub const TRACE_PREFIX: &str = "trace=";
+
+// the trace id of a punch hole exchange gets its own field, as does the
+// instance id
+fn json_log_format(
+    w: &mut dyn Write,
+    _now: &mut flexi_logger::DeferredNow,
+    record: &log::Record,
+) -> std::io::Result<()> {
+    let msg = record.args().to_string();
+    let (trace, msg) = split_trace(&msg);
+    let mut line = serde_json::json!({
+        "ts": SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|x| x.as_millis() as u64)
+            .unwrap_or_default(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "msg": msg,
+    });
+    if let Some(trace) = trace {
+        line["trace"] = trace.into();
+    }
+    let instance = crate::instance::id();
+    if !instance.is_empty() {
+        line["instance"] = instance.into();
+    }
+    write!(w, "{}", line)
+}
+
+fn split_trace(msg: &str) -> (Option<&str>, &str) {
+    msg.strip_prefix(TRACE_PREFIX)
+        .and_then(|x| x.split_once(' '))
+        .map_or((None, msg), |(id, rest)| (Some(id), rest))
+}
+
 pub fn gen_sk(wait: u64) -> (String, Option<sign::SecretKey>) {
     let sk_file = "id_ed25519";
     if wait > 0 && !std::path::Path::new(sk_file).exists() {
@@ -247,7 +491,7 @@ pub async fn listen_signal() -> Result<()> {
 pub fn check_software_update() {
     const ONE_DAY_IN_SECONDS: u64 = 60 * 60 * 24;
     std::thread::spawn(move || loop {
-        std::thread::spawn(move || allow_err!(check_software_update_()));
+        std::thread::spawn(move || errors::check(Site::Update, check_software_update_()));
         std::thread::sleep(std::time::Duration::from_secs(ONE_DAY_IN_SECONDS));
     });
 }
@@ -276,6 +520,16 @@ mod tests {
     use super::*;
     use std::net::{Ipv4Addr, Ipv6Addr};
 
+    #[test]
+    fn lifts_the_trace_id() {
+        assert_eq!(
+            split_trace("trace=00ab12cd Punch hole request"),
+            (Some("00ab12cd"), "Punch hole request")
+        );
+        assert_eq!(split_trace("trace=x"), (None, "trace=x"));
+        assert_eq!(split_trace("Relay 1 joined"), (None, "Relay 1 joined"));
+    }
+
     #[test]
     fn argument_names_ignore_case_and_separator() {
         let aliases = [
@@ -305,6 +559,21 @@ mod tests {
         std::env::remove_var("RUSTDESK_CONFIG_ALIAS_TEST");
     }
 
+    #[test]
+    fn lists_documented_variables() {
+        let hbbs = documented("hbbs");
+        assert!(hbbs.contains(&("PORT".to_owned(), "21116".to_owned())));
+        assert!(hbbs.contains(&("MIRROR_ADDR".to_owned(), "(off)".to_owned())));
+        assert!(!hbbs.iter().any(|x| x.0.contains('<')));
+        let hbbr = documented("hbbr");
+        assert!(hbbr.contains(&("PORT".to_owned(), "21117".to_owned())));
+        assert!(hbbr.contains(&("SINGLE_BANDWIDTH".to_owned(), "128".to_owned())));
+        assert!(hbbr.contains(&("RELAY_SECRET".to_owned(), "(none)".to_owned())));
+        assert!(is_secret("RELAY_SECRET") && !is_secret("PORT"));
+        assert!(is_secret("ADMIN_API_TOKEN"));
+        assert!(hbbs.iter().any(|x| x.0 == "WARM_FROM_TOKEN") && is_secret("WARM_FROM_TOKEN"));
+    }
+
     #[test]
     fn parses_bind_address() {
         assert_eq!(parse_bind_address("").unwrap(), None);
@@ -0,0 +1,200 @@
+use crate::common::{get_arg_or, now};
+use hbb_common::log;
+use sodiumoxide::crypto::sign;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// `PeerDiscovery.cmd` of a device asking for the connection attempts to it,
+/// sent over udp with its id in `id` and the current unix time in seconds
+/// signed with its key, base64, in `misc`. The reply has the same cmd and a
+/// json array of `{"time", "ip", "outcome"}`, newest first, in `misc`, or
+/// `{"error"}` if the request was refused.
+pub(crate) const CMD: &str = "connection-log";
+const DEFAULT_SIZE: usize = 20; // per device
+const MAX_DEVICES: usize = 100_000;
+const MAX_AGE: u64 = 7 * 24 * 3600; // in seconds, dropped first when full
+const MAX_SKEW: u64 = 60; // in seconds, of a request's time
+const MAX_REPLY: usize = 1_200; // in bytes, one unfragmented datagram
+const MAX_RECENT: usize = 100; // of all devices, for the admin ui
+
+static SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_SIZE);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Outcome {
+    Forwarded,
+    Relayed,
+    Offline,
+    Refused,
+}
+
+impl Outcome {
+    pub(crate) const ALL: [Outcome; 4] = [
+        Outcome::Forwarded,
+        Outcome::Relayed,
+        Outcome::Offline,
+        Outcome::Refused,
+    ];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Forwarded => "forwarded",
+            Outcome::Relayed => "relayed",
+            Outcome::Offline => "offline",
+            Outcome::Refused => "refused",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Device {
+    attempts: VecDeque<(u64, String, Outcome)>, // (unix time, requester ip, outcome)
+    last_request: u64, // time of the last log request, against replays
+}
+
+lazy_static::lazy_static! {
+    static ref DEVICES: Mutex<HashMap<String, Device>> = Default::default();
+    // (unix time, id, requester ip, outcome), oldest first
+    static ref RECENT: Mutex<VecDeque<(u64, String, String, Outcome)>> = Default::default();
+}
+
+/// `CONNECTION_LOG_SIZE` is how many attempts are kept per device, 0 is off.
+pub(crate) fn init() {
+    let size = get_arg_or("CONNECTION_LOG_SIZE", DEFAULT_SIZE.to_string())
+        .parse()
+        .unwrap_or(DEFAULT_SIZE);
+    SIZE.store(size, Ordering::SeqCst);
+    log::info!("CONNECTION_LOG_SIZE={}", size);
+}
+
+/// A connection to `id` was requested from `ip`.
+pub(crate) fn record(id: &str, ip: &str, outcome: Outcome) {
+    crate::search::record(id, ip, outcome);
+    crate::peer_stats::on_punch(id, outcome.as_str());
+    let size = SIZE.load(Ordering::Relaxed);
+    if size == 0 {
+        return;
+    }
+    let Ok(mut devices) = DEVICES.lock() else {
+        return;
+    };
+    let now = now();
+    if devices.len() >= MAX_DEVICES && !devices.contains_key(id) {
+        devices.retain(|_, d| d.attempts.back().is_some_and(|x| x.0 + MAX_AGE > now));
+        if devices.len() >= MAX_DEVICES {
+            return;
+        }
+    }
+    let attempts = &mut devices.entry(id.to_owned()).or_default().attempts;
+    attempts.push_back((now, ip.to_owned(), outcome));
+    while attempts.len() > size {
+        attempts.pop_front();
+    }
+    drop(devices);
+    if let Ok(mut recent) = RECENT.lock() {
+        if recent.len() >= MAX_RECENT {
+            recent.pop_front();
+        }
+        recent.push_back((now, id.to_owned(), ip.to_owned(), outcome));
+    }
+}
+
+/// The latest attempts at any device as a json array of `{"time", "id",
+/// "ip", "outcome"}`, newest first.
+pub(crate) fn recent() -> serde_json::Value {
+    let Ok(recent) = RECENT.lock() else {
+        return serde_json::Value::Array(Vec::new());
+    };
+    recent
+        .iter()
+        .rev()
+        .map(|(tm, id, ip, outcome)| {
+            serde_json::json!({ "time": tm, "id": id, "ip": ip, "outcome": outcome.as_str() })
+        })
+        .collect()
+}
+
+/// The reply to a device's request, checking it was signed with the
+/// device's `pk` recently and not seen before.
+pub(crate) fn answer(id: &str, pk: &[u8], misc: &str) -> String {
+    let verified = base64::decode(misc).ok().and_then(|signed| {
+        let pk = sign::PublicKey::from_slice(pk)?;
+        let tm = sign::verify(&signed, &pk).ok()?;
+        std::str::from_utf8(&tm).ok()?.parse::<u64>().ok()
+    });
+    let Some(tm) = verified else {
+        return error("bad signature");
+    };
+    if tm.abs_diff(now()) > MAX_SKEW {
+        return error("time out of sync");
+    }
+    let Ok(mut devices) = DEVICES.lock() else {
+        return error("unavailable");
+    };
+    let Some(device) = devices.get_mut(id) else {
+        return "[]".to_owned();
+    };
+    if tm <= device.last_request {
+        return error("replayed");
+    }
+    device.last_request = tm;
+    to_json(&device.attempts)
+}
+
+fn to_json(attempts: &VecDeque<(u64, String, Outcome)>) -> String {
+    let mut res = Vec::new();
+    let mut len = 2;
+    for (tm, ip, outcome) in attempts.iter().rev() {
+        let v = serde_json::json!({ "time": tm, "ip": ip, "outcome": outcome.as_str() });
+        len += v.to_string().len() + 1;
+        if len > MAX_REPLY {
+            break;
+        }
+        res.push(v);
+    }
+    serde_json::Value::Array(res).to_string()
+}
+
+#[inline]
+fn error(err: &str) -> String {
+    serde_json::json!({ "error": err }).to_string()
+}
+
+/// The attempts to `id` as csv, oldest first.
+pub(crate) fn status(id: &str) -> String {
+    let mut res = "time,ip,outcome\n".to_owned();
+    if let Ok(devices) = DEVICES.lock() {
+        for (tm, ip, outcome) in devices.get(id).into_iter().flat_map(|d| d.attempts.iter()) {
+            let _ = writeln!(res, "{},{},{}", tm, ip, outcome.as_str());
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_signed_requests_once() {
+        let (pk, sk) = sign::gen_keypair();
+        record("dev", "10.0.0.1", Outcome::Forwarded);
+        record("dev", "10.0.0.2", Outcome::Refused);
+        let request = |tm: u64| base64::encode(sign::sign(tm.to_string().as_bytes(), &sk));
+        let now = now();
+        let res: serde_json::Value =
+            serde_json::from_str(&answer("dev", &pk.0, &request(now))).unwrap();
+        assert_eq!(res[0]["ip"], "10.0.0.2");
+        assert_eq!(res[1]["outcome"], "forwarded");
+        assert_eq!(recent()[0]["id"], "dev");
+        assert!(answer("dev", &pk.0, &request(now)).contains("replayed"));
+        assert!(answer("dev", &pk.0, &request(now - 3600)).contains("error"));
+        let (other, _) = sign::gen_keypair();
+        assert!(answer("dev", &other.0, &request(now + 1)).contains("bad signature"));
+    }
+}
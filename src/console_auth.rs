@@ -0,0 +1,97 @@
+use crate::common::get_arg;
+use hbb_common::log;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+
+// Commands a helpdesk account may run: looking up peers, their online status
+// and recent or stored connection attempts. `-` (remove/ban) arguments are refused.
+const HELPDESK_COMMANDS: &[&str] = &[
+    "h",
+    "peer",
+    "p",
+    "punch-requests",
+    "pr",
+    "ip-changes",
+    "ic",
+    "presence",
+    "pre",
+    "healthz",
+    "hz",
+    "connection-log",
+    "cl",
+    "search",
+    "sr",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Admin,
+    Helpdesk,
+}
+
+static ACCOUNTS: OnceCell<HashMap<String, Role>> = OnceCell::new();
+
+/// `CONSOLE_TOKENS` is a comma separated list of `token:role`, role is
+/// `admin` or `helpdesk`. Empty keeps the console open to any local user.
+pub(crate) fn init() {
+    let mut accounts = HashMap::new();
+    for x in get_arg("CONSOLE_TOKENS").split(',').map(str::trim) {
+        if x.is_empty() {
+            continue;
+        }
+        let (token, role) = match x.rsplit_once(':') {
+            Some((token, "admin")) if !token.is_empty() => (token, Role::Admin),
+            Some((token, "helpdesk")) if !token.is_empty() => (token, Role::Helpdesk),
+            _ => {
+                log::error!("Invalid console token, expected <token>:<admin|helpdesk>");
+                continue;
+            }
+        };
+        accounts.insert(token.to_owned(), role);
+    }
+    if !accounts.is_empty() {
+        log::info!("Console requires a token, {} accounts", accounts.len());
+    }
+    ACCOUNTS.set(accounts).ok();
+}
+
+/// With accounts configured a command is `<token> <command> [args]`,
+/// returns the command if the token's role may run it.
+pub(crate) fn authorize(cmd: &str) -> Result<&str, &'static str> {
+    let Some(accounts) = ACCOUNTS.get().filter(|x| !x.is_empty()) else {
+        return Ok(cmd);
+    };
+    let cmd = cmd.trim();
+    let (token, cmd) = cmd.split_once(' ').unwrap_or((cmd, ""));
+    match accounts.get(token) {
+        Some(role) if is_allowed(*role, cmd) => Ok(cmd),
+        Some(_) => Err("permission denied\n"),
+        None => Err("unauthorized\n"),
+    }
+}
+
+fn is_allowed(role: Role, cmd: &str) -> bool {
+    match role {
+        Role::Admin => true,
+        Role::Helpdesk => {
+            let mut fds = cmd.split_whitespace();
+            fds.next().is_some_and(|x| HELPDESK_COMMANDS.contains(&x)) && !fds.any(|x| x == "-")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn helpdesk_is_read_only() {
+        assert!(is_allowed(Role::Helpdesk, "peer 123456789"));
+        assert!(is_allowed(Role::Helpdesk, "pr 10"));
+        assert!(!is_allowed(Role::Helpdesk, "ic 123456789 -"));
+        assert!(!is_allowed(Role::Helpdesk, "ip-blocker 1.1.1.1 -"));
+        assert!(!is_allowed(Role::Helpdesk, "always-use-relay Y"));
+        assert!(!is_allowed(Role::Helpdesk, ""));
+        assert!(is_allowed(Role::Admin, "always-use-relay Y"));
+    }
+}
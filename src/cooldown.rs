@@ -0,0 +1,198 @@
+use crate::common::{get_arg, get_arg_or};
+use hbb_common::log;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const DEFAULT_WINDOW: u64 = 60; // in seconds
+const DEFAULT_MINUTES: u64 = 10;
+const MAX_TARGETS: usize = 100_000;
+
+static ATTEMPTS: AtomicUsize = AtomicUsize::new(0); // 0 means off unless set per device
+static WINDOW: AtomicU64 = AtomicU64::new(DEFAULT_WINDOW);
+static MINUTES: AtomicU64 = AtomicU64::new(DEFAULT_MINUTES);
+static REFUSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Policy {
+    attempts: usize,
+    minutes: u64,
+}
+
+struct Target {
+    window: (Instant, usize),
+    until: Option<Instant>,
+}
+
+lazy_static::lazy_static! {
+    static ref TARGETS: Mutex<HashMap<String, Target>> = Default::default();
+    // per device policies set by admins, overriding the global one
+    static ref POLICIES: Mutex<HashMap<String, Policy>> = Default::default();
+}
+
+pub(crate) fn init() {
+    ATTEMPTS.store(get_arg("COOLDOWN_ATTEMPTS").parse().unwrap_or(0), Ordering::SeqCst);
+    WINDOW.store(
+        get_arg_or("COOLDOWN_WINDOW", DEFAULT_WINDOW.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_WINDOW),
+        Ordering::SeqCst,
+    );
+    MINUTES.store(
+        get_arg_or("COOLDOWN_MINUTES", DEFAULT_MINUTES.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_MINUTES),
+        Ordering::SeqCst,
+    );
+    if ATTEMPTS.load(Ordering::SeqCst) > 0 {
+        log::info!(
+            "COOLDOWN_ATTEMPTS={}, COOLDOWN_WINDOW={}s, COOLDOWN_MINUTES={}",
+            ATTEMPTS.load(Ordering::SeqCst),
+            WINDOW.load(Ordering::SeqCst),
+            MINUTES.load(Ordering::SeqCst)
+        );
+    }
+}
+
+fn get_policy(id: &str) -> Option<Policy> {
+    if let Ok(policies) = POLICIES.lock() {
+        if let Some(p) = policies.get(id) {
+            return (p.attempts > 0).then_some(*p);
+        }
+    }
+    match ATTEMPTS.load(Ordering::Relaxed) {
+        0 => None,
+        attempts => Some(Policy {
+            attempts,
+            minutes: MINUTES.load(Ordering::Relaxed),
+        }),
+    }
+}
+
+/// Count a punch hole request for `id`, each password retry of a controller
+/// is a new request. Returns the remaining minutes if `id` is cooling down
+/// and the request should be refused.
+pub(crate) fn on_attempt(id: &str) -> Option<u64> {
+    let policy = get_policy(id)?;
+    let window = WINDOW.load(Ordering::Relaxed);
+    let mut targets = TARGETS.lock().ok()?;
+    if targets.len() >= MAX_TARGETS && !targets.contains_key(id) {
+        targets.retain(|_, t| !is_idle(t, window));
+        if targets.len() >= MAX_TARGETS {
+            return None;
+        }
+    }
+    let target = targets.entry(id.to_owned()).or_insert_with(|| Target {
+        window: (Instant::now(), 0),
+        until: None,
+    });
+    let res = count(target, policy, window);
+    if res.is_some() {
+        REFUSED.fetch_add(1, Ordering::Relaxed);
+    }
+    res
+}
+
+fn count(target: &mut Target, policy: Policy, window: u64) -> Option<u64> {
+    if let Some(until) = target.until {
+        let now = Instant::now();
+        if now < until {
+            return Some((until - now).as_secs() / 60 + 1);
+        }
+        target.until = None;
+        target.window = (now, 0);
+    }
+    if target.window.0.elapsed().as_secs() >= window {
+        target.window = (Instant::now(), 0);
+    }
+    target.window.1 += 1;
+    if target.window.1 > policy.attempts {
+        target.until = Some(Instant::now() + Duration::from_secs(policy.minutes * 60));
+        return Some(policy.minutes);
+    }
+    None
+}
+
+#[inline]
+fn is_idle(target: &Target, window: u64) -> bool {
+    target.until.is_none_or(|x| x <= Instant::now())
+        && target.window.0.elapsed().as_secs() >= window
+}
+
+/// Set the policy of a device, `attempts == 0` disables cooldown for it.
+pub(crate) fn set_policy(id: &str, attempts: usize, minutes: u64) {
+    if let Ok(mut policies) = POLICIES.lock() {
+        policies.insert(id.to_owned(), Policy { attempts, minutes });
+    }
+}
+
+/// Forget the device policy and lift an ongoing cooldown.
+pub(crate) fn reset(id: &str) {
+    if let Ok(mut policies) = POLICIES.lock() {
+        policies.remove(id);
+    }
+    if let Ok(mut targets) = TARGETS.lock() {
+        targets.remove(id);
+    }
+}
+
+pub(crate) fn status() -> String {
+    let mut res = format!(
+        "attempts: {}/{}s\nminutes: {}\nrefused: {}\n",
+        ATTEMPTS.load(Ordering::SeqCst),
+        WINDOW.load(Ordering::SeqCst),
+        MINUTES.load(Ordering::SeqCst),
+        REFUSED.load(Ordering::Relaxed)
+    );
+    if let Ok(policies) = POLICIES.lock() {
+        for (id, p) in policies.iter() {
+            let _ = writeln!(res, "{}: attempts={} minutes={}", id, p.attempts, p.minutes);
+        }
+    }
+    if let Ok(targets) = TARGETS.lock() {
+        let now = Instant::now();
+        for (id, t) in targets.iter() {
+            if let Some(until) = t.until.filter(|x| *x > now) {
+                let _ = writeln!(res, "{}: cooling down for {}s", id, (until - now).as_secs());
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_after_too_many_attempts() {
+        let policy = Policy {
+            attempts: 3,
+            minutes: 10,
+        };
+        let mut target = Target {
+            window: (Instant::now(), 0),
+            until: None,
+        };
+        for _ in 0..3 {
+            assert_eq!(count(&mut target, policy, 60), None);
+        }
+        assert_eq!(count(&mut target, policy, 60), Some(10));
+        assert_eq!(count(&mut target, policy, 60), Some(10));
+        assert!(!is_idle(&target, 0));
+        // attempts outside the window don't add up
+        let mut target = Target {
+            window: (Instant::now(), 0),
+            until: None,
+        };
+        for _ in 0..10 {
+            assert_eq!(count(&mut target, policy, 0), None);
+        }
+    }
+}
@@ -3,7 +3,7 @@ use hbb_common::{log, ResultType};
 use sqlx::{
     sqlite::SqliteConnectOptions, ConnectOptions, Connection, Error as SqlxError, SqliteConnection,
 };
-use std::{ops::DerefMut, str::FromStr};
+use std::{ops::DerefMut, str::FromStr, sync::Arc, time::Duration};
 //use sqlx::postgres::PgPoolOptions;
 //use sqlx::mysql::MySqlPoolOptions;
 
@@ -46,8 +46,85 @@ pub struct Peer {
     pub status: Option<i64>,
 }
 
+/// A peer as moved between servers, see migration.
+#[derive(Default, sqlx::FromRow)]
+pub struct PeerRecord {
+    pub id: String,
+    pub uuid: Vec<u8>,
+    pub pk: Vec<u8>,
+    pub user: Option<Vec<u8>>,
+    pub status: Option<i64>,
+    pub note: Option<String>,
+    pub info: String,
+}
+
+/// The peer records as the rest of the server sees them, so another
+/// database can take SQLite's place, see peer_storage.
+#[async_trait]
+pub trait PeerStorage: Send + Sync {
+    async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>>;
+    /// Returns the guid of the new record.
+    async fn insert_peer(&self, id: &str, uuid: &[u8], pk: &[u8], info: &str)
+        -> ResultType<Vec<u8>>;
+    async fn update_pk(&self, guid: &Vec<u8>, id: &str, pk: &[u8], info: &str) -> ResultType<()>;
+    /// Returns false if there is no record of `id`.
+    async fn delete_peer(&self, id: &str) -> ResultType<bool>;
+    /// Returns false if there is no record of `id`.
+    async fn clear_pk(&self, id: &str) -> ResultType<bool>;
+    /// All records, by id.
+    async fn get_peer_records(&self) -> ResultType<Vec<PeerRecord>>;
+    /// Returns false if the id is taken.
+    async fn import_peer_record(&self, peer: &PeerRecord) -> ResultType<bool>;
+    /// (id, info) of peers whose info is older than `version`, or unreadable.
+    async fn get_peer_infos_before(&self, version: u32) -> ResultType<Vec<(String, String)>>;
+    async fn update_info(&self, id: &str, info: &str) -> ResultType<()>;
+    async fn touch_peers(&self, ids: &[String], seen: u64) -> ResultType<()>;
+    /// See Database::update_peer_stats.
+    async fn update_peer_stats(
+        &self,
+        rows: &[(String, u64, u64, Option<(u64, &str)>)],
+    ) -> ResultType<()>;
+    /// (id, info) of peers last seen, or created if never seen, before `before`.
+    async fn get_peers_seen_before(&self, before: u64) -> ResultType<Vec<(String, String)>>;
+    /// Move the record of `id` aside, returns false if there is none.
+    async fn quarantine_peer(&self, id: &str, reason: &str) -> ResultType<bool>;
+    /// (id, reason, quarantined at in seconds since the epoch, info), newest first.
+    async fn get_quarantined(
+        &self,
+        id: Option<&str>,
+    ) -> ResultType<Vec<(String, String, i64, Option<String>)>>;
+    /// Returns false if the id has been registered again meanwhile.
+    async fn restore_quarantined(&self, id: &str, info: &str) -> ResultType<bool>;
+    async fn delete_quarantined(&self, id: &str) -> ResultType<bool>;
+}
+
+/// The peer records at `url`: in `db` for SQLite, else in PostgreSQL for a
+/// `postgres://` url if built with the `postgres` feature.
+pub async fn peer_storage(url: &str, db: &Database) -> ResultType<Arc<dyn PeerStorage>> {
+    if !is_postgres(url) {
+        return Ok(Arc::new(db.clone()));
+    }
+    #[cfg(feature = "postgres")]
+    {
+        Ok(Arc::new(crate::pg_storage::PgStorage::new(url).await?))
+    }
+    #[cfg(not(feature = "postgres"))]
+    {
+        hbb_common::bail!("DB_URL {} needs hbbs built with the postgres feature", url)
+    }
+}
+
+#[inline]
+pub fn is_postgres(url: &str) -> bool {
+    url.starts_with("postgres://") || url.starts_with("postgresql://")
+}
+
 impl Database {
     pub async fn new(url: &str) -> ResultType<Database> {
+        // anything else would end up as the name of a local file
+        if url.contains("://") && !url.starts_with("sqlite:") {
+            hbb_common::bail!("Unsupported database {}, expected SQLite", url);
+        }
         if !std::path::Path::new(url).exists() {
             std::fs::File::create(url).ok();
         }
@@ -64,6 +141,10 @@ impl Database {
         let _ = pool.get().await?; // test
         let db = Database { pool };
         db.create_tables().await?;
+        db.create_quarantine_table().await?;
+        db.create_tombstone_table().await?;
+        db.create_alias_table().await?;
+        db.create_ban_table().await?;
         Ok(db)
     }
 
@@ -92,6 +173,190 @@ impl Database {
         Ok(())
     }
 
+    // Loosely typed, it takes rows which failed to decode.
+    async fn create_quarantine_table(&self) -> ResultType<()> {
+        sqlx::query(
+            "
+            create table if not exists peer_quarantine (
+                guid blob,
+                id varchar(100) not null,
+                uuid blob,
+                pk blob,
+                created_at datetime,
+                user blob,
+                status tinyint,
+                note varchar(300),
+                info text,
+                reason text not null,
+                quarantined_at integer not null
+            );
+            create index if not exists index_peer_quarantine_id on peer_quarantine (id);
+        ",
+        )
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    // who deleted which peer, kept after the record is gone
+    async fn create_tombstone_table(&self) -> ResultType<()> {
+        sqlx::query(
+            "
+            create table if not exists peer_tombstone (
+                id varchar(100) not null,
+                reason text not null,
+                operator text not null,
+                deleted_at integer not null,
+                blocked_until integer not null
+            );
+            create index if not exists index_peer_tombstone_id on peer_tombstone (id);
+        ",
+        )
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    pub async fn add_tombstone(
+        &self,
+        id: &str,
+        reason: &str,
+        operator: &str,
+        blocked_until: i64,
+    ) -> ResultType<()> {
+        sqlx::query(
+            "insert into peer_tombstone(id, reason, operator, deleted_at, blocked_until)
+            values(?, ?, ?, ?, ?)",
+        )
+        .bind(id)
+        .bind(reason)
+        .bind(operator)
+        .bind(crate::common::now() as i64)
+        .bind(blocked_until)
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// (id, reason, operator, deleted_at, blocked_until) of `id` or all,
+    /// blocked after `blocked_after`, newest first.
+    pub async fn get_tombstones(
+        &self,
+        id: Option<&str>,
+        blocked_after: i64,
+    ) -> ResultType<Vec<(String, String, String, i64, i64)>> {
+        Ok(sqlx::query_as::<_, (String, String, String, i64, i64)>(
+            "select id, reason, operator, deleted_at, blocked_until from peer_tombstone
+            where (? is null or id = ?) and blocked_until >= ? order by deleted_at desc",
+        )
+        .bind(id)
+        .bind(id)
+        .bind(blocked_after)
+        .fetch_all(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+
+    async fn create_alias_table(&self) -> ResultType<()> {
+        sqlx::query(
+            "
+            create table if not exists peer_alias (
+                alias varchar(100) primary key not null,
+                id varchar(100) not null,
+                updated_at integer not null
+            ) without rowid;
+        ",
+        )
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// (alias, id) of all aliases.
+    pub async fn get_aliases(&self) -> ResultType<Vec<(String, String)>> {
+        Ok(
+            sqlx::query_as::<_, (String, String)>("select alias, id from peer_alias")
+                .fetch_all(self.pool.get().await?.deref_mut())
+                .await?,
+        )
+    }
+
+    pub async fn set_alias(&self, alias: &str, id: &str) -> ResultType<()> {
+        sqlx::query("insert or replace into peer_alias(alias, id, updated_at) values(?, ?, ?)")
+            .bind(alias)
+            .bind(id)
+            .bind(crate::common::now() as i64)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_alias(&self, alias: &str) -> ResultType<bool> {
+        let res = sqlx::query("delete from peer_alias where alias = ?")
+            .bind(alias)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    // ids and ips banned by an operator, until 0 is for good
+    async fn create_ban_table(&self) -> ResultType<()> {
+        sqlx::query(
+            "
+            create table if not exists ban (
+                target varchar(100) primary key not null,
+                reason text not null,
+                operator text not null,
+                created_at integer not null,
+                until integer not null
+            ) without rowid;
+        ",
+        )
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// (target, reason, operator, created_at, until) of the bans still
+    /// running at `now`.
+    pub async fn get_bans(&self, now: i64) -> ResultType<Vec<(String, String, String, i64, i64)>> {
+        Ok(sqlx::query_as::<_, (String, String, String, i64, i64)>(
+            "select target, reason, operator, created_at, until from ban
+            where until = 0 or until > ?",
+        )
+        .bind(now)
+        .fetch_all(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+
+    pub async fn set_ban(
+        &self,
+        target: &str,
+        reason: &str,
+        operator: &str,
+        until: i64,
+    ) -> ResultType<()> {
+        sqlx::query(
+            "insert or replace into ban(target, reason, operator, created_at, until)
+            values(?, ?, ?, ?, ?)",
+        )
+        .bind(target)
+        .bind(reason)
+        .bind(operator)
+        .bind(crate::common::now() as i64)
+        .bind(until)
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove_ban(&self, target: &str) -> ResultType<bool> {
+        let res = sqlx::query("delete from ban where target = ?")
+            .bind(target)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
     pub async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>> {
         Ok(sqlx::query_as!(
             Peer,
@@ -141,6 +406,502 @@ impl Database {
         .await?;
         Ok(())
     }
+
+    /// (id, info) of peers whose info is older than `version`, or unreadable.
+    pub async fn get_peer_infos_before(&self, version: u32) -> ResultType<Vec<(String, String)>> {
+        Ok(sqlx::query_as::<_, (String, String)>(
+            "select id, ifnull(cast(info as text), '') from peer where case when json_valid(info) then ifnull(json_extract(info, '$.v'), 0) else -1 end < ?",
+        )
+        .bind(version)
+        .fetch_all(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+
+    pub async fn touch_peers(&self, ids: &[String], seen: u64) -> ResultType<()> {
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.begin().await?;
+        for id in ids {
+            sqlx::query(
+                "update peer set info = json_set(info, '$.seen', ?) where id = ? and json_valid(info)",
+            )
+            .bind(seen as i64)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Add (id, last online, registrations, last punch) to the peers' info,
+    /// a last online of 0 is left alone, see peer_stats.
+    pub async fn update_peer_stats(
+        &self,
+        rows: &[(String, u64, u64, Option<(u64, &str)>)],
+    ) -> ResultType<()> {
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.begin().await?;
+        for (id, last_online, registrations, punch) in rows {
+            sqlx::query(
+                "update peer set info = json_set(info,
+                    '$.last_online', max(ifnull(json_extract(info, '$.last_online'), 0), ?),
+                    '$.registrations', ifnull(json_extract(info, '$.registrations'), 0) + ?)
+                where id = ? and json_valid(info)",
+            )
+            .bind(*last_online as i64)
+            .bind(*registrations as i64)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+            if let Some((time, outcome)) = punch {
+                sqlx::query(
+                    "update peer set info = json_set(info, '$.last_punch',
+                        json_object('time', ?, 'outcome', ?))
+                    where id = ? and json_valid(info)",
+                )
+                .bind(*time as i64)
+                .bind(*outcome)
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// (id, info) of peers last seen, or created if never seen, before `before`.
+    pub async fn get_peers_seen_before(&self, before: u64) -> ResultType<Vec<(String, String)>> {
+        Ok(sqlx::query_as::<_, (String, String)>(
+            "select id, ifnull(cast(info as text), '') from peer where ifnull(case when json_valid(info) then json_extract(info, '$.seen') end, cast(strftime('%s', created_at) as integer)) < ?",
+        )
+        .bind(before as i64)
+        .fetch_all(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+
+    pub async fn update_info(&self, id: &str, info: &str) -> ResultType<()> {
+        sqlx::query("update peer set info = ? where id = ?")
+            .bind(info)
+            .bind(id)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(())
+    }
+
+    /// Move the record of `id` aside, returns false if there is none.
+    pub async fn quarantine_peer(&self, id: &str, reason: &str) -> ResultType<bool> {
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.begin().await?;
+        let res = sqlx::query(
+            "insert into peer_quarantine(guid, id, uuid, pk, created_at, user, status, note, info, reason, quarantined_at)
+            select guid, id, uuid, pk, created_at, user, status, note, info, ?, ? from peer where id = ?",
+        )
+        .bind(reason)
+        .bind(crate::common::now() as i64)
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("delete from peer where id = ?")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// (id, reason, quarantined at in seconds since the epoch, info), newest first.
+    pub async fn get_quarantined(
+        &self,
+        id: Option<&str>,
+    ) -> ResultType<Vec<(String, String, i64, Option<String>)>> {
+        Ok(sqlx::query_as::<_, (String, String, i64, Option<String>)>(
+            "select id, reason, quarantined_at, cast(info as text) from peer_quarantine
+            where ? is null or id = ? order by quarantined_at desc",
+        )
+        .bind(id)
+        .bind(id)
+        .fetch_all(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+
+    /// Put the latest quarantined record of `id` back with `info`, returns
+    /// false if the id has been registered again meanwhile.
+    pub async fn restore_quarantined(&self, id: &str, info: &str) -> ResultType<bool> {
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.begin().await?;
+        let res = sqlx::query(
+            "insert or ignore into peer(guid, id, uuid, pk, created_at, user, status, note, info)
+            select ifnull(guid, randomblob(16)), id, ifnull(uuid, x''), ifnull(pk, x''),
+            ifnull(created_at, current_timestamp), user, status, note, ?
+            from peer_quarantine where id = ? order by quarantined_at desc limit 1",
+        )
+        .bind(info)
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("delete from peer_quarantine where id = ?")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    pub async fn delete_quarantined(&self, id: &str) -> ResultType<bool> {
+        let res = sqlx::query("delete from peer_quarantine where id = ?")
+            .bind(id)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    pub async fn delete_peer(&self, id: &str) -> ResultType<bool> {
+        let res = sqlx::query("delete from peer where id = ?")
+            .bind(id)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    pub async fn clear_pk(&self, id: &str) -> ResultType<bool> {
+        let res = sqlx::query("update peer set uuid = x'', pk = x'' where id = ?")
+            .bind(id)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    pub async fn get_peer_records(&self) -> ResultType<Vec<PeerRecord>> {
+        Ok(sqlx::query_as::<_, PeerRecord>(
+            "select id, uuid, pk, user, status, note, info from peer order by id",
+        )
+        .fetch_all(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+
+    /// Returns false if the id is taken.
+    pub async fn import_peer_record(&self, peer: &PeerRecord) -> ResultType<bool> {
+        let guid = uuid::Uuid::new_v4().as_bytes().to_vec();
+        let res = sqlx::query(
+            "insert or ignore into peer(guid, id, uuid, pk, user, status, note, info) values(?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(guid)
+        .bind(&peer.id)
+        .bind(&peer.uuid)
+        .bind(&peer.pk)
+        .bind(&peer.user)
+        .bind(peer.status)
+        .bind(&peer.note)
+        .bind(&peer.info)
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    /// The file's size and how much of it is free pages, in bytes.
+    pub async fn get_size(&self) -> ResultType<(i64, i64)> {
+        let mut conn = self.pool.get().await?;
+        let mut res = [0i64; 3];
+        for (i, pragma) in ["page_size", "page_count", "freelist_count"].iter().enumerate() {
+            let (v,): (i64,) = sqlx::query_as(&format!("pragma {}", pragma))
+                .fetch_one(conn.deref_mut())
+                .await?;
+            res[i] = v;
+        }
+        Ok((res[1] * res[0], res[2] * res[0]))
+    }
+
+    pub async fn vacuum(&self) -> ResultType<()> {
+        sqlx::query("vacuum")
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the connections in use to be returned, e.g.
+    /// for writes in flight on shutdown. False if some still aren't.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let start = std::time::Instant::now();
+        loop {
+            let status = self.pool.status();
+            if status.available >= status.size as isize {
+                return true;
+            }
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            hbb_common::tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    pub async fn create_attempt_table(&self) -> ResultType<()> {
+        sqlx::query(
+            "
+            create table if not exists connection_attempt (
+                time integer not null,
+                id varchar(100) not null,
+                ip varchar(100) not null,
+                outcome varchar(20) not null
+            );
+            create index if not exists index_connection_attempt_time on connection_attempt (time);
+            create index if not exists index_connection_attempt_id on connection_attempt (id, time);
+            create index if not exists index_connection_attempt_ip on connection_attempt (ip, time);
+        ",
+        )
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// Store (time, id, ip, outcome) of connection attempts.
+    pub async fn insert_attempts(
+        &self,
+        attempts: &[(i64, String, String, &str)],
+    ) -> ResultType<()> {
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.begin().await?;
+        for (tm, id, ip, outcome) in attempts {
+            sqlx::query("insert into connection_attempt(time, id, ip, outcome) values(?, ?, ?, ?)")
+                .bind(tm)
+                .bind(id)
+                .bind(ip)
+                .bind(*outcome)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn delete_attempts(&self, before: i64) -> ResultType<()> {
+        sqlx::query("delete from connection_attempt where time < ?")
+            .bind(before)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(())
+    }
+
+    /// (rowid, time, id, ip, outcome) of the latest `limit` attempts in
+    /// [since, until), at one of `ids`, each an id or a `<prefix>*`, from one
+    /// of `ips` and with `outcome`, each filter only if given. `before` is the
+    /// (time, rowid) of the last row of the previous page.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn search_attempts(
+        &self,
+        since: i64,
+        until: i64,
+        ids: &[String],
+        ips: &[String],
+        outcome: Option<&str>,
+        before: Option<(i64, i64)>,
+        limit: i64,
+    ) -> ResultType<Vec<(i64, i64, String, String, String)>> {
+        let mut sql = "select rowid, time, id, ip, outcome from connection_attempt
+            where time >= ? and time < ?"
+            .to_owned();
+        let mut bounds = Vec::new();
+        if !ids.is_empty() {
+            // a prefix as a range, so the index is used
+            let conds: Vec<_> = ids
+                .iter()
+                .map(|x| match x.strip_suffix('*') {
+                    Some(prefix) => {
+                        bounds.push(prefix.to_owned());
+                        bounds.push(format!("{prefix}\u{10ffff}"));
+                        "(id >= ? and id < ?)"
+                    }
+                    None => {
+                        bounds.push(x.clone());
+                        "id = ?"
+                    }
+                })
+                .collect();
+            sql += &format!(" and ({})", conds.join(" or "));
+        }
+        if !ips.is_empty() {
+            sql += &format!(" and ip in ({})", vec!["?"; ips.len()].join(", "));
+        }
+        if outcome.is_some() {
+            sql += " and outcome = ?";
+        }
+        if before.is_some() {
+            sql += " and (time < ? or (time = ? and rowid < ?))";
+        }
+        sql += " order by time desc, rowid desc limit ?";
+        let mut query = sqlx::query_as::<_, (i64, i64, String, String, String)>(&sql)
+            .bind(since)
+            .bind(until);
+        for x in bounds.iter().chain(ips) {
+            query = query.bind(x);
+        }
+        if let Some(outcome) = outcome {
+            query = query.bind(outcome);
+        }
+        if let Some((tm, rowid)) = before {
+            query = query.bind(tm).bind(tm).bind(rowid);
+        }
+        Ok(query
+            .bind(limit)
+            .fetch_all(self.pool.get().await?.deref_mut())
+            .await?)
+    }
+
+    pub async fn create_metric_table(&self) -> ResultType<()> {
+        sqlx::query(
+            "
+            create table if not exists metric (
+                name varchar(50) not null,
+                resolution integer not null,
+                ts integer not null,
+                value real not null,
+                primary key (name, resolution, ts)
+            ) without rowid;
+        ",
+        )
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
+    pub async fn insert_metrics(
+        &self,
+        resolution: i64,
+        ts: i64,
+        names: &[&str],
+        values: &[f64],
+    ) -> ResultType<()> {
+        let mut conn = self.pool.get().await?;
+        for (name, value) in names.iter().zip(values) {
+            sqlx::query(
+                "insert or replace into metric(name, resolution, ts, value) values(?, ?, ?, ?)",
+            )
+            .bind(*name)
+            .bind(resolution)
+            .bind(ts)
+            .bind(*value)
+            .execute(conn.deref_mut())
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn delete_metrics(&self, resolution: i64, before: i64) -> ResultType<()> {
+        sqlx::query("delete from metric where resolution = ? and ts < ?")
+            .bind(resolution)
+            .bind(before)
+            .execute(self.pool.get().await?.deref_mut())
+            .await?;
+        Ok(())
+    }
+
+    /// The latest `limit` values, newest first.
+    pub async fn get_metrics(
+        &self,
+        name: &str,
+        resolution: i64,
+        limit: i64,
+    ) -> ResultType<Vec<(i64, f64)>> {
+        Ok(sqlx::query_as::<_, (i64, f64)>(
+            "select ts, value from metric where name = ? and resolution = ? order by ts desc limit ?",
+        )
+        .bind(name)
+        .bind(resolution)
+        .bind(limit)
+        .fetch_all(self.pool.get().await?.deref_mut())
+        .await?)
+    }
+}
+
+#[async_trait]
+impl PeerStorage for Database {
+    async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>> {
+        Database::get_peer(self, id).await
+    }
+
+    async fn insert_peer(
+        &self,
+        id: &str,
+        uuid: &[u8],
+        pk: &[u8],
+        info: &str,
+    ) -> ResultType<Vec<u8>> {
+        Database::insert_peer(self, id, uuid, pk, info).await
+    }
+
+    async fn update_pk(&self, guid: &Vec<u8>, id: &str, pk: &[u8], info: &str) -> ResultType<()> {
+        Database::update_pk(self, guid, id, pk, info).await
+    }
+
+    async fn delete_peer(&self, id: &str) -> ResultType<bool> {
+        Database::delete_peer(self, id).await
+    }
+
+    async fn get_peer_records(&self) -> ResultType<Vec<PeerRecord>> {
+        Database::get_peer_records(self).await
+    }
+
+    async fn import_peer_record(&self, peer: &PeerRecord) -> ResultType<bool> {
+        Database::import_peer_record(self, peer).await
+    }
+
+    async fn clear_pk(&self, id: &str) -> ResultType<bool> {
+        Database::clear_pk(self, id).await
+    }
+
+    async fn get_peer_infos_before(&self, version: u32) -> ResultType<Vec<(String, String)>> {
+        Database::get_peer_infos_before(self, version).await
+    }
+
+    async fn update_info(&self, id: &str, info: &str) -> ResultType<()> {
+        Database::update_info(self, id, info).await
+    }
+
+    async fn touch_peers(&self, ids: &[String], seen: u64) -> ResultType<()> {
+        Database::touch_peers(self, ids, seen).await
+    }
+
+    async fn update_peer_stats(
+        &self,
+        rows: &[(String, u64, u64, Option<(u64, &str)>)],
+    ) -> ResultType<()> {
+        Database::update_peer_stats(self, rows).await
+    }
+
+    async fn get_peers_seen_before(&self, before: u64) -> ResultType<Vec<(String, String)>> {
+        Database::get_peers_seen_before(self, before).await
+    }
+
+    async fn quarantine_peer(&self, id: &str, reason: &str) -> ResultType<bool> {
+        Database::quarantine_peer(self, id, reason).await
+    }
+
+    async fn get_quarantined(
+        &self,
+        id: Option<&str>,
+    ) -> ResultType<Vec<(String, String, i64, Option<String>)>> {
+        Database::get_quarantined(self, id).await
+    }
+
+    async fn restore_quarantined(&self, id: &str, info: &str) -> ResultType<bool> {
+        Database::restore_quarantined(self, id, info).await
+    }
+
+    async fn delete_quarantined(&self, id: &str) -> ResultType<bool> {
+        Database::delete_quarantined(self, id).await
+    }
+}
+
+/// Whether `err` means a row couldn't be decoded, rather than the database
+/// being unavailable.
+pub fn is_corruption(err: &hbb_common::anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<SqlxError>(),
+        Some(SqlxError::ColumnDecode { .. } | SqlxError::Decode(_))
+    )
 }
 
 #[cfg(test)]
@@ -0,0 +1,214 @@
+use crate::{
+    common::{get_arg, get_arg_or},
+    database::PeerStorage,
+    expiry, id_norm, migration,
+    peer::{db_url, open_databases},
+    tombstone,
+};
+use hbb_common::{bail, config::RENDEZVOUS_PORT, tokio, ResultType};
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+const USAGE: &str = "Usage: hbbs db list|get <id>|delete <id>|export [<file>]|import <file>";
+
+/// `hbbs db ...` shows and repairs the peer records. With hbbs running it's
+/// asked through its loopback console, with `CONSOLE_TOKEN` if the console
+/// requires one, so deletes and imports reach the peers in memory too;
+/// otherwise the database at `DB_URL` is opened directly.
+pub fn main(args: &[String]) -> ResultType<()> {
+    let cmd = match command(args) {
+        Ok(cmd) => cmd,
+        Err(err) => bail!("{}\n{}", err, USAGE),
+    };
+    let res = match ask_console(&cmd) {
+        Some(res) => res?,
+        None => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(exec_stopped(&cmd))?,
+    };
+    print!("{}", res);
+    Ok(())
+}
+
+// The console command, files made absolute for a running hbbs.
+fn command(args: &[String]) -> Result<String, &'static str> {
+    let arg = |i: usize| args.get(i).map(|x| x.as_str());
+    if args.len() > 2 {
+        return Err("too many arguments");
+    }
+    if args.iter().any(|x| x.contains(char::is_whitespace)) {
+        return Err("no spaces in arguments, the console splits on them");
+    }
+    match (arg(0), arg(1)) {
+        (Some(cmd @ ("list" | "export")), None) => Ok(cmd.to_owned()),
+        (Some(cmd @ ("get" | "delete")), Some(id)) => Ok(format!("{cmd} {id}")),
+        (Some(cmd @ ("export" | "import")), Some(file)) => {
+            let file = std::env::current_dir()
+                .map(|x| x.join(file))
+                .unwrap_or_else(|_| file.into());
+            let file = file.display().to_string();
+            if file.contains(char::is_whitespace) {
+                return Err("no spaces in the path of the file, the console splits on them");
+            }
+            Ok(format!("{cmd} {file}"))
+        }
+        (Some("get" | "delete"), None) => Err("missing id"),
+        (Some("import"), None) => Err("missing file"),
+        _ => Err("unknown command"),
+    }
+}
+
+// None if hbbs isn't running.
+fn ask_console(cmd: &str) -> Option<ResultType<String>> {
+    let port = get_arg_or("port", RENDEZVOUS_PORT.to_string())
+        .parse::<i32>()
+        .unwrap_or(RENDEZVOUS_PORT);
+    let port = get_arg("NAT_TEST_PORT").parse::<u16>().unwrap_or((port - 1) as _);
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(1)).ok()?;
+    Some(talk(stream, cmd))
+}
+
+fn talk(mut stream: TcpStream, cmd: &str) -> ResultType<String> {
+    let token = get_arg("CONSOLE_TOKEN");
+    let line = if token.is_empty() {
+        format!("peer-db {cmd}")
+    } else {
+        format!("{token} peer-db {cmd}")
+    };
+    stream.write_all(line.as_bytes())?;
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+    let mut res = String::new();
+    stream.read_to_string(&mut res)?;
+    Ok(res)
+}
+
+async fn exec_stopped(cmd: &str) -> ResultType<String> {
+    let url = db_url();
+    // Database::new would create it
+    if !url.contains("://") && !std::path::Path::new(&url).exists() {
+        bail!("No database at {}, set DB_URL", url);
+    }
+    tombstone::init();
+    let (db, storage) = open_databases().await?;
+    if let Some(("delete", id)) = cmd.split_once(' ') {
+        let id = id_norm::normalize(id);
+        if !storage.delete_peer(&id).await? {
+            return Ok("not found\n".to_owned());
+        }
+        db.add_tombstone(&id, "deleted", "db cli", tombstone::block_until() as _)
+            .await?;
+        return Ok(format!("deleted {id}\n"));
+    }
+    Ok(exec(&*storage, cmd).await)
+}
+
+/// `list`, `get <id>`, `export [<file>]` or `import <file>` against `db`,
+/// deletes are up to the caller, to leave a tombstone.
+pub(crate) async fn exec(db: &dyn PeerStorage, cmd: &str) -> String {
+    let mut fds = cmd.split(' ');
+    let res = match (fds.next(), fds.next()) {
+        (Some("list"), None) => list(db).await,
+        (Some("get"), Some(id)) => get(db, &id_norm::normalize(id)).await,
+        (Some("export"), file) => export(db, file).await,
+        (Some("import"), Some(file)) => import(db, file).await,
+        _ => Ok(format!("{USAGE}\n")),
+    };
+    res.unwrap_or_else(|err| format!("{err}\n"))
+}
+
+fn ip_of(info: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(info)
+        .ok()
+        .and_then(|x| x.get("ip")?.as_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+async fn list(db: &dyn PeerStorage) -> ResultType<String> {
+    let mut res = String::new();
+    for x in db.get_peer_records().await? {
+        let ip = ip_of(&x.info);
+        let ip = if ip.is_empty() { "-" } else { &ip };
+        let online = if expiry::is_online(&x.id) {
+            "online"
+        } else {
+            "offline"
+        };
+        let no_pk = if x.pk.is_empty() { " no-pk" } else { "" };
+        let _ = writeln!(res, "{} {} {}{}", x.id, ip, online, no_pk);
+    }
+    Ok(res)
+}
+
+// the record decoded, uuid and keys in base64
+async fn get(db: &dyn PeerStorage, id: &str) -> ResultType<String> {
+    let Some(peer) = db.get_peer(id).await? else {
+        return Ok("not found\n".to_owned());
+    };
+    let info: serde_json::Value = serde_json::from_str(&peer.info).unwrap_or(peer.info.into());
+    let json = serde_json::json!({
+        "id": peer.id,
+        "online": expiry::is_online(id),
+        "uuid": base64::encode(&peer.uuid),
+        "pk": base64::encode(&peer.pk),
+        "user": peer.user.map(base64::encode),
+        "status": peer.status,
+        "info": info,
+    });
+    Ok(format!("{:#}\n", json))
+}
+
+async fn export(db: &dyn PeerStorage, file: Option<&str>) -> ResultType<String> {
+    let records = db.get_peer_records().await?;
+    let n = records.len();
+    let json = migration::to_json(records)?;
+    let Some(file) = file else {
+        return Ok(json + "\n");
+    };
+    std::fs::write(file, json)?;
+    Ok(format!("exported {n} peers to {file}\n"))
+}
+
+// never replaces a record, as import-peers
+async fn import(db: &dyn PeerStorage, file: &str) -> ResultType<String> {
+    let mut imported = 0;
+    let mut taken = Vec::new();
+    for peer in migration::from_json(&std::fs::read_to_string(file)?)? {
+        if db.import_peer_record(&peer).await? {
+            imported += 1;
+        } else {
+            taken.push(peer.id);
+        }
+    }
+    Ok(if taken.is_empty() {
+        format!("imported {imported} peers\n")
+    } else {
+        format!("imported {} peers, ids taken: {}\n", imported, taken.join(","))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_console_commands() {
+        let args = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(command(&args(&["list"])), Ok("list".to_owned()));
+        assert_eq!(command(&args(&["get", "123456789"])), Ok("get 123456789".to_owned()));
+        assert_eq!(command(&args(&["export"])), Ok("export".to_owned()));
+        let import = command(&args(&["import", "peers.json"])).unwrap();
+        let file = import.strip_prefix("import ").unwrap();
+        assert!(std::path::Path::new(file).is_absolute());
+        assert!(command(&args(&["delete"])).is_err());
+        assert!(command(&args(&["import", "my peers.json"])).is_err());
+        assert!(command(&args(&["get", "123 456"])).is_err());
+        assert!(command(&args(&["list", "x", "y"])).is_err());
+        assert!(command(&args(&["drop"])).is_err());
+    }
+}
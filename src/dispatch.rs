@@ -0,0 +1,498 @@
+use crate::{
+    capture,
+    common::{get_arg, get_arg_or, now_ms},
+    ip_filter, pcap,
+};
+use hbb_common::{log, rendezvous_proto::*, try_into_v4};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::Write as _,
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+pub(crate) const KINDS: [&str; 11] = [
+    "register-peer",
+    "register-pk",
+    "punch-hole-request",
+    "punch-hole-sent",
+    "local-addr",
+    "request-relay",
+    "relay-response",
+    "online-request",
+    "test-nat-request",
+    "peer-discovery",
+    "other",
+];
+const MAX_SOURCES: usize = 100_000;
+const DEFAULT_DEDUP_WINDOW: u64 = 500; // in ms
+const MAX_DEDUP: usize = 100_000;
+const DEFAULT_RATE_BAN: u64 = 60; // in seconds
+const MAX_RATE_BAN: u64 = 24 * 3600 * 1000; // in ms
+const RATE_BAN_RESET: u64 = 600 * 1000; // in ms without refusals
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Transport {
+    Udp = 0,
+    Tcp = 1,
+    Ws = 2,
+}
+
+const TRANSPORTS: [Transport; 3] = [Transport::Udp, Transport::Tcp, Transport::Ws];
+
+impl Transport {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Ws => "ws",
+        }
+    }
+}
+
+/// A parsed incoming message, before its handler runs.
+pub(crate) struct Inbound<'a> {
+    pub(crate) transport: Transport,
+    pub(crate) addr: SocketAddr,
+    pub(crate) bytes: &'a [u8],
+    pub(crate) msg: &'a RendezvousMessage,
+}
+
+impl Inbound<'_> {
+    fn kind(&self) -> usize {
+        kind_of(self.msg)
+    }
+}
+
+fn kind_of(msg: &RendezvousMessage) -> usize {
+    use rendezvous_message::Union;
+    match msg.union {
+        Some(Union::RegisterPeer(_)) => 0,
+        Some(Union::RegisterPk(_)) => 1,
+        Some(Union::PunchHoleRequest(_)) => 2,
+        Some(Union::PunchHoleSent(_)) => 3,
+        Some(Union::LocalAddr(_)) => 4,
+        Some(Union::RequestRelay(_)) => 5,
+        Some(Union::RelayResponse(_)) => 6,
+        Some(Union::OnlineRequest(_)) => 7,
+        Some(Union::TestNatRequest(_)) => 8,
+        Some(Union::PeerDiscovery(_)) => 9,
+        _ => KINDS.len() - 1,
+    }
+}
+
+/// The name of a message's type in the metrics, e.g. `punch-hole-request`.
+pub(crate) fn kind_name(msg: &RendezvousMessage) -> &'static str {
+    KINDS[kind_of(msg)]
+}
+
+/// Runs on every message of every transport ahead of its handler, so a
+/// policy doesn't have to be repeated in each match arm.
+pub(crate) trait Middleware: Sync {
+    fn name(&self) -> &'static str;
+    /// False drops the message.
+    fn inbound(&self, m: &Inbound) -> bool;
+    fn status(&self) -> String;
+}
+
+// in order, a dropped message isn't seen by the ones after
+static CHAIN: [&dyn Middleware; 6] = [&IpFilter, &Capture, &Pcap, &DEDUP, &METRICS, &RATE_LIMIT];
+
+/// Pass a message through the middleware, false if it's to be dropped.
+#[inline]
+pub(crate) fn inbound(
+    transport: Transport,
+    addr: SocketAddr,
+    bytes: &[u8],
+    msg: &RendezvousMessage,
+) -> bool {
+    let m = Inbound {
+        transport,
+        addr,
+        bytes,
+        msg,
+    };
+    CHAIN.iter().all(|x| x.inbound(&m))
+}
+
+pub(crate) fn init() {
+    DEDUP.init();
+    RATE_LIMIT.init();
+}
+
+pub(crate) fn status() -> String {
+    let mut res = String::new();
+    for m in CHAIN.iter() {
+        let _ = write!(res, "[{}]\n{}", m.name(), m.status());
+    }
+    res
+}
+
+struct IpFilter;
+
+impl Middleware for IpFilter {
+    fn name(&self) -> &'static str {
+        "ip-filter"
+    }
+
+    fn inbound(&self, m: &Inbound) -> bool {
+        ip_filter::admit(m.addr.ip())
+    }
+
+    fn status(&self) -> String {
+        ip_filter::status()
+    }
+}
+
+struct Capture;
+
+impl Middleware for Capture {
+    fn name(&self) -> &'static str {
+        "capture"
+    }
+
+    fn inbound(&self, m: &Inbound) -> bool {
+        capture::log_msg(m.transport.as_str(), m.addr, m.bytes, m.msg);
+        true
+    }
+
+    fn status(&self) -> String {
+        capture::status()
+    }
+}
+
+struct Pcap;
+
+impl Middleware for Pcap {
+    fn name(&self) -> &'static str {
+        "pcap"
+    }
+
+    fn inbound(&self, m: &Inbound) -> bool {
+        // the pcap file only has udp datagrams
+        if m.transport == Transport::Udp {
+            pcap::write(m.addr, m.bytes, m.msg);
+        }
+        true
+    }
+
+    fn status(&self) -> String {
+        pcap::status()
+    }
+}
+
+// UDP clients retransmit, the same datagram from the same address within
+// `DEDUP_WINDOW` ms is handled once. 0 is off.
+struct Dedup {
+    window: AtomicU64,
+    dropped: AtomicUsize,
+    // (source, hash of the datagram) -> first seen
+    seen: Mutex<Option<HashMap<(SocketAddr, u64), Instant>>>,
+}
+
+static DEDUP: Dedup = Dedup {
+    window: AtomicU64::new(DEFAULT_DEDUP_WINDOW),
+    dropped: AtomicUsize::new(0),
+    seen: Mutex::new(None),
+};
+
+impl Dedup {
+    fn init(&self) {
+        let window = get_arg_or("DEDUP_WINDOW", DEFAULT_DEDUP_WINDOW.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_DEDUP_WINDOW);
+        self.window.store(window, Ordering::SeqCst);
+        log::info!("DEDUP_WINDOW={}ms", window);
+    }
+
+    // false for a retransmit
+    fn admit(&self, addr: SocketAddr, bytes: &[u8], window: Duration, now: Instant) -> bool {
+        let Ok(mut seen) = self.seen.lock() else {
+            return true;
+        };
+        let seen = seen.get_or_insert_with(Default::default);
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        let key = (addr, hasher.finish());
+        if let Some(first) = seen.get(&key) {
+            if now.duration_since(*first) < window {
+                return false;
+            }
+        }
+        if seen.len() >= MAX_DEDUP && !seen.contains_key(&key) {
+            seen.retain(|_, first| now.duration_since(*first) < window);
+            if seen.len() >= MAX_DEDUP {
+                return true;
+            }
+        }
+        seen.insert(key, now);
+        true
+    }
+}
+
+impl Middleware for Dedup {
+    fn name(&self) -> &'static str {
+        "dedup"
+    }
+
+    fn inbound(&self, m: &Inbound) -> bool {
+        let window = self.window.load(Ordering::Relaxed);
+        if m.transport != Transport::Udp
+            || window == 0
+            || self.admit(m.addr, m.bytes, Duration::from_millis(window), Instant::now())
+        {
+            return true;
+        }
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    fn status(&self) -> String {
+        format!(
+            "window: {}ms\ndropped: {}\n",
+            self.window.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed)
+        )
+    }
+}
+
+// Messages by transport and kind.
+struct Metrics {
+    counts: [[AtomicUsize; KINDS.len()]; TRANSPORTS.len()],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZEROS: [AtomicUsize; KINDS.len()] = [ZERO; KINDS.len()];
+static METRICS: Metrics = Metrics {
+    counts: [ZEROS; TRANSPORTS.len()],
+};
+
+impl Middleware for Metrics {
+    fn name(&self) -> &'static str {
+        "metrics"
+    }
+
+    fn inbound(&self, m: &Inbound) -> bool {
+        self.counts[m.transport as usize][m.kind()].fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn status(&self) -> String {
+        let mut res = String::new();
+        for t in TRANSPORTS {
+            for (i, kind) in KINDS.iter().enumerate() {
+                let n = self.counts[t as usize][i].load(Ordering::Relaxed);
+                if n > 0 {
+                    let _ = writeln!(res, "{} {}: {}", t.as_str(), kind, n);
+                }
+            }
+        }
+        res
+    }
+}
+
+// At most `MSG_RATE_LIMIT` messages per second from an IP, over all
+// transports, in bursts of up to `MSG_RATE_BURST`, 0 is off. A source going
+// on past a second's worth of refusals is banned for `MSG_RATE_BAN` seconds,
+// doubling with every ban until it calms down.
+struct RateLimit {
+    limit: AtomicU32,
+    burst: AtomicU32,
+    ban: AtomicU64,
+    dropped: AtomicUsize,
+    bans: AtomicUsize,
+    sources: Mutex<Option<HashMap<IpAddr, Bucket>>>,
+}
+
+#[derive(Default)]
+struct Bucket {
+    tokens: u64, // in thousandths of a message
+    last: u64,   // in ms
+    refused: u32,
+    banned_until: u64, // in ms
+    bans: u32,         // in a row, for the escalation
+    last_refused: u64, // in ms
+}
+
+static RATE_LIMIT: RateLimit = RateLimit {
+    limit: AtomicU32::new(0),
+    burst: AtomicU32::new(0),
+    ban: AtomicU64::new(DEFAULT_RATE_BAN),
+    dropped: AtomicUsize::new(0),
+    bans: AtomicUsize::new(0),
+    sources: Mutex::new(None),
+};
+
+impl RateLimit {
+    fn init(&self) {
+        let limit = get_arg("MSG_RATE_LIMIT").parse().unwrap_or(0);
+        let burst = get_arg_or("MSG_RATE_BURST", limit.to_string())
+            .parse()
+            .unwrap_or(limit)
+            .max(limit);
+        let ban = get_arg_or("MSG_RATE_BAN", DEFAULT_RATE_BAN.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_RATE_BAN);
+        self.limit.store(limit, Ordering::SeqCst);
+        self.burst.store(burst, Ordering::SeqCst);
+        self.ban.store(ban, Ordering::SeqCst);
+        if limit > 0 {
+            log::info!(
+                "MSG_RATE_LIMIT={}/s, MSG_RATE_BURST={}, MSG_RATE_BAN={}s",
+                limit,
+                burst,
+                ban
+            );
+        }
+    }
+
+    // `now` in ms
+    fn admit(&self, ip: IpAddr, limit: u32, burst: u32, now: u64) -> bool {
+        let Ok(mut sources) = self.sources.lock() else {
+            return true;
+        };
+        let sources = sources.get_or_insert_with(Default::default);
+        if sources.len() >= MAX_SOURCES && !sources.contains_key(&ip) {
+            sources.retain(|_, x| x.banned_until > now || now < x.last + 1000);
+            if sources.len() >= MAX_SOURCES {
+                return true;
+            }
+        }
+        let full = burst as u64 * 1000;
+        let x = sources.entry(ip).or_insert_with(|| Bucket {
+            tokens: full,
+            last: now,
+            ..Default::default()
+        });
+        if x.banned_until > now {
+            return false;
+        }
+        x.tokens = full.min(x.tokens + now.saturating_sub(x.last) * limit as u64);
+        x.last = now;
+        if x.bans > 0 && now > x.last_refused + RATE_BAN_RESET {
+            x.bans = 0;
+        }
+        if x.tokens >= 1000 {
+            x.tokens -= 1000;
+            return true;
+        }
+        x.refused += 1;
+        x.last_refused = now;
+        let ban = self.ban.load(Ordering::Relaxed) * 1000;
+        if x.refused > limit && ban > 0 {
+            let ban = (ban << x.bans.min(16)).min(MAX_RATE_BAN);
+            x.banned_until = now + ban;
+            x.bans += 1;
+            x.refused = 0;
+            self.bans.fetch_add(1, Ordering::Relaxed);
+            log::warn!("{} banned for {}s, over MSG_RATE_LIMIT", ip, ban / 1000);
+        }
+        false
+    }
+}
+
+impl Middleware for RateLimit {
+    fn name(&self) -> &'static str {
+        "rate-limit"
+    }
+
+    fn inbound(&self, m: &Inbound) -> bool {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let burst = self.burst.load(Ordering::Relaxed);
+        if limit == 0 || self.admit(try_into_v4(m.addr).ip(), limit, burst, now_ms()) {
+            return true;
+        }
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    fn status(&self) -> String {
+        let now = now_ms();
+        let banned = self.sources.lock().map_or(0, |x| {
+            x.as_ref()
+                .map_or(0, |x| x.values().filter(|x| x.banned_until > now).count())
+        });
+        format!(
+            "limit: {}/s\nburst: {}\ndropped: {}\nbans: {}\nbanned now: {}\n",
+            self.limit.load(Ordering::Relaxed),
+            self.burst.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.bans.load(Ordering::Relaxed),
+            banned
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limit(ban: u64) -> RateLimit {
+        RateLimit {
+            limit: AtomicU32::new(2),
+            burst: AtomicU32::new(2),
+            ban: AtomicU64::new(ban),
+            dropped: AtomicUsize::new(0),
+            bans: AtomicUsize::new(0),
+            sources: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn limits_messages_per_second() {
+        let limit = rate_limit(0);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(limit.admit(ip, 2, 2, 100_000));
+        assert!(limit.admit(ip, 2, 2, 100_000));
+        assert!(!limit.admit(ip, 2, 2, 100_000));
+        assert!(limit.admit("10.0.0.2".parse().unwrap(), 2, 2, 100_000));
+        assert!(limit.admit(ip, 2, 2, 101_000));
+        let msg = RendezvousMessage::new();
+        let m = Inbound {
+            transport: Transport::Tcp,
+            addr: "10.0.0.1:1".parse().unwrap(),
+            bytes: &[],
+            msg: &msg,
+        };
+        assert_eq!(KINDS[m.kind()], "other");
+    }
+
+    #[test]
+    fn escalates_bans() {
+        let limit = rate_limit(10);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let flood = |now| (0..5).filter(|_| limit.admit(ip, 2, 2, now)).count();
+        assert_eq!(flood(0), 2);
+        // banned for 10s, refill doesn't help
+        assert!(!limit.admit(ip, 2, 2, 9_000));
+        assert_eq!(flood(10_000), 2);
+        // then 20s
+        assert!(!limit.admit(ip, 2, 2, 29_000));
+        assert!(limit.admit(ip, 2, 2, 30_000));
+        assert_eq!(limit.bans.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn drops_retransmits_within_window() {
+        let dedup = Dedup {
+            window: AtomicU64::new(500),
+            dropped: AtomicUsize::new(0),
+            seen: Mutex::new(None),
+        };
+        let addr: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let window = Duration::from_millis(500);
+        let now = Instant::now();
+        assert!(dedup.admit(addr, b"reg", window, now));
+        assert!(!dedup.admit(addr, b"reg", window, now + Duration::from_millis(100)));
+        assert!(dedup.admit(addr, b"other", window, now));
+        assert!(dedup.admit("10.0.0.2:1".parse().unwrap(), b"reg", window, now));
+        assert!(dedup.admit(addr, b"reg", window, now + Duration::from_millis(600)));
+    }
+}
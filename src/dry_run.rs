@@ -0,0 +1,108 @@
+use crate::common::get_arg;
+use hbb_common::log;
+use std::{
+    fmt::{Display, Write as _},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// A policy that may refuse a client.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Rule {
+    Ban = 0,
+    IpBlocker = 1,
+    Cooldown = 2,
+    Quota = 3,
+    LoadShed = 4,
+    Unregistered = 5,
+}
+
+const NAMES: [&str; 6] = [
+    "ban",
+    "ip-blocker",
+    "cooldown",
+    "quota",
+    "load-shed",
+    "unregistered",
+];
+
+static DRY_RUN: [AtomicBool; 6] = [const { AtomicBool::new(false) }; 6];
+// refusals, or would-be refusals in dry run
+static HITS: [AtomicUsize; 6] = [const { AtomicUsize::new(0) }; 6];
+
+/// `POLICY_DRY_RUN` is a comma separated list of rules, or `all`, which only
+/// log what they would have refused.
+pub(crate) fn init() {
+    let v = get_arg("POLICY_DRY_RUN").to_lowercase();
+    for x in v.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        if x == "all" {
+            DRY_RUN.iter().for_each(|x| x.store(true, Ordering::SeqCst));
+        } else if !set(x, true) {
+            log::error!("Unknown rule {} in POLICY_DRY_RUN, expected one of {:?}", x, NAMES);
+        }
+    }
+    if !v.is_empty() {
+        log::info!("POLICY_DRY_RUN={}", dry_rules().join(","));
+    }
+}
+
+fn dry_rules() -> Vec<&'static str> {
+    NAMES
+        .iter()
+        .zip(DRY_RUN.iter())
+        .filter(|x| x.1.load(Ordering::SeqCst))
+        .map(|x| *x.0)
+        .collect()
+}
+
+/// Returns false if the rule doesn't exist.
+pub(crate) fn set(rule: &str, dry_run: bool) -> bool {
+    let Some(i) = NAMES.iter().position(|x| *x == rule) else {
+        return false;
+    };
+    DRY_RUN[i].store(dry_run, Ordering::SeqCst);
+    true
+}
+
+/// Called when `rule` would refuse `subject`. Returns whether to refuse,
+/// false in dry run.
+pub(crate) fn enforce(rule: Rule, subject: impl Display) -> bool {
+    HITS[rule as usize].fetch_add(1, Ordering::Relaxed);
+    if DRY_RUN[rule as usize].load(Ordering::Relaxed) {
+        log::info!("Dry run: {} would have refused {}", NAMES[rule as usize], subject);
+        return false;
+    }
+    true
+}
+
+pub(crate) fn status() -> String {
+    let mut res = String::new();
+    for (i, name) in NAMES.iter().enumerate() {
+        let _ = writeln!(
+            res,
+            "{}: {} hits={}",
+            name,
+            if DRY_RUN[i].load(Ordering::SeqCst) {
+                "dry run"
+            } else {
+                "enforced"
+            },
+            HITS[i].load(Ordering::Relaxed)
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dry_run_does_not_refuse() {
+        assert!(!set("geo", true));
+        assert!(enforce(Rule::Cooldown, "123456789"));
+        assert!(set("cooldown", true));
+        assert!(!enforce(Rule::Cooldown, "123456789"));
+        assert_eq!(HITS[Rule::Cooldown as usize].load(Ordering::Relaxed), 2);
+        assert!(set("cooldown", false));
+    }
+}
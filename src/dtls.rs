@@ -0,0 +1,137 @@
+use crate::common::{get_arg, get_arg_or};
+use hbb_common::{bail, log, timeout, ResultType};
+use sodiumoxide::crypto::hash::sha256;
+use std::{
+    io::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use webrtc_dtls::{
+    config::{Config, ExtendedMasterSecretType},
+    conn::DTLSConn,
+    crypto::Certificate,
+};
+use webrtc_util::conn::{conn_udp_listener, Conn, Listener};
+
+const DEFAULT_CERT: &str = "dtls.pem";
+const HANDSHAKE_TIMEOUT: u64 = 10_000; // in ms
+
+pub(crate) type DtlsConn = Arc<dyn Conn + Send + Sync>;
+pub(crate) type DtlsListener = Box<dyn Listener + Send + Sync>;
+
+static ACCEPTED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+/// `DTLS_PORT` is a UDP port taking the messages of the TCP listener, one per
+/// DTLS datagram, so a client can register and punch holes over UDP without
+/// its key and addresses being read or altered on the way. The certificate
+/// and its private key are in `DTLS_CERT`, `dtls.pem` by default, made up
+/// self-signed on the first start; clients pin its fingerprint, which is
+/// logged. 0 is off.
+pub(crate) fn init() -> ResultType<Option<(u16, Config)>> {
+    let port = get_arg("DTLS_PORT").parse::<u16>().unwrap_or(0);
+    if port == 0 {
+        return Ok(None);
+    }
+    let path = get_arg_or("DTLS_CERT", DEFAULT_CERT.to_owned());
+    let cert = load_or_create(&path)?;
+    let der: &[u8] = match cert.certificate.first() {
+        Some(x) => x.as_ref(),
+        None => &[],
+    };
+    log::info!("DTLS_PORT={}, certificate fingerprint sha-256 {}", port, fingerprint(der));
+    let config = Config {
+        certificates: vec![cert],
+        extended_master_secret: ExtendedMasterSecretType::Require,
+        ..Default::default()
+    };
+    Ok(Some((port, config)))
+}
+
+// in the PEM form of webrtc-dtls, the private key, then the certificate
+fn load_or_create(path: &str) -> ResultType<Certificate> {
+    if let Ok(pem) = std::fs::read_to_string(path) {
+        return Ok(Certificate::from_pem(&pem)?);
+    }
+    let cert = Certificate::generate_self_signed(vec!["rustdesk".to_owned()])?;
+    write_private(path, &cert.serialize_pem())?;
+    log::info!("Generated the DTLS certificate {}", path);
+    Ok(cert)
+}
+
+// readable by the owner only, as it holds the private key
+fn write_private(path: &str, pem: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(pem.as_bytes())
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    sha256::hash(der)
+        .0
+        .iter()
+        .map(|x| format!("{:02X}", x))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+pub(crate) async fn listen(addr: SocketAddr) -> ResultType<DtlsListener> {
+    Ok(Box::new(conn_udp_listener::listen(addr).await?))
+}
+
+/// The handshake of a new association, whose datagrams come plain from the
+/// listener.
+pub(crate) async fn accept(conn: DtlsConn, config: Config) -> ResultType<DtlsConn> {
+    let res = timeout(HANDSHAKE_TIMEOUT, DTLSConn::new(conn.clone(), config, false, None)).await;
+    let err = match res {
+        Ok(Ok(conn)) => {
+            ACCEPTED.fetch_add(1, Ordering::Relaxed);
+            return Ok(Arc::new(conn));
+        }
+        Ok(Err(err)) => err.to_string(),
+        Err(_) => "timed out".to_owned(),
+    };
+    FAILED.fetch_add(1, Ordering::Relaxed);
+    conn.close().await.ok();
+    bail!("dtls handshake: {}", err)
+}
+
+pub(crate) fn status() -> String {
+    if get_arg("DTLS_PORT").parse::<u16>().unwrap_or(0) == 0 {
+        return "off, set DTLS_PORT\n".to_owned();
+    }
+    format!(
+        "accepted: {}\nfailed: {}\n",
+        ACCEPTED.load(Ordering::Relaxed),
+        FAILED.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_is_colon_separated_hex() {
+        let x = fingerprint(b"");
+        assert_eq!(x.len(), 32 * 3 - 1);
+        assert!(x.starts_with("E3:B0:C4:42:98:FC"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_the_key_for_the_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("dtls-test-{}.pem", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        write_private(&path, "pem").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::common::now;
+use hbb_common::{anyhow, log, protobuf, tokio::time::error::Elapsed};
+use std::{
+    fmt::Write as _,
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// What an error says about its cause, and so how loud it is logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Class {
+    /// The network, e.g. a peer gone mid-request, passes by itself.
+    Transient = 0,
+    /// What a client sent, e.g. a malformed message or a refused request.
+    Client = 1,
+    /// Our side, e.g. the database or no file descriptors left.
+    Fatal = 2,
+}
+
+const CLASSES: [&str; 3] = ["transient", "client", "fatal"];
+
+/// Where an error of a single request or connection was handled.
+#[allow(dead_code)] // hbbr only has some of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Site {
+    Connection = 0,
+    PunchHole = 1,
+    Forward = 2,
+    Reply = 3,
+    Online = 4,
+    Relay = 5,
+    Update = 6,
+}
+
+const SITES: [&str; 7] = [
+    "connection",
+    "punch-hole",
+    "forward",
+    "reply",
+    "online",
+    "relay",
+    "update",
+];
+
+static COUNTS: [[AtomicUsize; 3]; 7] = [const { [const { AtomicUsize::new(0) }; 3] }; 7];
+// unix time of the last fatal error logged
+static LAST_LOGGED: [AtomicU64; 7] = [const { AtomicU64::new(0) }; 7];
+
+lazy_static::lazy_static! {
+    static ref LAST_FATAL: Mutex<String> = Default::default();
+}
+
+pub(crate) fn classify(err: &anyhow::Error) -> Class {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            return classify_io(err);
+        }
+        if cause.is::<Elapsed>() {
+            return Class::Transient;
+        }
+        if cause.is::<protobuf::Error>() {
+            return Class::Client;
+        }
+        if cause.is::<sqlx::Error>() {
+            return Class::Fatal;
+        }
+        if let Some(err) = cause.downcast_ref::<tungstenite::Error>() {
+            use tungstenite::Error::*;
+            return match err {
+                ConnectionClosed | AlreadyClosed => Class::Transient,
+                Io(err) => classify_io(err),
+                Protocol(_) | Capacity(_) | Utf8 | Url(_) | Http(_) | HttpFormat(_) => {
+                    Class::Client
+                }
+                _ => Class::Fatal,
+            };
+        }
+    }
+    // bail! of a handler, about the request
+    Class::Client
+}
+
+fn classify_io(err: &std::io::Error) -> Class {
+    match err.kind() {
+        ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::NotConnected
+        | ErrorKind::BrokenPipe
+        | ErrorKind::TimedOut
+        | ErrorKind::UnexpectedEof
+        | ErrorKind::WouldBlock
+        | ErrorKind::Interrupted
+        | ErrorKind::AddrNotAvailable => Class::Transient,
+        ErrorKind::InvalidData | ErrorKind::InvalidInput => Class::Client,
+        // e.g. EMFILE
+        _ => Class::Fatal,
+    }
+}
+
+/// Count and log the error of one request or connection at `site`, where
+/// it can't be answered any more. Nothing is restarted for it, it concerns a
+/// single peer: only the sockets of the main loop are rebuilt, when they keep
+/// failing, see socket_errors.
+pub(crate) fn check<T, E: Into<anyhow::Error>>(site: Site, res: Result<T, E>) {
+    let Err(err) = res else {
+        return;
+    };
+    let err = err.into();
+    let class = classify(&err);
+    COUNTS[site as usize][class as usize].fetch_add(1, Ordering::Relaxed);
+    if class != Class::Fatal {
+        log::debug!("{} error ({}): {:?}", SITES[site as usize], CLASSES[class as usize], err);
+        return;
+    }
+    if let Ok(mut last) = LAST_FATAL.lock() {
+        *last = format!("{}: {}", SITES[site as usize], err);
+    }
+    // at most once a second per site, e.g. while out of file descriptors
+    let now = now();
+    if LAST_LOGGED[site as usize].swap(now, Ordering::Relaxed) != now {
+        log::error!("{} error: {:?}", SITES[site as usize], err);
+    }
+}
+
+pub(crate) fn status() -> String {
+    let mut res = format!("site {}\n", CLASSES.join(" "));
+    for (name, counts) in SITES.iter().zip(COUNTS.iter()) {
+        let _ = write!(res, "{}", name);
+        for n in counts {
+            let _ = write!(res, " {}", n.load(Ordering::Relaxed));
+        }
+        res.push('\n');
+    }
+    if let Ok(last) = LAST_FATAL.lock() {
+        if !last.is_empty() {
+            let _ = writeln!(res, "last fatal: {}", last);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_errors() {
+        let io = |kind| anyhow::Error::from(std::io::Error::new(kind, "x"));
+        assert_eq!(classify(&io(ErrorKind::ConnectionReset)), Class::Transient);
+        assert_eq!(classify(&io(ErrorKind::InvalidData)), Class::Client);
+        assert_eq!(classify(&io(ErrorKind::PermissionDenied)), Class::Fatal);
+        assert_eq!(
+            classify(&anyhow::Error::from(tungstenite::Error::ConnectionClosed)),
+            Class::Transient
+        );
+        assert_eq!(classify(&anyhow::anyhow!("invalid request")), Class::Client);
+        check(Site::Forward, Err::<(), _>(io(ErrorKind::BrokenPipe)));
+        let n = &COUNTS[Site::Forward as usize][Class::Transient as usize];
+        assert!(n.load(Ordering::SeqCst) > 0);
+    }
+}
@@ -0,0 +1,252 @@
+use crate::{
+    canary::Cohort,
+    churn,
+    common::get_arg_or,
+    registered,
+    timing::Stamp,
+    webhook::{self, Event},
+};
+use hbb_common::{
+    log,
+    tokio::{
+        self,
+        sync::watch,
+        time::{interval, Duration},
+    },
+};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+};
+
+const DEFAULT_REG_TIMEOUT: i64 = 30_000; // in ms
+const TICK: u64 = 100; // in ms, the resolution of expiry
+const BITS: u32 = 6;
+const SLOTS: u64 = 1 << BITS;
+const LEVELS: usize = 4; // SLOTS^LEVELS ticks is about 19 days
+
+static REG_TIMEOUT: AtomicI64 = AtomicI64::new(DEFAULT_REG_TIMEOUT);
+
+lazy_static::lazy_static! {
+    static ref START: Stamp = Stamp::now();
+    static ref WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+    // bumped on every online/offline transition
+    static ref CHANGES: watch::Sender<u64> = watch::channel(0).0;
+}
+
+// Online peers on a hierarchical timer wheel: level l holds deadlines less
+// than SLOTS^(l+1) ticks away and is cascaded into the levels below as time
+// gets there, so each tick only looks at the peers expiring in it. A peer
+// registering again gets a new entry, the old one is skipped as stale.
+struct Wheel {
+    now: u64, // in ticks since START
+    levels: Vec<Vec<Vec<(String, u64)>>>, // (id, deadline)
+    deadlines: HashMap<String, u64>,      // of the online peers
+}
+
+impl Wheel {
+    fn new() -> Self {
+        Self {
+            now: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            deadlines: Default::default(),
+        }
+    }
+
+    // Returns true if `id` came online.
+    fn schedule(&mut self, id: &str, deadline: u64) -> bool {
+        let deadline = deadline.max(self.now + 1);
+        let came_online = self.deadlines.insert(id.to_owned(), deadline).is_none();
+        self.insert(id.to_owned(), deadline);
+        came_online
+    }
+
+    fn insert(&mut self, id: String, deadline: u64) {
+        let at = deadline.max(self.now);
+        let delta = at - self.now;
+        let level = (0..LEVELS)
+            .find(|l| delta < 1 << (BITS * (*l as u32 + 1)))
+            .unwrap_or(LEVELS - 1);
+        // beyond the last level, wait in it and look again when cascaded
+        let at = at.min(self.now + (1 << (BITS * LEVELS as u32)) - 1);
+        let slot = (at >> (BITS * level as u32)) & (SLOTS - 1);
+        self.levels[level][slot as usize].push((id, deadline));
+    }
+
+    // Advance one tick, pushing the peers which went offline.
+    fn step(&mut self, offline: &mut Vec<String>) {
+        self.now += 1;
+        for level in 1..LEVELS {
+            let mask = (1 << (BITS * level as u32)) - 1;
+            if self.now & mask != 0 {
+                break;
+            }
+            let slot = (self.now >> (BITS * level as u32)) & (SLOTS - 1);
+            for (id, deadline) in std::mem::take(&mut self.levels[level][slot as usize]) {
+                self.insert(id, deadline);
+            }
+        }
+        let slot = self.now & (SLOTS - 1);
+        for (id, deadline) in std::mem::take(&mut self.levels[0][slot as usize]) {
+            if self.deadlines.get(&id) != Some(&deadline) {
+                continue;
+            }
+            if deadline <= self.now {
+                self.deadlines.remove(&id);
+                offline.push(id);
+            } else {
+                self.insert(id, deadline);
+            }
+        }
+    }
+}
+
+#[inline]
+fn now() -> u64 {
+    START.elapsed_ms() as u64 / TICK
+}
+
+/// `REG_TIMEOUT` is how long a peer stays online without registering
+/// again, in ms. Canary peers may have their own, see canary.
+pub(crate) fn init() {
+    let v = get_arg_or("REG_TIMEOUT", DEFAULT_REG_TIMEOUT.to_string())
+        .parse::<i64>()
+        .unwrap_or(DEFAULT_REG_TIMEOUT);
+    REG_TIMEOUT.store(v.max(1_000), Ordering::SeqCst);
+    log::info!("REG_TIMEOUT={}ms", reg_timeout());
+}
+
+#[inline]
+pub(crate) fn reg_timeout() -> i64 {
+    REG_TIMEOUT.load(Ordering::Relaxed)
+}
+
+/// Tick the wheel, also catching up after the host was suspended.
+pub(crate) fn start() {
+    tokio::spawn(async {
+        let mut timer = interval(Duration::from_millis(TICK));
+        loop {
+            timer.tick().await;
+            let mut offline = Vec::new();
+            if let Ok(mut wheel) = WHEEL.lock() {
+                let now = now();
+                while wheel.now < now {
+                    wheel.step(&mut offline);
+                }
+            }
+            for id in offline.iter() {
+                churn::on_offline(id);
+                webhook::emit(Event::PeerOffline, id, "", "");
+            }
+            if !offline.is_empty() {
+                CHANGES.send_modify(|x| *x += 1);
+            }
+        }
+    });
+}
+
+/// A peer registered from `ip` and stays online for its registration timeout.
+pub(crate) fn on_register(id: &str, ip: IpAddr) {
+    schedule(id, ip, Cohort::of(id).reg_timeout(reg_timeout()) as u64);
+}
+
+/// A peer registered `age` ms ago, before a restart, and stays online for
+/// the rest of its registration timeout.
+pub(crate) fn on_restore(id: &str, ip: IpAddr, age: i64) {
+    let timeout = Cohort::of(id).reg_timeout(reg_timeout()) - age;
+    if timeout > 0 {
+        schedule(id, ip, timeout as u64);
+    }
+}
+
+fn schedule(id: &str, ip: IpAddr, timeout: u64) {
+    registered::on_register(ip);
+    let came_online = match WHEEL.lock() {
+        Ok(mut wheel) => wheel.schedule(id, now() + (timeout + TICK - 1) / TICK),
+        Err(_) => return,
+    };
+    if came_online {
+        churn::on_online(id, ip);
+        webhook::emit(Event::PeerOnline, id, &ip.to_string(), "");
+        CHANGES.send_modify(|x| *x += 1);
+    }
+}
+
+/// Take a peer offline before its timeout, e.g. when it's dropped from memory.
+pub(crate) fn forget(id: &str) {
+    let went_offline = WHEEL
+        .lock()
+        .is_ok_and(|mut wheel| wheel.deadlines.remove(id).is_some());
+    if went_offline {
+        churn::on_offline(id);
+        webhook::emit(Event::PeerOffline, id, "", "");
+        CHANGES.send_modify(|x| *x += 1);
+    }
+}
+
+#[inline]
+pub(crate) fn is_online(id: &str) -> bool {
+    // not waiting for the next tick
+    let now = now();
+    WHEEL
+        .lock()
+        .is_ok_and(|wheel| wheel.deadlines.get(id).is_some_and(|x| *x > now))
+}
+
+#[inline]
+pub(crate) fn online() -> usize {
+    WHEEL.lock().map(|wheel| wheel.deadlines.len()).unwrap_or(0)
+}
+
+/// Changes whenever a peer goes online or offline.
+pub(crate) fn subscribe() -> watch::Receiver<u64> {
+    CHANGES.subscribe()
+}
+
+pub(crate) fn status() -> String {
+    let mut res = String::new();
+    if let Ok(wheel) = WHEEL.lock() {
+        let entries: usize = wheel.levels.iter().flatten().map(Vec::len).sum();
+        let _ = writeln!(res, "online: {}", wheel.deadlines.len());
+        let _ = writeln!(res, "timer entries: {}", entries);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wheel_expires_on_time() {
+        let mut wheel = Wheel::new();
+        let mut offline = Vec::new();
+        assert!(wheel.schedule("short", 10));
+        assert!(wheel.schedule("long", 5_000));
+        assert!(wheel.schedule("renewed", 10));
+        assert!(!wheel.schedule("renewed", 300));
+        assert!(wheel.schedule("far", 1 << 25)); // beyond the last level
+        while wheel.now < 10 {
+            wheel.step(&mut offline);
+        }
+        assert_eq!(offline, vec!["short"]);
+        while wheel.now < 300 {
+            wheel.step(&mut offline);
+        }
+        assert_eq!(offline, vec!["short", "renewed"]);
+        while wheel.now < 4_999 {
+            wheel.step(&mut offline);
+        }
+        assert_eq!(offline.len(), 2);
+        wheel.step(&mut offline);
+        assert_eq!(offline, vec!["short", "renewed", "long"]);
+        assert_eq!(wheel.deadlines.len(), 1);
+    }
+}
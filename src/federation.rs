@@ -0,0 +1,130 @@
+use crate::common::get_arg;
+use hbb_common::{
+    bail, config, log, protobuf::Message as _, rendezvous_proto::*, tcp::FramedStream, ResultType,
+};
+use once_cell::sync::OnceCell;
+use std::{collections::HashMap, fmt::Write as _, sync::Mutex, time::Instant};
+
+const CACHE_DUR: u64 = 60; // in seconds, online states change
+const MAX_CACHE: usize = 100_000;
+const LOOKUP_TIMEOUT: u64 = 3_000; // in ms
+
+static UPSTREAMS: OnceCell<Vec<String>> = OnceCell::new();
+
+lazy_static::lazy_static! {
+    // id -> (upstream it is online on, looked up at)
+    static ref CACHE: Mutex<HashMap<String, (Option<String>, Instant)>> = Default::default();
+}
+
+/// `UPSTREAM_SERVERS` is a comma separated list of `host[:port]` of other
+/// rendezvous servers asked for ids unknown here.
+pub(crate) fn init() {
+    let upstreams: Vec<String> = get_arg("UPSTREAM_SERVERS")
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            if x.contains(':') {
+                x.to_owned()
+            } else {
+                format!("{}:{}", x, config::RENDEZVOUS_PORT)
+            }
+        })
+        .collect();
+    if upstreams.is_empty() {
+        return;
+    }
+    log::info!("UPSTREAM_SERVERS={}", upstreams.join(","));
+    UPSTREAMS.set(upstreams).ok();
+}
+
+/// The upstream server `id` is online on, cached for CACHE_DUR.
+pub(crate) async fn lookup(id: &str) -> Option<String> {
+    let upstreams = UPSTREAMS.get()?;
+    if let Ok(cache) = CACHE.lock() {
+        if let Some((upstream, tm)) = cache.get(id) {
+            if tm.elapsed().as_secs() < CACHE_DUR {
+                return upstream.clone();
+            }
+        }
+    }
+    let mut found = None;
+    for upstream in upstreams {
+        match is_online(upstream, id).await {
+            Ok(true) => {
+                found = Some(upstream.clone());
+                break;
+            }
+            Ok(false) => {}
+            Err(err) => log::debug!("Failed to look up {} on {}: {}", id, upstream, err),
+        }
+    }
+    if let Ok(mut cache) = CACHE.lock() {
+        if cache.len() >= MAX_CACHE {
+            cache.retain(|_, x| x.1.elapsed().as_secs() < CACHE_DUR);
+        }
+        if cache.len() < MAX_CACHE {
+            cache.insert(id.to_owned(), (found.clone(), Instant::now()));
+        }
+    }
+    found
+}
+
+// Ask like a client would, so upstreams need nothing new.
+async fn is_online(upstream: &str, id: &str) -> ResultType<bool> {
+    let mut stream = FramedStream::new(upstream, None, LOOKUP_TIMEOUT).await?;
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_online_request(OnlineRequest {
+        peers: vec![id.to_owned()],
+        ..Default::default()
+    });
+    stream.send(&msg_out).await?;
+    let Some(Ok(bytes)) = stream.next_timeout(LOOKUP_TIMEOUT).await else {
+        bail!("no response");
+    };
+    match RendezvousMessage::parse_from_bytes(&bytes)?.union {
+        Some(rendezvous_message::Union::OnlineResponse(res)) => Ok(is_first_online(&res.states)),
+        _ => bail!("unexpected response"),
+    }
+}
+
+// one bit per id from the most significant bit of the first byte
+#[inline]
+fn is_first_online(states: &[u8]) -> bool {
+    states.first().is_some_and(|x| x & 0x80 != 0)
+}
+
+/// What a client connecting to `id` is told, the id with the server suffix
+/// lets it connect through the other server.
+pub(crate) fn redirect(id: &str, upstream: &str) -> String {
+    format!("{id} is on another server, please connect to {id}@{upstream}")
+}
+
+pub(crate) fn status() -> String {
+    let Some(upstreams) = UPSTREAMS.get() else {
+        return "off, set UPSTREAM_SERVERS\n".to_owned();
+    };
+    let mut res = format!("upstreams: {}\n", upstreams.join(","));
+    if let Ok(cache) = CACHE.lock() {
+        let found = cache.values().filter(|x| x.0.is_some()).count();
+        let _ = writeln!(res, "cached: {} ({} found upstream)", cache.len(), found);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_first_state() {
+        assert!(is_first_online(&[0x80]));
+        assert!(is_first_online(&[0xff, 0]));
+        assert!(!is_first_online(&[0x40]));
+        assert!(!is_first_online(&[]));
+        assert_eq!(
+            redirect("123456789", "hbbs.example.com:21116"),
+            "123456789 is on another server, please connect to 123456789@hbbs.example.com:21116"
+        );
+    }
+}
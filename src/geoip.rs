@@ -0,0 +1,136 @@
+use crate::common::get_arg;
+use hbb_common::{log, ResultType};
+use maxminddb::{geoip2, Reader};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    net::IpAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+struct Geo {
+    reader: Reader<Vec<u8>>,
+    regions: HashMap<String, String>, // country or continent code -> region
+    relays: HashMap<String, String>,  // relay of RELAY_SERVERS -> region
+}
+
+static GEO: OnceCell<Geo> = OnceCell::new();
+static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+static MISSES: AtomicUsize = AtomicUsize::new(0); // no region for the address
+
+/// `GEOIP_DB` is a MaxMind database, GeoLite2 or GeoIP2 Country or City, to
+/// hand out the relay in the region of the requester. `GEO_REGIONS` maps
+/// ISO country codes or continent codes to regions, e.g. `DE=eu,EU=eu,NA=us`,
+/// the country's first; relays which report themselves are in their
+/// `RELAY_REGION`, those of `RELAY_SERVERS` in `RELAY_REGIONS`, e.g.
+/// `relay-eu.example.com=eu`.
+pub(crate) fn init() -> ResultType<()> {
+    let path = get_arg("GEOIP_DB");
+    if path.is_empty() {
+        return Ok(());
+    }
+    let reader = Reader::open_readfile(&path)?;
+    let regions = parse(&get_arg("GEO_REGIONS"), true);
+    let relays = parse(&get_arg("RELAY_REGIONS"), false);
+    log::info!(
+        "GEOIP_DB={}, {} codes in {} regions",
+        path,
+        regions.len(),
+        regions.values().collect::<std::collections::HashSet<_>>().len()
+    );
+    GEO.set(Geo {
+        reader,
+        regions,
+        relays,
+    })
+    .ok();
+    Ok(())
+}
+
+// `<key>=<region>`, comma separated, codes upper case and regions lower case
+fn parse(v: &str, codes: bool) -> HashMap<String, String> {
+    let mut res = HashMap::new();
+    for x in v.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        match x.split_once('=') {
+            Some((key, region)) if !key.trim().is_empty() && !region.trim().is_empty() => {
+                let key = if codes {
+                    key.trim().to_uppercase()
+                } else {
+                    key.trim().to_owned()
+                };
+                res.insert(key, region.trim().to_lowercase());
+            }
+            _ => log::error!("Invalid region {}, expected <key>=<region>", x),
+        }
+    }
+    res
+}
+
+/// The region of `ip`, by its country, then its continent.
+pub(crate) fn region(ip: IpAddr) -> Option<String> {
+    let geo = GEO.get()?;
+    LOOKUPS.fetch_add(1, Ordering::Relaxed);
+    let res = geo
+        .reader
+        .lookup::<geoip2::Country>(ip.to_canonical())
+        .ok()
+        .and_then(|x| {
+            let country = x.country.and_then(|x| x.iso_code);
+            let continent = x.continent.and_then(|x| x.code);
+            [country, continent]
+                .into_iter()
+                .flatten()
+                .find_map(|x| geo.regions.get(x).cloned())
+        });
+    if res.is_none() {
+        MISSES.fetch_add(1, Ordering::Relaxed);
+    }
+    res
+}
+
+/// The ISO country code of `ip`.
+pub(crate) fn country(ip: IpAddr) -> Option<String> {
+    let geo = GEO.get()?;
+    let res = geo.reader.lookup::<geoip2::Country>(ip.to_canonical()).ok()?;
+    res.country?.iso_code.map(|x| x.to_owned())
+}
+
+#[inline]
+pub(crate) fn enabled() -> bool {
+    GEO.get().is_some()
+}
+
+/// The region of `relay` of `RELAY_SERVERS`, if configured.
+pub(crate) fn relay_region(relay: &str) -> Option<&'static str> {
+    GEO.get()?.relays.get(relay).map(|x| x.as_str())
+}
+
+pub(crate) fn status() -> String {
+    let Some(geo) = GEO.get() else {
+        return "off, set GEOIP_DB\n".to_owned();
+    };
+    let mut res = format!(
+        "lookups: {}\nmisses: {}\n",
+        LOOKUPS.load(Ordering::Relaxed),
+        MISSES.load(Ordering::Relaxed)
+    );
+    for (relay, region) in geo.relays.iter() {
+        let _ = writeln!(res, "{}: {}", relay, region);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_regions() {
+        let regions = parse("de=EU, EU=eu,bad, =x, NA=us", true);
+        assert_eq!(regions.len(), 3);
+        assert_eq!(regions.get("DE").map(|x| x.as_str()), Some("eu"));
+        let relays = parse("Relay-EU.example.com=eu", false);
+        assert!(relays.contains_key("Relay-EU.example.com"));
+    }
+}
@@ -1,5 +1,9 @@
 use clap::App;
 mod common;
+mod errors;
+mod instance;
+mod relay_quota;
+mod relay_report;
 mod relay_server;
 use flexi_logger::*;
 use hbb_common::{config::RELAY_PORT, ResultType};
@@ -9,13 +13,14 @@ mod version;
 fn main() -> ResultType<()> {
     let _logger = Logger::try_with_env_or_str("info")?
         .log_to_stdout()
-        .format(opt_format)
+        .format(common::log_format())
         .write_mode(WriteMode::Async)
         .start()?;
     let args = format!(
         "-b, --bind=[IP] 'Sets the IP address to bind to (default: all interfaces)'
         -p, --port=[NUMBER(default={RELAY_PORT})] 'Sets the listening port'
         -k, --key=[KEY] 'Only allow the client with the same key'
+        --print-config 'Prints the effective configuration and where each value comes from, then exits'
         ",
     );
     let matches = App::new("hbbr")
@@ -26,9 +31,16 @@ fn main() -> ResultType<()> {
         .get_matches();
     if let Ok(v) = ini::Ini::load_from_file(".env") {
         if let Some(section) = v.section(None::<String>) {
-            section.iter().for_each(|(k, v)| common::set_arg(k, v));
+            section
+                .iter()
+                .for_each(|(k, v)| common::set_arg_from(k, v, ".env"));
         }
     }
+    if matches.is_present("print-config") {
+        common::set_args_from(&matches);
+        print!("{}", common::effective_config("hbbr"));
+        return Ok(());
+    }
     let mut port = RELAY_PORT;
     if let Some(v) = common::get_arg_opt("PORT") {
         let v: i32 = v.parse().unwrap_or_default();
@@ -45,6 +57,7 @@ fn main() -> ResultType<()> {
         .value_of("key")
         .map(str::to_owned)
         .unwrap_or_else(|| common::get_arg("KEY"));
+    instance::init("hbbr");
     start_with_bind(
         bind_addr,
         matches.value_of("port").unwrap_or(&port.to_string()),
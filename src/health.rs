@@ -0,0 +1,183 @@
+use crate::common::{get_arg, get_arg_or, listen_tcp};
+use hbb_common::{
+    log,
+    tokio::{
+        self,
+        io::{AsyncReadExt, AsyncWriteExt},
+    },
+    ResultType,
+};
+use std::{
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const DEFAULT_RETRY_TIMEOUT: u64 = 60; // in seconds
+const INITIAL_BACKOFF: u64 = 500; // in ms
+const MAX_BACKOFF: u64 = 10_000; // in ms
+
+static READY: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref STATUS: Mutex<String> = Mutex::new("starting".to_owned());
+}
+
+pub(crate) fn set_status(ready: bool, status: &str) {
+    READY.store(ready, Ordering::SeqCst);
+    if let Ok(mut s) = STATUS.lock() {
+        *s = status.to_owned();
+    }
+}
+
+pub(crate) fn get_status() -> (bool, String) {
+    (
+        READY.load(Ordering::SeqCst),
+        STATUS.lock().map(|x| x.clone()).unwrap_or_default(),
+    )
+}
+
+/// Serve `GET /healthz`-style probes on HEALTHZ_PORT: 200 once ready, 503 with
+/// what we are waiting for otherwise. Started before anything that may need retries.
+/// `GET /status` serves the branded status page instead, see status_page, and
+/// `GET /relays` the demand on the relay pool, `GET /client-config(.png)` the
+/// client configuration of this server, see client_config. Only the probe
+/// with STRICT. Each response names the instance in `X-Instance-Id`.
+pub(crate) async fn start_healthz(bind_addr: Option<IpAddr>) -> ResultType<()> {
+    let port = get_arg("HEALTHZ_PORT").parse::<u16>().unwrap_or(0);
+    if port == 0 {
+        return Ok(());
+    }
+    let listener = listen_tcp(bind_addr, port).await?;
+    log::info!("Listening on tcp {} for health checks", listener.local_addr()?);
+    crate::status_page::init();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((mut stream, _)) => {
+                    tokio::spawn(async move {
+                        let mut buf = [0u8; 1024];
+                        let n = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut buf))
+                            .await
+                            .ok()
+                            .and_then(|x| x.ok())
+                            .unwrap_or(0);
+                        let (ready, status) = get_status();
+                        let req = &buf[..n];
+                        let (content_type, body) = match path(req) {
+                            _ if crate::strict::is_on() => ("text/plain", (status + "\n").into_bytes()),
+                            b"/status" => {
+                                ("text/html; charset=utf-8", crate::status_page::render(ready, &status).into_bytes())
+                            }
+                            b"/relays" => ("text/plain", crate::relay_registry::demand().into_bytes()),
+                            b"/client-config" => {
+                                ("text/plain", crate::client_config::status(&host(req)).into_bytes())
+                            }
+                            b"/client-config.png" => match crate::client_config::current_png(&host(req)) {
+                                Some(png) => ("image/png", png),
+                                None => ("text/plain", b"no client config\n".to_vec()),
+                            },
+                            _ => ("text/plain", (status + "\n").into_bytes()),
+                        };
+                        let res = format!(
+                            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nX-Instance-Id: {}\r\nConnection: close\r\n\r\n",
+                            if ready { "200 OK" } else { "503 Service Unavailable" },
+                            content_type,
+                            body.len(),
+                            crate::instance::id()
+                        );
+                        stream.write_all(&[res.into_bytes(), body].concat()).await.ok();
+                    });
+                }
+                Err(err) => {
+                    log::error!("healthz accept failed: {}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// any other path, or no request line at all, is a probe
+fn path(req: &[u8]) -> &[u8] {
+    let path = req.split(|c| *c == b' ').nth(1).unwrap_or_default();
+    path.split(|c| *c == b'?').next().unwrap_or_default()
+}
+
+// the host name of the Host header, without the port
+fn host(req: &[u8]) -> String {
+    let req = String::from_utf8_lossy(req);
+    let Some(host) = req.lines().find_map(|x| {
+        let (name, value) = x.split_once(':')?;
+        name.eq_ignore_ascii_case("host").then(|| value.trim())
+    }) else {
+        return "".to_owned();
+    };
+    if let Some(v6) = host.strip_prefix('[') {
+        return v6.split(']').next().unwrap_or_default().to_owned();
+    }
+    match host.split_once(':') {
+        Some((name, port)) if !port.contains(':') => name.to_owned(),
+        _ => host.to_owned(),
+    }
+}
+
+/// Retry `f` with exponential backoff for up to STARTUP_RETRY_TIMEOUT seconds,
+/// e.g. when the bind address or database isn't available yet in a container.
+pub(crate) async fn wait_for<T, F, Fut>(what: &str, mut f: F) -> ResultType<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ResultType<T>>,
+{
+    let timeout = get_arg_or("STARTUP_RETRY_TIMEOUT", DEFAULT_RETRY_TIMEOUT.to_string())
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_RETRY_TIMEOUT);
+    let started = Instant::now();
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(err) => {
+                if started.elapsed().as_secs() >= timeout {
+                    set_status(false, &format!("failed: {what}: {err}"));
+                    return Err(err);
+                }
+                set_status(false, &format!("waiting for {what}: {err}"));
+                log::warn!("{} not available: {}, retry in {}ms", what, err, backoff);
+                tokio::time::sleep(Duration::from_millis(backoff)).await;
+                backoff = next_backoff(backoff);
+            }
+        }
+    }
+}
+
+#[inline]
+fn next_backoff(backoff: u64) -> u64 {
+    (backoff * 2).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut backoff = INITIAL_BACKOFF;
+        for _ in 0..20 {
+            backoff = next_backoff(backoff);
+        }
+        assert_eq!(backoff, MAX_BACKOFF);
+        assert_eq!(next_backoff(INITIAL_BACKOFF), INITIAL_BACKOFF * 2);
+        assert_eq!(path(b"GET /status HTTP/1.1\r\n"), b"/status");
+        assert_eq!(path(b"GET /relays?x=1 HTTP/1.1\r\n"), b"/relays");
+        assert_eq!(path(b""), b"");
+        assert_eq!(host(b"GET / HTTP/1.1\r\nHost: hbbs.example.com:8080\r\n"), "hbbs.example.com");
+        assert_eq!(host(b"GET / HTTP/1.1\r\nhost: [::1]:8080\r\n"), "::1");
+        assert_eq!(host(b"GET / HTTP/1.1\r\n"), "");
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{
+    common::{get_arg, now},
+    database::Database,
+};
+use hbb_common::{log, tokio, ResultType};
+use once_cell::sync::OnceCell;
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+    time::UNIX_EPOCH,
+};
+
+pub(crate) const SAMPLE_INTERVAL: u64 = 60_000; // in ms
+const MINUTE: i64 = 60;
+const HOUR: i64 = 3600;
+const MINUTE_RETENTION: i64 = 2 * 24 * HOUR; // in seconds, older minutes are only kept as hours
+pub(crate) const METRICS: [&str; 5] = [
+    "peers",
+    "online",
+    "sessions",
+    "punch-requests",
+    "relay-requests",
+];
+
+static DB: OnceCell<Database> = OnceCell::new();
+static RETENTION: AtomicI64 = AtomicI64::new(0); // of hourly rollups, in seconds
+
+// the hour being rolled up
+struct Hour {
+    ts: i64,
+    sums: [f64; METRICS.len()],
+    n: usize,
+}
+
+lazy_static::lazy_static! {
+    static ref CURRENT: Mutex<Option<Hour>> = Default::default();
+}
+
+/// Keep METRICS_RETENTION_DAYS of hourly averages in the database, and the
+/// last two days by the minute. 0 is off.
+pub(crate) async fn init(db: Database) {
+    let days = get_arg("METRICS_RETENTION_DAYS").parse::<i64>().unwrap_or(0);
+    if days <= 0 {
+        return;
+    }
+    if let Err(err) = db.create_metric_table().await {
+        log::error!("Failed to create metric table: {}", err);
+        return;
+    }
+    log::info!("METRICS_RETENTION_DAYS={}", days);
+    RETENTION.store(days * 24 * HOUR, Ordering::SeqCst);
+    DB.set(db).ok();
+}
+
+#[inline]
+pub(crate) fn enabled() -> bool {
+    DB.get().is_some()
+}
+
+/// Add a sample of METRICS, taken every SAMPLE_INTERVAL.
+pub(crate) fn record(values: [f64; METRICS.len()]) {
+    let Some(db) = DB.get() else {
+        return;
+    };
+    let now = now() as i64;
+    let ts = now - now % MINUTE;
+    let hour = CURRENT.lock().ok().and_then(|mut x| rollup(&mut x, ts, values));
+    let db = db.clone();
+    // off the main loop, the database may be slow
+    tokio::spawn(async move {
+        if let Err(err) = store(&db, now, ts, values, hour).await {
+            log::error!("Failed to store metrics: {}", err);
+        }
+    });
+}
+
+async fn store(
+    db: &Database,
+    now: i64,
+    ts: i64,
+    values: [f64; METRICS.len()],
+    hour: Option<(i64, [f64; METRICS.len()])>,
+) -> ResultType<()> {
+    db.insert_metrics(MINUTE, ts, &METRICS, &values).await?;
+    if let Some((ts, values)) = hour {
+        db.insert_metrics(HOUR, ts, &METRICS, &values).await?;
+        db.delete_metrics(MINUTE, now - MINUTE_RETENTION).await?;
+        db.delete_metrics(HOUR, now - RETENTION.load(Ordering::SeqCst)).await?;
+    }
+    Ok(())
+}
+
+// Returns the averages of the previous hour once a sample of a new one arrives.
+fn rollup(
+    current: &mut Option<Hour>,
+    ts: i64,
+    values: [f64; METRICS.len()],
+) -> Option<(i64, [f64; METRICS.len()])> {
+    let hour = ts - ts % HOUR;
+    let mut done = None;
+    if let Some(h) = current.as_ref().filter(|h| h.ts != hour) {
+        done = Some((h.ts, h.sums.map(|x| x / h.n as f64)));
+        *current = None;
+    }
+    let h = current.get_or_insert(Hour {
+        ts: hour,
+        sums: [0.; METRICS.len()],
+        n: 0,
+    });
+    for (sum, v) in h.sums.iter_mut().zip(values) {
+        *sum += v;
+    }
+    h.n += 1;
+    done
+}
+
+/// The last `n` values of `metric` as csv, by the minute or the hour.
+pub(crate) async fn query(metric: &str, hourly: bool, n: usize) -> String {
+    let Some(db) = DB.get() else {
+        return "off, set METRICS_RETENTION_DAYS\n".to_owned();
+    };
+    if !METRICS.contains(&metric) {
+        return format!("unknown metric {}, expected one of {:?}\n", metric, METRICS);
+    }
+    let resolution = if hourly { HOUR } else { MINUTE };
+    let rows = match db.get_metrics(metric, resolution, n as i64).await {
+        Ok(rows) => rows,
+        Err(err) => return format!("{err}\n"),
+    };
+    let mut res = "time,value\n".to_owned();
+    for (ts, v) in rows.into_iter().rev() {
+        let tm = UNIX_EPOCH + std::time::Duration::from_secs(ts as u64);
+        let _ = writeln!(
+            res,
+            "{},{:.1}",
+            chrono::DateTime::<chrono::Utc>::from(tm)
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            v
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolls_up_hourly_averages() {
+        let mut current = None;
+        assert_eq!(rollup(&mut current, 7200, [1., 2., 3., 0.]), None);
+        assert_eq!(rollup(&mut current, 7260, [3., 4., 5., 2.]), None);
+        assert_eq!(
+            rollup(&mut current, 10800, [9., 9., 9., 9.]),
+            Some((7200, [2., 3., 4., 1.]))
+        );
+        assert_eq!(current.map(|h| (h.ts, h.n)), Some((10800, 1)));
+    }
+}
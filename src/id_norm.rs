@@ -0,0 +1,100 @@
+use crate::common::get_arg;
+use hbb_common::{
+    log,
+    rendezvous_proto::{rendezvous_message::Union, RendezvousMessage},
+};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const TRIM: u8 = 1;
+const CASE: u8 = 2;
+const SEPARATORS: u8 = 4;
+const NAMES: [(&str, u8); 3] = [("trim", TRIM), ("case", CASE), ("separators", SEPARATORS)];
+
+static RULES: AtomicU8 = AtomicU8::new(0);
+
+/// `ID_NORMALIZE` is a comma separated list of what to ignore in the ids
+/// clients send: `trim` the whitespace around them, `case` and
+/// `separators`, spaces and dashes within, e.g. `123 456 789` as typed from
+/// the client's display. Read once, a change needs the registered ids to match.
+pub(crate) fn init() {
+    let v = get_arg("ID_NORMALIZE");
+    RULES.store(parse(&v), Ordering::SeqCst);
+    if !v.is_empty() {
+        log::info!("ID_NORMALIZE={}", v);
+    }
+}
+
+fn parse(v: &str) -> u8 {
+    let mut rules = 0;
+    for x in v.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        match NAMES.iter().find(|n| n.0.eq_ignore_ascii_case(x)) {
+            Some(n) => rules |= n.1,
+            None => log::error!(
+                "Unknown {} in ID_NORMALIZE, expected trim, case or separators",
+                x
+            ),
+        }
+    }
+    rules
+}
+
+/// `id` the way it's registered and looked up.
+pub(crate) fn normalize(id: &str) -> String {
+    normalize_with(id, RULES.load(Ordering::Relaxed))
+}
+
+fn normalize_with(id: &str, rules: u8) -> String {
+    let mut id = if rules & TRIM != 0 { id.trim() } else { id }.to_owned();
+    if rules & SEPARATORS != 0 {
+        id.retain(|c| c != ' ' && c != '-');
+    }
+    if rules & CASE != 0 {
+        id = id.to_lowercase();
+    }
+    id
+}
+
+/// Normalize the ids in a message from a client, of the peer itself or of
+/// the peers it looks up.
+pub(crate) fn apply(msg: &mut RendezvousMessage) {
+    let rules = RULES.load(Ordering::Relaxed);
+    if rules == 0 {
+        return;
+    }
+    let norm = |id: &mut String| {
+        if !id.is_empty() {
+            *id = normalize_with(id, rules);
+        }
+    };
+    match msg.union.as_mut() {
+        Some(Union::RegisterPeer(x)) => norm(&mut x.id),
+        Some(Union::RegisterPk(x)) => {
+            norm(&mut x.id);
+            norm(&mut x.old_id);
+        }
+        Some(Union::PunchHoleRequest(x)) => norm(&mut x.id),
+        Some(Union::RequestRelay(x)) => norm(&mut x.id),
+        Some(Union::PeerDiscovery(x)) => norm(&mut x.id),
+        Some(Union::OnlineRequest(x)) => {
+            norm(&mut x.id);
+            x.peers.iter_mut().for_each(norm);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_by_the_rules() {
+        let rules = parse("trim, separators,CASE");
+        assert_eq!(rules, TRIM | CASE | SEPARATORS);
+        assert_eq!(normalize_with(" 123 456-789\n", rules), "123456789");
+        assert_eq!(normalize_with("My-PC", rules), "mypc");
+        assert_eq!(normalize_with(" AbC ", parse("case")), " abc ");
+        assert_eq!(normalize_with(" AbC ", parse("trim,case")), "abc");
+        assert_eq!(normalize_with(" 1 2 ", 0), " 1 2 ");
+    }
+}
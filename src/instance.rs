@@ -0,0 +1,85 @@
+use crate::common::get_arg;
+use hbb_common::log;
+use once_cell::sync::OnceCell;
+
+const MAX_LEN: usize = 64;
+
+static ID: OnceCell<String> = OnceCell::new();
+
+/// `INSTANCE_ID` names this running server, e.g. one of the nodes behind a
+/// load balancer, otherwise one is made up from the hostname and kept in
+/// `<name>.instance` in the working directory, to survive restarts. It's in
+/// the json logs, the healthz responses and the cluster announcements, which
+/// tell the instance each peer is homed on.
+pub(crate) fn init(name: &str) {
+    if ID.get().is_some() {
+        return;
+    }
+    let id = get_arg("INSTANCE_ID");
+    let id = if is_valid(&id) {
+        id
+    } else {
+        if !id.is_empty() {
+            log::error!("Invalid INSTANCE_ID {:?}, only letters, digits, '-', '_' and '.'", id);
+        }
+        load_or_create(name)
+    };
+    log::info!("instance id {}", id);
+    ID.set(id).ok();
+}
+
+/// This instance's id, empty before init.
+#[inline]
+pub(crate) fn id() -> &'static str {
+    ID.get().map_or("", |x| x.as_str())
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn load_or_create(name: &str) -> String {
+    let file = format!("{name}.instance");
+    if let Ok(id) = std::fs::read_to_string(&file) {
+        if is_valid(id.trim()) {
+            return id.trim().to_owned();
+        }
+    }
+    let host: String = whoami::hostname()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .take(MAX_LEN - 7)
+        .collect();
+    let suffix: String = sodiumoxide::randombytes::randombytes(3)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect();
+    let id = if host.is_empty() {
+        suffix
+    } else {
+        format!("{host}-{suffix}")
+    };
+    if let Err(err) = std::fs::write(&file, &id) {
+        log::warn!("Failed to keep the instance id in {}: {}", file, err);
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_only_plain_ids() {
+        assert!(is_valid("hbbs-eu-1"));
+        assert!(is_valid("node_2.example"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("a b"));
+        assert!(!is_valid("a\n{\"x\":1}"));
+        assert!(!is_valid(&"a".repeat(MAX_LEN + 1)));
+    }
+}
@@ -0,0 +1,118 @@
+use crate::common::get_arg;
+use hbb_common::log;
+use ipnetwork::IpNetwork;
+use std::{
+    fmt::Write as _,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+static REFUSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+struct Lists {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+
+lazy_static::lazy_static! {
+    static ref LISTS: Mutex<Lists> = Default::default();
+}
+
+/// `ALLOW_IPS` and `DENY_IPS` are comma separated networks, e.g.
+/// `10.0.0.0/8,192.168.1.0/24`. With an allow list only sources in it are
+/// served, and sources in the deny list never are. Loopback always is.
+pub(crate) fn init() {
+    let (allow, deny) = (get_arg("ALLOW_IPS"), get_arg("DENY_IPS"));
+    if let Err(err) = set(Some(&allow), Some(&deny)) {
+        log::error!("Invalid ALLOW_IPS or DENY_IPS: {}", err);
+        return;
+    }
+    if !allow.is_empty() || !deny.is_empty() {
+        log::info!("ALLOW_IPS={}, DENY_IPS={}", allow, deny);
+    }
+}
+
+/// Replace the lists given, none if any entry is invalid.
+pub(crate) fn set(allow: Option<&str>, deny: Option<&str>) -> Result<(), String> {
+    let allow = allow.map(parse).transpose()?;
+    let deny = deny.map(parse).transpose()?;
+    let Ok(mut lists) = LISTS.lock() else {
+        return Err("unavailable".to_owned());
+    };
+    if let Some(allow) = allow {
+        lists.allow = allow;
+    }
+    if let Some(deny) = deny {
+        lists.deny = deny;
+    }
+    Ok(())
+}
+
+pub(crate) fn parse(nets: &str) -> Result<Vec<IpNetwork>, String> {
+    nets.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse().map_err(|_| format!("{x} isn't an IP address or network")))
+        .collect()
+}
+
+/// The lists as (allow, deny).
+pub(crate) fn get() -> (Vec<String>, Vec<String>) {
+    let to_strings = |x: &[IpNetwork]| x.iter().map(|x| x.to_string()).collect();
+    LISTS
+        .lock()
+        .map(|x| (to_strings(&x.allow), to_strings(&x.deny)))
+        .unwrap_or_default()
+}
+
+/// False if messages from `ip` are to be dropped.
+pub(crate) fn admit(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    if ip.is_loopback() {
+        return true;
+    }
+    let Ok(lists) = LISTS.lock() else {
+        return true;
+    };
+    let allowed = !lists.deny.iter().any(|x| x.contains(ip))
+        && (lists.allow.is_empty() || lists.allow.iter().any(|x| x.contains(ip)));
+    if !allowed {
+        REFUSED.fetch_add(1, Ordering::Relaxed);
+    }
+    allowed
+}
+
+pub(crate) fn status() -> String {
+    let (allow, deny) = get();
+    let mut res = String::new();
+    let _ = writeln!(res, "allow: {}", allow.join(","));
+    let _ = writeln!(res, "deny: {}", deny.join(","));
+    let _ = writeln!(res, "refused: {}", REFUSED.load(Ordering::Relaxed));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denies_before_allowing() {
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        assert!(admit(ip("8.8.8.8")));
+        set(Some("10.0.0.0/8, 192.168.1.0/24"), None).unwrap();
+        set(None, Some("10.1.0.0/16")).unwrap();
+        assert!(admit(ip("10.2.0.1")));
+        assert!(admit(ip("::ffff:192.168.1.5")));
+        assert!(!admit(ip("10.1.0.1")));
+        assert!(!admit(ip("8.8.8.8")));
+        assert!(admit(ip("127.0.0.1")));
+        assert!(set(Some("10.0.0.0/8"), Some("nonsense")).is_err());
+        let (allow, deny) = get();
+        assert_eq!(allow, ["10.0.0.0/8", "192.168.1.0/24"]);
+        assert_eq!(deny, ["10.1.0.0/16"]);
+    }
+}
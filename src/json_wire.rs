@@ -0,0 +1,52 @@
+use crate::{common::get_arg, strict};
+use hbb_common::{log, protobuf::Message as _, rendezvous_proto::*, ResultType};
+
+pub(crate) const MAX_LINE: usize = 64 * 1024;
+
+/// `DEBUG_JSON_PORT` is a loopback port taking RendezvousMessage in the
+/// protobuf JSON mapping, one message per line, so the TCP signaling can be
+/// scripted with netcat. 0 is off.
+pub(crate) fn init() -> Option<u16> {
+    let port = get_arg("DEBUG_JSON_PORT").parse::<u16>().unwrap_or(0);
+    if port == 0 {
+        return None;
+    }
+    if strict::is_on() {
+        log::error!("DEBUG_JSON_PORT is off with STRICT");
+        return None;
+    }
+    log::warn!("DEBUG_JSON_PORT={}, not for production", port);
+    Some(port)
+}
+
+/// The protobuf encoding of a JSON line, for the handlers of the binary
+/// listeners.
+pub(crate) fn decode(line: &str) -> ResultType<Vec<u8>> {
+    let msg = protobuf_json_mapping::parse_from_str::<RendezvousMessage>(line)?;
+    Ok(msg.write_to_bytes()?)
+}
+
+pub(crate) fn encode(msg: &RendezvousMessage) -> String {
+    protobuf_json_mapping::print_to_string(msg)
+        .unwrap_or_else(|err| error(&err.to_string()))
+}
+
+#[inline]
+pub(crate) fn error(err: &str) -> String {
+    serde_json::json!({ "error": err }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_json_lines() {
+        let bytes = decode(r#"{"testNatRequest": {"serial": 3}}"#).unwrap();
+        let msg = RendezvousMessage::parse_from_bytes(&bytes).unwrap();
+        assert_eq!(msg.test_nat_request().serial, 3);
+        assert!(encode(&msg).contains("testNatRequest"));
+        assert!(decode(r#"{"noSuchMessage": {}}"#).is_err());
+        assert!(decode("not json").is_err());
+    }
+}
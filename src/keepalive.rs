@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// `PeerDiscovery.cmd` of a LAN gateway refreshing the registrations of the
+/// devices behind it in one datagram, `misc` carries their comma separated
+/// ids. Only devices which registered themselves from the gateway's public IP
+/// are refreshed, they keep their own address. The reply has the same cmd
+/// with the ids which weren't refreshed in `misc`, those have to register
+/// on their own.
+pub(crate) const CMD: &str = "register-batch";
+pub(crate) const MAX_IDS: usize = 1_000; // per datagram
+
+static BATCHES: AtomicUsize = AtomicUsize::new(0);
+static REFRESHED: AtomicUsize = AtomicUsize::new(0);
+static REJECTED: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn parse(misc: &str) -> Vec<&str> {
+    let mut ids: Vec<&str> = misc
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .take(MAX_IDS)
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+#[inline]
+pub(crate) fn count(refreshed: usize, rejected: usize) {
+    BATCHES.fetch_add(1, Ordering::Relaxed);
+    REFRESHED.fetch_add(refreshed, Ordering::Relaxed);
+    REJECTED.fetch_add(rejected, Ordering::Relaxed);
+}
+
+pub(crate) fn status() -> String {
+    format!(
+        "batches: {}\nrefreshed: {}\nrejected: {}\n",
+        BATCHES.load(Ordering::Relaxed),
+        REFRESHED.load(Ordering::Relaxed),
+        REJECTED.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ids() {
+        assert_eq!(parse("2, 1,,2"), vec!["1", "2"]);
+        assert!(parse("").is_empty());
+        let many = vec!["1"; MAX_IDS * 2].join(",");
+        assert_eq!(parse(&many).len(), 1);
+    }
+}
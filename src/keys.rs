@@ -0,0 +1,347 @@
+use crate::dry_run::{self, Rule};
+use crate::peer::DAY_SECONDS;
+use hbb_common::{log, rendezvous_proto::punch_hole_response::Failure};
+use sodiumoxide::crypto::sign;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+const QUOTA_WINDOW: u64 = 60; // in seconds
+const CLIENTS_DUR: u64 = 3600 * 24; // in seconds
+const MAX_CLIENTS: usize = 100_000;
+const DEFAULT_ROTATION_GRACE: u64 = 30; // in days
+
+pub(crate) const DEFAULT_NAME: &str = "default";
+pub(crate) const OLD_NAME: &str = "old";
+// written by `rustdesk-utils rotatekey`
+pub(crate) const OLD_SK_FILE: &str = "id_ed25519.old";
+
+/// A key clients may present, with the policies applied to requests made with it.
+pub(crate) struct KeyEntry {
+    pub(crate) name: String,
+    pub(crate) key: String,
+    sk: Option<sign::SecretKey>,
+    quota: usize, // requests per QUOTA_WINDOW, 0 is unlimited
+    pub(crate) always_use_relay: bool,
+    priority: bool, // served while load shedding
+    window: Mutex<(Instant, usize)>,
+    requests: AtomicUsize,
+    rejected: AtomicUsize,
+    last_used: Mutex<Option<Instant>>,
+    expires: Option<SystemTime>,
+}
+
+/// The primary key (passed around as `key`) plus any number of extra keys from
+/// `EXTRA_KEYS`, e.g. the old key during rotation or one key per customer.
+pub(crate) struct KeyRing {
+    default: KeyEntry,
+    extra: Vec<KeyEntry>,
+    // client ip -> (key name, last request)
+    clients: Mutex<HashMap<String, (String, Instant)>>,
+    started: Instant,
+}
+
+impl KeyEntry {
+    fn new(name: &str, key: &str) -> Self {
+        let (key, sk) = parse_key(key);
+        Self {
+            name: name.to_owned(),
+            key,
+            sk,
+            quota: 0,
+            always_use_relay: false,
+            priority: false,
+            window: Mutex::new((Instant::now(), 0)),
+            requests: Default::default(),
+            rejected: Default::default(),
+            last_used: Default::default(),
+            expires: None,
+        }
+    }
+
+    fn consume_quota(&self) -> bool {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut last_used) = self.last_used.lock() {
+            *last_used = Some(Instant::now());
+        }
+        if self.quota == 0 {
+            return true;
+        }
+        let Ok(mut window) = self.window.lock() else {
+            return true;
+        };
+        if window.0.elapsed().as_secs() >= QUOTA_WINDOW {
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= self.quota {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+}
+
+impl KeyRing {
+    /// `extra` is a comma separated list of `name:key[:quota=<n>][:relay][:priority]`,
+    /// key is a public key or a base64 secret key.
+    pub(crate) fn new(extra: &str) -> Self {
+        let mut ring = Self {
+            default: KeyEntry::new(DEFAULT_NAME, ""),
+            extra: Vec::new(),
+            clients: Default::default(),
+            started: Instant::now(),
+        };
+        for x in extra.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let mut fds = x.split(':');
+            let (Some(name), Some(key)) = (fds.next(), fds.next()) else {
+                log::error!("Invalid extra key: {}", x);
+                continue;
+            };
+            if name.is_empty() || key.is_empty() || name == DEFAULT_NAME {
+                log::error!("Invalid extra key: {}", name);
+                continue;
+            }
+            let mut entry = KeyEntry::new(name, key);
+            for opt in fds {
+                match opt.split_once('=') {
+                    Some(("quota", v)) => entry.quota = v.parse().unwrap_or(0),
+                    None if opt == "relay" => entry.always_use_relay = true,
+                    None if opt == "priority" => entry.priority = true,
+                    _ => log::error!("Unknown option {} of key {}", opt, name),
+                }
+            }
+            log::info!(
+                "Extra key {}: quota={}/{}s relay={} priority={}",
+                entry.name,
+                entry.quota,
+                QUOTA_WINDOW,
+                entry.always_use_relay,
+                entry.priority
+            );
+            ring.extra.push(entry);
+        }
+        ring
+    }
+
+    /// Accept the previous key pair left by `rustdesk-utils rotatekey` for a
+    /// grace period counted from the rotation, so clients can be moved over
+    /// to the new key before the old one stops working.
+    pub(crate) fn load_old_key(&mut self) {
+        let Ok(contents) = std::fs::read_to_string(OLD_SK_FILE) else {
+            return;
+        };
+        let mut entry = KeyEntry::new(OLD_NAME, contents.trim());
+        if entry.sk.is_none() {
+            log::error!("Malformed private key in {}, ignored", OLD_SK_FILE);
+            return;
+        }
+        let grace = crate::common::get_arg("KEY_ROTATION_GRACE")
+            .parse::<u64>()
+            .unwrap_or(DEFAULT_ROTATION_GRACE);
+        let rotated = std::fs::metadata(OLD_SK_FILE)
+            .and_then(|x| x.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+        let expires = rotated + Duration::from_secs(grace * DAY_SECONDS);
+        if expires <= SystemTime::now() {
+            log::warn!(
+                "Old key from {} expired after {} days and is no longer accepted, remove the file",
+                OLD_SK_FILE,
+                grace
+            );
+            return;
+        }
+        log::info!(
+            "Old key from {} accepted for {} more days",
+            OLD_SK_FILE,
+            expires
+                .duration_since(SystemTime::now())
+                .map(|x| x.as_secs() / DAY_SECONDS)
+                .unwrap_or_default()
+        );
+        entry.expires = Some(expires);
+        self.extra.retain(|x| x.name != OLD_NAME);
+        self.extra.push(entry);
+    }
+
+    /// Whether the licence key presented by a client is accepted, without
+    /// applying a quota.
+    pub(crate) fn is_accepted(&self, key: &str, licence_key: &str) -> bool {
+        key.is_empty()
+            || licence_key == key
+            || self
+                .extra
+                .iter()
+                .any(|x| x.key == licence_key && x.expires.is_none_or(|t| t > SystemTime::now()))
+    }
+
+    /// Whether the licence key presented by a client is an extra key with
+    /// priority.
+    pub(crate) fn is_priority(&self, licence_key: &str) -> bool {
+        self.extra.iter().any(|x| {
+            x.priority && x.key == licence_key && x.expires.is_none_or(|t| t > SystemTime::now())
+        })
+    }
+
+    /// Find the entry matching the licence key presented by a client and
+    /// apply its quota. `key` is the primary key, empty disables validation.
+    pub(crate) fn check(
+        &self,
+        key: &str,
+        licence_key: &str,
+        ip: &str,
+    ) -> Result<&KeyEntry, Failure> {
+        let entry = if !key.is_empty() && licence_key == key {
+            &self.default
+        } else if let Some(entry) = self
+            .extra
+            .iter()
+            .find(|x| x.key == licence_key && x.expires.is_none_or(|t| t > SystemTime::now()))
+        {
+            entry
+        } else if key.is_empty() {
+            &self.default
+        } else {
+            return Err(Failure::LICENSE_MISMATCH);
+        };
+        if !entry.consume_quota() && dry_run::enforce(Rule::Quota, ip) {
+            return Err(Failure::LICENSE_OVERUSE);
+        }
+        if let Ok(mut clients) = self.clients.lock() {
+            if clients.len() >= MAX_CLIENTS {
+                clients.retain(|_, v| v.1.elapsed().as_secs() < CLIENTS_DUR);
+            }
+            if clients.len() < MAX_CLIENTS || clients.contains_key(ip) {
+                clients.insert(ip.to_owned(), (entry.name.clone(), Instant::now()));
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Secret key of the extra key the client ip used last, so responses are
+    /// signed with a key the client can verify during rotation.
+    pub(crate) fn get_sk(&self, ip: &str) -> Option<&sign::SecretKey> {
+        let name = self.clients.lock().ok()?.get(ip)?.0.clone();
+        self.extra.iter().find(|x| x.name == name)?.sk.as_ref()
+    }
+
+    pub(crate) fn rotation_status(&self) -> String {
+        let Some(old) = self.extra.iter().find(|x| x.name == OLD_NAME) else {
+            return format!("no rotation in progress, {} not loaded\n", OLD_SK_FILE);
+        };
+        let last_used = old.last_used.lock().ok().and_then(|x| *x);
+        let idle = last_used.unwrap_or(self.started).elapsed().as_secs();
+        let days_left = old
+            .expires
+            .and_then(|t| t.duration_since(SystemTime::now()).ok())
+            .map(|x| x.as_secs() / DAY_SECONDS)
+            .unwrap_or_default();
+        format!(
+            "old key: {}\nclients in last day: {}\nlast used: {}\naccepted for: {} days\nready to retire: {}\n",
+            old.key,
+            self.get_clients(OLD_NAME).len(),
+            match last_used {
+                Some(t) => format!("{}s ago", t.elapsed().as_secs()),
+                None => "never since start".to_owned(),
+            },
+            days_left,
+            if idle >= CLIENTS_DUR { "yes" } else { "no" }
+        )
+    }
+
+    /// Client ips which used the given key within the last day, most recent first.
+    pub(crate) fn get_clients(&self, name: &str) -> Vec<(String, u64)> {
+        let mut res: Vec<(String, u64)> = match self.clients.lock() {
+            Ok(clients) => clients
+                .iter()
+                .filter(|(_, v)| v.0 == name && v.1.elapsed().as_secs() < CLIENTS_DUR)
+                .map(|(ip, v)| (ip.clone(), v.1.elapsed().as_secs()))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        res.sort_by_key(|x| x.1);
+        res
+    }
+
+    pub(crate) fn status(&self) -> String {
+        let mut res = String::new();
+        for entry in std::iter::once(&self.default).chain(self.extra.iter()) {
+            let _ = writeln!(
+                res,
+                "{}: requests={} rejected={} clients={} quota={} relay={} priority={}",
+                entry.name,
+                entry.requests.load(Ordering::Relaxed),
+                entry.rejected.load(Ordering::Relaxed),
+                self.get_clients(&entry.name).len(),
+                entry.quota,
+                entry.always_use_relay,
+                entry.priority
+            );
+        }
+        res
+    }
+}
+
+// Same as the primary key: a base64 secret key is turned into its public key.
+fn parse_key(key: &str) -> (String, Option<sign::SecretKey>) {
+    if let Ok(sk) = base64::decode(key) {
+        if sk.len() == sign::SECRETKEYBYTES {
+            let mut tmp = [0u8; sign::SECRETKEYBYTES];
+            tmp[..].copy_from_slice(&sk);
+            return (
+                base64::encode(&sk[(sign::SECRETKEYBYTES / 2)..]),
+                Some(sign::SecretKey(tmp)),
+            );
+        }
+    }
+    (key.to_owned(), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_primary_and_extra_keys() {
+        let ring = KeyRing::new("old:T0xES0VZ:quota=2, acme:QUNNRQ==:relay:priority,:bad");
+        assert_eq!(ring.extra.len(), 2);
+        let default = ring.check("primary", "primary", "1.1.1.1").unwrap();
+        assert_eq!(default.name, DEFAULT_NAME);
+        let default = ring.check("", "anything", "1.1.1.1").unwrap();
+        assert_eq!(default.name, DEFAULT_NAME);
+        assert!(matches!(
+            ring.check("primary", "wrong", "1.1.1.1"),
+            Err(Failure::LICENSE_MISMATCH)
+        ));
+        let acme = ring.check("primary", "QUNNRQ==", "2.2.2.2").unwrap();
+        assert!(acme.always_use_relay);
+        assert!(ring.is_priority("QUNNRQ=="));
+        assert!(!ring.is_priority("T0xES0VZ"));
+        assert!(ring.check("primary", "T0xES0VZ", "3.3.3.3").is_ok());
+        assert!(ring.check("primary", "T0xES0VZ", "3.3.3.3").is_ok());
+        assert!(matches!(
+            ring.check("primary", "T0xES0VZ", "3.3.3.3"),
+            Err(Failure::LICENSE_OVERUSE)
+        ));
+        assert_eq!(ring.get_clients("old").len(), 1);
+        assert_eq!(ring.get_clients("acme")[0].0, "2.2.2.2");
+    }
+
+    #[test]
+    fn signs_with_the_key_the_client_used() {
+        let (pk, sk) = sign::gen_keypair();
+        let ring = KeyRing::new(&format!("old:{}", base64::encode(&sk)));
+        assert_eq!(ring.extra[0].key, base64::encode(&pk));
+        ring.check("new", &base64::encode(&pk), "1.1.1.1").unwrap();
+        ring.check("new", "new", "2.2.2.2").unwrap();
+        assert_eq!(ring.get_sk("1.1.1.1"), Some(&sk));
+        assert_eq!(ring.get_sk("2.2.2.2"), None);
+        assert!(ring.rotation_status().contains("no rotation"));
+    }
+}
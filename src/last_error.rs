@@ -0,0 +1,128 @@
+use crate::{
+    common::now,
+    webhook::{self, Event},
+};
+use std::{collections::HashMap, sync::Mutex};
+
+const MAX_ENTRIES: usize = 100_000;
+const MAX_AGE: u64 = 7 * 24 * 3600; // in seconds, dropped first when full
+
+#[derive(Clone)]
+struct Failure {
+    time: u64, // unix time
+    reason: &'static str,
+    other: String, // the requester's ip, or the target's id
+}
+
+lazy_static::lazy_static! {
+    // target id -> the last connection to it that failed
+    static ref TARGETS: Mutex<HashMap<String, Failure>> = Default::default();
+    // requester ip -> the last connection it requested that failed
+    static ref REQUESTERS: Mutex<HashMap<String, Failure>> = Default::default();
+}
+
+/// A connection to `id` requested from `ip` failed for `reason`, e.g.
+/// `OFFLINE`.
+pub(crate) fn record(id: &str, ip: &str, reason: &'static str) {
+    let time = now();
+    let failure = |other: &str| Failure {
+        time,
+        reason,
+        other: other.to_owned(),
+    };
+    insert(&TARGETS, id, failure(ip));
+    insert(&REQUESTERS, ip, failure(id));
+    webhook::emit(Event::PunchHoleFailed, id, ip, reason);
+}
+
+fn insert(map: &Mutex<HashMap<String, Failure>>, key: &str, failure: Failure) {
+    let Ok(mut map) = map.lock() else {
+        return;
+    };
+    if map.len() >= MAX_ENTRIES && !map.contains_key(key) {
+        map.retain(|_, x| x.time + MAX_AGE > failure.time);
+        if map.len() >= MAX_ENTRIES {
+            return;
+        }
+    }
+    map.insert(key.to_owned(), failure);
+}
+
+// The newer of the failures at `id` and from `ip`, the ip it registered
+// from; other devices behind the same NAT share it.
+fn get(id: &str, ip: &str) -> Option<(&'static str, Failure)> {
+    let target = TARGETS.lock().ok()?.get(id).cloned();
+    let requester = if ip.is_empty() {
+        None
+    } else {
+        REQUESTERS.lock().ok()?.get(ip).cloned()
+    };
+    match (target, requester) {
+        (Some(t), Some(r)) if r.time > t.time => Some(("requester", r)),
+        (Some(t), _) => Some(("target", t)),
+        (None, Some(r)) => Some(("requester", r)),
+        (None, None) => None,
+    }
+}
+
+/// For the admin API, null if nothing failed.
+pub(crate) fn to_json(id: &str, ip: &str) -> serde_json::Value {
+    match get(id, ip) {
+        Some((role, x)) => serde_json::json!({
+            "time": x.time,
+            "reason": x.reason,
+            "as": role,
+            "peer": x.other,
+        }),
+        None => serde_json::Value::Null,
+    }
+}
+
+/// For the console, e.g. `last attempt failed: OFFLINE 10 min ago, as target
+/// from 1.2.3.4`.
+pub(crate) fn status(id: &str, ip: &str) -> String {
+    let Some((role, x)) = get(id, ip) else {
+        return "last attempt failed: never\n".to_owned();
+    };
+    let from = if role == "target" { "from" } else { "to" };
+    format!(
+        "last attempt failed: {} {} ago, as {} {} {}\n",
+        x.reason,
+        ago(now().saturating_sub(x.time)),
+        role,
+        from,
+        x.other
+    )
+}
+
+fn ago(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{} s", secs),
+        60..=3599 => format!("{} min", secs / 60),
+        3600..=86399 => format!("{} h", secs / 3600),
+        _ => format!("{} d", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_the_newest_failure_either_way() {
+        record("111111111", "10.0.0.1", "OFFLINE");
+        assert!(status("111111111", "").contains("OFFLINE 0 s ago, as target from 10.0.0.1"));
+        assert!(status("222222222", "10.0.0.1").contains("as requester to 111111111"));
+        assert_eq!(to_json("333333333", "10.0.0.9"), serde_json::Value::Null);
+        if let Ok(mut targets) = TARGETS.lock() {
+            targets.get_mut("111111111").unwrap().time -= 600;
+        }
+        record("444444444", "10.0.0.2", "COOLDOWN");
+        // the device at 10.0.0.2 failed to reach 444444444 after failing as target
+        let v = to_json("111111111", "10.0.0.2");
+        assert_eq!(v["as"], "requester");
+        assert_eq!(v["reason"], "COOLDOWN");
+        assert!(status("111111111", "").contains("OFFLINE 10 min ago"));
+        assert_eq!(ago(7200), "2 h");
+    }
+}
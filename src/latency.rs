@@ -0,0 +1,110 @@
+use crate::{
+    common::get_arg,
+    dispatch::{self, KINDS},
+    strict,
+};
+use hbb_common::{log, rendezvous_proto::RendezvousMessage};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+const MAX_DELAY: u64 = 60_000; // in ms
+
+static DELAYED: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    // message type or "*" -> ms
+    static ref DELAYS: Mutex<HashMap<&'static str, u64>> = Default::default();
+}
+
+/// `INJECT_LATENCY` delays handling messages by type, to see how clients
+/// cope with a slow server in staging: comma separated `<type>=<ms>`, the
+/// types as named by `dispatch` on the console, or `*` for all, e.g.
+/// `punch-hole-request=3000`. Not for production, off with `STRICT`.
+pub(crate) fn init() {
+    let v = get_arg("INJECT_LATENCY");
+    let delays = match parse(&v) {
+        Ok(delays) => delays,
+        Err(err) => {
+            log::error!("Invalid INJECT_LATENCY: {}", err);
+            return;
+        }
+    };
+    if !delays.is_empty() {
+        if strict::is_on() {
+            log::error!("INJECT_LATENCY is off with STRICT");
+            return;
+        }
+        log::warn!("INJECT_LATENCY={}, not for production", v);
+    }
+    if let Ok(mut x) = DELAYS.lock() {
+        *x = delays;
+    }
+}
+
+fn parse(v: &str) -> Result<HashMap<&'static str, u64>, String> {
+    let mut res = HashMap::new();
+    for x in v.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let Some((kind, ms)) = x.split_once('=') else {
+            return Err(format!("{x}, expected <type>=<ms>"));
+        };
+        let kind = match kind.trim() {
+            "*" => "*",
+            kind => *KINDS
+                .iter()
+                .find(|x| **x == kind)
+                .ok_or(format!("unknown message type {kind}"))?,
+        };
+        let ms = ms.trim().parse::<u64>().map_err(|_| format!("{x}, invalid ms"))?;
+        res.insert(kind, ms.min(MAX_DELAY));
+    }
+    Ok(res)
+}
+
+/// How long to hold `msg` before handling it.
+pub(crate) fn delay(msg: &RendezvousMessage) -> Option<Duration> {
+    let delays = DELAYS.lock().ok()?;
+    if delays.is_empty() {
+        return None;
+    }
+    let ms = delays
+        .get(dispatch::kind_name(msg))
+        .or_else(|| delays.get("*"))
+        .copied()
+        .filter(|x| *x > 0)?;
+    DELAYED.fetch_add(1, Ordering::Relaxed);
+    Some(Duration::from_millis(ms))
+}
+
+pub(crate) fn status() -> String {
+    let mut res = String::new();
+    if let Ok(delays) = DELAYS.lock() {
+        for (kind, ms) in delays.iter() {
+            let _ = writeln!(res, "{}: {}ms", kind, ms);
+        }
+    }
+    let _ = writeln!(res, "delayed: {}", DELAYED.load(Ordering::Relaxed));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_delays_by_type() {
+        let delays = parse("punch-hole-request=3000, *=100").unwrap();
+        assert_eq!(delays.get("punch-hole-request"), Some(&3000));
+        assert_eq!(delays.get("*"), Some(&100));
+        assert_eq!(parse("register-peer=999999").unwrap()["register-peer"], MAX_DELAY);
+        assert!(parse("no-such-message=1").is_err());
+        assert!(parse("register-peer").is_err());
+        assert!(parse("").unwrap().is_empty());
+    }
+}
@@ -1,6 +1,82 @@
 mod rendezvous_server;
 pub use rendezvous_server::*;
+mod admin_api;
+mod advertise;
+mod alias;
+mod auth_failures;
+mod ban;
+pub mod bench;
+mod canary;
+mod capture;
+mod churn;
+pub mod client_config;
+mod cluster;
 pub mod common;
+mod connection_log;
+mod console_auth;
+mod cooldown;
 mod database;
+pub mod db_cli;
+mod dispatch;
+mod dry_run;
+mod dtls;
+mod errors;
+mod expiry;
+mod federation;
+mod geoip;
+mod health;
+mod history;
+mod id_norm;
+mod instance;
+mod ip_filter;
+mod json_wire;
+mod keepalive;
+mod keys;
+mod last_error;
+mod latency;
+mod load_shed;
+mod local_subnets;
+mod log_id;
+mod memory_budget;
+mod migration;
+mod mirror;
+mod nat_test;
+mod os_stats;
+mod pcap;
 mod peer;
+mod peer_stats;
+#[cfg(feature = "postgres")]
+mod pg_storage;
+pub mod pk_attest;
+mod pk_grace;
+mod port_check;
+mod presence;
+mod punch_stats;
+mod punch_timeout;
+mod refusal;
+mod registered;
+mod reload;
+mod relay_health;
+mod relay_pin;
+mod relay_quota;
+mod relay_registry;
+mod relay_report;
+pub mod relay_server;
+mod search;
+mod snapshot;
+mod socket_errors;
+mod status_page;
+mod strict;
+mod suppressed;
+mod systemd;
+mod telemetry;
+mod timing;
+mod tls;
+mod tombstone;
+mod trace;
+mod ttl_class;
+mod udp_sign;
+mod vacuum;
 mod version;
+mod watchdog;
+mod webhook;
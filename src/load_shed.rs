@@ -0,0 +1,181 @@
+use crate::{
+    common::{get_arg, get_arg_or},
+    ip_filter,
+};
+use hbb_common::log;
+use ipnetwork::IpNetwork;
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+const DEFAULT_RETRY_AFTER: usize = 10; // in seconds
+pub(crate) const CHECK_INTERVAL: u64 = 1_000; // in ms
+
+static QUEUE_LIMIT: AtomicUsize = AtomicUsize::new(0); // 0 means no limit
+static CPU_LIMIT: AtomicUsize = AtomicUsize::new(0); // in percent of all cores, 0 means no limit
+static RETRY_AFTER: AtomicUsize = AtomicUsize::new(DEFAULT_RETRY_AFTER);
+static OVERLOADED: AtomicBool = AtomicBool::new(false);
+static LAST_QUEUE: AtomicUsize = AtomicUsize::new(0);
+static LAST_CPU: AtomicUsize = AtomicUsize::new(0);
+static SHED: AtomicUsize = AtomicUsize::new(0);
+static PRIORITY: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref PRIORITY_IPS: Mutex<Vec<IpNetwork>> = Default::default();
+}
+
+pub(crate) fn init() {
+    QUEUE_LIMIT.store(get_arg("LOAD_SHED_QUEUE").parse().unwrap_or(0), Ordering::SeqCst);
+    CPU_LIMIT.store(get_arg("LOAD_SHED_CPU").parse().unwrap_or(0), Ordering::SeqCst);
+    RETRY_AFTER.store(
+        get_arg_or("LOAD_SHED_RETRY", DEFAULT_RETRY_AFTER.to_string())
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_RETRY_AFTER)
+            .max(1),
+        Ordering::SeqCst,
+    );
+    // controllers served even while overloaded, e.g. the admins' network
+    let v = get_arg("LOAD_SHED_PRIORITY_IPS");
+    match ip_filter::parse(&v) {
+        Ok(nets) => {
+            if let Ok(mut x) = PRIORITY_IPS.lock() {
+                *x = nets;
+            }
+            if !v.is_empty() {
+                log::info!("LOAD_SHED_PRIORITY_IPS={}", v);
+            }
+        }
+        Err(err) => log::error!("Invalid LOAD_SHED_PRIORITY_IPS: {}", err),
+    }
+    if enabled() {
+        log::info!(
+            "LOAD_SHED_QUEUE={}, LOAD_SHED_CPU={}%, LOAD_SHED_RETRY={}s",
+            QUEUE_LIMIT.load(Ordering::SeqCst),
+            CPU_LIMIT.load(Ordering::SeqCst),
+            retry_after()
+        );
+    }
+}
+
+#[inline]
+pub(crate) fn enabled() -> bool {
+    QUEUE_LIMIT.load(Ordering::Relaxed) > 0 || CPU_LIMIT.load(Ordering::Relaxed) > 0
+}
+
+#[inline]
+pub(crate) fn set_limits(queue: usize, cpu: usize) {
+    QUEUE_LIMIT.store(queue, Ordering::SeqCst);
+    CPU_LIMIT.store(cpu, Ordering::SeqCst);
+    if !enabled() {
+        OVERLOADED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Whether punch requests should be answered with a retry hint, updated by `update()`.
+#[inline]
+pub(crate) fn is_overloaded() -> bool {
+    OVERLOADED.load(Ordering::Relaxed)
+}
+
+#[inline]
+pub(crate) fn retry_after() -> usize {
+    RETRY_AFTER.load(Ordering::Relaxed)
+}
+
+/// Whether a punch request from `ip` is shed because of the load. Requests
+/// from `LOAD_SHED_PRIORITY_IPS` or with a priority key never are.
+pub(crate) fn sheds(ip: IpAddr, priority_key: impl FnOnce() -> bool) -> bool {
+    if !is_overloaded() {
+        return false;
+    }
+    let priority_ip = PRIORITY_IPS
+        .lock()
+        .is_ok_and(|x| x.iter().any(|net| net.contains(ip)));
+    if priority_ip || priority_key() {
+        PRIORITY.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    true
+}
+
+/// Count a shed request and return the failure text carrying the backoff hint.
+pub(crate) fn on_shed() -> String {
+    SHED.fetch_add(1, Ordering::Relaxed);
+    format!("Server is busy, please retry in {} seconds", retry_after())
+}
+
+/// Record the current queue depth and cpu load and re-evaluate the thresholds.
+pub(crate) fn update(queued: usize) {
+    let cpu = cpu_percent().unwrap_or(0);
+    LAST_QUEUE.store(queued, Ordering::Relaxed);
+    LAST_CPU.store(cpu, Ordering::Relaxed);
+    let over = is_over(
+        queued,
+        cpu,
+        QUEUE_LIMIT.load(Ordering::Relaxed),
+        CPU_LIMIT.load(Ordering::Relaxed),
+    );
+    if over != OVERLOADED.swap(over, Ordering::SeqCst) {
+        if over {
+            log::warn!(
+                "Overloaded (queue: {}, cpu: {}%), asking clients to retry in {}s",
+                queued,
+                cpu,
+                retry_after()
+            );
+        } else {
+            log::info!("Load back to normal (queue: {}, cpu: {}%)", queued, cpu);
+        }
+    }
+}
+
+#[inline]
+fn is_over(queued: usize, cpu: usize, queue_limit: usize, cpu_limit: usize) -> bool {
+    (queue_limit > 0 && queued > queue_limit) || (cpu_limit > 0 && cpu > cpu_limit)
+}
+
+// 1 minute load average relative to the number of cores, not available on other platforms
+#[cfg(target_os = "linux")]
+fn cpu_percent() -> Option<usize> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let load = loadavg.split_whitespace().next()?.parse::<f64>().ok()?;
+    let cores = std::thread::available_parallelism().map_or(1, |x| x.get());
+    Some((load * 100. / cores as f64) as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_percent() -> Option<usize> {
+    None
+}
+
+pub(crate) fn status() -> String {
+    format!(
+        "queue: {}/{}\ncpu: {}%/{}%\nretry after: {}s\noverloaded: {}\nshed: {}\npriority: {}\n",
+        LAST_QUEUE.load(Ordering::Relaxed),
+        QUEUE_LIMIT.load(Ordering::SeqCst),
+        LAST_CPU.load(Ordering::Relaxed),
+        CPU_LIMIT.load(Ordering::SeqCst),
+        retry_after(),
+        is_overloaded(),
+        SHED.load(Ordering::Relaxed),
+        PRIORITY.load(Ordering::Relaxed),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_limits_are_off() {
+        assert!(!is_over(1_000_000, 1_000, 0, 0));
+        assert!(is_over(101, 0, 100, 0));
+        assert!(!is_over(100, 0, 100, 0));
+        assert!(is_over(0, 81, 0, 80));
+        assert!(is_over(101, 50, 100, 80));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{common::get_arg, ip_filter};
+use hbb_common::{
+    log,
+    protobuf::{Message, UnknownValueRef},
+    try_into_v4,
+};
+use ipnetwork::IpNetwork;
+use once_cell::sync::OnceCell;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Number of the field clients append their subnets as, unknown to servers
+/// that don't read it, so they skip it.
+pub(crate) const FIELD: u32 = 1001;
+const MAX_SUBNETS: usize = 16; // per peer
+const MAX_PEERS: usize = 1_000_000;
+const TTL: Duration = Duration::from_secs(300); // clients send them with each registration
+
+static ON: AtomicBool = AtomicBool::new(false);
+static SITES: OnceCell<Vec<IpNetwork>> = OnceCell::new();
+static OVERLAPS: AtomicUsize = AtomicUsize::new(0);
+static DISJOINT: AtomicUsize = AtomicUsize::new(0);
+static ACROSS_SITES: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    // id -> subnets, when they were reported
+    static ref SUBNETS: Mutex<HashMap<String, (Vec<IpNetwork>, Instant)>> = Default::default();
+}
+
+/// `LOCAL_SUBNETS=Y` takes the local subnets clients append to `RegisterPeer`
+/// and `PunchHoleRequest`, as field `FIELD`: comma separated networks like
+/// `192.168.1.0/24`. When both sides of a connection request sent theirs,
+/// their subnets have to overlap besides their public IP being the same for
+/// the target to send its local address, so peers behind one carrier-grade
+/// NAT aren't taken for neighbours. `LOCAL_SITE_NETWORKS` lists the
+/// operator's own networks, unique across its sites, e.g. `10.20.0.0/16`:
+/// subnets overlapping within one of them are the same network also behind
+/// different public IPs, e.g. several layers of NAT in a corporate network.
+pub(crate) fn init() {
+    if get_arg("LOCAL_SUBNETS").to_uppercase() != "Y" {
+        return;
+    }
+    log::info!("LOCAL_SUBNETS=Y");
+    ON.store(true, Ordering::Relaxed);
+    match ip_filter::parse(&get_arg("LOCAL_SITE_NETWORKS")) {
+        Ok(sites) => {
+            if !sites.is_empty() {
+                log::info!("LOCAL_SITE_NETWORKS={:?}", sites);
+            }
+            SITES.set(sites).ok();
+        }
+        Err(err) => log::error!("Invalid LOCAL_SITE_NETWORKS: {}", err),
+    }
+}
+
+/// The subnets appended to `msg`, None if there are none or it's off.
+pub(crate) fn of(msg: &impl Message) -> Option<Vec<IpNetwork>> {
+    if !ON.load(Ordering::Relaxed) {
+        return None;
+    }
+    let Some(UnknownValueRef::LengthDelimited(bytes)) = msg.unknown_fields().get(FIELD) else {
+        return None;
+    };
+    let nets = parse(std::str::from_utf8(bytes).ok()?);
+    Some(nets).filter(|x| !x.is_empty())
+}
+
+// every host has its loopback and link-local networks, they tell nothing
+fn parse(v: &str) -> Vec<IpNetwork> {
+    v.split(',')
+        .filter_map(|x| x.trim().parse::<IpNetwork>().ok())
+        .filter(|x| match x.network() {
+            IpAddr::V4(ip) => x.prefix() >= 8 && !ip.is_loopback() && !ip.is_link_local(),
+            IpAddr::V6(ip) => {
+                x.prefix() >= 16 && !ip.is_loopback() && (ip.segments()[0] & 0xffc0) != 0xfe80
+            }
+        })
+        .take(MAX_SUBNETS)
+        .collect()
+}
+
+/// Keep what `id` sent with its registration.
+pub(crate) fn on_register(id: &str, msg: &impl Message) {
+    let Some(nets) = of(msg) else {
+        return;
+    };
+    let Ok(mut subnets) = SUBNETS.lock() else {
+        return;
+    };
+    if subnets.len() >= MAX_PEERS && !subnets.contains_key(id) {
+        subnets.retain(|_, x| x.1.elapsed() < TTL);
+        if subnets.len() >= MAX_PEERS {
+            return;
+        }
+    }
+    subnets.insert(id.to_owned(), (nets, Instant::now()));
+}
+
+/// Whether the requester at `a` with subnets `nets` and the peer `id` at `b`
+/// are in the same network: behind the same public IP and, if both sent their
+/// subnets, with overlapping ones. Unrelated sites share common home ranges,
+/// so behind different public IPs only subnets overlapping within one of
+/// `LOCAL_SITE_NETWORKS` do.
+pub(crate) fn same_network(
+    a: SocketAddr,
+    nets: Option<&[IpNetwork]>,
+    b: SocketAddr,
+    id: &str,
+) -> bool {
+    same_network_in(a, nets, b, id, SITES.get().map_or(&[], |x| x.as_slice()))
+}
+
+fn same_network_in(
+    a: SocketAddr,
+    nets: Option<&[IpNetwork]>,
+    b: SocketAddr,
+    id: &str,
+    sites: &[IpNetwork],
+) -> bool {
+    let same = same_ip(a, b);
+    if !same && sites.is_empty() {
+        return false;
+    }
+    let (Some(nets), Ok(subnets)) = (nets, SUBNETS.lock()) else {
+        return same;
+    };
+    let Some(theirs) = subnets.get(id).filter(|x| x.1.elapsed() < TTL) else {
+        return same;
+    };
+    let res = if same {
+        overlap(nets, &theirs.0)
+    } else {
+        overlap(&in_sites(nets, sites), &in_sites(&theirs.0, sites))
+    };
+    if res && !same {
+        ACROSS_SITES.fetch_add(1, Ordering::Relaxed);
+    } else if res {
+        OVERLAPS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        DISJOINT.fetch_add(1, Ordering::Relaxed);
+    }
+    res
+}
+
+// the subnets within one of the site networks
+fn in_sites(nets: &[IpNetwork], sites: &[IpNetwork]) -> Vec<IpNetwork> {
+    nets.iter()
+        .filter(|x| sites.iter().any(|y| y.prefix() <= x.prefix() && y.contains(x.network())))
+        .copied()
+        .collect()
+}
+
+fn same_ip(a: SocketAddr, b: SocketAddr) -> bool {
+    match (try_into_v4(a), try_into_v4(b)) {
+        (SocketAddr::V4(a), SocketAddr::V4(b)) => a.ip() == b.ip(),
+        (SocketAddr::V6(a), SocketAddr::V6(b)) => a.ip() == b.ip(),
+        _ => false,
+    }
+}
+
+fn overlap(a: &[IpNetwork], b: &[IpNetwork]) -> bool {
+    a.iter()
+        .any(|x| b.iter().any(|y| x.contains(y.network()) || y.contains(x.network())))
+}
+
+pub(crate) fn status() -> String {
+    if !ON.load(Ordering::Relaxed) {
+        return "off, set LOCAL_SUBNETS=Y\n".to_owned();
+    }
+    format!(
+        "peers: {}\noverlapping: {}\nacross sites: {}\ndisjoint: {}\n",
+        SUBNETS.lock().map(|x| x.len()).unwrap_or(0),
+        OVERLAPS.load(Ordering::Relaxed),
+        ACROSS_SITES.load(Ordering::Relaxed),
+        DISJOINT.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_subnets() {
+        let home = parse("192.168.1.0/24, 127.0.0.0/8, 169.254.0.0/16, fe80::/64, bad");
+        assert_eq!(home.len(), 1);
+        let office = parse("10.0.0.0/8,192.168.1.64/26");
+        let cgnat = parse("10.20.0.0/16");
+        assert!(overlap(&home, &office));
+        assert!(overlap(&office, &cgnat));
+        assert!(!overlap(&home, &cgnat));
+        assert!(parse("0.0.0.0/0,::/0").is_empty());
+        // common home ranges at two sites are no neighbours
+        if let Ok(mut subnets) = SUBNETS.lock() {
+            subnets.insert("subnets-test".to_owned(), (home.clone(), Instant::now()));
+        }
+        let (a, b) = ("1.2.3.4:1".parse().unwrap(), "5.6.7.8:1".parse().unwrap());
+        assert!(!same_network(a, Some(&home), b, "subnets-test"));
+        assert!(same_network(a, Some(&home), a, "subnets-test"));
+        assert!(!same_network(a, Some(&cgnat), a, "subnets-test"));
+        assert!(same_network(a, None, "[::ffff:1.2.3.4]:2".parse().unwrap(), "subnets-test"));
+        // unless the operator lists them as its own site networks
+        let sites = parse("192.168.0.0/16");
+        assert!(same_network_in(a, Some(&home), b, "subnets-test", &sites));
+        assert!(!same_network_in(a, Some(&office), b, "subnets-test", &parse("10.0.0.0/8")));
+        assert!(!same_network_in(a, None, b, "subnets-test", &sites));
+    }
+}
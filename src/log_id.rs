@@ -0,0 +1,94 @@
+use crate::common::get_arg;
+use hbb_common::log;
+use once_cell::sync::OnceCell;
+use sodiumoxide::{crypto::hash::sha256, randombytes::randombytes};
+use std::fmt::{self, Write as _};
+
+const HASH_BYTES: usize = 8;
+
+/// How peer ids are written to logs, for operators who ship logs to
+/// third-party platforms. The database and the loopback console always
+/// have the raw ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Raw,
+    Hash,
+    Redact,
+}
+
+static MODE: OnceCell<(Mode, Vec<u8>)> = OnceCell::new();
+
+pub(crate) fn init() {
+    let mode = match get_arg("LOG_ID_MODE").to_lowercase().as_str() {
+        "hash" => Mode::Hash,
+        "redact" => Mode::Redact,
+        "" | "raw" => Mode::Raw,
+        x => {
+            log::error!("Invalid LOG_ID_MODE {}, ids are redacted", x);
+            Mode::Redact
+        }
+    };
+    // ids are short numbers, an unsalted hash is trivially reversed
+    let mut salt = get_arg("LOG_ID_SALT").into_bytes();
+    if salt.is_empty() {
+        salt = randombytes(16);
+    }
+    if mode != Mode::Raw {
+        log::info!("LOG_ID_MODE={:?}", mode);
+    }
+    MODE.set((mode, salt)).ok();
+}
+
+/// Wrap a peer id for logging.
+#[inline]
+pub(crate) fn id(id: &str) -> LogId<'_> {
+    LogId(id)
+}
+
+pub(crate) struct LogId<'a>(&'a str);
+
+impl fmt::Display for LogId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match MODE.get() {
+            None | Some((Mode::Raw, _)) => f.write_str(self.0),
+            Some((Mode::Hash, salt)) => f.write_str(&hash(salt, self.0)),
+            Some((Mode::Redact, _)) => f.write_str("<id>"),
+        }
+    }
+}
+
+impl fmt::Debug for LogId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+// Stable for a given salt, so one peer can still be followed across log lines.
+fn hash(salt: &[u8], id: &str) -> String {
+    let mut state = sha256::State::new();
+    state.update(salt);
+    state.update(id.as_bytes());
+    let digest = state.finalize();
+    let mut res = String::with_capacity(HASH_BYTES * 2 + 1);
+    res.push('#');
+    for b in &digest.0[..HASH_BYTES] {
+        let _ = write!(res, "{b:02x}");
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_is_salted_and_stable() {
+        let a = hash(b"salt", "123456789");
+        assert_eq!(a.len(), HASH_BYTES * 2 + 1);
+        assert_eq!(a, hash(b"salt", "123456789"));
+        assert_ne!(a, hash(b"pepper", "123456789"));
+        assert_ne!(a, hash(b"salt", "123456780"));
+        // raw until configured
+        assert_eq!(id("123456789").to_string(), "123456789");
+    }
+}
@@ -2,15 +2,24 @@
 // https://blog.csdn.net/bytxl/article/details/44344855
 
 use flexi_logger::*;
-use hbb_common::{bail, config::RENDEZVOUS_PORT, ResultType};
+use hbb_common::{bail, config::RENDEZVOUS_PORT, log, ResultType};
 use hbbs::{common::*, *};
 
 const RMEM: usize = 0;
 
 fn main() -> ResultType<()> {
+    // before the logger, its output would mix with an export
+    if std::env::args().nth(1).as_deref() == Some("db") {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        return db_cli::main(&args);
+    }
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        return bench::main(&args);
+    }
     let _logger = Logger::try_with_env_or_str("info")?
         .log_to_stdout()
-        .format(opt_format)
+        .format(log_format())
         .write_mode(WriteMode::Async)
         .start()?;
     let args = format!(
@@ -23,7 +32,16 @@ fn main() -> ResultType<()> {
         -r, --relay-servers=[HOST] 'Sets the default relay servers, separated by comma'
         -M, --rmem=[NUMBER(default={RMEM})] 'Sets UDP recv buffer size, set system rmem_max first, e.g., sudo sysctl -w net.core.rmem_max=52428800. vi /etc/sysctl.conf, net.core.rmem_max=52428800, sudo sysctl –p'
         , --mask=[MASK] '[DEPRECATED] Determine if the connection comes from LAN, e.g. 192.168.0.0/16'
-        -k, --key=[KEY] 'Only allow the client with the same key'",
+        -k, --key=[KEY] 'Only allow the client with the same key'
+        , --allow-ips=[CIDRS] 'Only serves these networks, separated by comma, e.g. 10.0.0.0/8'
+        , --deny-ips=[CIDRS] 'Never serves these networks, separated by comma'
+        , --reg-timeout=[MS] 'Sets how long a peer stays online after registering (default: 30000)'
+        , --punch-timeout=[MS] 'Sets how long the target of a punch hole request has to answer (default: off)'
+        , --tls-cert=[FILE] 'Serves TLS on the TCP port with this PEM certificate chain (default: plaintext)'
+        , --tls-key=[FILE] 'Sets the PEM private key of --tls-cert'
+        --public 'Applies the defaults for a public community server, each can still be set'
+        --strict 'Locks down a private server: requires the key and turns off unauthenticated endpoints'
+        --print-config 'Prints the effective configuration and where each value comes from, then exits'",
     );
     init_args(&args, "hbbs", "RustDesk ID/Rendezvous Server");
     let port = get_arg_or("port", RENDEZVOUS_PORT.to_string()).parse::<i32>()?;
@@ -34,12 +52,17 @@ fn main() -> ResultType<()> {
     let rmem = get_arg("rmem").parse::<usize>().unwrap_or(RMEM);
     let serial: i32 = get_arg("serial").parse().unwrap_or(0);
     crate::common::check_software_update();
-    RendezvousServer::start_with_bind(
-        bind_addr,
-        port,
-        serial,
-        &get_arg_or("key", "-".to_owned()),
-        rmem,
-    )?;
+    let key = get_arg_or("key", "-".to_owned());
+    if get_arg("BUILTIN_RELAY").to_uppercase() == "Y" {
+        // on its own runtime, at the ports hbbr would take next to ours
+        let relay_port = (port + 1).to_string();
+        let key = key.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = relay_server::start_with_bind(bind_addr, &relay_port, &key) {
+                log::error!("Built-in relay failed: {}", err);
+            }
+        });
+    }
+    RendezvousServer::start_with_bind(bind_addr, port, serial, &key, rmem)?;
     Ok(())
 }
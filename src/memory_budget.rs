@@ -0,0 +1,153 @@
+use crate::common::get_arg;
+use hbb_common::log;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Mutex,
+};
+
+// Rough per-entry costs, including map and allocator overhead.
+const PEER_BYTES: usize = 512;
+const SINK_BYTES: usize = 16 * 1024; // framed tcp/ws sink with its buffers
+const QUEUED_MSG_BYTES: usize = 256;
+// Shed down to this share of the budget so we don't shed again on the next check.
+const LOW_WATERMARK_100: usize = 90;
+pub(crate) const CHECK_INTERVAL: u64 = 5_000; // in ms
+
+static BUDGET: AtomicUsize = AtomicUsize::new(0); // in bytes, 0 means unlimited
+static OVER_BUDGET: AtomicBool = AtomicBool::new(false);
+static REJECTED: AtomicUsize = AtomicUsize::new(0);
+static SHED: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref LAST_USAGE: Mutex<Usage> = Default::default();
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Usage {
+    pub(crate) peers: usize,
+    pub(crate) sinks: usize,
+    pub(crate) queued: usize,
+}
+
+impl Usage {
+    pub(crate) fn bytes(&self) -> usize {
+        self.peers * PEER_BYTES + self.sinks * SINK_BYTES + self.queued * QUEUED_MSG_BYTES
+    }
+}
+
+pub(crate) fn init() {
+    let mb = get_arg("MEMORY_BUDGET").parse::<usize>().unwrap_or(0);
+    set_budget_mb(mb);
+    log::info!(
+        "MEMORY_BUDGET={}",
+        if mb == 0 {
+            "unlimited".to_owned()
+        } else {
+            format!("{mb}MB")
+        }
+    );
+}
+
+#[inline]
+pub(crate) fn set_budget_mb(mb: usize) {
+    BUDGET.store(mb.saturating_mul(1024 * 1024), Ordering::SeqCst);
+    if mb == 0 {
+        OVER_BUDGET.store(false, Ordering::SeqCst);
+    }
+}
+
+#[inline]
+pub(crate) fn budget() -> usize {
+    BUDGET.load(Ordering::SeqCst)
+}
+
+/// Whether new TCP connections should be rejected, updated by `update()`.
+#[inline]
+pub(crate) fn is_over() -> bool {
+    OVER_BUDGET.load(Ordering::SeqCst)
+}
+
+#[inline]
+pub(crate) fn on_rejected() {
+    REJECTED.fetch_add(1, Ordering::SeqCst);
+}
+
+#[inline]
+pub(crate) fn on_shed(n: usize) {
+    SHED.fetch_add(n, Ordering::SeqCst);
+}
+
+/// Record the latest usage and return how many peers should be shed from memory.
+pub(crate) fn update(usage: Usage) -> usize {
+    if let Ok(mut last) = LAST_USAGE.lock() {
+        *last = usage;
+    }
+    let budget = budget();
+    let over = budget > 0 && usage.bytes() > budget;
+    if over != OVER_BUDGET.swap(over, Ordering::SeqCst) {
+        if over {
+            log::warn!(
+                "Memory budget exceeded: {}KB > {}KB, rejecting new tcp connections",
+                usage.bytes() / 1024,
+                budget / 1024
+            );
+        } else {
+            log::info!("Memory usage back under budget: {}KB", usage.bytes() / 1024);
+        }
+    }
+    if over {
+        peers_to_shed(&usage, budget)
+    } else {
+        0
+    }
+}
+
+fn peers_to_shed(usage: &Usage, budget: usize) -> usize {
+    let target = budget / 100 * LOW_WATERMARK_100;
+    let excess = usage.bytes().saturating_sub(target);
+    ((excess + PEER_BYTES - 1) / PEER_BYTES).min(usage.peers)
+}
+
+pub(crate) fn status() -> String {
+    let usage = LAST_USAGE.lock().map(|x| *x).unwrap_or_default();
+    format!(
+        "budget: {}KB\nusage: {}KB (peers: {}, sinks: {}, queued: {})\nover: {}\nrejected: {}\nshed: {}\n",
+        budget() / 1024,
+        usage.bytes() / 1024,
+        usage.peers,
+        usage.sinks,
+        usage.queued,
+        is_over(),
+        REJECTED.load(Ordering::SeqCst),
+        SHED.load(Ordering::SeqCst),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheds_down_to_low_watermark() {
+        let budget = 100 * PEER_BYTES;
+        let usage = Usage {
+            peers: 120,
+            sinks: 0,
+            queued: 0,
+        };
+        assert_eq!(peers_to_shed(&usage, budget), 30);
+        let usage = Usage {
+            peers: 10,
+            sinks: 10,
+            queued: 0,
+        };
+        // sinks can't be shed, never shed more peers than we have
+        assert_eq!(peers_to_shed(&usage, budget), 10);
+        let usage = Usage {
+            peers: 80,
+            sinks: 0,
+            queued: 0,
+        };
+        assert_eq!(peers_to_shed(&usage, budget), 0);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{
+    common::now,
+    database::{PeerRecord, PeerStorage},
+};
+use hbb_common::{bail, log, ResultType};
+use serde_derive::{Deserialize, Serialize};
+use sodiumoxide::crypto::sign;
+
+// Peers in transit between servers. The file is the base64 of the json signed
+// with the exporting server's key pair, so ids can't be paired with other
+// keys on the way; the importing server checks it against that public key.
+#[derive(Serialize, Deserialize)]
+struct Export {
+    created: u64, // in seconds since the epoch
+    peers: Vec<Record>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    id: String,
+    uuid: String, // base64
+    pk: String,   // base64
+    #[serde(default)]
+    user: Option<String>, // base64
+    #[serde(default)]
+    status: Option<i64>,
+    #[serde(default)]
+    note: Option<String>,
+    #[serde(default)]
+    info: String,
+}
+
+impl From<PeerRecord> for Record {
+    fn from(x: PeerRecord) -> Self {
+        Self {
+            id: x.id,
+            uuid: base64::encode(x.uuid),
+            pk: base64::encode(x.pk),
+            user: x.user.map(base64::encode),
+            status: x.status,
+            note: x.note,
+            info: x.info,
+        }
+    }
+}
+
+impl TryFrom<Record> for PeerRecord {
+    type Error = base64::DecodeError;
+
+    fn try_from(x: Record) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: x.id,
+            uuid: base64::decode(x.uuid)?,
+            pk: base64::decode(x.pk)?,
+            user: x.user.map(base64::decode).transpose()?,
+            status: x.status,
+            note: x.note,
+            info: x.info,
+        })
+    }
+}
+
+/// `filter` is empty or `*` for all peers, `<prefix>*`, or a comma separated
+/// list of ids.
+fn is_selected(id: &str, filter: &str) -> bool {
+    if filter.is_empty() || filter == "*" {
+        return true;
+    }
+    if let Some(prefix) = filter.strip_suffix('*') {
+        return id.starts_with(prefix);
+    }
+    filter.split(',').any(|x| x.trim() == id)
+}
+
+pub(crate) async fn export(
+    db: &dyn PeerStorage,
+    sk: &sign::SecretKey,
+    path: &str,
+    filter: &str,
+) -> ResultType<usize> {
+    let peers: Vec<Record> = db
+        .get_peer_records()
+        .await?
+        .into_iter()
+        .filter(|x| is_selected(&x.id, filter))
+        .map(Record::from)
+        .collect();
+    let n = peers.len();
+    let export = Export {
+        created: now(),
+        peers,
+    };
+    std::fs::write(path, seal(&export, sk)?)?;
+    log::info!("Exported {} peers to {}", n, path);
+    Ok(n)
+}
+
+/// Returns the number of imported peers and the ids which were taken here.
+pub(crate) async fn import(
+    db: &dyn PeerStorage,
+    path: &str,
+    pk: &str,
+) -> ResultType<(usize, Vec<String>)> {
+    let export = open(&std::fs::read_to_string(path)?, pk)?;
+    let mut imported = 0;
+    let mut taken = Vec::new();
+    for record in export.peers {
+        let peer = match PeerRecord::try_from(record) {
+            Ok(peer) => peer,
+            Err(err) => bail!("invalid peer in {}: {}", path, err),
+        };
+        // never replace the key of a peer known here
+        if db.import_peer_record(&peer).await? {
+            imported += 1;
+        } else {
+            taken.push(peer.id);
+        }
+    }
+    log::info!(
+        "Imported {} peers from {}, {} ids taken",
+        imported,
+        path,
+        taken.len()
+    );
+    Ok((imported, taken))
+}
+
+/// The records as unsigned, readable json, for a backup or to edit by hand,
+/// see db_cli.
+pub(crate) fn to_json(records: Vec<PeerRecord>) -> ResultType<String> {
+    let peers: Vec<Record> = records.into_iter().map(Record::from).collect();
+    Ok(serde_json::to_string_pretty(&peers)?)
+}
+
+pub(crate) fn from_json(data: &str) -> ResultType<Vec<PeerRecord>> {
+    let peers: Vec<Record> = serde_json::from_str(data)?;
+    let mut res = Vec::with_capacity(peers.len());
+    for record in peers {
+        match PeerRecord::try_from(record) {
+            Ok(peer) => res.push(peer),
+            Err(err) => bail!("invalid peer: {}", err),
+        }
+    }
+    Ok(res)
+}
+
+fn seal(export: &Export, sk: &sign::SecretKey) -> ResultType<String> {
+    Ok(base64::encode(sign::sign(&serde_json::to_vec(export)?, sk)))
+}
+
+fn open(data: &str, pk: &str) -> ResultType<Export> {
+    let Some(pk) = base64::decode(pk.trim())
+        .ok()
+        .and_then(|x| sign::PublicKey::from_slice(&x))
+    else {
+        bail!("invalid public key");
+    };
+    let Ok(json) = sign::verify(&base64::decode(data.trim())?, &pk) else {
+        bail!("signature mismatch, the file was altered or signed with another key");
+    };
+    Ok(serde_json::from_slice(&json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_is_tamper_proof() {
+        assert!(is_selected("123456789", ""));
+        assert!(is_selected("123456789", "123*"));
+        assert!(!is_selected("223456789", "123*"));
+        assert!(is_selected("223456789", "123456789, 223456789"));
+        let (pk, sk) = sign::gen_keypair();
+        let export = Export {
+            created: 0,
+            peers: vec![Record::from(PeerRecord {
+                id: "123456789".to_owned(),
+                pk: vec![1, 2, 3],
+                ..Default::default()
+            })],
+        };
+        let data = seal(&export, &sk).unwrap();
+        let pk = base64::encode(pk);
+        assert_eq!(open(&data, &pk).unwrap().peers[0].pk, "AQID");
+        let mut signed = base64::decode(&data).unwrap();
+        let n = signed.len();
+        signed[n - 2] ^= 1;
+        assert!(open(&base64::encode(signed), &pk).is_err());
+        let (other, _) = sign::gen_keypair();
+        assert!(open(&data, &base64::encode(other)).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use crate::common::{get_arg, get_arg_or};
+use hbb_common::{bail, log, ResultType};
+use once_cell::sync::OnceCell;
+use std::{
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const DEFAULT_SAMPLE: usize = 10;
+
+static MIRROR: OnceCell<Mirror> = OnceCell::new();
+
+// Copies sampled incoming UDP signaling to a staging server. Replies from
+// the staging server come back to this socket and are never read.
+struct Mirror {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    sample: usize,
+    counter: AtomicUsize,
+    sent: AtomicUsize,
+}
+
+pub(crate) fn init() {
+    let addr = get_arg("MIRROR_ADDR");
+    if addr.is_empty() {
+        return;
+    }
+    let sample = get_arg_or("MIRROR_SAMPLE", DEFAULT_SAMPLE.to_string())
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_SAMPLE)
+        .max(1);
+    match new_mirror(&addr, sample) {
+        Ok(m) => {
+            log::info!("MIRROR_ADDR={}, MIRROR_SAMPLE=1/{}", m.addr, sample);
+            MIRROR.set(m).ok();
+        }
+        Err(err) => log::error!("Failed to mirror to {}: {}", addr, err),
+    }
+}
+
+fn new_mirror(addr: &str, sample: usize) -> ResultType<Mirror> {
+    let Some(addr) = addr.to_socket_addrs()?.next() else {
+        bail!("can't resolve {}", addr);
+    };
+    let socket = if addr.is_ipv4() {
+        UdpSocket::bind("0.0.0.0:0")?
+    } else {
+        UdpSocket::bind("[::]:0")?
+    };
+    // never block the signaling loop, drop the copy instead
+    socket.set_nonblocking(true)?;
+    Ok(Mirror {
+        socket,
+        addr,
+        sample,
+        counter: AtomicUsize::new(0),
+        sent: AtomicUsize::new(0),
+    })
+}
+
+#[inline]
+pub(crate) fn mirror(bytes: &[u8]) {
+    if let Some(m) = MIRROR.get() {
+        if is_sampled(m.counter.fetch_add(1, Ordering::Relaxed), m.sample)
+            && m.socket.send_to(bytes, m.addr).is_ok()
+        {
+            m.sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub(crate) fn status() -> String {
+    match MIRROR.get() {
+        Some(m) => format!(
+            "{} 1/{}: {}/{}\n",
+            m.addr,
+            m.sample,
+            m.sent.load(Ordering::Relaxed),
+            m.counter.load(Ordering::Relaxed)
+        ),
+        None => "off\n".to_owned(),
+    }
+}
+
+#[inline]
+fn is_sampled(n: usize, sample: usize) -> bool {
+    n % sample == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_one_in_n() {
+        assert_eq!((0..100).filter(|n| is_sampled(*n, 10)).count(), 10);
+        assert_eq!((0..100).filter(|n| is_sampled(*n, 1)).count(), 100);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{
+    common::get_arg,
+    dispatch::{self, Transport},
+};
+use hbb_common::{
+    log,
+    protobuf::Message as _,
+    rendezvous_proto::{RendezvousMessage, TestNatResponse},
+    try_into_v4,
+    udp::FramedSocket,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(5); // between the two requests of a test
+const MAX_PENDING: usize = 10_000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static ANSWERED: AtomicUsize = AtomicUsize::new(0);
+static CONE: AtomicUsize = AtomicUsize::new(0);
+static SYMMETRIC: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    // ip -> the source ports seen on the main and the second port
+    static ref PENDING: Mutex<HashMap<IpAddr, ([Option<u16>; 2], Instant)>> = Default::default();
+}
+
+/// `NAT_TEST_UDP=Y` answers `TestNatRequest` over udp too, on the main port
+/// and on the NAT test port, with the source port each one saw. A client
+/// whose two answers differ is behind a symmetric NAT and can go straight to
+/// the relay.
+pub(crate) fn init() -> bool {
+    let on = get_arg("NAT_TEST_UDP").to_uppercase() == "Y";
+    ENABLED.store(on, Ordering::SeqCst);
+    if on {
+        log::info!("NAT_TEST_UDP=Y");
+    }
+    on
+}
+
+#[inline]
+pub(crate) fn is_on() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The answer to a request from `addr`, `second` if it came in on the NAT
+/// test port.
+pub(crate) fn answer(addr: SocketAddr, second: bool) -> RendezvousMessage {
+    let addr = try_into_v4(addr);
+    on_request(addr.ip(), addr.port(), second);
+    ANSWERED.fetch_add(1, Ordering::Relaxed);
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_test_nat_response(TestNatResponse {
+        port: addr.port() as _,
+        ..Default::default()
+    });
+    msg_out
+}
+
+// Count the NAT type once a client's requests on both ports came in. Clients
+// sharing an address within WINDOW may be counted as one.
+fn on_request(ip: IpAddr, port: u16, second: bool) {
+    let Ok(mut pending) = PENDING.lock() else {
+        return;
+    };
+    if pending.len() >= MAX_PENDING && !pending.contains_key(&ip) {
+        pending.retain(|_, x| x.1.elapsed() < WINDOW);
+        if pending.len() >= MAX_PENDING {
+            return;
+        }
+    }
+    let entry = pending.entry(ip).or_insert(([None; 2], Instant::now()));
+    if entry.1.elapsed() >= WINDOW {
+        *entry = ([None; 2], Instant::now());
+    }
+    entry.0[second as usize] = Some(port);
+    if let [Some(a), Some(b)] = entry.0 {
+        if a == b {
+            CONE.fetch_add(1, Ordering::Relaxed);
+        } else {
+            SYMMETRIC.fetch_add(1, Ordering::Relaxed);
+        }
+        pending.remove(&ip);
+    }
+}
+
+/// Answer on the NAT test port, through the same middleware as the main
+/// port.
+pub(crate) async fn serve(mut socket: FramedSocket) {
+    while let Some(res) = socket.next().await {
+        let Ok((bytes, addr)) = res else {
+            continue;
+        };
+        let addr: SocketAddr = addr.into();
+        let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) else {
+            continue;
+        };
+        if !dispatch::inbound(Transport::Udp, addr, &bytes, &msg_in) {
+            continue;
+        }
+        if msg_in.has_test_nat_request() {
+            if let Err(err) = socket.send(&answer(addr, true), addr).await {
+                log::debug!("Failed to answer the nat test of {}: {}", addr, err);
+            }
+        }
+    }
+}
+
+pub(crate) fn status() -> String {
+    if !is_on() {
+        return "off, set NAT_TEST_UDP=Y\n".to_owned();
+    }
+    format!(
+        "answered: {}\ncone: {}\nsymmetric: {}\n",
+        ANSWERED.load(Ordering::Relaxed),
+        CONE.load(Ordering::Relaxed),
+        SYMMETRIC.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_the_ports_of_both_requests() {
+        let ip = "10.9.9.9".parse::<IpAddr>().unwrap();
+        let (cone, symmetric) = (CONE.load(Ordering::SeqCst), SYMMETRIC.load(Ordering::SeqCst));
+        on_request(ip, 4000, false);
+        on_request(ip, 4000, true);
+        assert_eq!(CONE.load(Ordering::SeqCst), cone + 1);
+        on_request(ip, 4000, true);
+        on_request(ip, 4001, false);
+        assert_eq!(SYMMETRIC.load(Ordering::SeqCst), symmetric + 1);
+        assert!(PENDING.lock().unwrap().get(&ip).is_none());
+    }
+}
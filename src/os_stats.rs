@@ -0,0 +1,115 @@
+use std::{collections::HashMap, fmt::Write as _, sync::Mutex};
+
+// Udp counters worth looking at when peers show offline although they run:
+// the kernel dropping datagrams because the receive buffer (see RMEM) is full.
+const UDP_FIELDS: &[&str] = &[
+    "InDatagrams",
+    "NoPorts",
+    "InErrors",
+    "OutDatagrams",
+    "RcvbufErrors",
+    "SndbufErrors",
+    "MemErrors",
+];
+
+lazy_static::lazy_static! {
+    // values of the previous call, to show what changed in between
+    static ref LAST: Mutex<HashMap<String, u64>> = Default::default();
+}
+
+/// OS-level counters next to our own, with the change since the last call.
+pub(crate) fn status() -> String {
+    let stats = read();
+    if stats.is_empty() {
+        return "not available on this platform\n".to_owned();
+    }
+    let mut res = String::new();
+    let Ok(mut last) = LAST.lock() else {
+        return res;
+    };
+    for (name, v) in stats {
+        let _ = match last.get(&name) {
+            Some(old) => writeln!(res, "{}: {} (+{})", name, v, v.saturating_sub(*old)),
+            None => writeln!(res, "{}: {}", name, v),
+        };
+        last.insert(name, v);
+    }
+    res
+}
+
+#[cfg(target_os = "linux")]
+fn read() -> Vec<(String, u64)> {
+    let load = |path| std::fs::read_to_string(path).unwrap_or_default();
+    let mut res = Vec::new();
+    for (name, v) in parse_snmp(&load("/proc/net/snmp"), "Udp") {
+        if UDP_FIELDS.contains(&name.as_str()) {
+            res.push((format!("udp.{name}"), v));
+        }
+    }
+    for line in load("/proc/net/snmp6").lines() {
+        let mut fds = line.split_whitespace();
+        if let (Some(name), Some(v)) = (fds.next(), fds.next()) {
+            if let Some(name) = name.strip_prefix("Udp6") {
+                if UDP_FIELDS.contains(&name) {
+                    if let Ok(v) = v.parse() {
+                        res.push((format!("udp6.{name}"), v));
+                    }
+                }
+            }
+        }
+    }
+    for line in load("/proc/self/status").lines() {
+        if let Some((name, v)) = line.split_once(':') {
+            if name.ends_with("ctxt_switches") {
+                if let Ok(v) = v.trim().parse() {
+                    res.push((name.to_owned(), v));
+                }
+            }
+        }
+    }
+    for line in load("/proc/stat").lines() {
+        if let Some(v) = line.strip_prefix("softirq ") {
+            if let Some(Ok(v)) = v.split_whitespace().next().map(str::parse) {
+                res.push(("softirq".to_owned(), v));
+            }
+        }
+    }
+    res
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read() -> Vec<(String, u64)> {
+    Vec::new()
+}
+
+// /proc/net/snmp has a header line followed by a value line per protocol.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_snmp(contents: &str, proto: &str) -> Vec<(String, u64)> {
+    let prefix = format!("{proto}:");
+    let mut lines = contents.lines().filter(|x| x.starts_with(&prefix));
+    let (Some(names), Some(values)) = (lines.next(), lines.next()) else {
+        return Vec::new();
+    };
+    names
+        .split_whitespace()
+        .zip(values.split_whitespace())
+        .skip(1)
+        .filter_map(|(name, v)| Some((name.to_owned(), v.parse().ok()?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_udp_counters() {
+        let contents = "Tcp: RtoAlgorithm RtoMin\nTcp: 1 200\n\
+            Udp: InDatagrams NoPorts InErrors OutDatagrams RcvbufErrors\n\
+            Udp: 100 2 7 90 5\n";
+        let udp = parse_snmp(contents, "Udp");
+        assert_eq!(udp.len(), 5);
+        assert_eq!(udp[4], ("RcvbufErrors".to_owned(), 5));
+        assert!(parse_snmp(contents, "UdpLite").is_empty());
+    }
+}
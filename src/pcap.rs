@@ -0,0 +1,260 @@
+use crate::{
+    capture,
+    common::{get_arg, get_arg_or},
+};
+use hbb_common::{bail, log, rendezvous_proto::*, try_into_v4, ResultType};
+use ipnetwork::IpNetwork;
+use once_cell::sync::OnceCell;
+use std::{
+    fs::File,
+    io::Write as _,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const DEFAULT_SAMPLE: usize = 1;
+const DEFAULT_MAX_SIZE: u64 = 100; // in MB
+const LINKTYPE_RAW: u32 = 101; // raw ipv4 or ipv6 packets
+const SNAPLEN: u32 = 65535;
+
+static PCAP: OnceCell<Pcap> = OnceCell::new();
+
+// Sampled incoming UDP signaling written as a pcap file, wrapped in made-up
+// IP and UDP headers so Wireshark shows the client addresses. The local
+// address is unknown on a wildcard socket and left unspecified.
+struct Pcap {
+    path: String,
+    filter: Vec<Term>,
+    sample: usize,
+    max_size: u64, // in bytes, rotated to <path>.1 when exceeded
+    port: u16,
+    counter: AtomicUsize, // matched the filter
+    written: AtomicUsize,
+    file: Mutex<Option<(File, u64)>>, // opened on the first packet, with its size
+}
+
+#[derive(Debug, PartialEq)]
+enum Term {
+    Net(IpNetwork),
+    Id(String),
+}
+
+/// `PCAP_FILE` is the file to write to, `PCAP_FILTER` selects datagrams the
+/// way a BPF expression would, e.g. `host 10.0.0.1 or net 10.1.0.0/16 or id 123456789`.
+pub(crate) fn init(port: i32) {
+    let path = get_arg("PCAP_FILE");
+    if path.is_empty() {
+        return;
+    }
+    let filter = match parse_filter(&get_arg("PCAP_FILTER")) {
+        Ok(filter) => filter,
+        Err(err) => {
+            log::error!("Invalid PCAP_FILTER, not capturing: {}", err);
+            return;
+        }
+    };
+    let sample = get_arg_or("PCAP_SAMPLE", DEFAULT_SAMPLE.to_string())
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_SAMPLE)
+        .max(1);
+    let max_size = get_arg_or("PCAP_MAX_SIZE", DEFAULT_MAX_SIZE.to_string())
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_MAX_SIZE)
+        .max(1);
+    log::info!(
+        "PCAP_FILE={}, PCAP_FILTER={:?}, PCAP_SAMPLE=1/{}, PCAP_MAX_SIZE={}MB",
+        path,
+        filter,
+        sample,
+        max_size
+    );
+    PCAP.set(Pcap {
+        path,
+        filter,
+        sample,
+        max_size: max_size * 1024 * 1024,
+        port: port as _,
+        counter: AtomicUsize::new(0),
+        written: AtomicUsize::new(0),
+        file: Mutex::new(None),
+    })
+    .ok();
+}
+
+fn parse_filter(v: &str) -> ResultType<Vec<Term>> {
+    let mut terms = Vec::new();
+    let v = v.trim();
+    if v.is_empty() {
+        return Ok(terms);
+    }
+    for term in v.split(" or ") {
+        let mut fds = term.split_whitespace();
+        let term = match (fds.next(), fds.next(), fds.next()) {
+            (Some("host" | "net"), Some(net), None) => match net.parse::<IpNetwork>() {
+                Ok(net) => Term::Net(net),
+                Err(err) => bail!("{}: {}", net, err),
+            },
+            (Some("id"), Some(id), None) => Term::Id(id.to_owned()),
+            _ => bail!("{:?}, expected host <ip>, net <cidr> or id <id>", term.trim()),
+        };
+        terms.push(term);
+    }
+    Ok(terms)
+}
+
+fn matches(filter: &[Term], addr: SocketAddr, msg: &RendezvousMessage) -> bool {
+    filter.is_empty()
+        || filter.iter().any(|x| match x {
+            Term::Net(net) => net.contains(try_into_v4(addr).ip()),
+            Term::Id(id) => capture::get_msg_id(msg) == Some(id.as_str()),
+        })
+}
+
+#[inline]
+pub(crate) fn write(addr: SocketAddr, bytes: &[u8], msg: &RendezvousMessage) {
+    let Some(p) = PCAP.get() else {
+        return;
+    };
+    if !matches(&p.filter, addr, msg)
+        || p.counter.fetch_add(1, Ordering::Relaxed) % p.sample != 0
+    {
+        return;
+    }
+    let Ok(mut file) = p.file.lock() else {
+        return;
+    };
+    let record = record(try_into_v4(addr), p.port, bytes);
+    if let Err(err) = p.append(&mut file, &record) {
+        log::error!("Failed to write {}: {}", p.path, err);
+        // try again with a new file next time
+        *file = None;
+        return;
+    }
+    p.written.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Pcap {
+    fn append(&self, file: &mut Option<(File, u64)>, record: &[u8]) -> ResultType<()> {
+        if file.as_ref().is_some_and(|x| x.1 + record.len() as u64 > self.max_size) {
+            *file = None;
+            std::fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        if file.is_none() {
+            let mut f = File::create(&self.path)?;
+            let header = header();
+            f.write_all(&header)?;
+            *file = Some((f, header.len() as _));
+        }
+        if let Some((f, size)) = file.as_mut() {
+            f.write_all(record)?;
+            *size += record.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+fn header() -> Vec<u8> {
+    let mut v = Vec::with_capacity(24);
+    v.extend(0xa1b2c3d4u32.to_le_bytes());
+    v.extend(2u16.to_le_bytes());
+    v.extend(4u16.to_le_bytes());
+    v.extend(0i32.to_le_bytes()); // timezone
+    v.extend(0u32.to_le_bytes()); // timestamp accuracy
+    v.extend(SNAPLEN.to_le_bytes());
+    v.extend(LINKTYPE_RAW.to_le_bytes());
+    v
+}
+
+fn record(from: SocketAddr, port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut udp = Vec::with_capacity(8 + payload.len());
+    udp.extend(from.port().to_be_bytes());
+    udp.extend(port.to_be_bytes());
+    udp.extend(((8 + payload.len()) as u16).to_be_bytes());
+    udp.extend(0u16.to_be_bytes()); // no checksum
+    udp.extend(payload);
+    let mut packet = match from.ip() {
+        IpAddr::V4(ip) => {
+            let mut v = vec![0x45, 0];
+            v.extend(((20 + udp.len()) as u16).to_be_bytes());
+            v.extend([0, 0, 0x40, 0, 64, 17, 0, 0]); // don't fragment, ttl, udp
+            v.extend(ip.octets());
+            v.extend(Ipv4Addr::UNSPECIFIED.octets());
+            let checksum = checksum(&v);
+            v[10..12].copy_from_slice(&checksum.to_be_bytes());
+            v
+        }
+        IpAddr::V6(ip) => {
+            let mut v = vec![0x60, 0, 0, 0];
+            v.extend((udp.len() as u16).to_be_bytes());
+            v.extend([17, 64]); // udp, hop limit
+            v.extend(ip.octets());
+            v.extend(Ipv6Addr::UNSPECIFIED.octets());
+            v
+        }
+    };
+    packet.extend(udp);
+    let len = packet.len() as u32;
+    let tm = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut v = Vec::with_capacity(16 + packet.len());
+    v.extend((tm.as_secs() as u32).to_le_bytes());
+    v.extend(tm.subsec_micros().to_le_bytes());
+    v.extend(len.min(SNAPLEN).to_le_bytes());
+    v.extend(len.to_le_bytes());
+    v.extend(&packet[..packet.len().min(SNAPLEN as _)]);
+    v
+}
+
+// of the ipv4 header
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|x| u16::from_be_bytes([x[0], x.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub(crate) fn status() -> String {
+    match PCAP.get() {
+        Some(p) => format!(
+            "{} 1/{}: {}/{}, filter {:?}\n",
+            p.path,
+            p.sample,
+            p.written.load(Ordering::Relaxed),
+            p.counter.load(Ordering::Relaxed),
+            p.filter
+        ),
+        None => "off\n".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_and_wraps_datagrams() {
+        let filter = parse_filter("host 10.0.0.1 or net 10.1.0.0/16 or id 123456789").unwrap();
+        assert_eq!(filter.len(), 3);
+        assert!(parse_filter("port 21116").is_err());
+        assert!(parse_filter("").unwrap().is_empty());
+        let msg = RendezvousMessage::new();
+        let addr: SocketAddr = "[::ffff:10.1.2.3]:5000".parse().unwrap();
+        assert!(matches(&filter, addr, &msg));
+        assert!(!matches(&filter, "10.2.0.1:5000".parse().unwrap(), &msg));
+        let record = record(try_into_v4(addr), 21116, &[1, 2, 3]);
+        // record header, ipv4 header, udp header, payload
+        assert_eq!(record.len(), 16 + 20 + 8 + 3);
+        assert_eq!(checksum(&record[16..36]), 0);
+        assert_eq!(&record[28..32], &[10, 1, 2, 3]);
+        assert_eq!(&record[38..40], &21116u16.to_be_bytes());
+    }
+}
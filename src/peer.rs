@@ -1,14 +1,29 @@
+use crate::cluster;
 use crate::common::*;
 use crate::database;
+use crate::expiry;
+use crate::log_id;
+use crate::peer_stats;
+use crate::snapshot;
+use crate::timing::Stamp;
+use crate::tombstone;
+use crate::ttl_class;
 use hbb_common::{
-    bytes::Bytes,
+    bail,
+    bytes::{Bytes, BytesMut},
     log,
     rendezvous_proto::*,
     tokio::sync::{Mutex, RwLock},
-    ResultType,
+    try_into_v4, ResultType,
 };
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, collections::HashSet, net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap,
+    collections::HashSet,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 type IpBlockMap = HashMap<String, ((u32, Instant), (HashSet<String>, Instant))>;
 type UserStatusMap = HashMap<Vec<u8>, Arc<(Option<Vec<u8>>, bool)>>;
@@ -23,15 +38,74 @@ pub const IP_CHANGE_DUR_X2: u64 = IP_CHANGE_DUR * 2;
 pub const DAY_SECONDS: u64 = 3600 * 24;
 pub const IP_BLOCK_DUR: u64 = 60;
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+/// Version of PeerInfo written to the database. Raise it with a step in
+/// PeerInfo::migrate() when existing records need rewriting, new optional
+/// fields only need `#[serde(default)]`.
+pub(crate) const PEER_INFO_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct PeerInfo {
+    #[serde(default)]
+    pub(crate) v: u32, // 0 before versioning
     #[serde(default)]
     pub(crate) ip: String,
+    // unix time, updated once a day while online, see ttl_class
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seen: Option<u64>,
+    // unix time of the last registration, see peer_stats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_online: Option<u64>,
+    #[serde(default)]
+    pub(crate) registrations: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_punch: Option<Punch>,
+    // fields of newer versions, kept when an older server writes the record back
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The last connection request to a peer.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct Punch {
+    pub(crate) time: u64, // unix time
+    pub(crate) outcome: String,
+}
+
+impl Default for PeerInfo {
+    fn default() -> Self {
+        Self {
+            v: PEER_INFO_VERSION,
+            ip: Default::default(),
+            seen: None,
+            last_online: None,
+            registrations: 0,
+            last_punch: None,
+            extra: Default::default(),
+        }
+    }
+}
+
+impl PeerInfo {
+    pub(crate) fn parse(s: &str) -> serde_json::Result<Self> {
+        let mut info = serde_json::from_str::<Self>(s)?;
+        info.migrate();
+        Ok(info)
+    }
+
+    // one step per version, a record of a newer version is left alone
+    fn migrate(&mut self) {
+        if self.v < 1 {
+            // only ip, nothing to convert
+            self.v = 1;
+        }
+    }
 }
 
 pub(crate) struct Peer {
     pub(crate) socket_addr: SocketAddr,
-    pub(crate) last_reg_time: Instant,
+    // of a dual-stack device also registering over IPv6, see punch_addr
+    pub(crate) socket_addr_v6: Option<(SocketAddr, Stamp)>,
+    pub(crate) last_reg_time: Stamp,
     pub(crate) guid: Vec<u8>,
     pub(crate) uuid: Bytes,
     pub(crate) pk: Bytes,
@@ -44,8 +118,9 @@ pub(crate) struct Peer {
 impl Default for Peer {
     fn default() -> Self {
         Self {
-            socket_addr: "0.0.0.0:0".parse().unwrap(),
-            last_reg_time: get_expired_time(),
+            socket_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            socket_addr_v6: None,
+            last_reg_time: Stamp::expired(),
             guid: Vec::new(),
             uuid: Bytes::new(),
             pk: Bytes::new(),
@@ -57,38 +132,143 @@ impl Default for Peer {
     }
 }
 
+impl Peer {
+    /// Where to punch a hole to from `requester`, the device's IPv6 address
+    /// if both sides registered over IPv6 within `timeout` ms.
+    pub(crate) fn punch_addr(&self, requester: SocketAddr, timeout: i64) -> SocketAddr {
+        match self.socket_addr_v6 {
+            Some((addr, tm)) if is_v6(requester) && tm.elapsed_ms() < timeout => addr,
+            _ => self.socket_addr,
+        }
+    }
+
+    /// Whether `addr` is the IPv6 side of this device, which registered over
+    /// IPv4 within `timeout` ms, rather than an IP change.
+    pub(crate) fn is_other_family(&self, addr: SocketAddr, timeout: i64) -> bool {
+        is_v6(addr)
+            && !is_v6(self.socket_addr)
+            && !self.pk.is_empty()
+            && self.last_reg_time.elapsed_ms() < timeout
+    }
+}
+
+/// Native IPv6, not IPv4 mapped on a dual-stack socket.
+#[inline]
+pub(crate) fn is_v6(addr: SocketAddr) -> bool {
+    try_into_v4(addr).is_ipv6()
+}
+
 pub(crate) type LockPeer = Arc<RwLock<Peer>>;
 
 #[derive(Clone)]
 pub(crate) struct PeerMap {
     map: Arc<RwLock<HashMap<String, LockPeer>>>,
+    /// The server's own tables, always SQLite.
     pub(crate) db: database::Database,
+    /// The peer records, in `db` unless `DB_URL` is another database.
+    pub(crate) storage: Arc<dyn database::PeerStorage>,
+}
+
+/// `DB_URL`, or the database next to the server.
+pub(crate) fn db_url() -> String {
+    get_arg_opt("DB_URL").unwrap_or_else(local_db_url)
+}
+
+/// The SQLite database next to the server.
+fn local_db_url() -> String {
+    let mut db = "db_v2.sqlite3".to_owned();
+    #[cfg(all(windows, not(debug_assertions)))]
+    {
+        if let Some(path) = hbb_common::config::Config::icon_path().parent() {
+            db = format!("{}\\{}", path.to_str().unwrap_or("."), db);
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        db = format!("./{db}");
+    }
+    db
+}
+
+// the url without its password, for the log
+fn log_url(url: &str) -> String {
+    match url.split_once("://").zip(url.rsplit_once('@')) {
+        Some(((scheme, rest), (_, host))) => {
+            let user = rest.split([':', '@']).next().unwrap_or_default();
+            format!("{scheme}://{user}@{host}")
+        }
+        None => url.to_owned(),
+    }
+}
+
+/// The server's own database and the peer records of `DB_URL`.
+pub(crate) async fn open_databases(
+) -> ResultType<(database::Database, Arc<dyn database::PeerStorage>)> {
+    let url = db_url();
+    let local = if database::is_postgres(&url) {
+        local_db_url()
+    } else {
+        url.clone()
+    };
+    let db = database::Database::new(&local).await?;
+    let storage = database::peer_storage(&url, &db).await?;
+    Ok((db, storage))
 }
 
 impl PeerMap {
     pub(crate) async fn new() -> ResultType<Self> {
-        let db = get_arg_opt("DB_URL").unwrap_or_else(|| {
-            let mut db = "db_v2.sqlite3".to_owned();
-            #[cfg(all(windows, not(debug_assertions)))]
-            {
-                if let Some(path) = hbb_common::config::Config::icon_path().parent() {
-                    db = format!("{}\\{}", path.to_str().unwrap_or("."), db);
-                }
-            }
-            #[cfg(not(windows))]
-            {
-                db = format!("./{db}");
-            }
-            db
-        });
-        log::info!("DB_URL={}", db);
+        log::info!("DB_URL={}", log_url(&db_url()));
+        let (db, storage) = open_databases().await?;
         let pm = Self {
             map: Default::default(),
-            db: database::Database::new(&db).await?,
+            db,
+            storage,
         };
+        pm.migrate_peer_infos().await?;
         Ok(pm)
     }
 
+    // Rewrite records of older PeerInfo versions once, so reading them stays
+    // a plain parse, and quarantine those which can't be read.
+    async fn migrate_peer_infos(&self) -> ResultType<()> {
+        let old = self.storage.get_peer_infos_before(PEER_INFO_VERSION).await?;
+        if old.is_empty() {
+            return Ok(());
+        }
+        log::info!(
+            "Migrating {} peer records to version {}",
+            old.len(),
+            PEER_INFO_VERSION
+        );
+        for (id, info) in old {
+            match PeerInfo::parse(&info) {
+                Ok(info) => {
+                    let info = serde_json::to_string(&info).unwrap_or_default();
+                    self.storage.update_info(&id, &info).await?;
+                }
+                Err(err) => self.quarantine(&id, &format!("info: {err}")).await,
+            }
+        }
+        Ok(())
+    }
+
+    // Move a record that can't be read aside instead of losing the peer
+    // silently, it registers again as new. See the quarantine console command.
+    async fn quarantine(&self, id: &str, reason: &str) {
+        match self.storage.quarantine_peer(id, reason).await {
+            Ok(_) => log::error!(
+                "Quarantined the corrupted record of {}: {}",
+                log_id::id(id),
+                reason
+            ),
+            Err(err) => log::error!(
+                "Failed to quarantine the record of {}: {}",
+                log_id::id(id),
+                err
+            ),
+        }
+    }
+
     #[inline]
     pub(crate) async fn update_pk(
         &mut self,
@@ -99,21 +279,27 @@ impl PeerMap {
         pk: Bytes,
         ip: String,
     ) -> register_pk_response::Result {
-        log::info!("update_pk {} {:?} {:?} {:?}", id, addr, uuid, pk);
+        log::info!("update_pk {} {:?} {:?} {:?}", log_id::id(&id), addr, uuid, pk);
         let (info_str, guid) = {
             let mut w = peer.write().await;
             w.socket_addr = addr;
             w.uuid = uuid.clone();
             w.pk = pk.clone();
-            w.last_reg_time = Instant::now();
+            w.last_reg_time = Stamp::now();
             w.info.ip = ip;
             (
                 serde_json::to_string(&w.info).unwrap_or_default(),
                 w.guid.clone(),
             )
         };
+        expiry::on_register(&id, addr.ip());
+        peer_stats::on_register(&id);
+        cluster::on_register(&id, addr, &pk);
+        if ttl_class::get(&id, Some(addr.ip())) == ttl_class::Class::Ephemeral {
+            return register_pk_response::Result::OK;
+        }
         if guid.is_empty() {
-            match self.db.insert_peer(&id, &uuid, &pk, &info_str).await {
+            match self.storage.insert_peer(&id, &uuid, &pk, &info_str).await {
                 Err(err) => {
                     log::error!("db.insert_peer failed: {}", err);
                     return register_pk_response::Result::SERVER_ERROR;
@@ -123,7 +309,7 @@ impl PeerMap {
                 }
             }
         } else {
-            if let Err(err) = self.db.update_pk(&guid, &id, &pk, &info_str).await {
+            if let Err(err) = self.storage.update_pk(&guid, &id, &pk, &info_str).await {
                 log::error!("db.update_pk failed: {}", err);
                 return register_pk_response::Result::SERVER_ERROR;
             }
@@ -132,26 +318,180 @@ impl PeerMap {
         register_pk_response::Result::OK
     }
 
+    /// Put a quarantined record back, with empty info if it can't be read.
+    /// Returns false if the id has registered again meanwhile.
+    pub(crate) async fn restore_quarantined(&self, id: &str) -> ResultType<bool> {
+        let Some((_, _, _, info)) = self
+            .storage
+            .get_quarantined(Some(id))
+            .await?
+            .into_iter()
+            .next()
+        else {
+            bail!("{} isn't quarantined", id);
+        };
+        let info = PeerInfo::parse(&info.unwrap_or_default()).unwrap_or_default();
+        let restored = self
+            .storage
+            .restore_quarantined(id, &serde_json::to_string(&info)?)
+            .await?;
+        if restored {
+            log::info!("Restored the quarantined record of {}", log_id::id(id));
+        }
+        Ok(restored)
+    }
+
+    /// Forget `id` in memory and the database, leaving a tombstone saying
+    /// why and by whom. `block` keeps the id from registering again for
+    /// `TOMBSTONE_BLOCK` minutes. Returns false if it's unknown.
+    pub(crate) async fn remove(
+        &self,
+        id: &str,
+        reason: &str,
+        operator: &str,
+        block: bool,
+    ) -> ResultType<bool> {
+        let in_memory = self.map.write().await.remove(id).is_some();
+        expiry::forget(id);
+        let in_db = self.storage.delete_peer(id).await?;
+        if !in_memory && !in_db {
+            return Ok(false);
+        }
+        let until = if block { tombstone::block_until() } else { 0 };
+        self.db.add_tombstone(id, reason, operator, until as _).await?;
+        tombstone::on_removed(id, until);
+        log::info!("Removed peer {} by {}: {}", log_id::id(id), operator, reason);
+        Ok(true)
+    }
+
+    /// Clear the uuid and key of `id`, the next device registering it is
+    /// taken as its owner. Returns false if it's unknown.
+    pub(crate) async fn expire_pk(&self, id: &str) -> ResultType<bool> {
+        let in_memory = match self.get_in_memory(id).await {
+            Some(peer) => {
+                let mut w = peer.write().await;
+                w.uuid = Bytes::new();
+                w.pk = Bytes::new();
+                true
+            }
+            None => false,
+        };
+        let in_db = self.storage.clear_pk(id).await?;
+        if in_memory || in_db {
+            log::info!("Expired the key of {}", log_id::id(id));
+        }
+        Ok(in_memory || in_db)
+    }
+
+    /// Drop the peers in memory whose database record was deleted or given
+    /// another key behind our back, so they aren't served until loaded
+    /// again. Returns how many were checked and the dropped ids with why.
+    pub(crate) async fn reconcile(&self) -> ResultType<(usize, Vec<(String, &'static str)>)> {
+        let mut cached = Vec::new();
+        for (id, peer) in self.map.read().await.iter() {
+            let peer = peer.read().await;
+            // never written, e.g. ephemeral
+            if !peer.guid.is_empty() {
+                cached.push((id.clone(), peer.guid.clone(), peer.pk.clone()));
+            }
+        }
+        let mut dropped = Vec::new();
+        for (id, guid, pk) in cached.iter() {
+            let why = match self.storage.get_peer(id).await? {
+                None => "deleted",
+                Some(v) if v.guid != *guid => "recreated",
+                Some(v) if v.pk != *pk => "pk changed",
+                Some(_) => continue,
+            };
+            let mut map = self.map.write().await;
+            // unless it registered again meanwhile
+            let unchanged = match map.get(id) {
+                Some(peer) => peer.read().await.pk == *pk,
+                None => false,
+            };
+            if unchanged {
+                map.remove(id);
+                log::warn!("Dropped {} from memory, its record was {}", log_id::id(id), why);
+                dropped.push((id.clone(), why));
+            }
+        }
+        Ok((cached.len(), dropped))
+    }
+
+    /// Record `now` as when the peers online were last seen.
+    pub(crate) async fn touch_online(&self, now: u64) -> ResultType<()> {
+        let mut ids = Vec::new();
+        for (id, peer) in self.online_peers().await {
+            peer.write().await.info.seen = Some(now);
+            ids.push(id);
+        }
+        self.storage.touch_peers(&ids, now).await
+    }
+
+    // The online peers in memory, taken without holding the map lock while
+    // each peer's lock is awaited.
+    async fn online_peers(&self) -> Vec<(String, LockPeer)> {
+        let map = self.map.read().await;
+        map.iter()
+            .filter(|(id, _)| expiry::is_online(id))
+            .map(|(id, peer)| (id.clone(), peer.clone()))
+            .collect()
+    }
+
+    /// Remove the records of peers not seen since `before`, unless pinned,
+    /// returns how many.
+    pub(crate) async fn purge_unseen(&self, before: u64) -> ResultType<usize> {
+        let mut n = 0;
+        for (id, info) in self.storage.get_peers_seen_before(before).await? {
+            let ip = PeerInfo::parse(&info).ok().and_then(|x| x.ip.parse().ok());
+            if ttl_class::get(&id, ip) == ttl_class::Class::Pinned || expiry::is_online(&id) {
+                continue;
+            }
+            let reason = format!("not seen since {}", before);
+            if self.remove(&id, &reason, "ttl", false).await? {
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
     #[inline]
     pub(crate) async fn get(&self, id: &str) -> Option<LockPeer> {
         let p = self.map.read().await.get(id).cloned();
         if p.is_some() {
             return p;
-        } else if let Ok(Some(v)) = self.db.get_peer(id).await {
-            let peer = Peer {
-                guid: v.guid,
-                uuid: v.uuid.into(),
-                pk: v.pk.into(),
-                // user: v.user,
-                info: serde_json::from_str::<PeerInfo>(&v.info).unwrap_or_default(),
-                // disabled: v.status == Some(0),
-                ..Default::default()
-            };
-            let peer = Arc::new(RwLock::new(peer));
-            self.map.write().await.insert(id.to_owned(), peer.clone());
-            return Some(peer);
         }
-        None
+        let v = match self.storage.get_peer(id).await {
+            Ok(Some(v)) => v,
+            Ok(None) => return None,
+            Err(err) => {
+                if database::is_corruption(&err) {
+                    self.quarantine(id, &err.to_string()).await;
+                } else {
+                    log::error!("db.get_peer {} failed: {}", log_id::id(id), err);
+                }
+                return None;
+            }
+        };
+        let info = match PeerInfo::parse(&v.info) {
+            Ok(info) => info,
+            Err(err) => {
+                self.quarantine(id, &format!("info: {err}")).await;
+                return None;
+            }
+        };
+        let peer = Peer {
+            guid: v.guid,
+            uuid: v.uuid.into(),
+            pk: v.pk.into(),
+            // user: v.user,
+            info,
+            // disabled: v.status == Some(0),
+            ..Default::default()
+        };
+        let peer = Arc::new(RwLock::new(peer));
+        self.map.write().await.insert(id.to_owned(), peer.clone());
+        Some(peer)
     }
 
     #[inline]
@@ -168,6 +508,66 @@ impl PeerMap {
         tmp
     }
 
+    /// A peer registered on another node of the cluster, only kept in
+    /// memory, the node it registered on has it in its database.
+    pub(crate) async fn set_remote(&self, id: &str, addr: SocketAddr, pk: Bytes) {
+        let peer = self.get_or(id).await;
+        {
+            let mut w = peer.write().await;
+            w.socket_addr = addr;
+            w.pk = pk;
+            w.last_reg_time = Stamp::now();
+        }
+        expiry::on_register(id, addr.ip());
+    }
+
+    /// The online peers with their address and ms since their last
+    /// registration.
+    pub(crate) async fn online(&self) -> Vec<(String, SocketAddr, i64)> {
+        let mut res = Vec::new();
+        for (id, peer) in self.online_peers().await {
+            let p = peer.read().await;
+            res.push((id, try_into_v4(p.socket_addr), p.last_reg_time.elapsed_ms()));
+        }
+        res
+    }
+
+    /// The online peers, for a restart, except ephemeral ones.
+    pub(crate) async fn snapshot(&self) -> Vec<snapshot::Entry> {
+        let mut res = Vec::new();
+        for (id, peer) in self.online_peers().await {
+            let p = peer.read().await;
+            if ttl_class::get(&id, Some(p.socket_addr.ip())) == ttl_class::Class::Ephemeral {
+                continue;
+            }
+            res.push(snapshot::Entry {
+                id,
+                addr: p.socket_addr,
+                age: p.last_reg_time.elapsed_ms(),
+                v6: p.socket_addr_v6.map(|(addr, tm)| (addr, tm.elapsed_ms())),
+            });
+        }
+        res
+    }
+
+    /// A peer of the snapshot taken before a restart, false if it isn't in
+    /// the database anymore.
+    pub(crate) async fn restore(&self, entry: snapshot::Entry) -> bool {
+        let Some(peer) = self.get(&entry.id).await else {
+            return false;
+        };
+        {
+            let mut w = peer.write().await;
+            w.socket_addr = entry.addr;
+            w.last_reg_time = Stamp::ago(Duration::from_millis(entry.age.max(0) as _));
+            w.socket_addr_v6 = entry
+                .v6
+                .map(|(addr, age)| (addr, Stamp::ago(Duration::from_millis(age.max(0) as _))));
+        }
+        expiry::on_restore(&entry.id, entry.addr.ip(), entry.age);
+        true
+    }
+
     #[inline]
     pub(crate) async fn get_in_memory(&self, id: &str) -> Option<LockPeer> {
         self.map.read().await.get(id).cloned()
@@ -177,4 +577,112 @@ impl PeerMap {
     pub(crate) async fn is_in_memory(&self, id: &str) -> bool {
         self.map.read().await.contains_key(id)
     }
+
+    #[inline]
+    pub(crate) async fn len(&self) -> usize {
+        self.map.read().await.len()
+    }
+
+    /// Online bitmap for a batch of ids, one bit per id from the most
+    /// significant bit of the first byte, answered from memory. The peers are
+    /// taken under a single map lock, which is released before reading them,
+    /// so registrations don't wait for the whole scan.
+    pub(crate) async fn get_online_states(
+        &self,
+        ids: &[String],
+        is_online: impl Fn(&str, &Peer) -> bool,
+    ) -> BytesMut {
+        let mut states = BytesMut::zeroed((ids.len() + 7) / 8);
+        let peers: Vec<_> = {
+            let map = self.map.read().await;
+            ids.iter().map(|id| map.get(id).cloned()).collect()
+        };
+        for (i, (id, peer)) in ids.iter().zip(peers).enumerate() {
+            if let Some(peer) = peer {
+                if is_online(id, &*peer.read().await) {
+                    states[i / 8] |= 0x80 >> (i % 8);
+                }
+            }
+        }
+        states
+    }
+
+    /// Drop the peers not registered for `idle` from memory, they are loaded
+    /// from the database again on next lookup. Returns how many.
+    pub(crate) async fn evict_idle(&self, idle: Duration) -> usize {
+        let mut w = self.map.write().await;
+        let before = w.len();
+        w.retain(|id, peer| {
+            // keep peers being updated right now
+            peer.try_read().map_or(true, |p| p.last_reg_time.elapsed() < idle)
+                || expiry::is_online(id)
+        });
+        before - w.len()
+    }
+
+    // Drop up to n peers with the oldest registration from memory,
+    // they are loaded from the database again on next lookup.
+    pub(crate) async fn shed_lru(&self, n: usize) -> usize {
+        if n == 0 {
+            return 0;
+        }
+        let mut candidates = Vec::new();
+        for (id, peer) in self.map.read().await.iter() {
+            // skip peers being updated right now
+            if let Ok(p) = peer.try_read() {
+                candidates.push((p.last_reg_time, id.clone()));
+            }
+        }
+        candidates.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut w = self.map.write().await;
+        candidates
+            .into_iter()
+            .take(n)
+            .filter(|(_, id)| w.remove(id).is_some())
+            .inspect(|(_, id)| expiry::forget(id))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_info_migrates_and_keeps_newer_fields() {
+        let info = PeerInfo::parse(r#"{"ip":"10.0.0.1"}"#).unwrap();
+        assert_eq!((info.v, info.ip.as_str()), (PEER_INFO_VERSION, "10.0.0.1"));
+        let info = PeerInfo::parse(r#"{"v":99,"ip":"10.0.0.1","labels":["a"]}"#).unwrap();
+        assert_eq!(info.v, 99);
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(json.contains(r#""labels":["a"]"#) && json.contains(r#""v":99"#));
+        assert!(PeerInfo::parse("not json").is_err());
+    }
+
+    #[test]
+    fn logs_db_url_without_password() {
+        assert_eq!(log_url("postgres://hbbs:secret@db/rustdesk"), "postgres://hbbs@db/rustdesk");
+        assert_eq!(log_url("./db_v2.sqlite3"), "./db_v2.sqlite3");
+    }
+
+    #[test]
+    fn punches_v6_to_v6() {
+        let v4: SocketAddr = "1.2.3.4:1".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:1".parse().unwrap();
+        let mut peer = Peer {
+            socket_addr: v4,
+            ..Default::default()
+        };
+        assert_eq!(peer.punch_addr("[2001:db8::2]:1".parse().unwrap(), 1000), v4);
+        assert!(!peer.is_other_family(v6, 1000));
+        peer.pk = Bytes::from_static(b"pk");
+        peer.last_reg_time = Stamp::now();
+        assert!(peer.is_other_family(v6, 1000));
+        assert!(!peer.is_other_family("[::ffff:5.6.7.8]:1".parse().unwrap(), 1000));
+        peer.socket_addr_v6 = Some((v6, Stamp::now()));
+        assert_eq!(peer.punch_addr("[2001:db8::2]:1".parse().unwrap(), 1000), v6);
+        assert_eq!(peer.punch_addr("[::ffff:5.6.7.8]:1".parse().unwrap(), 1000), v4);
+        peer.socket_addr_v6 = Some((v6, Stamp::expired()));
+        assert_eq!(peer.punch_addr("[2001:db8::2]:1".parse().unwrap(), 1000), v4);
+    }
 }
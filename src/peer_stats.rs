@@ -0,0 +1,134 @@
+use crate::{
+    common::now,
+    peer::{PeerInfo, PeerMap, Punch},
+};
+use hbb_common::{log, tokio};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+const FLUSH_INTERVAL: u64 = 60; // in seconds
+const MAX_PENDING: usize = 100_000;
+
+#[derive(Default)]
+struct Pending {
+    last_online: u64, // unix time, 0 if it didn't register meanwhile
+    registrations: u64,
+    punch: Option<(u64, &'static str)>, // (unix time, outcome)
+}
+
+static WRITTEN: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    // id -> what happened since the last flush
+    static ref PENDING: Mutex<HashMap<String, Pending>> = Default::default();
+}
+
+fn update(id: &str, f: impl FnOnce(&mut Pending)) {
+    let Ok(mut pending) = PENDING.lock() else {
+        return;
+    };
+    if pending.len() >= MAX_PENDING && !pending.contains_key(id) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    f(pending.entry(id.to_owned()).or_default());
+}
+
+pub(crate) fn on_register(id: &str) {
+    let now = now();
+    update(id, |x| {
+        x.last_online = now;
+        x.registrations += 1;
+    });
+}
+
+/// The outcome of the last connection request to `id`, see connection_log.
+pub(crate) fn on_punch(id: &str, outcome: &'static str) {
+    let now = now();
+    update(id, |x| x.punch = Some((now, outcome)));
+}
+
+/// Each peer's last registration, how many times it registered and the
+/// outcome of the last connection request to it are kept in its `info`, for
+/// the admin API to tell installations gone dead. Counted in memory and
+/// written every minute and on shutdown, off the request path.
+pub(crate) fn start(pm: PeerMap) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(FLUSH_INTERVAL)).await;
+            flush(&pm).await;
+        }
+    });
+}
+
+pub(crate) async fn flush(pm: &PeerMap) {
+    let pending = match PENDING.lock() {
+        Ok(mut pending) => std::mem::take(&mut *pending),
+        Err(_) => return,
+    };
+    if pending.is_empty() {
+        return;
+    }
+    let mut rows = Vec::with_capacity(pending.len());
+    for (id, x) in pending {
+        // the peers in memory write their info back, e.g. on a new key
+        if let Some(peer) = pm.get_in_memory(&id).await {
+            apply(&mut peer.write().await.info, &x);
+        }
+        rows.push((id, x.last_online, x.registrations, x.punch));
+    }
+    match pm.storage.update_peer_stats(&rows).await {
+        Ok(()) => {
+            WRITTEN.fetch_add(rows.len(), Ordering::Relaxed);
+        }
+        Err(err) => log::error!("Failed to write the stats of {} peers: {}", rows.len(), err),
+    }
+}
+
+fn apply(info: &mut PeerInfo, x: &Pending) {
+    info.last_online = info.last_online.max(Some(x.last_online).filter(|x| *x > 0));
+    info.registrations += x.registrations;
+    if let Some((time, outcome)) = x.punch {
+        info.last_punch = Some(Punch {
+            time,
+            outcome: outcome.to_owned(),
+        });
+    }
+}
+
+pub(crate) fn status() -> String {
+    format!(
+        "pending: {}\nwritten: {}\ndropped: {}\n",
+        PENDING.lock().map(|x| x.len()).unwrap_or(0),
+        WRITTEN.load(Ordering::Relaxed),
+        DROPPED.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_up_until_written() {
+        on_register("stats-test");
+        on_register("stats-test");
+        on_punch("stats-test", "offline");
+        let x = PENDING.lock().unwrap().remove("stats-test").unwrap();
+        assert_eq!(x.registrations, 2);
+        assert_eq!(x.punch.map(|x| x.1), Some("offline"));
+        let mut info = PeerInfo::default();
+        info.registrations = 5;
+        info.last_online = Some(u64::MAX);
+        apply(&mut info, &x);
+        assert_eq!((info.registrations, info.last_online), (7, Some(u64::MAX)));
+        assert_eq!(info.last_punch.unwrap().outcome, "offline");
+    }
+}
@@ -0,0 +1,185 @@
+use crate::common::{get_arg, get_arg_or};
+use hbb_common::log;
+use sodiumoxide::crypto::sign;
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+const DEFAULT_CERT_DIR: &str = "pk_certs";
+const MAX_CHAIN: usize = 4;
+
+static ACCEPTED: AtomicUsize = AtomicUsize::new(0);
+static REFUSED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+struct Config {
+    cas: Vec<sign::PublicKey>,
+    dir: String,
+}
+
+lazy_static::lazy_static! {
+    static ref CONFIG: Mutex<Config> = Default::default();
+}
+
+/// `PK_CA` is the comma separated base64 ed25519 public keys of the CAs the
+/// pk of a registering device must chain to, `PK_CERT_DIR` the directory with
+/// the devices' certificates, one file named after each id. Off without
+/// `PK_CA`.
+///
+/// A certificate is whitespace separated base64 ed25519 signed messages, the
+/// first signed by a CA and each next one by the key the previous one
+/// certifies, see [`intermediate`] and [`leaf`].
+pub(crate) fn init() {
+    let mut cas = Vec::new();
+    for x in get_arg("PK_CA").split(',').map(str::trim) {
+        if x.is_empty() {
+            continue;
+        }
+        match base64::decode(x)
+            .ok()
+            .and_then(|x| sign::PublicKey::from_slice(&x))
+        {
+            Some(pk) => cas.push(pk),
+            None => log::error!("Invalid PK_CA key {}, ignored", x),
+        }
+    }
+    let dir = get_arg_or("PK_CERT_DIR", DEFAULT_CERT_DIR.to_owned());
+    if !cas.is_empty() {
+        log::info!("PK_CA: {} CA(s), PK_CERT_DIR={}", cas.len(), dir);
+    }
+    if let Ok(mut config) = CONFIG.lock() {
+        *config = Config { cas, dir };
+    }
+}
+
+/// What a CA signs to certify the intermediate CA `pk`.
+pub fn intermediate(pk: &[u8]) -> Vec<u8> {
+    format!("ca {}", base64::encode(pk)).into_bytes()
+}
+
+/// What the last CA of a chain signs to certify `pk` as the key of `id`.
+pub fn leaf(id: &str, pk: &[u8]) -> Vec<u8> {
+    format!("pk {} {}", id, base64::encode(pk)).into_bytes()
+}
+
+/// Whether `pk` may be registered for `id`, always without `PK_CA`.
+pub(crate) fn verify(id: &str, pk: &[u8]) -> bool {
+    let (cas, dir) = match CONFIG.lock() {
+        Ok(config) if !config.cas.is_empty() => (config.cas.clone(), config.dir.clone()),
+        _ => return true,
+    };
+    // ids are checked by the caller, but never leave the directory
+    let res = if id.contains(['/', '\\']) || id.starts_with('.') {
+        Err("invalid id")
+    } else {
+        match std::fs::read_to_string(Path::new(&dir).join(id)) {
+            Ok(cert) => verify_chain(&cas, &cert, id, pk),
+            Err(_) => Err("no certificate"),
+        }
+    };
+    match res {
+        Ok(()) => {
+            ACCEPTED.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        Err(err) => {
+            REFUSED.fetch_add(1, Ordering::Relaxed);
+            log::warn!(
+                "pk {} of {} refused: {}",
+                base64::encode(pk),
+                crate::log_id::id(id),
+                err
+            );
+            false
+        }
+    }
+}
+
+fn verify_chain(
+    cas: &[sign::PublicKey],
+    cert: &str,
+    id: &str,
+    pk: &[u8],
+) -> Result<(), &'static str> {
+    let links = cert.split_whitespace().collect::<Vec<_>>();
+    if links.is_empty() || links.len() > MAX_CHAIN {
+        return Err("malformed certificate");
+    }
+    let mut signers = cas.to_vec();
+    for (i, link) in links.iter().enumerate() {
+        let signed = base64::decode(link).map_err(|_| "malformed certificate")?;
+        let statement = signers
+            .iter()
+            .find_map(|x| sign::verify(&signed, x).ok())
+            .ok_or("certificate not signed by a trusted CA")?;
+        if i + 1 == links.len() {
+            return if statement == leaf(id, pk) {
+                Ok(())
+            } else {
+                Err("certificate for another id or pk")
+            };
+        }
+        let key = std::str::from_utf8(&statement)
+            .ok()
+            .and_then(|x| x.strip_prefix("ca "))
+            .and_then(|x| base64::decode(x).ok())
+            .and_then(|x| sign::PublicKey::from_slice(&x))
+            .ok_or("malformed intermediate CA")?;
+        signers = vec![key];
+    }
+    Err("malformed certificate")
+}
+
+pub(crate) fn status() -> String {
+    let Ok(config) = CONFIG.lock() else {
+        return String::new();
+    };
+    if config.cas.is_empty() {
+        return "off, set PK_CA\n".to_owned();
+    }
+    format!(
+        "CAs: {}\ncertificates: {}\naccepted: {}\nrefused: {}\n",
+        config.cas.len(),
+        config.dir,
+        ACCEPTED.load(Ordering::Relaxed),
+        REFUSED.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_chains_to_a_ca() {
+        let (ca, ca_sk) = sign::gen_keypair();
+        let (sub, sub_sk) = sign::gen_keypair();
+        let (other, other_sk) = sign::gen_keypair();
+        let pk = [1u8; 32];
+        let link = |statement: Vec<u8>, sk| base64::encode(sign::sign(&statement, sk));
+        let direct = link(leaf("123456789", &pk), &ca_sk);
+        assert!(verify_chain(&[other, ca], &direct, "123456789", &pk).is_ok());
+        assert!(verify_chain(&[ca], &direct, "987654321", &pk).is_err());
+        assert!(verify_chain(&[ca], &direct, "123456789", &[2u8; 32]).is_err());
+        assert!(verify_chain(&[other], &direct, "123456789", &pk).is_err());
+        let chain = format!(
+            "{}\n{}",
+            link(intermediate(sub.as_ref()), &ca_sk),
+            link(leaf("123456789", &pk), &sub_sk)
+        );
+        assert!(verify_chain(&[ca], &chain, "123456789", &pk).is_ok());
+        let forged = format!(
+            "{}\n{}",
+            link(intermediate(sub.as_ref()), &ca_sk),
+            link(leaf("123456789", &pk), &other_sk)
+        );
+        assert!(verify_chain(&[ca], &forged, "123456789", &pk).is_err());
+        // an intermediate can't stand in for the device's pk
+        let short = link(intermediate(&pk), &ca_sk);
+        assert!(verify_chain(&[ca], &short, "123456789", &pk).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{common::get_arg, log_id};
+use hbb_common::log;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+static GRACE: AtomicI64 = AtomicI64::new(0); // in minutes
+static ACCEPTED: AtomicUsize = AtomicUsize::new(0);
+
+/// `PK_GRACE` is how many minutes after its last registration a peer may come
+/// back with another uuid and key from the same ip, e.g. reinstalled, and is
+/// taken as the same device instead of refused with UUID_MISMATCH until an
+/// operator expires its key. 0 is off.
+pub(crate) fn init() {
+    let grace = get_arg("PK_GRACE").parse::<i64>().unwrap_or(0).max(0);
+    GRACE.store(grace, Ordering::SeqCst);
+    if grace > 0 {
+        log::info!("PK_GRACE={}", grace);
+    }
+}
+
+/// Whether `id` registering from `ip` with another uuid than stored is let
+/// through, as the peer registered from `registered_ip` `last_reg_ms` ago.
+/// Logged and counted if so.
+pub(crate) fn accepts(id: &str, ip: &str, registered_ip: &str, last_reg_ms: i64) -> bool {
+    let grace = GRACE.load(Ordering::Relaxed);
+    if !within(grace, ip, registered_ip, last_reg_ms) {
+        return false;
+    }
+    ACCEPTED.fetch_add(1, Ordering::Relaxed);
+    log::warn!("Peer {} from {} changed its uuid and key, within PK_GRACE", log_id::id(id), ip);
+    true
+}
+
+#[inline]
+fn within(grace: i64, ip: &str, registered_ip: &str, last_reg_ms: i64) -> bool {
+    grace > 0 && !ip.is_empty() && ip == registered_ip && last_reg_ms <= grace * 60_000
+}
+
+pub(crate) fn status() -> String {
+    match GRACE.load(Ordering::Relaxed) {
+        0 => "off, set PK_GRACE\n".to_owned(),
+        grace => format!(
+            "grace: {} minutes\naccepted: {}\n",
+            grace,
+            ACCEPTED.load(Ordering::Relaxed)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_lets_the_same_ip_through_within_the_grace() {
+        assert!(within(10, "10.0.0.1", "10.0.0.1", 60_000));
+        assert!(!within(10, "10.0.0.2", "10.0.0.1", 60_000));
+        assert!(!within(10, "10.0.0.1", "10.0.0.1", 11 * 60_000));
+        assert!(!within(0, "10.0.0.1", "10.0.0.1", 0));
+        assert!(!within(10, "", "", 0));
+    }
+}
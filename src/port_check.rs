@@ -0,0 +1,126 @@
+use crate::common::get_arg;
+use hbb_common::{
+    log,
+    protobuf::Message as _,
+    rendezvous_proto::*,
+    tokio::{
+        self,
+        net::{lookup_host, TcpStream, UdpSocket},
+        time::timeout,
+    },
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+lazy_static::lazy_static! {
+    static ref RESULTS: Mutex<String> = Mutex::new("off, set PORT_CHECK\n".to_owned());
+}
+
+/// `PORT_CHECK` is the public address clients reach this server at. Once
+/// the listeners are up, each port is tried at that address, the way a
+/// client would, and the firewall settings needed are logged for those
+/// which can't be reached. `ports` are (protocol, port) of the listeners.
+pub(crate) fn start(ports: Vec<(&'static str, u16)>) {
+    let host = get_arg("PORT_CHECK");
+    if host.is_empty() {
+        return;
+    }
+    log::info!("PORT_CHECK={}", host);
+    tokio::spawn(async move {
+        let mut res = String::new();
+        for (proto, port) in ports {
+            let open = if proto == "udp" {
+                check_udp(&host, port).await
+            } else {
+                check_tcp(&host, port).await
+            };
+            if open {
+                log::info!("Port {}/{} reachable at {}", port, proto, host);
+            } else {
+                log::warn!("{}", guidance(&host, proto, port));
+            }
+            res += &format!(
+                "{}/{}: {}\n",
+                port,
+                proto,
+                if open { "open" } else { "filtered" }
+            );
+        }
+        if let Ok(mut results) = RESULTS.lock() {
+            *results = format!("host: {}\n{}", host, res);
+        }
+    });
+}
+
+async fn check_tcp(host: &str, port: u16) -> bool {
+    matches!(timeout(TIMEOUT, TcpStream::connect((host, port))).await, Ok(Ok(_)))
+}
+
+// any answer to a registration will do
+async fn check_udp(host: &str, port: u16) -> bool {
+    let Some(addr) = lookup_host((host, port)).await.ok().and_then(|mut x| x.next()) else {
+        return false;
+    };
+    let any = if addr.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    let Ok(socket) = UdpSocket::bind(SocketAddr::new(any, 0)).await else {
+        return false;
+    };
+    let mut msg = RendezvousMessage::new();
+    msg.set_register_peer(RegisterPeer {
+        id: "(:test_hbbs:)".to_owned(),
+        ..Default::default()
+    });
+    let Ok(bytes) = msg.write_to_bytes() else {
+        return false;
+    };
+    let mut buf = [0u8; 1024];
+    // udp may be lost, try a few times
+    for _ in 0..3 {
+        if socket.send_to(&bytes, addr).await.is_err() {
+            return false;
+        }
+        if let Ok(Ok(_)) = timeout(TIMEOUT, socket.recv_from(&mut buf)).await {
+            return true;
+        }
+    }
+    false
+}
+
+fn guidance(host: &str, proto: &str, port: u16) -> String {
+    let flag = if proto == "udp" { "u" } else { "" };
+    format!(
+        "Port {port}/{proto} not reachable at {host}, clients can't connect until it's \
+         allowed in the host firewall (e.g. `ufw allow {port}/{proto}` or \
+         `firewall-cmd --permanent --add-port={port}/{proto}`), the cloud security group \
+         and the router's port forwarding. If {host} is the router's own address, it may \
+         just not support hairpin NAT, check from outside with `nc -vz{flag} {host} {port}`"
+    )
+}
+
+pub(crate) fn status() -> String {
+    RESULTS.lock().map(|x| x.clone()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guidance_names_the_port() {
+        let udp = guidance("hbbs.example.com", "udp", 21116);
+        assert!(udp.contains("ufw allow 21116/udp"));
+        assert!(udp.contains("nc -vzu hbbs.example.com 21116"));
+        let tcp = guidance("203.0.113.7", "tcp", 21115);
+        assert!(tcp.contains("--add-port=21115/tcp"));
+        assert!(tcp.contains("nc -vz 203.0.113.7 21115"));
+    }
+}
@@ -0,0 +1,63 @@
+use hbb_common::tokio::sync::watch;
+use std::{collections::HashMap, sync::Mutex};
+
+pub(crate) const PUSH_INTERVAL: u64 = 1_000; // in ms
+pub(crate) const MAX_PEERS: usize = 1_000; // per subscription
+
+lazy_static::lazy_static! {
+    // connection token -> subscribed ids
+    static ref SUBSCRIPTIONS: Mutex<HashMap<u64, watch::Sender<Vec<String>>>> = Default::default();
+}
+
+/// Subscribe the connection to online/offline changes of `peers`, replacing
+/// any ids it subscribed to before. Returns the receiver for the push task
+/// if this is a new subscription.
+pub(crate) fn subscribe(
+    token: u64,
+    mut peers: Vec<String>,
+) -> Option<watch::Receiver<Vec<String>>> {
+    peers.truncate(MAX_PEERS);
+    let Ok(mut lock) = SUBSCRIPTIONS.lock() else {
+        return None;
+    };
+    if let Some(tx) = lock.get(&token) {
+        tx.send(peers).ok();
+        return None;
+    }
+    let (tx, rx) = watch::channel(peers);
+    lock.insert(token, tx);
+    Some(rx)
+}
+
+/// Called when the connection closes, which also stops its push task.
+pub(crate) fn unsubscribe(token: u64) {
+    if let Ok(mut lock) = SUBSCRIPTIONS.lock() {
+        lock.remove(&token);
+    }
+}
+
+pub(crate) fn status() -> String {
+    match SUBSCRIPTIONS.lock() {
+        Ok(lock) => format!(
+            "subscriptions: {}\npeers: {}\n",
+            lock.len(),
+            lock.values().map(|x| x.borrow().len()).sum::<usize>()
+        ),
+        Err(_) => "".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resubscribing_replaces_ids() {
+        let mut rx = subscribe(u64::MAX, vec!["1".to_owned()]).unwrap();
+        assert!(subscribe(u64::MAX, vec!["2".to_owned(); MAX_PEERS + 1]).is_none());
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(rx.borrow_and_update().len(), MAX_PEERS);
+        unsubscribe(u64::MAX);
+        assert!(rx.has_changed().is_err());
+    }
+}
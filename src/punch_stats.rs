@@ -0,0 +1,194 @@
+use hbb_common::rendezvous_proto::NatType;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::Mutex,
+    time::Instant,
+};
+
+// An answered attempt not followed by a relay request within this time is
+// counted as a direct connection.
+const SETTLE_TIME: u64 = 30; // in seconds
+const MAX_PENDING: usize = 100_000;
+pub(crate) const CHECK_INTERVAL: u64 = 5_000; // in ms
+
+// A brokered attempt keyed by the requester's address, which both the
+// answer and the relay request refer to.
+struct Attempt {
+    tm: Instant,
+    to_id: String,
+    nat_a: NatType,
+    same_ip: bool,
+    class: Option<String>, // known once the target answered
+    relayed: bool,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Counters {
+    answered: usize,
+    direct: usize,
+    relayed: usize,
+    // From forwarding the request to the answer, in ms. The target starts
+    // punching when it gets the request, the requester only when the answer
+    // reaches it, so this plus the requester's one-way delay is the target's
+    // head start. Starting both at once needs a start time in PunchHole and
+    // PunchHoleResponse, which the protocol doesn't have yet; waiting on our
+    // side can only make the requester later.
+    answer_ms: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    pending: HashMap<SocketAddr, Attempt>,
+    classes: BTreeMap<String, Counters>,
+    attempts: usize,
+    unanswered: usize,
+}
+
+lazy_static::lazy_static! {
+    static ref STATS: Mutex<Stats> = Default::default();
+}
+
+/// A punch hole or local address request was forwarded from `addr_a` to `to_id`.
+pub(crate) fn on_request(addr_a: SocketAddr, to_id: &str, nat_a: NatType, same_ip: bool) {
+    if let Ok(mut stats) = STATS.lock() {
+        stats.attempts += 1;
+        if stats.pending.len() >= MAX_PENDING && !stats.pending.contains_key(&addr_a) {
+            return;
+        }
+        stats.pending.insert(
+            addr_a,
+            Attempt {
+                tm: Instant::now(),
+                to_id: to_id.to_owned(),
+                nat_a,
+                same_ip,
+                class: None,
+                relayed: false,
+            },
+        );
+    }
+}
+
+/// The target answered. `port_preserving` is whether its NAT kept the source
+/// port across protocols, if that could be observed.
+pub(crate) fn on_answer(addr_a: SocketAddr, nat_b: NatType, port_preserving: Option<bool>) {
+    if let Ok(mut stats) = STATS.lock() {
+        let stats = &mut *stats;
+        let Some(attempt) = stats.pending.get_mut(&addr_a) else {
+            return;
+        };
+        if attempt.class.is_some() {
+            return;
+        }
+        let class = if attempt.same_ip {
+            "same-ip".to_owned()
+        } else {
+            format!(
+                "{}/{}",
+                nat_label(attempt.nat_a, None),
+                nat_label(nat_b, port_preserving)
+            )
+        };
+        let c = stats.classes.entry(class.clone()).or_default();
+        c.answered += 1;
+        c.answer_ms += attempt.tm.elapsed().as_millis() as u64;
+        attempt.class = Some(class);
+    }
+}
+
+/// The requester asked for a relay to `to_id`, i.e. punching didn't work or wasn't tried.
+pub(crate) fn on_relay(addr_a: SocketAddr, to_id: &str) {
+    if let Ok(mut stats) = STATS.lock() {
+        if let Some(attempt) = stats.pending.get_mut(&addr_a) {
+            if attempt.to_id == to_id {
+                attempt.relayed = true;
+            }
+        }
+    }
+}
+
+/// Settle attempts older than SETTLE_TIME.
+pub(crate) fn check() {
+    if let Ok(mut stats) = STATS.lock() {
+        let stats = &mut *stats;
+        let classes = &mut stats.classes;
+        let mut unanswered = 0;
+        stats.pending.retain(|_, attempt| {
+            if attempt.tm.elapsed().as_secs() < SETTLE_TIME {
+                return true;
+            }
+            match &attempt.class {
+                Some(class) => {
+                    let c = classes.entry(class.clone()).or_default();
+                    if attempt.relayed {
+                        c.relayed += 1;
+                    } else {
+                        c.direct += 1;
+                    }
+                }
+                None => unanswered += 1,
+            }
+            false
+        });
+        stats.unanswered += unanswered;
+    }
+}
+
+// The reported NAT type, refined by what we saw: a cone NAT which changes
+// the source port is probably symmetric.
+fn nat_label(nat: NatType, port_preserving: Option<bool>) -> &'static str {
+    match (nat, port_preserving) {
+        (NatType::SYMMETRIC, _) => "symmetric",
+        (_, Some(true)) => "port-preserving",
+        (NatType::ASYMMETRIC, Some(false)) => "symmetric?",
+        (NatType::ASYMMETRIC, None) => "cone",
+        _ => "unknown",
+    }
+}
+
+/// Punch hole and local address requests so far.
+pub(crate) fn attempts() -> usize {
+    STATS.lock().map(|x| x.attempts).unwrap_or_default()
+}
+
+pub(crate) fn status() -> String {
+    let Ok(stats) = STATS.lock() else {
+        return "".to_owned();
+    };
+    let mut res = format!(
+        "attempts: {}\npending: {}\nunanswered: {}\n",
+        stats.attempts,
+        stats.pending.len(),
+        stats.unanswered
+    );
+    for (class, c) in stats.classes.iter() {
+        let settled = c.direct + c.relayed;
+        let _ = writeln!(
+            res,
+            "{}: answered={} direct={} relayed={} success={}% answer={}ms",
+            class,
+            c.answered,
+            c.direct,
+            c.relayed,
+            if settled > 0 { c.direct * 100 / settled } else { 0 },
+            if c.answered > 0 { c.answer_ms / c.answered as u64 } else { 0 }
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_nat_from_report_and_observation() {
+        assert_eq!(nat_label(NatType::SYMMETRIC, Some(true)), "symmetric");
+        assert_eq!(nat_label(NatType::ASYMMETRIC, Some(true)), "port-preserving");
+        assert_eq!(nat_label(NatType::ASYMMETRIC, Some(false)), "symmetric?");
+        assert_eq!(nat_label(NatType::ASYMMETRIC, None), "cone");
+        assert_eq!(nat_label(NatType::UNKNOWN_NAT, Some(false)), "unknown");
+    }
+}
@@ -0,0 +1,129 @@
+use crate::common::get_arg;
+use hbb_common::{
+    log,
+    rendezvous_proto::{PunchHoleResponse, RendezvousMessage},
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+pub(crate) const CHECK_INTERVAL: u64 = 1_000; // in ms
+const MAX_PENDING: usize = 100_000;
+
+static TIMEOUT: AtomicU64 = AtomicU64::new(0); // in ms, 0 is off
+static TIMED_OUT: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    // requester's address -> the id asked for, when the request was forwarded
+    static ref PENDING: Mutex<HashMap<SocketAddr, (String, Instant)>> = Default::default();
+}
+
+/// `PUNCH_TIMEOUT` is how long the target of a forwarded punch hole request
+/// has to answer, in ms, before the requester is told it timed out instead
+/// of waiting for its own timeout. Off if 0.
+pub(crate) fn init() {
+    let v = get_arg("PUNCH_TIMEOUT").parse::<u64>().unwrap_or(0);
+    TIMEOUT.store(v, Ordering::SeqCst);
+    if v > 0 {
+        log::info!("PUNCH_TIMEOUT={}ms", v);
+    } else if let Ok(mut pending) = PENDING.lock() {
+        pending.clear();
+    }
+}
+
+/// A punch hole or local address request from `addr_a` was forwarded to `id`.
+pub(crate) fn on_forward(addr_a: SocketAddr, id: &str) {
+    if TIMEOUT.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    if let Ok(mut pending) = PENDING.lock() {
+        if pending.len() >= MAX_PENDING && !pending.contains_key(&addr_a) {
+            return;
+        }
+        pending.insert(addr_a, (id.to_owned(), Instant::now()));
+    }
+}
+
+/// The target answered, or the requester went on to the relay.
+pub(crate) fn on_done(addr_a: SocketAddr) {
+    if let Ok(mut pending) = PENDING.lock() {
+        pending.remove(&addr_a);
+    }
+}
+
+/// The requesters whose target didn't answer in time, with the id they
+/// asked for.
+pub(crate) fn expired() -> Vec<(SocketAddr, String)> {
+    let timeout = TIMEOUT.load(Ordering::Relaxed);
+    if timeout == 0 {
+        return Vec::new();
+    }
+    let Ok(mut pending) = PENDING.lock() else {
+        return Vec::new();
+    };
+    let mut res = Vec::new();
+    pending.retain(|addr, (id, tm)| {
+        if (tm.elapsed().as_millis() as u64) < timeout {
+            return true;
+        }
+        res.push((*addr, std::mem::take(id)));
+        false
+    });
+    TIMED_OUT.fetch_add(res.len(), Ordering::Relaxed);
+    res
+}
+
+/// The answer for a requester whose target didn't answer.
+pub(crate) fn response(id: &str) -> RendezvousMessage {
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_punch_hole_response(PunchHoleResponse {
+        other_failure: format!(
+            "TIMEOUT: {} didn't answer within {} seconds",
+            id,
+            TIMEOUT.load(Ordering::Relaxed) / 1000
+        ),
+        ..Default::default()
+    });
+    msg_out
+}
+
+pub(crate) fn status() -> String {
+    let timeout = TIMEOUT.load(Ordering::SeqCst);
+    if timeout == 0 {
+        return "off, set PUNCH_TIMEOUT\n".to_owned();
+    }
+    format!(
+        "timeout: {}ms\npending: {}\ntimed out: {}\n",
+        timeout,
+        PENDING.lock().map_or(0, |x| x.len()),
+        TIMED_OUT.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_unanswered_requests() {
+        TIMEOUT.store(60_000, Ordering::SeqCst);
+        let (a, b): (SocketAddr, SocketAddr) =
+            ("10.0.0.1:1".parse().unwrap(), "10.0.0.2:2".parse().unwrap());
+        on_forward(a, "123456789");
+        on_forward(b, "987654321");
+        on_done(b);
+        if let Ok(mut pending) = PENDING.lock() {
+            if let Some(x) = pending.get_mut(&a) {
+                x.1 -= std::time::Duration::from_secs(61);
+            }
+        }
+        assert_eq!(expired(), [(a, "123456789".to_owned())]);
+        assert!(expired().is_empty());
+    }
+}
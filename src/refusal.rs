@@ -0,0 +1,74 @@
+use crate::common::{get_arg, get_arg_or};
+use hbb_common::log;
+use once_cell::sync::OnceCell;
+
+/// Why a punch hole request is refused.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Reason {
+    Ban = 0,
+    Key = 1,
+    Quota = 2,
+    Busy = 3,
+    Cooldown = 4,
+    Unregistered = 5,
+    Banned = 6,
+}
+
+const NAMES: [&str; 7] = ["BAN", "KEY", "QUOTA", "BUSY", "COOLDOWN", "UNREGISTERED", "BANNED"];
+
+static MESSAGES: OnceCell<[String; 7]> = OnceCell::new();
+
+/// `REFUSAL_MESSAGE` is shown to users on every refusal, e.g. who to contact,
+/// `REFUSAL_MESSAGE_<reason>` replaces it for one reason.
+pub(crate) fn init() {
+    let generic = get_arg("REFUSAL_MESSAGE");
+    let messages =
+        NAMES.map(|name| get_arg_or(&format!("REFUSAL_MESSAGE_{name}"), generic.clone()));
+    for (name, msg) in NAMES.iter().zip(messages.iter()) {
+        if !msg.is_empty() {
+            log::info!("REFUSAL_MESSAGE_{}={}", name, msg);
+        }
+    }
+    MESSAGES.set(messages).ok();
+}
+
+/// The text for `other_failure`: our own explanation, if any, followed by the
+/// configured message.
+pub(crate) fn message(reason: Reason, text: &str) -> String {
+    let custom = MESSAGES
+        .get()
+        .map(|x| x[reason as usize].as_str())
+        .unwrap_or_default();
+    attach(text, custom)
+}
+
+#[inline]
+pub(crate) fn name(reason: Reason) -> &'static str {
+    NAMES[reason as usize]
+}
+
+fn attach(text: &str, custom: &str) -> String {
+    if custom.is_empty() {
+        text.to_owned()
+    } else if text.is_empty() {
+        custom.to_owned()
+    } else {
+        format!("{text}. {custom}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attaches_custom_message() {
+        assert_eq!(attach("Server is busy", ""), "Server is busy");
+        assert_eq!(attach("", "Contact IT at ext 1234"), "Contact IT at ext 1234");
+        assert_eq!(
+            attach("Server is busy", "Contact IT at ext 1234"),
+            "Server is busy. Contact IT at ext 1234"
+        );
+        assert_eq!(NAMES[Reason::Cooldown as usize], "COOLDOWN");
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{common::get_arg, expiry};
+use hbb_common::{
+    log,
+    protobuf::{Message, UnknownValueRef},
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Number of the field clients append the registration token to `RegisterPk`
+/// as, unknown to servers that don't read it, so they skip it.
+pub(crate) const FIELD: u32 = 1002;
+const MAX_SOURCES: usize = 100_000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REFUSED: AtomicUsize = AtomicUsize::new(0);
+static NO_TOKEN: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    // ip -> last registration from it
+    static ref SOURCES: Mutex<HashMap<IpAddr, Instant>> = Default::default();
+    static ref TOKEN: Mutex<String> = Default::default();
+}
+
+/// `REQUIRE_REGISTERED=Y` only takes connection requests from IPs a peer is
+/// registered from, so only clients set up for this server can use it.
+pub(crate) fn init() {
+    ENABLED.store(
+        get_arg("REQUIRE_REGISTERED").to_uppercase() == "Y",
+        Ordering::SeqCst,
+    );
+    if ENABLED.load(Ordering::SeqCst) {
+        log::info!("REQUIRE_REGISTERED=Y");
+    }
+    // `REGISTER_TOKEN` is a pre-shared key clients have to append to
+    // `RegisterPk` as field `FIELD`, so only they can take IDs.
+    let token = get_arg("REGISTER_TOKEN");
+    if !token.is_empty() {
+        log::info!("REGISTER_TOKEN set");
+    }
+    if let Ok(mut x) = TOKEN.lock() {
+        *x = token;
+    }
+}
+
+/// Whether `msg` carries the registration token, if one is required.
+pub(crate) fn has_token(msg: &impl Message) -> bool {
+    let Ok(token) = TOKEN.lock() else {
+        return false;
+    };
+    if token.is_empty() {
+        return true;
+    }
+    let res = match msg.unknown_fields().get(FIELD) {
+        Some(UnknownValueRef::LengthDelimited(bytes)) => {
+            sodiumoxide::utils::memcmp(bytes, token.as_bytes())
+        }
+        _ => false,
+    };
+    if !res {
+        NO_TOKEN.fetch_add(1, Ordering::Relaxed);
+    }
+    res
+}
+
+#[inline]
+fn timeout() -> Duration {
+    Duration::from_millis(expiry::reg_timeout() as _)
+}
+
+pub(crate) fn on_register(ip: IpAddr) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let Ok(mut sources) = SOURCES.lock() else {
+        return;
+    };
+    let ip = ip.to_canonical();
+    if sources.len() >= MAX_SOURCES && !sources.contains_key(&ip) {
+        sources.retain(|_, x| x.elapsed() < timeout());
+        if sources.len() >= MAX_SOURCES {
+            return;
+        }
+    }
+    sources.insert(ip, Instant::now());
+}
+
+/// Whether a connection request from `ip` is taken.
+pub(crate) fn is_registered(ip: IpAddr) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return true;
+    }
+    let registered = SOURCES
+        .lock()
+        .is_ok_and(|x| x.get(&ip.to_canonical()).is_some_and(|x| x.elapsed() < timeout()));
+    if !registered {
+        REFUSED.fetch_add(1, Ordering::Relaxed);
+    }
+    registered
+}
+
+pub(crate) fn status() -> String {
+    let mut res = if ENABLED.load(Ordering::Relaxed) {
+        format!(
+            "sources: {}\nrefused: {}\n",
+            SOURCES.lock().map(|x| x.len()).unwrap_or(0),
+            REFUSED.load(Ordering::Relaxed)
+        )
+    } else {
+        "off, set REQUIRE_REGISTERED=Y\n".to_owned()
+    };
+    if TOKEN.lock().is_ok_and(|x| !x.is_empty()) {
+        res += &format!("refused registrations: {}\n", NO_TOKEN.load(Ordering::Relaxed));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_requests_from_registered_ips() {
+        ENABLED.store(true, Ordering::SeqCst);
+        on_register("::ffff:10.0.0.1".parse().unwrap());
+        assert!(is_registered("10.0.0.1".parse().unwrap()));
+        assert!(!is_registered("10.0.0.2".parse().unwrap()));
+        if let Ok(mut sources) = SOURCES.lock() {
+            sources.insert("10.0.0.3".parse().unwrap(), Instant::now() - timeout());
+        }
+        assert!(!is_registered("10.0.0.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn requires_the_registration_token() {
+        use hbb_common::rendezvous_proto::RegisterPk;
+        let mut rk = RegisterPk::new();
+        assert!(has_token(&rk));
+        if let Ok(mut token) = TOKEN.lock() {
+            *token = "secret".to_owned();
+        }
+        assert!(!has_token(&rk));
+        rk.mut_unknown_fields().add_length_delimited(FIELD, b"wrong".to_vec());
+        assert!(!has_token(&rk));
+        let mut rk = RegisterPk::new();
+        rk.mut_unknown_fields().add_length_delimited(FIELD, b"secret".to_vec());
+        assert!(has_token(&rk));
+        if let Ok(mut token) = TOKEN.lock() {
+            token.clear();
+        }
+    }
+}
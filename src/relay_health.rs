@@ -0,0 +1,161 @@
+use hbb_common::{
+    bail, config, futures::future::join_all, log, protobuf::Message as _, rendezvous_proto::*,
+    tcp::FramedStream, timeout, ResultType,
+};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+const TIMEOUT: u64 = 3_000; // in ms
+const FAILS_DOWN: u32 = 2; // checks in a row before a relay is taken out
+
+#[derive(Debug, Default)]
+struct Health {
+    down: bool,
+    fails: u32,           // in a row
+    pings: bool,          // answered a ping, an older hbbr just hangs up
+    latency: Option<u64>, // in ms, smoothed
+    changed: Option<Instant>,
+}
+
+static CHECKING: AtomicBool = AtomicBool::new(false);
+static FAILOVERS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    // relay as configured or reported -> its health
+    static ref HEALTH: Mutex<HashMap<String, Health>> = Default::default();
+}
+
+/// Connect to each of `relays` and ping it, a relay is down after failing
+/// `FAILS_DOWN` checks in a row and up again once it passes one. False if
+/// the last check still runs.
+pub(crate) async fn check(relays: Vec<String>) -> bool {
+    if CHECKING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    let res = join_all(relays.iter().map(|x| probe(x))).await;
+    if let Ok(mut health) = HEALTH.lock() {
+        health.retain(|x, _| relays.contains(x));
+        for (relay, res) in relays.into_iter().zip(res) {
+            let h = health.entry(relay.clone()).or_default();
+            if !update(h, res) {
+                continue;
+            }
+            if h.down {
+                log::error!("Relay {} is down, no longer handed out", relay);
+            } else {
+                log::info!("Relay {} is up again", relay);
+            }
+        }
+    }
+    CHECKING.store(false, Ordering::SeqCst);
+    true
+}
+
+// Whether the relay went down or up.
+fn update(h: &mut Health, res: ResultType<Option<u64>>) -> bool {
+    let up = match res {
+        Ok(Some(ms)) => {
+            h.pings = true;
+            h.latency = Some(h.latency.map_or(ms, |x| (x * 3 + ms) / 4));
+            true
+        }
+        // hanging up instead of answering is only fine for an older relay
+        Ok(None) => !h.pings,
+        Err(_) => false,
+    };
+    if up {
+        h.fails = 0;
+    } else {
+        h.fails += 1;
+    }
+    let down = !up && (h.down || h.fails >= FAILS_DOWN);
+    if down == h.down {
+        return false;
+    }
+    if down {
+        FAILOVERS.fetch_add(1, Ordering::Relaxed);
+    }
+    h.down = down;
+    h.changed = Some(Instant::now());
+    true
+}
+
+// The round trip of a ping in ms, None if the relay hung up instead of
+// answering, as relays before the ping do.
+async fn probe(relay: &str) -> ResultType<Option<u64>> {
+    let host = if relay.contains(':') {
+        relay.to_owned()
+    } else {
+        format!("{}:{}", relay, config::RELAY_PORT)
+    };
+    let mut stream = FramedStream::new(&host, None, TIMEOUT).await?;
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_test_nat_request(TestNatRequest::default());
+    let start = Instant::now();
+    stream.send(&msg_out).await?;
+    let Some(Ok(bytes)) = timeout(TIMEOUT, stream.next()).await? else {
+        return Ok(None);
+    };
+    if !RendezvousMessage::parse_from_bytes(&bytes)?.has_test_nat_response() {
+        bail!("unexpected response");
+    }
+    Ok(Some(start.elapsed().as_millis() as u64))
+}
+
+/// Whether `relay` failed its last checks.
+pub(crate) fn is_down(relay: &str) -> bool {
+    HEALTH
+        .lock()
+        .is_ok_and(|x| x.get(relay).is_some_and(|x| x.down))
+}
+
+pub(crate) fn status() -> String {
+    let mut res = format!("failovers: {}\n", FAILOVERS.load(Ordering::Relaxed));
+    let Ok(health) = HEALTH.lock() else {
+        return res;
+    };
+    for (relay, h) in health.iter() {
+        let _ = writeln!(
+            res,
+            "{}: {} latency={} fails={} changed={}{}",
+            relay,
+            if h.down { "down" } else { "up" },
+            h.latency.map_or("-".to_owned(), |x| format!("{x}ms")),
+            h.fails,
+            h.changed.map_or("-".to_owned(), |x| format!("{}s ago", x.elapsed().as_secs())),
+            if h.pings { "" } else { " (no ping)" }
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::anyhow::anyhow;
+
+    #[test]
+    fn down_after_failures_in_a_row() {
+        let mut h = Health::default();
+        assert!(!update(&mut h, Ok(None)));
+        assert!(!h.pings);
+        update(&mut h, Err(anyhow!("refused")));
+        assert!(!h.down);
+        assert!(update(&mut h, Err(anyhow!("refused"))));
+        assert!(h.down);
+        update(&mut h, Ok(Some(40)));
+        assert!(!h.down && h.pings);
+        assert_eq!(h.latency, Some(40));
+        // a relay which pinged before and now hangs up is hung
+        update(&mut h, Ok(None));
+        update(&mut h, Ok(None));
+        assert!(h.down);
+    }
+}
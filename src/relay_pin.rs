@@ -0,0 +1,108 @@
+use crate::common::get_arg;
+use hbb_common::log;
+use ipnetwork::IpNetwork;
+use std::{collections::HashMap, fmt::Write as _, net::IpAddr, sync::Mutex};
+
+#[derive(Default)]
+struct Pins {
+    ids: HashMap<String, String>, // id -> relay
+    nets: Vec<(IpNetwork, String)>, // most specific first
+}
+
+lazy_static::lazy_static! {
+    static ref PINS: Mutex<Pins> = Default::default();
+}
+
+/// `RELAY_PINS` is a comma separated list of `<id>=<relay>` or
+/// `<cidr>=<relay>`, a device or the devices in a network always get that
+/// relay, e.g. the one in their datacenter.
+pub(crate) fn init() {
+    let v = get_arg("RELAY_PINS");
+    if v.is_empty() {
+        return;
+    }
+    for x in v.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        match x.split_once('=') {
+            Some((target, relay)) => set(target.trim(), relay.trim()),
+            None => log::error!("Invalid relay pin {}, expected <id|cidr>=<relay>", x),
+        }
+    }
+    log::info!("RELAY_PINS={}", v);
+}
+
+/// Pin `target`, an id or a network, to `relay`.
+pub(crate) fn set(target: &str, relay: &str) {
+    let Ok(mut pins) = PINS.lock() else {
+        return;
+    };
+    match target.parse::<IpNetwork>() {
+        Ok(net) => {
+            pins.nets.retain(|x| x.0 != net);
+            pins.nets.push((net, relay.to_owned()));
+            pins.nets.sort_by_key(|x| std::cmp::Reverse(x.0.prefix()));
+        }
+        Err(_) => {
+            pins.ids.insert(target.to_owned(), relay.to_owned());
+        }
+    }
+}
+
+pub(crate) fn remove(target: &str) {
+    let Ok(mut pins) = PINS.lock() else {
+        return;
+    };
+    match target.parse::<IpNetwork>() {
+        Ok(net) => pins.nets.retain(|x| x.0 != net),
+        Err(_) => {
+            pins.ids.remove(target);
+        }
+    }
+}
+
+/// The relay pinned for a connection to `id`, by its id first, then by the
+/// network of either side, the target's first.
+pub(crate) fn get(id: &str, ips: &[IpAddr]) -> Option<String> {
+    let pins = PINS.lock().ok()?;
+    if let Some(relay) = pins.ids.get(id) {
+        return Some(relay.clone());
+    }
+    ips.iter().find_map(|ip| {
+        pins.nets
+            .iter()
+            .find(|x| x.0.contains(ip.to_canonical()))
+            .map(|x| x.1.clone())
+    })
+}
+
+pub(crate) fn status() -> String {
+    let mut res = String::new();
+    if let Ok(pins) = PINS.lock() {
+        for (id, relay) in pins.ids.iter() {
+            let _ = writeln!(res, "{} {}", id, relay);
+        }
+        for (net, relay) in pins.nets.iter() {
+            let _ = writeln!(res, "{} {}", net, relay);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_by_id_then_network() {
+        set("10.0.0.0/8", "relay-a");
+        set("10.1.0.0/16", "relay-b");
+        set("123456789", "relay-c");
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        assert_eq!(get("1", &[ip("10.1.2.3")]).as_deref(), Some("relay-b"));
+        assert_eq!(get("1", &[ip("10.2.2.3")]).as_deref(), Some("relay-a"));
+        assert_eq!(get("123456789", &[ip("10.1.2.3")]).as_deref(), Some("relay-c"));
+        assert_eq!(get("1", &[ip("8.8.8.8"), ip("10.2.0.1")]).as_deref(), Some("relay-a"));
+        assert_eq!(get("1", &[ip("8.8.8.8")]), None);
+        remove("10.0.0.0/8");
+        assert_eq!(get("1", &[ip("10.2.2.3")]), None);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::common::get_arg;
+use async_speed_limit::Limiter;
+use hbb_common::log;
+use once_cell::sync::OnceCell;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// The relay sessions of one customer, sharing a bandwidth quota.
+pub(crate) struct Group {
+    name: String,
+    key: String,
+    limit: f64, // in bit/s
+    limiter: Limiter,
+    sessions: AtomicUsize,
+    total: AtomicU64,    // in bits
+    reported: AtomicU64, // total at the last report
+}
+
+static GROUPS: OnceCell<Vec<Group>> = OnceCell::new();
+
+/// `RELAY_GROUPS` is a comma separated list of `name:key:<Mb/s>`, the
+/// customers sharing this relay: sessions requested with a group's key, the
+/// same as one of `EXTRA_KEYS` on hbbs, share that bandwidth, so one
+/// customer's bulk transfers can't take the others'. Their keys are accepted
+/// besides the relay's own.
+pub(crate) fn init() {
+    let groups: Vec<Group> = parse(&get_arg("RELAY_GROUPS"))
+        .into_iter()
+        .map(|(name, key, limit)| {
+            log::info!("Relay group {}: {}Mb/s", name, limit / 1024. / 1024.);
+            Group {
+                name,
+                key,
+                limit,
+                limiter: <Limiter>::new(limit),
+                sessions: AtomicUsize::new(0),
+                total: AtomicU64::new(0),
+                reported: AtomicU64::new(0),
+            }
+        })
+        .collect();
+    GROUPS.set(groups).ok();
+}
+
+fn parse(v: &str) -> Vec<(String, String, f64)> {
+    let mut res = Vec::new();
+    for x in v.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let mut parts = x.split(':');
+        match (parts.next(), parts.next(), parts.next().map(str::parse::<f64>)) {
+            (Some(name), Some(key), Some(Ok(limit)))
+                if !name.is_empty() && !key.is_empty() && limit > 0. =>
+            {
+                res.push((name.to_owned(), key.to_owned(), limit * 1024. * 1024.));
+            }
+            _ => log::error!("Invalid relay group {}, expected name:key:<Mb/s>", x),
+        }
+    }
+    res
+}
+
+/// The group of sessions requested with `licence_key`, if any.
+pub(crate) fn find(licence_key: &str) -> Option<&'static Group> {
+    if licence_key.is_empty() {
+        return None;
+    }
+    GROUPS.get()?.iter().find(|x| x.key == licence_key)
+}
+
+impl Group {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Wait until the group's quota allows `nb` more bits.
+    pub(crate) async fn consume(&self, nb: usize) {
+        self.limiter.consume(nb).await;
+        self.total.fetch_add(nb as _, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_start(&self) {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_end(&self) {
+        self.sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The bandwidth of each group since the last call `secs` ago, in kbit/s,
+/// for the load report.
+pub(crate) fn take_bandwidth(secs: u64) -> BTreeMap<String, usize> {
+    let mut res = BTreeMap::new();
+    for g in GROUPS.get().map_or(&[][..], |x| x.as_slice()) {
+        let total = g.total.load(Ordering::Relaxed);
+        let bits = total.saturating_sub(g.reported.swap(total, Ordering::Relaxed));
+        res.insert(g.name.clone(), (bits / secs.max(1) / 1000) as usize);
+    }
+    res
+}
+
+pub(crate) fn status() -> String {
+    let Some(groups) = GROUPS.get().filter(|x| !x.is_empty()) else {
+        return "no groups, set RELAY_GROUPS\n".to_owned();
+    };
+    let mut res = String::new();
+    for g in groups {
+        let _ = writeln!(
+            res,
+            "{}: {}Mb/s {} sessions {:.2}MB",
+            g.name,
+            g.limit / 1024. / 1024.,
+            g.sessions.load(Ordering::Relaxed),
+            g.total.load(Ordering::Relaxed) as f64 / 1024. / 1024. / 8.
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_groups() {
+        let groups = parse("acme:QUNNRQ==:50, bad, slow:c2xvdw==:0.5, :x:1, none:bm9uZQ==:0");
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0], ("acme".to_owned(), "QUNNRQ==".to_owned(), 50. * 1024. * 1024.));
+        assert_eq!(groups[1].2, 512. * 1024.);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{
+    common::{get_arg, now},
+    relay_health,
+    relay_report::{self, Report, REPORT_INTERVAL},
+};
+use hbb_common::log;
+use once_cell::sync::OnceCell;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+const EXPIRE: u64 = REPORT_INTERVAL * 3; // in seconds
+const MAX_SKEW: u64 = 60; // in seconds
+const MAX_RELAYS: usize = 1_000;
+const DEFAULT_CAPACITY: usize = 1_000; // in sessions, if a relay doesn't know
+
+struct Relay {
+    report: Report,
+    from: Option<SocketAddr>, // None if added on the console
+    seen: Instant,
+    assigned: usize, // since its last report
+}
+
+static SECRET: OnceCell<String> = OnceCell::new();
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+static UNSERVED: AtomicUsize = AtomicUsize::new(0); // all relays full or draining
+static HANDOFFS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref RELAYS: Mutex<HashMap<String, Relay>> = Default::default();
+    // kept across reports, until undrained
+    static ref DRAINING: Mutex<HashSet<String>> = Default::default();
+}
+
+/// Relays report themselves if `RELAY_SECRET` is set, see relay_report.
+pub(crate) fn init() {
+    let secret = get_arg("RELAY_SECRET");
+    if secret.is_empty() {
+        return;
+    }
+    log::info!("Accepting relay reports");
+    SECRET.set(secret).ok();
+}
+
+pub(crate) fn on_report(misc: &str, mac: &str, from: SocketAddr) {
+    let Some(secret) = SECRET.get() else {
+        return;
+    };
+    let expected = relay_report::mac(misc, secret);
+    if !sodiumoxide::utils::memcmp(expected.as_bytes(), mac.as_bytes()) {
+        log::warn!("Relay report from {} with a bad signature", from);
+        return;
+    }
+    let report = match serde_json::from_str::<Report>(misc) {
+        Ok(report) if !report.addr.is_empty() => report,
+        _ => {
+            log::warn!("Invalid relay report from {}", from);
+            return;
+        }
+    };
+    if report.ts.abs_diff(now()) > MAX_SKEW {
+        log::warn!("Outdated relay report from {}, check the clocks", from);
+        return;
+    }
+    let Ok(mut relays) = RELAYS.lock() else {
+        return;
+    };
+    match relays.get_mut(&report.addr) {
+        // a replay of an older report
+        Some(relay) if relay.report.ts >= report.ts => {}
+        Some(relay) => {
+            relay.report = report;
+            relay.from = Some(from);
+            relay.seen = Instant::now();
+            relay.assigned = 0;
+        }
+        None if relays.len() < MAX_RELAYS => {
+            log::info!("Relay {} joined from {}", report.addr, from);
+            relays.insert(
+                report.addr.clone(),
+                Relay {
+                    report,
+                    from: Some(from),
+                    seen: Instant::now(),
+                    assigned: 0,
+                },
+            );
+        }
+        None => {}
+    }
+}
+
+/// The least loaded relay which isn't draining or down, in `region` if one
+/// there isn't full, None if there is none.
+pub(crate) fn pick(region: Option<&str>) -> Option<String> {
+    let mut relays = RELAYS.lock().ok()?;
+    if relays.is_empty() {
+        return None;
+    }
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+    relays.retain(|addr, x| {
+        if x.from.is_none() {
+            // no reports to reset it, count the sessions of the last interval
+            if x.seen.elapsed().as_secs() >= REPORT_INTERVAL {
+                x.seen = Instant::now();
+                x.assigned = 0;
+            }
+            return true;
+        }
+        let alive = x.seen.elapsed().as_secs() < EXPIRE;
+        if !alive {
+            log::info!("Relay {} left", addr);
+        }
+        alive
+    });
+    let Ok(draining) = DRAINING.lock() else {
+        return None;
+    };
+    let usable =
+        |x: &Relay| !draining.contains(&x.report.addr) && !relay_health::is_down(&x.report.addr);
+    let local = region.and_then(|region| {
+        relays
+            .values()
+            .filter(|x| usable(x))
+            .filter(|x| x.report.region.eq_ignore_ascii_case(region) && usage(x) < 1000)
+            .min_by_key(|x| usage(x))
+            .map(|x| x.report.addr.clone())
+    });
+    let Some(relay) = relays
+        .values_mut()
+        .filter(|x| usable(x))
+        .min_by_key(|x| (Some(&x.report.addr) != local.as_ref(), usage(x)))
+    else {
+        UNSERVED.fetch_add(1, Ordering::Relaxed);
+        return None;
+    };
+    if usage(relay) >= 1000 {
+        // still better than a relay nobody watches
+        UNSERVED.fetch_add(1, Ordering::Relaxed);
+    }
+    relay.assigned += 1;
+    Some(relay.report.addr.clone())
+}
+
+/// Put a relay into rotation by hand, e.g. one that doesn't report.
+pub(crate) fn add(addr: &str, capacity: usize) {
+    let Ok(mut relays) = RELAYS.lock() else {
+        return;
+    };
+    log::info!("Relay {} added, capacity {}", addr, capacity);
+    relays.insert(
+        addr.to_owned(),
+        Relay {
+            report: Report {
+                addr: addr.to_owned(),
+                capacity,
+                ..Default::default()
+            },
+            from: None,
+            seen: Instant::now(),
+            assigned: 0,
+        },
+    );
+}
+
+/// The relays in the registry, for relay_health to check.
+pub(crate) fn addrs() -> Vec<String> {
+    RELAYS
+        .lock()
+        .map(|x| x.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+#[inline]
+pub(crate) fn len() -> usize {
+    RELAYS.lock().map(|x| x.len()).unwrap_or(0)
+}
+
+pub(crate) fn remove(addr: &str) -> bool {
+    RELAYS.lock().is_ok_and(|mut x| x.remove(addr).is_some())
+}
+
+/// A draining relay gets no new sessions, it can be shut down once its load is 0.
+pub(crate) fn set_draining(addr: &str, draining: bool) {
+    if let Ok(mut set) = DRAINING.lock() {
+        if draining {
+            log::info!("Relay {} draining", addr);
+            set.insert(addr.to_owned());
+        } else {
+            set.remove(addr);
+        }
+    }
+}
+
+/// Another relay for a session brokered to `relay` if that is draining, e.g.
+/// one set up before the drain whose peers reconnect. Both peers learn the
+/// relay from the same relay request and response, so rewriting those
+/// brings them to the same new relay, under the session's uuid.
+pub(crate) fn handoff(relay: &str) -> Option<String> {
+    let draining = DRAINING.lock().ok()?.iter().any(|x| same_relay(x, relay));
+    if !draining {
+        return None;
+    }
+    let to = pick(None)?;
+    HANDOFFS.fetch_add(1, Ordering::Relaxed);
+    log::debug!("Relay session on draining {} handed off to {}", relay, to);
+    Some(to)
+}
+
+// clients may be given a relay without the default port
+fn same_relay(addr: &str, relay: &str) -> bool {
+    addr == relay
+        || addr.strip_suffix(&format!(":{}", hbb_common::config::RELAY_PORT)) == Some(relay)
+}
+
+/// Pool-wide demand, one `name value` per line, for autoscalers.
+pub(crate) fn demand() -> String {
+    let Ok(relays) = RELAYS.lock() else {
+        return "".to_owned();
+    };
+    let Ok(draining) = DRAINING.lock() else {
+        return "".to_owned();
+    };
+    let (mut load, mut capacity, mut n, mut bandwidth) = (0, 0, 0, 0);
+    for x in relays.values().filter(|x| !draining.contains(&x.report.addr)) {
+        load += x.report.load + x.assigned;
+        bandwidth += x.report.bandwidth;
+        capacity += if x.report.capacity > 0 {
+            x.report.capacity
+        } else {
+            DEFAULT_CAPACITY
+        };
+        n += 1;
+    }
+    format!(
+        "relays {}\ndraining {}\nload {}\ncapacity {}\nusage_percent {}\nbandwidth_kbps {}\nrequests {}\nunserved {}\nhandoffs {}\n",
+        n,
+        relays.len() - n,
+        load,
+        capacity,
+        (load * 100).checked_div(capacity).unwrap_or(0),
+        bandwidth,
+        REQUESTS.load(Ordering::Relaxed),
+        UNSERVED.load(Ordering::Relaxed),
+        HANDOFFS.load(Ordering::Relaxed)
+    )
+}
+
+#[inline]
+pub(crate) fn requests() -> usize {
+    REQUESTS.load(Ordering::Relaxed)
+}
+
+// in per mille of the capacity, counting sessions assigned since the last
+// report, or of the bandwidth limit if that is more used
+fn usage(relay: &Relay) -> usize {
+    let capacity = if relay.report.capacity > 0 {
+        relay.report.capacity
+    } else {
+        DEFAULT_CAPACITY
+    };
+    let sessions = (relay.report.load + relay.assigned) * 1000 / capacity;
+    let bandwidth = (relay.report.bandwidth * 1000)
+        .checked_div(relay.report.bandwidth_limit)
+        .unwrap_or(0);
+    sessions.max(bandwidth)
+}
+
+pub(crate) fn status() -> String {
+    let mut res = demand();
+    let Ok(relays) = RELAYS.lock() else {
+        return res;
+    };
+    let Ok(draining) = DRAINING.lock() else {
+        return res;
+    };
+    for (addr, x) in relays.iter() {
+        let _ = writeln!(
+            res,
+            "{}{} from {}: region={} load={}/{} bandwidth={}/{}kbps assigned={} usage={}‰ seen={}s ago",
+            addr,
+            if draining.contains(addr) { " (draining)" } else { "" },
+            x.from.map(|x| x.to_string()).unwrap_or_else(|| "console".to_owned()),
+            x.report.region,
+            x.report.load,
+            x.report.capacity,
+            x.report.bandwidth,
+            x.report.bandwidth_limit,
+            x.assigned,
+            usage(x),
+            x.seen.elapsed().as_secs()
+        );
+    }
+    // the bandwidth of each relay group, on all relays
+    let mut groups: BTreeMap<&str, usize> = BTreeMap::new();
+    for x in relays.values() {
+        for (name, bandwidth) in x.report.groups.iter() {
+            *groups.entry(name).or_default() += bandwidth;
+        }
+    }
+    for (name, bandwidth) in groups {
+        let _ = writeln!(res, "group {}: {}kbps", name, bandwidth);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_counts_assigned_sessions() {
+        let mut relay = Relay {
+            report: Report {
+                addr: "relay1:21117".to_owned(),
+                capacity: 200,
+                load: 50,
+                ..Default::default()
+            },
+            from: None,
+            seen: Instant::now(),
+            assigned: 0,
+        };
+        assert_eq!(usage(&relay), 250);
+        relay.assigned = 50;
+        assert_eq!(usage(&relay), 500);
+        relay.report.capacity = 0;
+        assert_eq!(usage(&relay), 100);
+        relay.report.bandwidth = 600;
+        relay.report.bandwidth_limit = 1000;
+        assert_eq!(usage(&relay), 600);
+        let mac = relay_report::mac("{}", "secret");
+        assert_eq!(mac, relay_report::mac("{}", "secret"));
+        assert_ne!(mac, relay_report::mac("{}", "other"));
+        assert!(same_relay("relay1:21117", "relay1"));
+        assert!(!same_relay("relay1:21118", "relay1"));
+    }
+}
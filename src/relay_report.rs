@@ -0,0 +1,33 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use sodiumoxide::crypto::{auth::hmacsha256, hash::sha256};
+
+/// `PeerDiscovery.cmd` of a relay reporting itself to the rendezvous server,
+/// sent over udp every REPORT_INTERVAL. `misc` carries the json of Report and
+/// `mac` its HMAC with RELAY_SECRET.
+pub(crate) const CMD: &str = "relay-report";
+pub(crate) const REPORT_INTERVAL: u64 = 10; // in seconds
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Report {
+    pub(crate) addr: String, // host:port for clients
+    #[serde(default)]
+    pub(crate) region: String,
+    #[serde(default)]
+    pub(crate) capacity: usize, // in sessions, 0 is unknown
+    #[serde(default)]
+    pub(crate) load: usize, // in sessions
+    #[serde(default)]
+    pub(crate) bandwidth: usize, // in kbit/s, of all sessions
+    #[serde(default)]
+    pub(crate) bandwidth_limit: usize, // in kbit/s, TOTAL_BANDWIDTH, 0 is unknown
+    #[serde(default)]
+    pub(crate) groups: BTreeMap<String, usize>, // in kbit/s, see relay_quota
+    pub(crate) ts: u64, // in seconds since the epoch, against replay
+}
+
+/// Base64 HMAC-SHA256 of `data`, keyed by the hash of the shared secret.
+pub(crate) fn mac(data: &str, secret: &str) -> String {
+    let key = hmacsha256::Key(sha256::hash(secret.as_bytes()).0);
+    base64::encode(hmacsha256::authenticate(data.as_bytes(), &key).0)
+}
@@ -1,8 +1,12 @@
 use async_speed_limit::Limiter;
 use async_trait::async_trait;
+use crate::errors::{self, Site};
+use crate::relay_quota::{self, Group};
+use crate::relay_report::{self, Report, REPORT_INTERVAL};
 use hbb_common::{
-    allow_err, bail,
+    bail,
     bytes::{Bytes, BytesMut},
+    config,
     futures_util::{sink::SinkExt, stream::StreamExt},
     log,
     protobuf::Message as _,
@@ -10,6 +14,7 @@ use hbb_common::{
     sleep,
     tcp::FramedStream,
     timeout,
+    udp::FramedSocket,
     tokio::{
         self,
         io::{AsyncReadExt, AsyncWriteExt},
@@ -86,6 +91,8 @@ pub async fn start_with_bind(
     log::info!("Listening on tcp :{}", port);
     let port2 = port + 2;
     log::info!("Listening on websocket :{}", port2);
+    relay_quota::init();
+    start_report();
     let main_task = async move {
         loop {
             log::info!("Start");
@@ -104,6 +111,86 @@ pub async fn start_with_bind(
     )
 }
 
+/// Report address, region, capacity, load and bandwidth, also of each relay
+/// group, to the rendezvous
+/// server in RELAY_REGISTRY, which then prefers the least loaded relay.
+fn start_report() {
+    let registry = crate::common::get_arg("RELAY_REGISTRY");
+    if registry.is_empty() {
+        return;
+    }
+    let secret = crate::common::get_arg("RELAY_SECRET");
+    let addr = crate::common::get_arg("RELAY_ADDR");
+    if secret.is_empty() || addr.is_empty() {
+        log::error!("RELAY_REGISTRY requires RELAY_SECRET and RELAY_ADDR");
+        return;
+    }
+    let registry = if registry.contains(':') {
+        registry
+    } else {
+        format!("{}:{}", registry, config::RENDEZVOUS_PORT)
+    };
+    let region = crate::common::get_arg("RELAY_REGION");
+    let capacity = crate::common::get_arg("RELAY_CAPACITY")
+        .parse::<usize>()
+        .unwrap_or(0);
+    log::info!(
+        "Reporting {} to {}, RELAY_REGION={}, RELAY_CAPACITY={}",
+        addr,
+        registry,
+        region,
+        capacity
+    );
+    tokio::spawn(async move {
+        let mut timer = interval(Duration::from_secs(REPORT_INTERVAL));
+        let mut failing = false;
+        loop {
+            timer.tick().await;
+            let (load, bandwidth) = {
+                let usage = USAGE.read().await;
+                // the speed of the last second, in bit/ms
+                (usage.len(), usage.values().map(|x| x.3).sum::<usize>())
+            };
+            let report = Report {
+                addr: addr.clone(),
+                region: region.clone(),
+                capacity,
+                load,
+                bandwidth,
+                bandwidth_limit: TOTAL_BANDWIDTH.load(Ordering::Relaxed) / 1000,
+                groups: relay_quota::take_bandwidth(REPORT_INTERVAL),
+                ts: crate::common::now(),
+            };
+            match send_report(&registry, &report, &secret).await {
+                Ok(()) => failing = false,
+                Err(err) => {
+                    // once, not every REPORT_INTERVAL
+                    if !failing {
+                        log::warn!("Failed to report to {}: {}", registry, err);
+                    }
+                    failing = true;
+                }
+            }
+        }
+    });
+}
+
+async fn send_report(registry: &str, report: &Report, secret: &str) -> ResultType<()> {
+    let Some(addr) = tokio::net::lookup_host(registry).await?.next() else {
+        bail!("can't resolve {}", registry);
+    };
+    let misc = serde_json::to_string(report)?;
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_peer_discovery(PeerDiscovery {
+        cmd: relay_report::CMD.to_owned(),
+        mac: relay_report::mac(&misc, secret),
+        misc,
+        ..Default::default()
+    });
+    let mut socket = FramedSocket::new(config::Config::get_any_listen_addr(addr.is_ipv4())).await?;
+    socket.send(&msg_out, addr).await
+}
+
 fn check_params() {
     let tmp = crate::common::get_arg("DOWNGRADE_THRESHOLD")
         .parse::<f64>()
@@ -166,7 +253,7 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
     match fds.next() {
         Some("h") => {
             res = format!(
-                "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
+                "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
                 "blacklist-add(ba) <ip>",
                 "blacklist-remove(br) <ip>",
                 "blacklist(b) <ip>",
@@ -178,7 +265,9 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
                 "limit-speed(ls) [value(Mb/s)]",
                 "total-bandwidth(tb) [value(Mb/s)]",
                 "single-bandwidth(sb) [value(Mb/s)]",
-                "usage(u)"
+                "usage(u)",
+                "errors(er)",
+                "groups(gr)"
             )
         }
         Some("blacklist-add" | "ba") => {
@@ -327,6 +416,12 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
                 );
             }
         }
+        Some("errors" | "er") => {
+            res = errors::status();
+        }
+        Some("groups" | "gr") => {
+            res = relay_quota::status();
+        }
         _ => {}
     }
     res
@@ -395,7 +490,7 @@ async fn handle_connection(
     let key = key.to_owned();
     let limiter = limiter.clone();
     tokio::spawn(async move {
-        allow_err!(make_pair(stream, addr, &key, limiter, ws).await);
+        errors::check(Site::Relay, make_pair(stream, addr, &key, limiter, ws).await);
     });
 }
 
@@ -444,8 +539,18 @@ async fn make_pair_(stream: impl StreamTrait, addr: SocketAddr, key: &str, limit
     let mut stream = stream;
     if let Ok(Some(Ok(bytes))) = timeout(30_000, stream.recv()).await {
         if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
+            // the health check of hbbs, see relay_health there
+            if msg_in.has_test_nat_request() {
+                let mut msg_out = RendezvousMessage::new();
+                msg_out.set_test_nat_response(TestNatResponse::default());
+                if let Ok(bytes) = msg_out.write_to_bytes() {
+                    stream.send_raw(bytes.into()).await.ok();
+                }
+                return;
+            }
             if let Some(rendezvous_message::Union::RequestRelay(rf)) = msg_in.union {
-                if !key.is_empty() && rf.licence_key != key {
+                let group = relay_quota::find(&rf.licence_key);
+                if !key.is_empty() && rf.licence_key != key && group.is_none() {
                     log::warn!("Relay authentication failed from {} - invalid key", addr);
                     return;
                 }
@@ -460,12 +565,19 @@ async fn make_pair_(stream: impl StreamTrait, addr: SocketAddr, key: &str, limit
                             stream.set_raw();
                             log::info!("Both are raw");
                         }
-                        if let Err(err) = relay(addr, &mut stream, peer, limiter, id.clone()).await
-                        {
+                        if let Some(group) = group {
+                            log::info!("Relay of {} in group {}", addr, group.name());
+                            group.on_start();
+                        }
+                        let res = relay(addr, &mut stream, peer, limiter, group, id.clone()).await;
+                        if let Err(err) = res {
                             log::info!("Relay of {} closed: {}", addr, err);
                         } else {
                             log::info!("Relay of {} closed", addr);
                         }
+                        if let Some(group) = group {
+                            group.on_end();
+                        }
                         USAGE.write().await.remove(&id);
                     } else {
                         log::info!("New relay request {} from {}", rf.uuid, addr);
@@ -484,6 +596,7 @@ async fn relay(
     stream: &mut impl StreamTrait,
     peer: &mut Box<dyn StreamTrait>,
     total_limiter: Limiter,
+    group: Option<&'static Group>,
     id: String,
 ) -> ResultType<()> {
     let ip = addr.ip().to_string();
@@ -513,6 +626,9 @@ async fn relay(
                         limiter.consume(nb).await;
                     }
                     total_limiter.consume(nb).await;
+                    if let Some(group) = group {
+                        group.consume(nb).await;
+                    }
                     total += nb;
                     total_s += nb;
                     if !bytes.is_empty() {
@@ -532,6 +648,9 @@ async fn relay(
                         limiter.consume(nb).await;
                     }
                     total_limiter.consume(nb).await;
+                    if let Some(group) = group {
+                        group.consume(nb).await;
+                    }
                     total += nb;
                     total_s += nb;
                     if !bytes.is_empty() {
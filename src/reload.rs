@@ -0,0 +1,58 @@
+use crate::{
+    auth_failures, canary, common, connection_log, cooldown, dispatch, expiry, ip_filter, latency,
+    load_shed, memory_budget, pk_attest, pk_grace, punch_timeout, registered, socket_errors,
+    suppressed, tombstone,
+};
+use hbb_common::{log, tokio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Reload on SIGHUP, the peer map and connections are kept.
+pub(crate) fn start() {
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use hbb_common::tokio::signal::unix::{signal, SignalKind};
+        let mut hup = match signal(SignalKind::hangup()) {
+            Ok(hup) => hup,
+            Err(err) => {
+                log::error!("Failed to listen for SIGHUP: {}", err);
+                return;
+            }
+        };
+        while hup.recv().await.is_some() {
+            log::info!("signal hangup");
+            reload();
+        }
+    });
+}
+
+/// Read .env and the `--config` file again and apply what can change at
+/// runtime: policies, limits and timeouts. Listening addresses, the
+/// database and the key take a restart.
+pub(crate) fn reload() {
+    common::reload_args();
+    expiry::init();
+    punch_timeout::init();
+    canary::init();
+    cooldown::init();
+    auth_failures::init();
+    registered::init();
+    connection_log::init();
+    suppressed::init();
+    tombstone::init();
+    pk_attest::init();
+    pk_grace::init();
+    load_shed::init();
+    memory_budget::init();
+    socket_errors::init();
+    ip_filter::init();
+    latency::init();
+    dispatch::init();
+    RELOADS.fetch_add(1, Ordering::Relaxed);
+    log::info!("Configuration reloaded");
+}
+
+pub(crate) fn status() -> String {
+    format!("reloads: {}\n", RELOADS.load(Ordering::Relaxed))
+}
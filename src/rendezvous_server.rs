@@ -1,11 +1,80 @@
+use crate::admin_api;
+use crate::advertise;
+use crate::alias;
+use crate::auth_failures;
+use crate::ban;
+use crate::canary::{self, Cohort};
+use crate::capture;
+use crate::churn;
+use crate::client_config;
+use crate::cluster;
 use crate::common::*;
+use crate::connection_log::{self, Outcome};
+use crate::console_auth;
+use crate::cooldown;
+use crate::db_cli;
+use crate::dispatch::{self, Transport};
+use crate::dry_run::{self, Rule};
+use crate::dtls::{self, DtlsConn, DtlsListener};
+use crate::errors::{self, Site};
+use crate::expiry;
+use crate::federation;
+use crate::geoip;
+use crate::health;
+use crate::history;
+use crate::id_norm;
+use crate::instance;
+use crate::ip_filter;
+use crate::json_wire;
+use crate::keepalive;
+use crate::keys::KeyRing;
+use crate::last_error;
+use crate::latency;
+use crate::load_shed;
+use crate::local_subnets;
+use crate::log_id;
+use crate::memory_budget;
+use crate::migration;
+use crate::mirror;
+use crate::nat_test;
+use crate::os_stats;
+use crate::pcap;
 use crate::peer::*;
+use crate::peer_stats;
+use crate::pk_attest;
+use crate::pk_grace;
+use crate::port_check;
+use crate::presence;
+use crate::punch_stats;
+use crate::punch_timeout;
+use crate::refusal::{self, Reason};
+use crate::registered;
+use crate::reload;
+use crate::relay_health;
+use crate::relay_pin;
+use crate::relay_registry;
+use crate::relay_report;
+use crate::search;
+use crate::snapshot;
+use crate::socket_errors::{self, Kind};
+use crate::strict;
+use crate::suppressed;
+use crate::systemd;
+use crate::telemetry;
+use crate::timing::Stamp;
+use crate::tls;
+use crate::tombstone;
+use crate::trace;
+use crate::ttl_class;
+use crate::udp_sign;
+use crate::vacuum;
+use crate::watchdog::{self, Stage};
+use crate::webhook::{self, Event};
 use hbb_common::{
-    allow_err, bail,
+    bail,
     bytes::{Bytes, BytesMut},
     bytes_codec::BytesCodec,
     config,
-    futures::future::join_all,
     futures_util::{
         sink::SinkExt,
         stream::{SplitSink, StreamExt},
@@ -13,19 +82,19 @@ use hbb_common::{
     log,
     protobuf::{Message as _, MessageField},
     rendezvous_proto::{
-        register_pk_response::Result::{TOO_FREQUENT, UUID_MISMATCH},
+        register_pk_response::Result::{NOT_SUPPORT, TOO_FREQUENT, UUID_MISMATCH},
         *,
     },
     tcp::FramedStream,
     timeout,
     tokio::{
         self,
-        io::{AsyncReadExt, AsyncWriteExt},
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
         net::{TcpListener, TcpStream},
-        sync::{mpsc, Mutex},
+        sync::{mpsc, watch, Mutex},
         time::{interval, Duration},
     },
-    tokio_util::codec::Framed,
+    tokio_util::codec::{Framed, LinesCodec},
     try_into_v4,
     udp::FramedSocket,
     AddrMangle, ResultType,
@@ -35,7 +104,7 @@ use sodiumoxide::crypto::sign;
 use std::{
     collections::HashMap,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     sync::Arc,
     time::Instant,
 };
@@ -45,15 +114,41 @@ enum Data {
     Msg(Box<RendezvousMessage>, SocketAddr),
     RelayServers0(String),
     RelayServers(RelayServers),
+    Delayed(Box<RendezvousMessage>, SocketAddr), // udp, see latency
+    Local(Box<RendezvousMessage>, SocketAddr), // from another node of the cluster
 }
 
-const REG_TIMEOUT: i64 = 30_000;
-type TcpStreamSink = SplitSink<Framed<TcpStream, BytesCodec>, Bytes>;
-type WsSink = SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, tungstenite::Message>;
+// per online request, ids beyond are reported offline
+const MAX_ONLINE_PEERS: usize = 10_000;
+// a plain or a tls stream, see tls
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+type TcpStreamSink = SplitSink<Framed<Box<dyn Io>, BytesCodec>, Bytes>;
+type WsSink = SplitSink<tokio_tungstenite::WebSocketStream<Box<dyn Io>>, tungstenite::Message>;
+type JsonSink = SplitSink<Framed<TcpStream, LinesCodec>, String>;
 enum Sink {
     TcpStream(TcpStreamSink),
     Ws(WsSink),
+    Json(JsonSink),
+    Dtls(DtlsConn),
 }
+// A connection waiting for the answer to its punch hole / relay request. The
+// token tells apart connections which end up with the same address, e.g. after
+// a NAT rebind or behind a proxy reusing source ports.
+struct TcpSession {
+    token: u64,
+    sink: Sink,
+}
+// A peer registered over tcp instead of udp, e.g. with udp blocked on its
+// network. What would go to it over udp goes through the connection it
+// registered on, written by its own task so a slow peer can't hold up others.
+struct TcpPeer {
+    id: String,
+    token: u64,
+    tx: mpsc::Sender<RendezvousMessage>,
+}
+const TCP_PEER_QUEUE: usize = 32; // messages, more are dropped like udp would
+static SESSION_TOKEN: AtomicU64 = AtomicU64::new(1);
 type Sender = mpsc::UnboundedSender<Data>;
 type Receiver = mpsc::UnboundedReceiver<Data>;
 static ROTATION_RELAY_SERVER: AtomicUsize = AtomicUsize::new(0);
@@ -77,11 +172,14 @@ struct Inner {
     mask: Option<Ipv4Network>,
     local_ip: String,
     sk: Option<sign::SecretKey>,
+    keys: Arc<KeyRing>,
+    hide_id_existence: bool,
 }
 
 #[derive(Clone)]
 pub struct RendezvousServer {
-    tcp_punch: Arc<Mutex<HashMap<SocketAddr, Sink>>>,
+    tcp_punch: Arc<Mutex<HashMap<SocketAddr, TcpSession>>>,
+    tcp_peers: Arc<Mutex<HashMap<SocketAddr, TcpPeer>>>,
     pm: PeerMap,
     tx: Sender,
     relay_servers: Arc<RelayServers>,
@@ -110,13 +208,30 @@ impl RendezvousServer {
         key: &str,
         rmem: usize,
     ) -> ResultType<()> {
+        systemd::init();
+        instance::init("hbbs");
         let (key, sk) = Self::get_server_sk(key);
-        let nat_port = port - 1;
-        let ws_port = port + 2;
-        let pm = PeerMap::new().await?;
+        udp_sign::init(sk.as_ref());
+        strict::init(&key)?;
+        advertise::init();
+        client_config::init(&key);
+        tls::init()?;
+        let mut keys = KeyRing::new(&get_arg("EXTRA_KEYS"));
+        keys.load_old_key();
+        let tcp_port = listen_port("TCP_PORT", port);
+        let nat_port = listen_port("NAT_TEST_PORT", port - 1);
+        let ws_port = listen_port("WS_PORT", port + 2);
+        if tcp_port == nat_port || tcp_port == ws_port || nat_port == ws_port {
+            bail!("TCP_PORT, NAT_TEST_PORT and WS_PORT must differ");
+        }
+        health::start_healthz(bind_addr).await?;
+        let pm = health::wait_for("database", PeerMap::new).await?;
         log::info!("serial={}", serial);
         let rendezvous_servers = get_servers(&get_arg("rendezvous-servers"), "rendezvous-servers");
-        let mut socket = create_udp_listener(bind_addr, port, rmem).await?;
+        let mut socket = health::wait_for("udp listener", || {
+            create_udp_listener(bind_addr, port, rmem)
+        })
+        .await?;
         let (tx, mut rx) = mpsc::unbounded_channel::<Data>();
         let software_url = get_arg("software-url");
         let version = hbb_common::get_version_from_url(&software_url);
@@ -134,8 +249,13 @@ impl RendezvousServer {
                     .unwrap_or_default(),
             )
         };
+        let hide_id_existence = get_arg("HIDE_ID_EXISTENCE").to_uppercase() == "Y";
+        if hide_id_existence {
+            log::info!("HIDE_ID_EXISTENCE=Y");
+        }
         let mut rs = Self {
             tcp_punch: Arc::new(Mutex::new(HashMap::new())),
+            tcp_peers: Default::default(),
             pm,
             tx: tx.clone(),
             relay_servers: Default::default(),
@@ -148,16 +268,36 @@ impl RendezvousServer {
                 sk,
                 mask,
                 local_ip,
+                keys: Arc::new(keys),
+                hide_id_existence,
             }),
         };
         log::info!("mask: {:?}", rs.inner.mask);
         log::info!("local-ip: {:?}", rs.inner.local_ip);
         std::env::set_var("PORT_FOR_API", port.to_string());
-        rs.parse_relay_servers(&get_arg("relay-servers"));
-        let mut listener = create_tcp_listener(bind_addr, port).await?;
-        let mut listener2 = create_tcp_listener(bind_addr, nat_port).await?;
-        let mut listener3 = create_tcp_listener(bind_addr, ws_port).await?;
-        log::info!("Listening on tcp/udp {}", listener.local_addr()?);
+        let relay_servers = get_arg("relay-servers");
+        rs.parse_relay_servers(&relay_servers);
+        if rs.relay_servers0.len() < relay_servers.split(',').filter(|x| !x.is_empty()).count() {
+            // DNS may not be ready yet, e.g. in container orchestration
+            let tx = rs.tx.clone();
+            tokio::spawn(async move {
+                resolve_relay_servers(relay_servers, tx).await;
+            });
+        }
+        let mut listener =
+            health::wait_for("tcp listener", || create_tcp_listener(bind_addr, tcp_port)).await?;
+        let mut listener2 =
+            health::wait_for("nat test listener", || create_tcp_listener(bind_addr, nat_port))
+                .await?;
+        let mut listener3 =
+            health::wait_for("websocket listener", || create_tcp_listener(bind_addr, ws_port))
+                .await?;
+        health::set_status(true, "ok");
+        if tcp_port == port {
+            log::info!("Listening on tcp/udp {}", listener.local_addr()?);
+        } else {
+            log::info!("Listening on tcp {}, udp on port {}", listener.local_addr()?, port);
+        }
         log::info!(
             "Listening on tcp {}, extra port for NAT test",
             listener2.local_addr()?
@@ -175,9 +315,91 @@ impl RendezvousServer {
                 "N"
             }
         );
+        log_id::init();
+        dry_run::init();
+        console_auth::init();
+        socket_errors::init();
+        memory_budget::init();
+        load_shed::init();
+        cooldown::init();
+        auth_failures::init();
+        connection_log::init();
+        churn::init();
+        refusal::init();
+        expiry::init();
+        punch_timeout::init();
+        registered::init();
+        suppressed::init();
+        relay_pin::init();
+        ip_filter::init();
+        latency::init();
+        ttl_class::init();
+        tombstone::init();
+        pk_attest::init();
+        pk_grace::init();
+        id_norm::init();
+        webhook::init();
+        tombstone::load(&rs.pm.db).await;
+        alias::load(&rs.pm.db).await;
+        ban::load(&rs.pm.db).await;
+        relay_registry::init();
+        geoip::init()?;
+        local_subnets::init();
+        federation::init();
+        history::init(rs.pm.db.clone()).await;
+        search::init(rs.pm.db.clone()).await;
+        dispatch::init();
+        mirror::init();
+        pcap::init(port);
+        canary::init();
+        snapshot::restore(&rs.pm).await;
+        snapshot::warm(&rs.pm).await;
+        if nat_test::init() {
+            let socket = health::wait_for("udp nat test listener", || {
+                create_udp_listener(bind_addr, nat_port, rmem)
+            })
+            .await?;
+            log::info!("Listening on udp {:?}, NAT test", socket.local_addr());
+            tokio::spawn(nat_test::serve(socket));
+        }
+        if let Some(json_port) = json_wire::init() {
+            let listener = health::wait_for("json debug listener", || {
+                create_tcp_listener(Some(IpAddr::V4(Ipv4Addr::LOCALHOST)), json_port as _)
+            })
+            .await?;
+            log::info!("Listening on tcp {}, json debug", listener.local_addr()?);
+            tokio::spawn(rs.clone().serve_json(listener, key.clone()));
+        }
+        if let Some((dtls_port, config)) = dtls::init()? {
+            let ip = bind_addr.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+            let addr = SocketAddr::new(ip, dtls_port);
+            let listener = health::wait_for("dtls listener", || dtls::listen(addr)).await?;
+            log::info!("Listening on udp {}, dtls", addr);
+            tokio::spawn(rs.clone().serve_dtls(listener, config, key.clone()));
+        }
+        if let Some(socket) = cluster::init(bind_addr).await? {
+            tokio::spawn(rs.clone().serve_cluster(socket));
+        }
+        admin_api::start(bind_addr, rs.pm.clone()).await?;
+        expiry::start();
+        reload::start();
+        watchdog::start();
+        telemetry::start(rs.pm.clone());
+        ttl_class::start(rs.pm.clone());
+        port_check::start(vec![
+            ("udp", port as _),
+            ("tcp", tcp_port as _),
+            ("tcp", nat_port as _),
+            ("tcp", ws_port as _),
+        ]);
+        vacuum::start(rs.pm.db.clone());
+        peer_stats::start(rs.pm.clone());
         if test_addr.to_lowercase() != "no" {
             let test_addr = if test_addr.is_empty() {
-                listener.local_addr()?
+                // the udp socket, on PORT whatever TCP_PORT is
+                let mut addr = listener.local_addr()?;
+                addr.set_port(port as _);
+                addr
             } else {
                 test_addr.parse()?
             };
@@ -197,6 +419,9 @@ impl RendezvousServer {
                 }
             });
         };
+        systemd::notify("READY=1");
+        systemd::start_watchdog();
+        let pm = rs.pm.clone();
         let main_task = async move {
             loop {
                 log::info!("Start");
@@ -213,28 +438,49 @@ impl RendezvousServer {
                 {
                     LoopFailure::UdpSocket => {
                         drop(socket);
-                        socket = create_udp_listener(bind_addr, port, rmem).await?;
+                        socket = health::wait_for("udp listener", || {
+                            create_udp_listener(bind_addr, port, rmem)
+                        })
+                        .await?;
+                        socket_errors::on_rebuild(Kind::Udp);
                     }
                     LoopFailure::Listener => {
                         drop(listener);
-                        listener = create_tcp_listener(bind_addr, port).await?;
+                        listener = health::wait_for("tcp listener", || {
+                            create_tcp_listener(bind_addr, tcp_port)
+                        })
+                        .await?;
+                        socket_errors::on_rebuild(Kind::Tcp);
                     }
                     LoopFailure::Listener2 => {
                         drop(listener2);
-                        listener2 = create_tcp_listener(bind_addr, nat_port).await?;
+                        listener2 = health::wait_for("nat test listener", || {
+                            create_tcp_listener(bind_addr, nat_port)
+                        })
+                        .await?;
+                        socket_errors::on_rebuild(Kind::NatTest);
                     }
                     LoopFailure::Listener3 => {
                         drop(listener3);
-                        listener3 = create_tcp_listener(bind_addr, ws_port).await?;
+                        listener3 = health::wait_for("websocket listener", || {
+                            create_tcp_listener(bind_addr, ws_port)
+                        })
+                        .await?;
+                        socket_errors::on_rebuild(Kind::Ws);
                     }
                 }
+                health::set_status(true, "ok");
             }
         };
         let listen_signal = listen_signal();
-        tokio::select!(
+        let res = tokio::select!(
             res = main_task => res,
             res = listen_signal => res,
-        )
+        );
+        systemd::notify("STOPPING=1");
+        peer_stats::flush(&pm).await;
+        snapshot::save(&pm).await;
+        res
     }
 
     async fn io_loop(
@@ -247,10 +493,22 @@ impl RendezvousServer {
         key: &str,
     ) -> LoopFailure {
         let mut timer_check_relay = interval(Duration::from_millis(CHECK_RELAY_TIMEOUT));
+        let mut timer_check_memory = interval(Duration::from_millis(memory_budget::CHECK_INTERVAL));
+        let mut timer_check_load = interval(Duration::from_millis(load_shed::CHECK_INTERVAL));
+        let mut timer_punch_stats = interval(Duration::from_millis(punch_stats::CHECK_INTERVAL));
+        let mut timer_punch_timeout = interval(Duration::from_millis(punch_timeout::CHECK_INTERVAL));
+        let mut timer_heartbeat = interval(Duration::from_millis(watchdog::HEARTBEAT_INTERVAL));
+        let mut timer_history = interval(Duration::from_millis(history::SAMPLE_INTERVAL));
+        let mut last_attempts = punch_stats::attempts();
+        let mut last_relay_requests = relay_registry::requests();
         loop {
             tokio::select! {
+                _ = timer_heartbeat.tick() => {
+                    watchdog::beat(Stage::Timer);
+                }
                 _ = timer_check_relay.tick() => {
-                    if self.relay_servers0.len() > 1 {
+                    watchdog::beat(Stage::Timer);
+                    if self.relay_servers0.len() + relay_registry::len() > 1 {
                         let rs = self.relay_servers0.clone();
                         let tx = self.tx.clone();
                         tokio::spawn(async move {
@@ -258,63 +516,156 @@ impl RendezvousServer {
                         });
                     }
                 }
+                _ = timer_check_memory.tick() => {
+                    watchdog::beat(Stage::Timer);
+                    self.check_memory_budget(rx.len()).await;
+                }
+                _ = timer_check_load.tick() => {
+                    watchdog::beat(Stage::Timer);
+                    if load_shed::enabled() {
+                        load_shed::update(rx.len());
+                    }
+                }
+                _ = timer_punch_stats.tick() => {
+                    watchdog::beat(Stage::Timer);
+                    punch_stats::check();
+                }
+                _ = timer_punch_timeout.tick() => {
+                    watchdog::beat(Stage::Timer);
+                    for (addr, id) in punch_timeout::expired() {
+                        let trace = trace::finish(addr);
+                        log::debug!(
+                            "{}Punch hole {:?} request from {:?} timed out",
+                            trace,
+                            log_id::id(&id),
+                            addr
+                        );
+                        last_error::record(&id, &try_into_v4(addr).ip().to_string(), "TIMEOUT");
+                        let mut msg = punch_timeout::response(&id);
+                        // the way the request came, like the answer
+                        if self.tcp_punch.lock().await.contains_key(&try_into_v4(addr)) {
+                            self.send_to_tcp(msg, addr).await;
+                        } else {
+                            udp_sign::apply(&mut msg);
+                            let res = socket.send(&msg, addr).await;
+                            if socket_errors::on_udp_send(&res, addr) {
+                                return LoopFailure::UdpSocket;
+                            }
+                        }
+                    }
+                }
+                _ = timer_history.tick() => {
+                    watchdog::beat(Stage::Timer);
+                    if history::enabled() {
+                        let attempts = punch_stats::attempts();
+                        let relay_requests = relay_registry::requests();
+                        history::record([
+                            self.pm.len().await as f64,
+                            expiry::online() as f64,
+                            self.tcp_punch.lock().await.len() as f64,
+                            attempts.saturating_sub(last_attempts) as f64,
+                            relay_requests.saturating_sub(last_relay_requests) as f64,
+                        ]);
+                        last_attempts = attempts;
+                        last_relay_requests = relay_requests;
+                    }
+                }
                 Some(data) = rx.recv() => {
+                    watchdog::beat(Stage::Queue);
                     match data {
-                        Data::Msg(msg, addr) => { allow_err!(socket.send(msg.as_ref(), addr).await); }
+                        // a peer registered on another node gets it from there
+                        Data::Msg(msg, addr) if cluster::forward(&msg, addr) => {}
+                        Data::Msg(msg, addr) | Data::Local(msg, addr) => {
+                            if let Some(mut msg) = self.send_to_tcp_peer(*msg, addr).await {
+                                udp_sign::apply(&mut msg);
+                                let res = socket.send(&msg, addr).await;
+                                if socket_errors::on_udp_send(&res, addr) {
+                                    return LoopFailure::UdpSocket;
+                                }
+                            }
+                        }
                         Data::RelayServers0(rs) => { self.parse_relay_servers(&rs); }
                         Data::RelayServers(rs) => { self.relay_servers = Arc::new(rs); }
+                        Data::Delayed(msg, addr) => {
+                            if let Err(err) = self.handle_udp_msg(*msg, addr, socket).await {
+                                if socket_errors::on_error(Kind::Udp, &format!("udp failure: {err}")) {
+                                    return LoopFailure::UdpSocket;
+                                }
+                            }
+                        }
                     }
                 }
                 res = socket.next() => {
+                    watchdog::beat(Stage::Udp);
                     match res {
                         Some(Ok((bytes, addr))) => {
+                            // a failed reply to one peer, not a broken socket
                             if let Err(err) = self.handle_udp(&bytes, addr.into(), socket, key).await {
-                                log::error!("udp failure: {}", err);
-                                return LoopFailure::UdpSocket;
+                                if socket_errors::on_error(Kind::Udp, &format!("udp failure: {err}")) {
+                                    return LoopFailure::UdpSocket;
+                                }
                             }
                         }
                         Some(Err(err)) => {
-                            log::error!("udp failure: {}", err);
+                            socket_errors::on_fatal(Kind::Udp, &format!("udp failure: {err}"));
                             return LoopFailure::UdpSocket;
                         }
                         None => {
-                            // unreachable!() ?
+                            // would match again right away and spin
+                            socket_errors::on_fatal(Kind::Udp, "udp stream ended");
+                            return LoopFailure::UdpSocket;
                         }
                     }
                 }
                 res = listener2.accept() => {
+                    watchdog::beat(Stage::NatTest);
                     match res {
                         Ok((stream, addr))  => {
+                            socket_errors::on_ok(Kind::NatTest);
                             stream.set_nodelay(true).ok();
                             self.handle_listener2(stream, addr).await;
                         }
                         Err(err) => {
-                           log::error!("listener2.accept failed: {}", err);
-                           return LoopFailure::Listener2;
+                           // e.g. EMFILE, rebuilding the listener doesn't help with one error
+                           if socket_errors::on_error(Kind::NatTest, &err.to_string()) {
+                               return LoopFailure::Listener2;
+                           }
                         }
                     }
                 }
                 res = listener3.accept() => {
+                    watchdog::beat(Stage::Ws);
                     match res {
                         Ok((stream, addr))  => {
+                            socket_errors::on_ok(Kind::Ws);
+                            if !ip_filter::admit(addr.ip()) {
+                                continue;
+                            }
                             stream.set_nodelay(true).ok();
                             self.handle_listener(stream, addr, key, true).await;
                         }
                         Err(err) => {
-                           log::error!("listener3.accept failed: {}", err);
-                           return LoopFailure::Listener3;
+                           if socket_errors::on_error(Kind::Ws, &err.to_string()) {
+                               return LoopFailure::Listener3;
+                           }
                         }
                     }
                 }
                 res = listener.accept() => {
+                    watchdog::beat(Stage::Tcp);
                     match res {
                         Ok((stream, addr)) => {
+                            socket_errors::on_ok(Kind::Tcp);
+                            if !ip_filter::admit(addr.ip()) {
+                                continue;
+                            }
                             stream.set_nodelay(true).ok();
                             self.handle_listener(stream, addr, key, false).await;
                         }
                        Err(err) => {
-                           log::error!("listener.accept failed: {}", err);
-                           return LoopFailure::Listener;
+                           if socket_errors::on_error(Kind::Tcp, &err.to_string()) {
+                               return LoopFailure::Listener;
+                           }
                        }
                     }
                 }
@@ -330,154 +681,229 @@ impl RendezvousServer {
         socket: &mut FramedSocket,
         key: &str,
     ) -> ResultType<()> {
-        if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(bytes) {
-            match msg_in.union {
-                Some(rendezvous_message::Union::RegisterPeer(rp)) => {
-                    // B registered
-                    if !rp.id.is_empty() {
-                        log::trace!("New peer registered: {:?} {:?}", &rp.id, &addr);
-                        self.update_addr(rp.id, addr, socket).await?;
-                        if self.inner.serial > rp.serial {
-                            let mut msg_out = RendezvousMessage::new();
-                            msg_out.set_configure_update(ConfigUpdate {
-                                serial: self.inner.serial,
-                                rendezvous_servers: (*self.rendezvous_servers).clone(),
-                                ..Default::default()
-                            });
-                            socket.send(&msg_out, addr).await?;
-                        }
+        mirror::mirror(bytes);
+        if let Ok(mut msg_in) = RendezvousMessage::parse_from_bytes(bytes) {
+            id_norm::apply(&mut msg_in);
+            alias::apply(&mut msg_in);
+            if !dispatch::inbound(Transport::Udp, addr, bytes, &msg_in) {
+                return Ok(());
+            }
+            if let Some(delay) = latency::delay(&msg_in) {
+                // back through the queue, not to hold up the other peers
+                let tx = self.tx.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    tx.send(Data::Delayed(Box::new(msg_in), addr)).ok();
+                });
+                return Ok(());
+            }
+            return self.handle_udp_msg(msg_in, addr, socket).await;
+        }
+        Ok(())
+    }
+
+    async fn handle_udp_msg(
+        &mut self,
+        msg_in: RendezvousMessage,
+        addr: SocketAddr,
+        socket: &mut FramedSocket,
+    ) -> ResultType<()> {
+        match msg_in.union {
+            Some(rendezvous_message::Union::RegisterPeer(rp)) => {
+                // B registered
+                if !rp.id.is_empty() && !ban::refuses(&rp.id, addr) {
+                    log::trace!("New peer registered: {:?} {:?}", log_id::id(&rp.id), &addr);
+                    local_subnets::on_register(&rp.id, &rp);
+                    let mut msg_out = self.update_addr(rp.id, addr).await;
+                    udp_sign::apply(&mut msg_out);
+                    socket.send(&msg_out, addr).await?;
+                    if self.inner.serial > rp.serial {
+                        let mut msg_out = RendezvousMessage::new();
+                        msg_out.set_configure_update(ConfigUpdate {
+                            serial: self.inner.serial,
+                            rendezvous_servers: advertise::rendezvous_servers(
+                                Transport::Udp,
+                                &self.rendezvous_servers,
+                            ),
+                            ..Default::default()
+                        });
+                        socket.send(&msg_out, addr).await?;
                     }
                 }
-                Some(rendezvous_message::Union::RegisterPk(rk)) => {
-                    if rk.uuid.is_empty() || rk.pk.is_empty() {
-                        return Ok(());
-                    }
-                    let id = rk.id;
-                    let ip = addr.ip().to_string();
-                    if id.len() < 6 {
-                        return send_rk_res(socket, addr, UUID_MISMATCH).await;
-                    } else if !self.check_ip_blocker(&ip, &id).await {
-                        return send_rk_res(socket, addr, TOO_FREQUENT).await;
-                    }
-                    let peer = self.pm.get_or(&id).await;
-                    let (changed, ip_changed) = {
-                        let peer = peer.read().await;
-                        if peer.uuid.is_empty() {
-                            (true, false)
-                        } else {
-                            if peer.uuid == rk.uuid {
-                                if peer.info.ip != ip && peer.pk != rk.pk {
-                                    log::warn!(
-                                        "Peer {} ip/pk mismatch: {}/{:?} vs {}/{:?}",
-                                        id,
-                                        ip,
-                                        rk.pk,
-                                        peer.info.ip,
-                                        peer.pk,
-                                    );
-                                    drop(peer);
-                                    return send_rk_res(socket, addr, UUID_MISMATCH).await;
-                                }
-                            } else {
-                                log::warn!(
-                                    "Peer {} uuid mismatch: {:?} vs {:?}",
-                                    id,
-                                    rk.uuid,
-                                    peer.uuid
-                                );
-                                drop(peer);
-                                return send_rk_res(socket, addr, UUID_MISMATCH).await;
-                            }
-                            let ip_changed = peer.info.ip != ip;
-                            (
-                                peer.uuid != rk.uuid || peer.pk != rk.pk || ip_changed,
-                                ip_changed,
-                            )
-                        }
-                    };
-                    let mut req_pk = peer.read().await.reg_pk;
-                    if req_pk.1.elapsed().as_secs() > 6 {
-                        req_pk.0 = 0;
-                    } else if req_pk.0 > 2 {
-                        return send_rk_res(socket, addr, TOO_FREQUENT).await;
-                    }
-                    req_pk.0 += 1;
-                    req_pk.1 = Instant::now();
-                    peer.write().await.reg_pk = req_pk;
-                    if ip_changed {
-                        let mut lock = IP_CHANGES.lock().await;
-                        if let Some((tm, ips)) = lock.get_mut(&id) {
-                            if tm.elapsed().as_secs() > IP_CHANGE_DUR {
-                                *tm = Instant::now();
-                                ips.clear();
-                                ips.insert(ip.clone(), 1);
-                            } else if let Some(v) = ips.get_mut(&ip) {
-                                *v += 1;
-                            } else {
-                                ips.insert(ip.clone(), 1);
-                            }
-                        } else {
-                            lock.insert(
-                                id.clone(),
-                                (Instant::now(), HashMap::from([(ip.clone(), 1)])),
-                            );
-                        }
-                    }
-                    if changed {
-                        self.pm.update_pk(id, peer, addr, rk.uuid, rk.pk, ip).await;
-                    }
-                    let mut msg_out = RendezvousMessage::new();
-                    msg_out.set_register_pk_response(RegisterPkResponse {
-                        result: register_pk_response::Result::OK.into(),
-                        ..Default::default()
-                    });
-                    socket.send(&msg_out, addr).await?
+            }
+            Some(rendezvous_message::Union::RegisterPk(rk)) => {
+                if rk.uuid.is_empty() || rk.pk.is_empty() {
+                    return Ok(());
                 }
-                Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
-                    // UDP PunchHoleRequest is intentionally unsupported.
-                    // The supported client path sends PunchHoleRequest over TCP/WS.
+                let res = self.handle_register_pk(rk, addr).await;
+                return send_rk_res(socket, addr, res).await;
+            }
+            Some(rendezvous_message::Union::TestNatRequest(_)) if nat_test::is_on() => {
+                socket.send(&nat_test::answer(addr, false), addr).await?;
+            }
+            Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
+                // UDP PunchHoleRequest is intentionally unsupported.
+                // The supported client path sends PunchHoleRequest over TCP/WS.
+            }
+            Some(rendezvous_message::Union::PunchHoleSent(phs)) => {
+                // UDP PunchHoleSent is intentionally unsupported to avoid UDP reflection/amplification
+            }
+            Some(rendezvous_message::Union::LocalAddr(la)) => {
+                // UDP LocalAddr is intentionally unsupported to avoid UDP reflection/amplification
+            }
+            Some(rendezvous_message::Union::ConfigureUpdate(mut cu)) => {
+                if try_into_v4(addr).ip().is_loopback() && cu.serial > self.inner.serial {
+                    let mut inner: Inner = (*self.inner).clone();
+                    inner.serial = cu.serial;
+                    self.inner = Arc::new(inner);
+                    self.rendezvous_servers = Arc::new(
+                        cu.rendezvous_servers
+                            .drain(..)
+                            .filter(|x| {
+                                !x.is_empty()
+                                    && test_if_valid_server(x, "rendezvous-server").is_ok()
+                            })
+                            .collect(),
+                    );
+                    log::info!(
+                        "configure updated: serial={} rendezvous-servers={:?}",
+                        self.inner.serial,
+                        self.rendezvous_servers
+                    );
                 }
-                Some(rendezvous_message::Union::PunchHoleSent(phs)) => {
-                    // UDP PunchHoleSent is intentionally unsupported to avoid UDP reflection/amplification
+            }
+            Some(rendezvous_message::Union::PeerDiscovery(pd)) => {
+                if pd.cmd == auth_failures::CMD {
+                    self.handle_auth_failed(&pd.id, addr).await;
+                } else if pd.cmd == relay_report::CMD {
+                    relay_registry::on_report(&pd.misc, &pd.mac, addr);
+                } else if pd.cmd == keepalive::CMD {
+                    self.handle_keepalive_batch(&pd.misc, addr, socket).await?;
+                } else if pd.cmd == connection_log::CMD {
+                    self.handle_connection_log(&pd.id, &pd.misc, addr, socket).await?;
                 }
-                Some(rendezvous_message::Union::LocalAddr(la)) => {
-                    // UDP LocalAddr is intentionally unsupported to avoid UDP reflection/amplification
-                }
-                Some(rendezvous_message::Union::ConfigureUpdate(mut cu)) => {
-                    if try_into_v4(addr).ip().is_loopback() && cu.serial > self.inner.serial {
-                        let mut inner: Inner = (*self.inner).clone();
-                        inner.serial = cu.serial;
-                        self.inner = Arc::new(inner);
-                        self.rendezvous_servers = Arc::new(
-                            cu.rendezvous_servers
-                                .drain(..)
-                                .filter(|x| {
-                                    !x.is_empty()
-                                        && test_if_valid_server(x, "rendezvous-server").is_ok()
-                                })
-                                .collect(),
-                        );
-                        log::info!(
-                            "configure updated: serial={} rendezvous-servers={:?}",
-                            self.inner.serial,
-                            self.rendezvous_servers
-                        );
-                    }
+            }
+            Some(rendezvous_message::Union::SoftwareUpdate(su)) => {
+                if !self.inner.version.is_empty() && su.url != self.inner.version {
+                    let mut msg_out = RendezvousMessage::new();
+                    msg_out.set_software_update(SoftwareUpdate {
+                        url: self.inner.software_url.clone(),
+                        ..Default::default()
+                    });
+                    socket.send(&msg_out, addr).await?;
                 }
-                Some(rendezvous_message::Union::SoftwareUpdate(su)) => {
-                    if !self.inner.version.is_empty() && su.url != self.inner.version {
-                        let mut msg_out = RendezvousMessage::new();
-                        msg_out.set_software_update(SoftwareUpdate {
-                            url: self.inner.software_url.clone(),
-                            ..Default::default()
-                        });
-                        socket.send(&msg_out, addr).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // A gateway refreshes the devices behind its public IP, see keepalive.
+    async fn handle_keepalive_batch(
+        &mut self,
+        misc: &str,
+        addr: SocketAddr,
+        socket: &mut FramedSocket,
+    ) -> ResultType<()> {
+        let ids = keepalive::parse(misc);
+        let mut rejected = Vec::new();
+        for id in ids.iter() {
+            let refreshed = match self.pm.get_in_memory(id).await {
+                Some(peer) => {
+                    let mut peer = peer.write().await;
+                    // the pk is needed like on a registration
+                    if peer.socket_addr.ip() == addr.ip() && !peer.pk.is_empty() {
+                        peer.last_reg_time = Stamp::now();
+                        true
+                    } else {
+                        false
                     }
                 }
-                _ => {}
+                None => false,
+            };
+            if refreshed {
+                expiry::on_register(id, addr.ip());
+            } else {
+                rejected.push(*id);
             }
         }
-        Ok(())
+        keepalive::count(ids.len() - rejected.len(), rejected.len());
+        let mut msg_out = RendezvousMessage::new();
+        msg_out.set_peer_discovery(PeerDiscovery {
+            cmd: keepalive::CMD.to_owned(),
+            misc: rejected.join(","),
+            ..Default::default()
+        });
+        socket.send(&msg_out, addr).await
+    }
+
+    // A device asks who tried to connect to it, see connection_log.
+    async fn handle_connection_log(
+        &mut self,
+        id: &str,
+        misc: &str,
+        addr: SocketAddr,
+        socket: &mut FramedSocket,
+    ) -> ResultType<()> {
+        // a device asking is online, no database lookups for anyone's datagrams
+        let Some(peer) = self.pm.get_in_memory(id).await else {
+            return Ok(());
+        };
+        let pk = peer.read().await.pk.clone();
+        let mut msg_out = RendezvousMessage::new();
+        msg_out.set_peer_discovery(PeerDiscovery {
+            cmd: connection_log::CMD.to_owned(),
+            id: id.to_owned(),
+            misc: connection_log::answer(id, &pk, misc),
+            ..Default::default()
+        });
+        socket.send(&msg_out, addr).await
+    }
+
+    // Tell a device its incoming attempts are being refused, see suppressed.
+    async fn notify_suppressed(&self, id: &str, ip: &str, reason: Reason) {
+        let Some(peer) = self.pm.get_in_memory(id).await else {
+            return;
+        };
+        let Some(misc) = suppressed::on_refused(id, ip, reason) else {
+            return;
+        };
+        let peer_addr = peer.read().await.socket_addr;
+        let mut msg_out = RendezvousMessage::new();
+        msg_out.set_peer_discovery(PeerDiscovery {
+            cmd: suppressed::CMD.to_owned(),
+            id: id.to_owned(),
+            misc,
+            ..Default::default()
+        });
+        self.tx.send(Data::Msg(msg_out.into(), peer_addr)).ok();
+    }
+
+    // A device reports a failed password authentication, blame whoever
+    // requested a connection to it last.
+    async fn handle_auth_failed(&self, id: &str, addr: SocketAddr) {
+        // only the device itself, from its registered address
+        let Some(peer) = self.pm.get_in_memory(id).await else {
+            return;
+        };
+        let registered = {
+            let peer = peer.read().await;
+            peer.socket_addr == addr || peer.socket_addr_v6.is_some_and(|x| x.0 == addr)
+        };
+        if !registered {
+            return;
+        }
+        let from_ip = PUNCH_REQS
+            .lock()
+            .await
+            .iter()
+            .rev()
+            .find(|e| e.to_id == id && e.tm.elapsed().as_secs() < auth_failures::REPORT_DUR)
+            .map(|e| (e.from_ip.clone(), e.tm));
+        if let Some((ip, request)) = from_ip {
+            log::debug!("{} reported failed authentication from {}", log_id::id(id), ip);
+            auth_failures::report(&ip, id, request);
+        }
     }
 
     #[inline]
@@ -486,29 +912,70 @@ impl RendezvousServer {
         bytes: &[u8],
         sink: &mut Option<Sink>,
         addr: SocketAddr,
+        token: u64,
         key: &str,
         ws: bool,
     ) -> bool {
-        if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(bytes) {
+        if let Ok(mut msg_in) = RendezvousMessage::parse_from_bytes(bytes) {
+            id_norm::apply(&mut msg_in);
+            alias::apply(&mut msg_in);
+            let transport = if ws { Transport::Ws } else { Transport::Tcp };
+            if !dispatch::inbound(transport, addr, bytes, &msg_in) {
+                return true;
+            }
+            // only this connection's task waits
+            if let Some(delay) = latency::delay(&msg_in) {
+                tokio::time::sleep(delay).await;
+            }
             match msg_in.union {
                 Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
                     // there maybe several attempt, so sink can be none
                     if let Some(sink) = sink.take() {
-                        self.tcp_punch.lock().await.insert(try_into_v4(addr), sink);
+                        self.add_tcp_session(addr, token, sink).await;
                     }
-                    allow_err!(self.handle_tcp_punch_hole_request(addr, ph, key, ws).await);
+                    errors::check(
+                        Site::PunchHole,
+                        self.handle_tcp_punch_hole_request(addr, ph, key, ws).await,
+                    );
                     return true;
                 }
                 Some(rendezvous_message::Union::RequestRelay(mut rf)) => {
+                    if strict::is_on() && !self.inner.keys.is_accepted(key, &rf.licence_key) {
+                        log::warn!(
+                            "Relay request from {} for peer {} without the key",
+                            addr,
+                            log_id::id(&rf.id)
+                        );
+                        return true;
+                    }
                     // there maybe several attempt, so sink can be none
                     if let Some(sink) = sink.take() {
-                        self.tcp_punch.lock().await.insert(try_into_v4(addr), sink);
+                        self.add_tcp_session(addr, token, sink).await;
                     }
+                    punch_stats::on_relay(addr, &rf.id);
+                    punch_timeout::on_done(addr);
+                    log::debug!(
+                        "{}Relay request for {:?} from {:?}",
+                        trace::get(addr),
+                        log_id::id(&rf.id),
+                        addr
+                    );
+                    connection_log::record(
+                        &rf.id,
+                        &try_into_v4(addr).ip().to_string(),
+                        Outcome::Relayed,
+                    );
                     if let Some(peer) = self.pm.get_in_memory(&rf.id).await {
                         let mut msg_out = RendezvousMessage::new();
                         rf.socket_addr = AddrMangle::encode(addr).into();
-                        msg_out.set_request_relay(rf);
                         let peer_addr = peer.read().await.socket_addr;
+                        if let Some(relay) = relay_pin::get(&rf.id, &[peer_addr.ip(), addr.ip()]) {
+                            rf.relay_server = relay;
+                        }
+                        if let Some(relay) = relay_registry::handoff(&rf.relay_server) {
+                            rf.relay_server = relay;
+                        }
+                        msg_out.set_request_relay(rf);
                         self.tx.send(Data::Msg(msg_out.into(), peer_addr)).ok();
                     }
                     return true;
@@ -518,7 +985,7 @@ impl RendezvousServer {
                     rr.socket_addr = Default::default();
                     let id = rr.id();
                     if !id.is_empty() {
-                        let pk = self.get_pk(&rr.version, id.to_owned()).await;
+                        let pk = self.get_pk(&rr.version, id.to_owned(), addr_b).await;
                         rr.set_pk(pk);
                     }
                     let mut msg_out = RendezvousMessage::new();
@@ -527,17 +994,29 @@ impl RendezvousServer {
                             // https://github.com/rustdesk/rustdesk-server/issues/24
                             rr.relay_server = self.inner.local_ip.clone();
                         } else if rr.relay_server == self.inner.local_ip {
-                            rr.relay_server = self.get_relay_server(addr.ip(), addr_b.ip());
+                            rr.relay_server = self.get_relay_server(id, addr_b.ip(), addr.ip());
+                        } else if let Some(relay) = relay_registry::handoff(&rr.relay_server) {
+                            rr.relay_server = relay;
                         }
                     }
                     msg_out.set_relay_response(rr);
-                    allow_err!(self.send_to_tcp_sync(msg_out, addr_b).await);
+                    errors::check(Site::Forward, self.send_to_tcp_sync(msg_out, addr_b).await);
                 }
                 Some(rendezvous_message::Union::PunchHoleSent(phs)) => {
-                    allow_err!(self.handle_hole_sent(phs, addr, None).await);
+                    errors::check(Site::Forward, self.handle_hole_sent(phs, addr, None).await);
+                }
+                Some(rendezvous_message::Union::OnlineRequest(or)) => {
+                    // subscription, resent by the client to keep the connection
+                    // open and to change the ids
+                    if let Some(peers) = presence::subscribe(token, or.peers) {
+                        if let Some(sink) = sink.take() {
+                            tokio::spawn(Self::push_presence(self.pm.clone(), sink, peers));
+                        }
+                    }
+                    return true;
                 }
                 Some(rendezvous_message::Union::LocalAddr(la)) => {
-                    allow_err!(self.handle_local_addr(la, addr, None).await);
+                    errors::check(Site::Forward, self.handle_local_addr(la, addr, None).await);
                 }
                 Some(rendezvous_message::Union::TestNatRequest(tar)) => {
                     let mut msg_out = RendezvousMessage::new();
@@ -548,20 +1027,44 @@ impl RendezvousServer {
                     if self.inner.serial > tar.serial {
                         let mut cu = ConfigUpdate::new();
                         cu.serial = self.inner.serial;
-                        cu.rendezvous_servers = (*self.rendezvous_servers).clone();
+                        cu.rendezvous_servers =
+                            advertise::rendezvous_servers(transport, &self.rendezvous_servers);
                         res.cu = MessageField::from_option(Some(cu));
                     }
                     msg_out.set_test_nat_response(res);
                     Self::send_to_sink(sink, msg_out).await;
                 }
-                Some(rendezvous_message::Union::RegisterPk(_)) => {
-                    let res = register_pk_response::Result::NOT_SUPPORT;
+                Some(rendezvous_message::Union::RegisterPeer(rp)) => {
+                    if rp.id.is_empty() || ban::refuses(&rp.id, addr) {
+                        return true;
+                    }
+                    local_subnets::on_register(&rp.id, &rp);
+                    if let Some(sink) = sink.take() {
+                        self.add_tcp_peer(addr, &rp.id, token, sink).await;
+                    }
+                    // not if the connection already waits for a punch hole answer
+                    if self.is_tcp_peer(addr, token).await {
+                        let msg_out = self.update_addr(rp.id, addr).await;
+                        self.send_to_tcp_peer(msg_out, addr).await;
+                    }
+                }
+                Some(rendezvous_message::Union::RegisterPk(rk)) => {
+                    // only for peers registered over tcp, see RegisterPeer
+                    let res = if !self.is_tcp_peer(addr, token).await {
+                        register_pk_response::Result::NOT_SUPPORT
+                    } else if rk.uuid.is_empty() || rk.pk.is_empty() {
+                        return true;
+                    } else {
+                        self.handle_register_pk(rk, addr).await
+                    };
                     let mut msg_out = RendezvousMessage::new();
                     msg_out.set_register_pk_response(RegisterPkResponse {
                         result: res.into(),
                         ..Default::default()
                     });
-                    Self::send_to_sink(sink, msg_out).await;
+                    if let Some(msg_out) = self.send_to_tcp_peer(msg_out, addr).await {
+                        Self::send_to_sink(sink, msg_out).await;
+                    }
                 }
                 _ => {}
             }
@@ -569,15 +1072,128 @@ impl RendezvousServer {
         false
     }
 
-    #[inline]
-    async fn update_addr(
+    async fn handle_register_pk(
         &mut self,
-        id: String,
-        socket_addr: SocketAddr,
-        socket: &mut FramedSocket,
-    ) -> ResultType<()> {
+        rk: RegisterPk,
+        addr: SocketAddr,
+    ) -> register_pk_response::Result {
+        if !registered::has_token(&rk) {
+            log::warn!("{} from {} refused, no registration token", log_id::id(&rk.id), addr);
+            return NOT_SUPPORT;
+        }
+        let id = rk.id;
+        let ip = addr.ip().to_string();
+        if id.len() < 6 {
+            return UUID_MISMATCH;
+        } else if !self.check_ip_blocker(&ip, &id).await
+            && dry_run::enforce(Rule::IpBlocker, &ip)
+        {
+            return TOO_FREQUENT;
+        } else if ban::refuses(&id, addr) {
+            log::warn!("{} from {} refused, banned", log_id::id(&id), ip);
+            return TOO_FREQUENT;
+        } else if tombstone::is_blocked(&id) {
+            log::warn!("{} from {} refused, deleted recently", log_id::id(&id), ip);
+            return TOO_FREQUENT;
+        } else if !pk_attest::verify(&id, &rk.pk) {
+            return NOT_SUPPORT;
+        }
+        let peer = self.pm.get_or(&id).await;
+        let other_family = peer.read().await.is_other_family(addr, expiry::reg_timeout());
+        let (changed, ip_changed) = {
+            let peer = peer.read().await;
+            if other_family {
+                // the IPv6 address of a dual-stack device, only with its key
+                if peer.uuid != rk.uuid || peer.pk != rk.pk {
+                    log::warn!("Peer {} IPv6 uuid/pk mismatch from {}", log_id::id(&id), ip);
+                    return UUID_MISMATCH;
+                }
+                (false, false)
+            } else if peer.uuid.is_empty() {
+                (true, false)
+            } else {
+                if peer.uuid == rk.uuid {
+                    if peer.info.ip != ip && peer.pk != rk.pk {
+                        log::warn!(
+                            "Peer {} ip/pk mismatch: {}/{:?} vs {}/{:?}",
+                            log_id::id(&id),
+                            ip,
+                            rk.pk,
+                            peer.info.ip,
+                            peer.pk,
+                        );
+                        drop(peer);
+                        webhook::emit(Event::PkMismatch, &id, &ip, "ip and key changed");
+                        return UUID_MISMATCH;
+                    }
+                } else if !pk_grace::accepts(
+                    &id,
+                    &ip,
+                    &peer.info.ip,
+                    peer.last_reg_time.elapsed_ms(),
+                ) {
+                    log::warn!(
+                        "Peer {} uuid mismatch: {:?} vs {:?}",
+                        log_id::id(&id),
+                        rk.uuid,
+                        peer.uuid
+                    );
+                    drop(peer);
+                    webhook::emit(Event::PkMismatch, &id, &ip, "uuid changed");
+                    return UUID_MISMATCH;
+                }
+                let ip_changed = peer.info.ip != ip;
+                (peer.uuid != rk.uuid || peer.pk != rk.pk || ip_changed, ip_changed)
+            }
+        };
+        let mut req_pk = peer.read().await.reg_pk;
+        if req_pk.1.elapsed().as_secs() > 6 {
+            req_pk.0 = 0;
+        } else if req_pk.0 > 2 {
+            return TOO_FREQUENT;
+        }
+        req_pk.0 += 1;
+        req_pk.1 = Instant::now();
+        peer.write().await.reg_pk = req_pk;
+        if ip_changed {
+            let mut lock = IP_CHANGES.lock().await;
+            if let Some((tm, ips)) = lock.get_mut(&id) {
+                if tm.elapsed().as_secs() > IP_CHANGE_DUR {
+                    *tm = Instant::now();
+                    ips.clear();
+                    ips.insert(ip.clone(), 1);
+                } else if let Some(v) = ips.get_mut(&ip) {
+                    *v += 1;
+                } else {
+                    ips.insert(ip.clone(), 1);
+                }
+            } else {
+                lock.insert(id.clone(), (Instant::now(), HashMap::from([(ip.clone(), 1)])));
+            }
+        }
+        if changed {
+            self.pm.update_pk(id, peer, addr, rk.uuid, rk.pk, ip).await;
+        } else if other_family {
+            peer.write().await.socket_addr_v6 = Some((addr, Stamp::now()));
+        }
+        register_pk_response::Result::OK
+    }
+
+    // Returns the answer for the peer.
+    #[inline]
+    async fn update_addr(&mut self, id: String, socket_addr: SocketAddr) -> RendezvousMessage {
         let (request_pk, ip_change) = if let Some(old) = self.pm.get_in_memory(&id).await {
             let mut old = old.write().await;
+            if old.is_other_family(socket_addr, expiry::reg_timeout()) {
+                // a dual-stack device registering over its other family, like
+                // an ip change it has to send its key from there first, see
+                // handle_register_pk
+                let known = old.socket_addr_v6.is_some_and(|x| x.0 == socket_addr);
+                if known {
+                    old.socket_addr_v6 = Some((socket_addr, Stamp::now()));
+                }
+                return Self::register_peer_response(!known);
+            }
             let ip = socket_addr.ip();
             let ip_change = if old.socket_addr.port() != 0 {
                 ip != old.socket_addr.ip()
@@ -587,7 +1203,10 @@ impl RendezvousServer {
             let request_pk = old.pk.is_empty() || ip_change;
             if !request_pk {
                 old.socket_addr = socket_addr;
-                old.last_reg_time = Instant::now();
+                old.last_reg_time = Stamp::now();
+                expiry::on_register(&id, socket_addr.ip());
+                peer_stats::on_register(&id);
+                cluster::on_register(&id, socket_addr, &old.pk);
             }
             let ip_change = if ip_change && old.reg_pk.0 <= 2 {
                 Some(if old.socket_addr.port() == 0 {
@@ -603,14 +1222,19 @@ impl RendezvousServer {
             (true, None)
         };
         if let Some(old) = ip_change {
-            log::info!("IP change of {} from {} to {}", id, old, socket_addr);
+            log::info!("IP change of {} from {} to {}", log_id::id(&id), old, socket_addr);
         }
+        Self::register_peer_response(request_pk)
+    }
+
+    #[inline]
+    fn register_peer_response(request_pk: bool) -> RendezvousMessage {
         let mut msg_out = RendezvousMessage::new();
         msg_out.set_register_peer_response(RegisterPeerResponse {
             request_pk,
             ..Default::default()
         });
-        socket.send(&msg_out, socket_addr).await
+        msg_out
     }
 
     #[inline]
@@ -622,16 +1246,28 @@ impl RendezvousServer {
     ) -> ResultType<()> {
         // punch hole sent from B, tell A that B is ready to be connected
         let addr_a = AddrMangle::decode(&phs.socket_addr);
+        let trace = trace::finish(addr_a);
         log::debug!(
-            "{} punch hole response to {:?} from {:?}",
+            "{}{} punch hole response to {:?} from {:?}",
+            trace,
             if socket.is_none() { "TCP" } else { "UDP" },
             &addr_a,
             &addr
         );
+        // B registered over udp, a tcp answer tells if its NAT kept the port
+        let port_preserving = match (&socket, self.pm.get_in_memory(&phs.id).await) {
+            (None, Some(peer)) => {
+                let reg_addr = peer.read().await.socket_addr;
+                (reg_addr.ip() == addr.ip()).then_some(reg_addr.port() == addr.port())
+            }
+            _ => None,
+        };
+        punch_stats::on_answer(addr_a, phs.nat_type.enum_value_or_default(), port_preserving);
+        punch_timeout::on_done(addr_a);
         let mut msg_out = RendezvousMessage::new();
         let mut p = PunchHoleResponse {
             socket_addr: AddrMangle::encode(addr).into(),
-            pk: self.get_pk(&phs.version, phs.id).await,
+            pk: self.get_pk(&phs.version, phs.id, addr_a).await,
             relay_server: phs.relay_server.clone(),
             ..Default::default()
         };
@@ -640,6 +1276,7 @@ impl RendezvousServer {
         }
         msg_out.set_punch_hole_response(p);
         if let Some(socket) = socket {
+            udp_sign::apply(&mut msg_out);
             socket.send(&msg_out, addr_a).await?;
         } else {
             self.send_to_tcp(msg_out, addr_a).await;
@@ -656,22 +1293,27 @@ impl RendezvousServer {
     ) -> ResultType<()> {
         // relay local addrs of B to A
         let addr_a = AddrMangle::decode(&la.socket_addr);
+        let trace = trace::finish(addr_a);
         log::debug!(
-            "{} local addrs response to {:?} from {:?}",
+            "{}{} local addrs response to {:?} from {:?}",
+            trace,
             if socket.is_none() { "TCP" } else { "UDP" },
             &addr_a,
             &addr
         );
+        punch_stats::on_answer(addr_a, NatType::UNKNOWN_NAT, None);
+        punch_timeout::on_done(addr_a);
         let mut msg_out = RendezvousMessage::new();
         let mut p = PunchHoleResponse {
             socket_addr: la.local_addr.clone(),
-            pk: self.get_pk(&la.version, la.id).await,
+            pk: self.get_pk(&la.version, la.id, addr_a).await,
             relay_server: la.relay_server,
             ..Default::default()
         };
         p.set_is_local(true);
         msg_out.set_punch_hole_response(p);
         if let Some(socket) = socket {
+            udp_sign::apply(&mut msg_out);
             socket.send(&msg_out, addr_a).await?;
         } else {
             self.send_to_tcp(msg_out, addr_a).await;
@@ -688,27 +1330,113 @@ impl RendezvousServer {
         ws: bool,
     ) -> ResultType<(RendezvousMessage, Option<SocketAddr>)> {
         let mut ph = ph;
-        if !key.is_empty() && ph.licence_key != key {
-            log::warn!("Authentication failed from {} for peer {} - invalid key", addr, ph.id);
+        let subnets = local_subnets::of(&ph);
+        if ban::refuses("", addr) {
+            let ip = try_into_v4(addr).ip().to_string();
+            connection_log::record(&ph.id, &ip, Outcome::Refused);
+            last_error::record(&ph.id, &ip, refusal::name(Reason::Banned));
+            let mut msg_out = RendezvousMessage::new();
+            msg_out.set_punch_hole_response(PunchHoleResponse {
+                other_failure: refusal::message(
+                    Reason::Banned,
+                    "This address is banned from the server",
+                ),
+                ..Default::default()
+            });
+            return Ok((msg_out, None));
+        }
+        let banned = auth_failures::is_banned(&try_into_v4(addr).ip().to_string())
+            .filter(|_| dry_run::enforce(Rule::Ban, addr));
+        if let Some(minutes) = banned {
+            let ip = try_into_v4(addr).ip().to_string();
+            connection_log::record(&ph.id, &ip, Outcome::Refused);
+            last_error::record(&ph.id, &ip, refusal::name(Reason::Ban));
+            self.notify_suppressed(&ph.id, &ip, Reason::Ban).await;
+            let mut msg_out = RendezvousMessage::new();
+            msg_out.set_punch_hole_response(PunchHoleResponse {
+                other_failure: refusal::message(
+                    Reason::Ban,
+                    &format!(
+                        "Too many failed authentications, please retry in {} minutes",
+                        minutes
+                    ),
+                ),
+                ..Default::default()
+            });
+            return Ok((msg_out, None));
+        }
+        let shed = load_shed::sheds(try_into_v4(addr).ip(), || {
+            self.inner.keys.is_priority(&ph.licence_key)
+        });
+        if shed && dry_run::enforce(Rule::LoadShed, addr) {
+            let ip = try_into_v4(addr).ip().to_string();
+            connection_log::record(&ph.id, &ip, Outcome::Refused);
+            last_error::record(&ph.id, &ip, refusal::name(Reason::Busy));
+            let mut msg_out = RendezvousMessage::new();
+            msg_out.set_punch_hole_response(PunchHoleResponse {
+                other_failure: refusal::message(Reason::Busy, &load_shed::on_shed()),
+                ..Default::default()
+            });
+            return Ok((msg_out, None));
+        }
+        if !registered::is_registered(try_into_v4(addr).ip())
+            && dry_run::enforce(Rule::Unregistered, addr)
+        {
+            let ip = try_into_v4(addr).ip().to_string();
+            connection_log::record(&ph.id, &ip, Outcome::Refused);
+            last_error::record(&ph.id, &ip, refusal::name(Reason::Unregistered));
             let mut msg_out = RendezvousMessage::new();
             msg_out.set_punch_hole_response(PunchHoleResponse {
-                failure: punch_hole_response::Failure::LICENSE_MISMATCH.into(),
+                other_failure: refusal::message(
+                    Reason::Unregistered,
+                    "This device isn't registered with the server",
+                ),
                 ..Default::default()
             });
             return Ok((msg_out, None));
         }
+        let ip = try_into_v4(addr).ip().to_string();
+        let key_always_use_relay = match self.inner.keys.check(key, &ph.licence_key, &ip) {
+            Ok(entry) => entry.always_use_relay,
+            Err(failure) => {
+                log::warn!(
+                    "Authentication failed from {} for peer {} - {:?}",
+                    addr,
+                    log_id::id(&ph.id),
+                    failure
+                );
+                connection_log::record(&ph.id, &ip, Outcome::Refused);
+                let reason = if failure == punch_hole_response::Failure::LICENSE_OVERUSE {
+                    Reason::Quota
+                } else {
+                    Reason::Key
+                };
+                last_error::record(&ph.id, &ip, refusal::name(reason));
+                let mut msg_out = RendezvousMessage::new();
+                msg_out.set_punch_hole_response(PunchHoleResponse {
+                    failure: failure.into(),
+                    // clients show it instead of their own text for the failure
+                    other_failure: refusal::message(reason, ""),
+                    ..Default::default()
+                });
+                return Ok((msg_out, None));
+            }
+        };
         let id = ph.id;
+        let nat_a = ph.nat_type.enum_value_or_default();
         // punch hole request from A, relay to B,
         // check if in same intranet first,
         // fetch local addrs if in same intranet.
         // because punch hole won't work if in the same intranet,
         // all routers will drop such self-connections.
+        let cohort = Cohort::of(&id);
+        cohort.on_punch_request();
         if let Some(peer) = self.pm.get(&id).await {
-            let (elapsed, peer_addr) = {
-                let r = peer.read().await;
-                (r.last_reg_time.elapsed().as_millis() as i64, r.socket_addr)
-            };
-            if elapsed >= REG_TIMEOUT {
+            let peer_addr = peer.read().await.punch_addr(addr, expiry::reg_timeout());
+            if !expiry::is_online(&id) {
+                cohort.on_offline();
+                connection_log::record(&id, &ip, Outcome::Offline);
+                last_error::record(&id, &ip, "OFFLINE");
                 let mut msg_out = RendezvousMessage::new();
                 msg_out.set_punch_hole_response(PunchHoleResponse {
                     failure: punch_hole_response::Failure::OFFLINE.into(),
@@ -716,6 +1444,30 @@ impl RendezvousServer {
                 });
                 return Ok((msg_out, None));
             }
+            let cooldown =
+                cooldown::on_attempt(&id).filter(|_| dry_run::enforce(Rule::Cooldown, &id));
+            if let Some(minutes) = cooldown {
+                connection_log::record(&id, &ip, Outcome::Refused);
+                last_error::record(&id, &ip, refusal::name(Reason::Cooldown));
+                self.notify_suppressed(&id, &ip, Reason::Cooldown).await;
+                log::warn!(
+                    "Punch hole request for {} from {} refused, cooling down",
+                    log_id::id(&id),
+                    addr
+                );
+                let mut msg_out = RendezvousMessage::new();
+                msg_out.set_punch_hole_response(PunchHoleResponse {
+                    other_failure: refusal::message(
+                        Reason::Cooldown,
+                        &format!(
+                            "Too many connection attempts, please retry in {} minutes",
+                            minutes
+                        ),
+                    ),
+                    ..Default::default()
+                });
+                return Ok((msg_out, None));
+            }
             
             // record punch hole request (from addr -> peer id/peer_addr)
             {
@@ -736,8 +1488,11 @@ impl RendezvousServer {
             let mut msg_out = RendezvousMessage::new();
             let peer_is_lan = self.is_lan(peer_addr);
             let is_lan = self.is_lan(addr);
-            let mut relay_server = self.get_relay_server(addr.ip(), peer_addr.ip());
-            if ALWAYS_USE_RELAY.load(Ordering::SeqCst) || (peer_is_lan ^ is_lan) {
+            let mut relay_server = self.get_relay_server(&id, addr.ip(), peer_addr.ip());
+            if ALWAYS_USE_RELAY.load(Ordering::SeqCst)
+                || key_always_use_relay
+                || (peer_is_lan ^ is_lan)
+            {
                 if peer_is_lan {
                     // https://github.com/rustdesk/rustdesk-server/issues/24
                     relay_server = self.inner.local_ip.clone()
@@ -745,18 +1500,18 @@ impl RendezvousServer {
                 ph.nat_type = NatType::SYMMETRIC.into(); // will force relay
             }
             let same_intranet: bool = !ws
-                && (peer_is_lan && is_lan || {
-                    match (peer_addr, addr) {
-                        (SocketAddr::V4(a), SocketAddr::V4(b)) => a.ip() == b.ip(),
-                        (SocketAddr::V6(a), SocketAddr::V6(b)) => a.ip() == b.ip(),
-                        _ => false,
-                    }
-                });
+                && (peer_is_lan && is_lan
+                    || local_subnets::same_network(addr, subnets.as_deref(), peer_addr, &id));
+            punch_stats::on_request(addr, &id, nat_a, same_intranet);
+            punch_timeout::on_forward(addr, &id);
+            let trace = trace::start(addr);
+            connection_log::record(&id, &ip, Outcome::Forwarded);
             let socket_addr = AddrMangle::encode(addr).into();
             if same_intranet {
                 log::debug!(
-                    "Fetch local addr {:?} {:?} request from {:?}",
-                    id,
+                    "{}Fetch local addr {:?} {:?} request from {:?}",
+                    trace,
+                    log_id::id(&id),
                     peer_addr,
                     addr
                 );
@@ -767,8 +1522,9 @@ impl RendezvousServer {
                 });
             } else {
                 log::debug!(
-                    "Punch hole {:?} {:?} request from {:?}",
-                    id,
+                    "{}Punch hole {:?} {:?} request from {:?}",
+                    trace,
+                    log_id::id(&id),
                     peer_addr,
                     addr
                 );
@@ -782,8 +1538,21 @@ impl RendezvousServer {
             Ok((msg_out, Some(peer_addr)))
         } else {
             let mut msg_out = RendezvousMessage::new();
+            let (failure, other_failure) = match federation::lookup(&id).await {
+                Some(upstream) => (
+                    punch_hole_response::Failure::ID_NOT_EXIST,
+                    federation::redirect(&id, &upstream),
+                ),
+                // the same answer as for an offline id, against enumeration
+                None if self.inner.hide_id_existence => {
+                    (punch_hole_response::Failure::OFFLINE, "".to_owned())
+                }
+                None => (punch_hole_response::Failure::ID_NOT_EXIST, "".to_owned()),
+            };
+            last_error::record(&id, &ip, "ID_NOT_EXIST");
             msg_out.set_punch_hole_response(PunchHoleResponse {
-                failure: punch_hole_response::Failure::ID_NOT_EXIST.into(),
+                failure: failure.into(),
+                other_failure,
                 ..Default::default()
             });
             Ok((msg_out, None))
@@ -796,19 +1565,7 @@ impl RendezvousServer {
         stream: &mut FramedStream,
         peers: Vec<String>,
     ) -> ResultType<()> {
-        let mut states = BytesMut::zeroed((peers.len() + 7) / 8);
-        for (i, peer_id) in peers.iter().enumerate() {
-            if let Some(peer) = self.pm.get_in_memory(peer_id).await {
-                let elapsed = peer.read().await.last_reg_time.elapsed().as_millis() as i64;
-                // bytes index from left to right
-                let states_idx = i / 8;
-                let bit_idx = 7 - i % 8;
-                if elapsed < REG_TIMEOUT {
-                    states[states_idx] |= 0x01 << bit_idx;
-                }
-            }
-        }
-
+        let states = Self::get_online_states(&self.pm, &peers).await;
         let mut msg_out = RendezvousMessage::new();
         msg_out.set_online_response(OnlineResponse {
             states: states.into(),
@@ -820,10 +1577,137 @@ impl RendezvousServer {
     }
 
     #[inline]
-    async fn send_to_tcp(&mut self, msg: RendezvousMessage, addr: SocketAddr) {
-        let mut tcp = self.tcp_punch.lock().await.remove(&try_into_v4(addr));
-        tokio::spawn(async move {
-            Self::send_to_sink(&mut tcp, msg).await;
+    async fn get_online_states(pm: &PeerMap, peers: &[String]) -> BytesMut {
+        let n = peers.len().min(MAX_ONLINE_PEERS);
+        let mut states = pm
+            .get_online_states(&peers[..n], |id, _| expiry::is_online(id))
+            .await;
+        states.resize((peers.len() + 7) / 8, 0);
+        states
+    }
+
+    // Push the online states of the subscribed ids whenever they change, until
+    // the connection closes. Woken by expiry on any online/offline transition,
+    // at most every PUSH_INTERVAL.
+    async fn push_presence(pm: PeerMap, sink: Sink, mut peers: watch::Receiver<Vec<String>>) {
+        let mut sink = Some(sink);
+        let mut changes = expiry::subscribe();
+        let mut last = None;
+        loop {
+            tokio::select! {
+                res = changes.changed() => {
+                    if res.is_err() {
+                        break;
+                    }
+                }
+                res = peers.changed() => {
+                    if res.is_err() {
+                        break;
+                    }
+                    // new ids, always answer
+                    last = None;
+                }
+            }
+            let ids = peers.borrow_and_update().clone();
+            let states = Self::get_online_states(&pm, &ids).await;
+            if last.as_ref() != Some(&states) {
+                let mut msg_out = RendezvousMessage::new();
+                msg_out.set_online_response(OnlineResponse {
+                    states: states.clone().into(),
+                    ..Default::default()
+                });
+                Self::send_to_sink(&mut sink, msg_out).await;
+                last = Some(states);
+                tokio::time::sleep(Duration::from_millis(presence::PUSH_INTERVAL)).await;
+            }
+        }
+    }
+
+    async fn add_tcp_session(&self, addr: SocketAddr, token: u64, sink: Sink) {
+        let addr = try_into_v4(addr);
+        let old = self
+            .tcp_punch
+            .lock()
+            .await
+            .insert(addr, TcpSession { token, sink });
+        if let Some(old) = old {
+            if old.token != token {
+                log::debug!("Tcp session {} of {} replaced by {}", old.token, addr, token);
+            }
+        }
+    }
+
+    // Only the connection which added the session may remove it on close,
+    // a newer connection from the same address may have replaced it meanwhile.
+    async fn remove_tcp_session(&self, addr: SocketAddr, token: u64) {
+        let addr = try_into_v4(addr);
+        let mut lock = self.tcp_punch.lock().await;
+        if lock.get(&addr).is_some_and(|x| x.token == token) {
+            lock.remove(&addr);
+        }
+    }
+
+    async fn add_tcp_peer(&self, addr: SocketAddr, id: &str, token: u64, sink: Sink) {
+        let (tx, mut rx) = mpsc::channel(TCP_PEER_QUEUE);
+        tokio::spawn(async move {
+            let mut sink = Some(sink);
+            while let Some(msg) = rx.recv().await {
+                Self::send_to_sink(&mut sink, msg).await;
+            }
+        });
+        log::debug!("{} registered over tcp from {}", log_id::id(id), addr);
+        let peer = TcpPeer {
+            id: id.to_owned(),
+            token,
+            tx,
+        };
+        self.tcp_peers.lock().await.insert(try_into_v4(addr), peer);
+    }
+
+    #[inline]
+    async fn is_tcp_peer(&self, addr: SocketAddr, token: u64) -> bool {
+        let lock = self.tcp_peers.lock().await;
+        lock.get(&try_into_v4(addr)).is_some_and(|x| x.token == token)
+    }
+
+    // Like remove_tcp_session, also ends the peer's writer task.
+    async fn remove_tcp_peer(&self, addr: SocketAddr, token: u64) {
+        let addr = try_into_v4(addr);
+        let mut lock = self.tcp_peers.lock().await;
+        if lock.get(&addr).is_some_and(|x| x.token == token) {
+            lock.remove(&addr);
+        }
+    }
+
+    // Returns the message back if `addr` isn't a peer registered over tcp.
+    async fn send_to_tcp_peer(
+        &self,
+        msg: RendezvousMessage,
+        addr: SocketAddr,
+    ) -> Option<RendezvousMessage> {
+        match self.tcp_peers.lock().await.get(&try_into_v4(addr)) {
+            Some(peer) => {
+                peer.tx.try_send(msg).ok();
+                None
+            }
+            None => Some(msg),
+        }
+    }
+
+    #[inline]
+    async fn send_to_tcp(&mut self, msg: RendezvousMessage, addr: SocketAddr) {
+        let mut tcp = self
+            .tcp_punch
+            .lock()
+            .await
+            .remove(&try_into_v4(addr))
+            .map(|x| x.sink);
+        if tcp.is_none() {
+            cluster::broadcast_session(&msg, addr);
+            return;
+        }
+        tokio::spawn(async move {
+            Self::send_to_sink(&mut tcp, msg).await;
         });
     }
 
@@ -833,10 +1717,19 @@ impl RendezvousServer {
             if let Ok(bytes) = msg.write_to_bytes() {
                 match sink {
                     Sink::TcpStream(s) => {
-                        allow_err!(s.send(Bytes::from(bytes)).await);
+                        errors::check(Site::Reply, s.send(Bytes::from(bytes)).await);
                     }
                     Sink::Ws(ws) => {
-                        allow_err!(ws.send(tungstenite::Message::Binary(bytes)).await);
+                        errors::check(
+                            Site::Reply,
+                            ws.send(tungstenite::Message::Binary(bytes)).await,
+                        );
+                    }
+                    Sink::Json(s) => {
+                        errors::check(Site::Reply, s.send(json_wire::encode(&msg)).await);
+                    }
+                    Sink::Dtls(conn) => {
+                        errors::check(Site::Reply, conn.send(&bytes).await);
                     }
                 }
             }
@@ -849,7 +1742,16 @@ impl RendezvousServer {
         msg: RendezvousMessage,
         addr: SocketAddr,
     ) -> ResultType<()> {
-        let mut sink = self.tcp_punch.lock().await.remove(&try_into_v4(addr));
+        let mut sink = self
+            .tcp_punch
+            .lock()
+            .await
+            .remove(&try_into_v4(addr))
+            .map(|x| x.sink);
+        if sink.is_none() {
+            cluster::broadcast_session(&msg, addr);
+            return Ok(());
+        }
         Self::send_to_sink(&mut sink, msg).await;
         Ok(())
     }
@@ -919,18 +1821,92 @@ impl RendezvousServer {
         true
     }
 
+    async fn check_memory_budget(&self, queued: usize) {
+        if memory_budget::budget() == 0 {
+            return;
+        }
+        let usage = memory_budget::Usage {
+            peers: self.pm.len().await,
+            sinks: self.tcp_punch.lock().await.len() + self.tcp_peers.lock().await.len(),
+            queued,
+        };
+        let n = memory_budget::update(usage);
+        if n > 0 {
+            let pm = self.pm.clone();
+            tokio::spawn(async move {
+                let n = pm.shed_lru(n).await;
+                memory_budget::on_shed(n);
+                log::warn!("Memory budget exceeded, shed {} peers from memory", n);
+            });
+        }
+    }
+
+    // What the server observed about a peer, for diagnosing unreachable peers
+    // without packet captures. Only reachable through the loopback console.
+    async fn get_peer_addr_info(&self, id: &str) -> String {
+        // not self.pm.get(), a lookup shouldn't load the peer into memory
+        if let Some(peer) = self.pm.get_in_memory(id).await {
+            let peer = peer.read().await;
+            let elapsed = peer.last_reg_time.elapsed_ms();
+            let addr = if peer.socket_addr.port() == 0 {
+                "-".to_owned()
+            } else {
+                try_into_v4(peer.socket_addr).to_string()
+            };
+            format!(
+                "addr: {}\nip: {}\nlast_reg: {}s ago\nonline: {}\n{}",
+                addr,
+                peer.info.ip,
+                elapsed / 1000,
+                expiry::is_online(id),
+                last_error::status(id, &peer.info.ip)
+            )
+        } else {
+            match self.pm.storage.get_peer(id).await {
+                Ok(Some(v)) => {
+                    let info = serde_json::from_str::<PeerInfo>(&v.info).unwrap_or_default();
+                    format!(
+                        "addr: -\nip: {}\nonline: false\n{}",
+                        info.ip,
+                        last_error::status(id, &info.ip)
+                    )
+                }
+                Ok(None) => "not found\n".to_owned(),
+                Err(err) => format!("{err}\n"),
+            }
+        }
+    }
+
     fn parse_relay_servers(&mut self, relay_servers: &str) {
         let rs = get_servers(relay_servers, "relay-servers");
         self.relay_servers0 = Arc::new(rs);
         self.relay_servers = self.relay_servers0.clone();
     }
 
-    fn get_relay_server(&self, _pa: IpAddr, _pb: IpAddr) -> String {
+    fn get_relay_server(&self, id: &str, pa: IpAddr, pb: IpAddr) -> String {
+        if let Some(relay) = relay_pin::get(id, &[pb, pa]) {
+            return relay;
+        }
+        // the requester's region, the target's if the requester's is unknown
+        let region = geoip::region(pa).or_else(|| geoip::region(pb));
+        // the pool of reporting relays and those added on the console comes first
+        if let Some(relay) = relay_registry::pick(region.as_deref()) {
+            return relay;
+        }
         if self.relay_servers.is_empty() {
             return "".to_owned();
         } else if self.relay_servers.len() == 1 {
             return self.relay_servers[0].clone();
         }
+        let local: Vec<&String> = self
+            .relay_servers
+            .iter()
+            .filter(|x| region.is_some() && geoip::relay_region(x) == region.as_deref())
+            .collect();
+        if !local.is_empty() {
+            let i = ROTATION_RELAY_SERVER.fetch_add(1, Ordering::SeqCst) % local.len();
+            return local[i].clone();
+        }
         let i = ROTATION_RELAY_SERVER.fetch_add(1, Ordering::SeqCst) % self.relay_servers.len();
         self.relay_servers[i].clone()
     }
@@ -943,14 +1919,73 @@ impl RendezvousServer {
         match fds.next() {
             Some("h") => {
                 res = format!(
-                    "{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
+                    "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
                     "relay-servers(rs) <separated by ,>",
                     "reload-geo(rg)",
                     "ip-blocker(ib) [<ip>|<number>] [-]",
                     "ip-changes(ic) [<id>|<number>] [-]",
                     "punch-requests(pr) [<number>] [-]",
                     "always-use-relay(aur)",
-                    "test-geo(tg) <ip1> <ip2>"
+                    "test-geo(tg) <ip1> <ip2>",
+                    "memory(mem) [<budget MB>]",
+                    "peer(p) <id>",
+                    "capture(cap) [<id>|<ip>|-] [<seconds>]",
+                    "mirror(mi)",
+                    "canary(ca) [<percent>]",
+                    "keys(k) [<name>]",
+                    "key-rotation(kr)",
+                    "healthz(hz)",
+                    "load-shed(ls) [<queue> <cpu%>]",
+                    "presence(pre)",
+                    "punch-stats(ps)",
+                    "cooldown(cd) [<id> [<attempts> <minutes>|-]]",
+                    "auth-failures(af) [<number>|<ip> -]",
+                    "os-stats(os)",
+                    "socket-errors(se)",
+                    "watchdog(wd)",
+                    "telemetry(tm)",
+                    "churn(ch) [<site>]",
+                    "dry-run(dr) [<rule> Y|N]",
+                    "history(hi) <metric> [minute|hour] [<number>]",
+                    "relays(rl) [add <addr> [<capacity>]|drain <addr>|undrain <addr>|<addr> -]",
+                    "federation(fed) [<id>]",
+                    "export-peers(exp) <file> [<id>,...|<prefix>*]",
+                    "import-peers(imp) <file> <public key of the exporting server>",
+                    "pcap(pc)",
+                    "client-config(cc) [<host>]",
+                    "quarantine(qu) [<id> restore|<id> -]",
+                    "keepalive(ka)",
+                    "connection-log(cl) <id>",
+                    "suppressed(su)",
+                    "tcp-peers(tp)",
+                    "relay-pin(pin) [<id|cidr> [<relay>|-]]",
+                    "dispatch(dp)",
+                    "port-check(pchk)",
+                    "peer-class(pcl) [<id|prefix*|cidr> [ephemeral|standard|pinned|-]]",
+                    "registered(reg)",
+                    "reload",
+                    "db-size(db)",
+                    "reconcile(rc)",
+                    "tombstones(ts) [<id>]",
+                    "latency(lat)",
+                    "cluster(cs)",
+                    "pk-ca",
+                    "nat-test(nt)",
+                    "aliases(al)",
+                    "punch-timeout(pt)",
+                    "bans(bn) [<id|ip> [-|<minutes> [<reason>]]]",
+                    "search(sr) [status|<key>=<value> ...]",
+                    "errors(er)",
+                    "expire-pk(xp) [<id>]",
+                    "webhook(wh)",
+                    "peer-db(pdb) list|get <id>|delete <id>|export [<file>]|import <file>",
+                    "udp-sign(us)",
+                    "peer-stats(pst)",
+                    "dtls",
+                    "geoip(gi) [<ip>]",
+                    "advertise(ad)",
+                    "local-subnets(lsn)",
+                    "relay-health(rh)"
                 )
             }
             Some("relay-servers" | "rs") => {
@@ -1082,15 +2117,395 @@ impl RendezvousServer {
                     );
                 }
             }
+            Some("memory" | "mem") => {
+                if let Some(v) = fds.next() {
+                    if let Ok(v) = v.parse::<usize>() {
+                        memory_budget::set_budget_mb(v);
+                    }
+                } else {
+                    res = memory_budget::status();
+                }
+            }
+            Some("peer" | "p") => {
+                if let Some(id) = fds.next() {
+                    res = self.get_peer_addr_info(&id_norm::normalize(id)).await;
+                }
+            }
+            Some("capture" | "cap") => match fds.next() {
+                Some("-") => capture::stop(),
+                Some(target) => {
+                    let secs = fds
+                        .next()
+                        .and_then(|x| x.parse::<u64>().ok())
+                        .unwrap_or(capture::DEFAULT_DURATION);
+                    capture::start(target, secs);
+                }
+                None => res = capture::status(),
+            },
+            Some("keepalive" | "ka") => {
+                res = keepalive::status();
+            }
+            Some("dispatch" | "dp") => {
+                res = dispatch::status();
+            }
+            Some("port-check" | "pchk") => {
+                res = port_check::status();
+            }
+            Some("db-size" | "db") => {
+                res = vacuum::status().await;
+            }
+            Some("reconcile" | "rc") => {
+                res = match self.pm.reconcile().await {
+                    Ok((checked, dropped)) => {
+                        let mut res = format!("checked: {}\ndropped: {}\n", checked, dropped.len());
+                        for (id, why) in dropped {
+                            let _ = writeln!(res, "{} {}", id, why);
+                        }
+                        res
+                    }
+                    Err(err) => format!("{err}\n"),
+                };
+            }
+            Some("tombstones" | "ts") => {
+                res = tombstone::status(&self.pm.db, fds.next()).await;
+            }
+            Some("latency" | "lat") => {
+                res = latency::status();
+            }
+            Some("cluster" | "cs") => {
+                res = cluster::status();
+            }
+            Some("pk-ca") => {
+                res = pk_attest::status();
+            }
+            Some("nat-test" | "nt") => {
+                res = nat_test::status();
+            }
+            Some("aliases" | "al") => {
+                res = alias::status();
+            }
+            Some("punch-timeout" | "pt") => {
+                res = punch_timeout::status();
+            }
+            Some("bans" | "bn") => {
+                res = match (fds.next(), fds.next()) {
+                    (Some(target), Some("-")) => match ban::remove(&self.pm.db, target).await {
+                        Ok(true) => "removed\n".to_owned(),
+                        Ok(false) => format!("{target} isn't banned\n"),
+                        Err(err) => format!("{err}\n"),
+                    },
+                    (Some(target), minutes) => match minutes.map_or(Ok(0), str::parse::<u64>) {
+                        Ok(minutes) => {
+                            let reason = fds.collect::<Vec<_>>().join(" ");
+                            let reason = if reason.is_empty() { "banned" } else { &reason };
+                            match ban::add(&self.pm.db, target, reason, "console", minutes).await {
+                                Ok(()) => "banned\n".to_owned(),
+                                Err(err) => format!("{err}\n"),
+                            }
+                        }
+                        Err(_) => "minutes expected\n".to_owned(),
+                    },
+                    _ => ban::status(),
+                };
+            }
+            Some("search" | "sr") => {
+                res = match fds.next() {
+                    Some("status") => search::status(),
+                    first => {
+                        let pairs = first
+                            .into_iter()
+                            .chain(fds)
+                            .filter(|x| !x.is_empty())
+                            .map(|x| x.split_once('=').unwrap_or((x, "")));
+                        match search::Query::parse(pairs) {
+                            Ok(query) => search::to_csv(&query).await,
+                            Err(err) => format!("{err}\n"),
+                        }
+                    }
+                };
+            }
+            Some("errors" | "er") => {
+                res = errors::status();
+            }
+            Some("expire-pk" | "xp") => {
+                res = match fds.next() {
+                    Some(id) => match self.pm.expire_pk(&id_norm::normalize(id)).await {
+                        Ok(true) => "expired, the next device registering it owns it\n".to_owned(),
+                        Ok(false) => format!("{id} not found\n"),
+                        Err(err) => format!("{err}\n"),
+                    },
+                    None => pk_grace::status(),
+                };
+            }
+            Some("webhook" | "wh") => {
+                res = webhook::status();
+            }
+            Some("peer-db" | "pdb") => {
+                let cmd = fds.collect::<Vec<_>>().join(" ");
+                res = match cmd.split_once(' ') {
+                    Some(("delete", id)) => {
+                        let id = id_norm::normalize(id);
+                        match self.pm.remove(&id, "deleted", "console", true).await {
+                            Ok(true) => format!("deleted {id}\n"),
+                            Ok(false) => "not found\n".to_owned(),
+                            Err(err) => format!("{err}\n"),
+                        }
+                    }
+                    _ => db_cli::exec(&*self.pm.storage, &cmd).await,
+                };
+            }
+            Some("udp-sign" | "us") => {
+                res = udp_sign::status();
+            }
+            Some("peer-stats" | "pst") => {
+                res = peer_stats::status();
+            }
+            Some("dtls") => {
+                res = dtls::status();
+            }
+            Some("geoip" | "gi") => {
+                res = geoip::status();
+                if let Some(ip) = fds.next().and_then(|x| x.parse::<IpAddr>().ok()) {
+                    let region = geoip::region(ip).unwrap_or_else(|| "-".to_owned());
+                    let _ = writeln!(res, "{}: {}", ip, region);
+                }
+            }
+            Some("advertise" | "ad") => {
+                res = advertise::status();
+            }
+            Some("local-subnets" | "lsn") => {
+                res = local_subnets::status();
+            }
+            Some("relay-health" | "rh") => {
+                res = relay_health::status();
+            }
+            Some("reload") => {
+                reload::reload();
+                res = reload::status();
+            }
+            Some("registered" | "reg") => {
+                res = registered::status();
+            }
+            Some("peer-class" | "pcl") => match (fds.next(), fds.next()) {
+                (Some(target), Some("-")) => ttl_class::remove(target),
+                (Some(target), Some(class)) => match ttl_class::Class::parse(class) {
+                    Some(class) => ttl_class::set(target, class),
+                    None => res = "class is ephemeral, standard or pinned\n".to_owned(),
+                },
+                _ => res = ttl_class::status(),
+            },
+            Some("relay-pin" | "pin") => match (fds.next(), fds.next()) {
+                (Some(target), Some("-")) => relay_pin::remove(target),
+                (Some(target), Some(relay)) => relay_pin::set(target, relay),
+                _ => res = relay_pin::status(),
+            },
+            Some("tcp-peers" | "tp") => {
+                for (addr, peer) in self.tcp_peers.lock().await.iter() {
+                    let _ = writeln!(res, "{} {}", log_id::id(&peer.id), addr);
+                }
+            }
+            Some("suppressed" | "su") => {
+                res = suppressed::status();
+            }
+            Some("connection-log" | "cl") => {
+                if let Some(id) = fds.next() {
+                    res = connection_log::status(id);
+                }
+            }
+            Some("mirror" | "mi") => {
+                res = mirror::status();
+            }
+            Some("pcap" | "pc") => {
+                res = pcap::status();
+            }
+            Some("quarantine" | "qu") => {
+                res = match (fds.next(), fds.next()) {
+                    (Some(id), Some("restore")) => match self.pm.restore_quarantined(id).await {
+                        Ok(true) => "restored\n".to_owned(),
+                        Ok(false) => format!("{id} has registered again, nothing restored\n"),
+                        Err(err) => format!("{err}\n"),
+                    },
+                    (Some(id), Some("-")) => match self.pm.storage.delete_quarantined(id).await {
+                        Ok(true) => "deleted\n".to_owned(),
+                        Ok(false) => format!("{id} isn't quarantined\n"),
+                        Err(err) => format!("{err}\n"),
+                    },
+                    (id, None) => match self.pm.storage.get_quarantined(id).await {
+                        Ok(records) => {
+                            let now = crate::common::now() as i64;
+                            for (id, reason, tm, info) in records {
+                                let _ = writeln!(
+                                    res,
+                                    "{} {}h ago: {}\n  info: {}",
+                                    id,
+                                    (now - tm) / 3600,
+                                    reason,
+                                    info.unwrap_or_default()
+                                );
+                            }
+                            res
+                        }
+                        Err(err) => format!("{err}\n"),
+                    },
+                    _ => "unknown action, use restore or -\n".to_owned(),
+                };
+            }
+            Some("client-config" | "cc") => {
+                res = client_config::status(fds.next().unwrap_or_default());
+            }
+            Some("canary" | "ca") => {
+                if let Some(v) = fds.next() {
+                    if let Ok(v) = v.parse::<usize>() {
+                        canary::set_percent(v);
+                    }
+                } else {
+                    res = canary::status();
+                }
+            }
+            Some("keys" | "k") => {
+                if let Some(name) = fds.next() {
+                    for (ip, secs) in self.inner.keys.get_clients(name) {
+                        let _ = writeln!(res, "{ip}: {secs}s");
+                    }
+                } else {
+                    res = self.inner.keys.status();
+                }
+            }
+            Some("key-rotation" | "kr") => {
+                res = self.inner.keys.rotation_status();
+            }
+            Some("load-shed" | "ls") => {
+                if let (Some(queue), Some(cpu)) = (fds.next(), fds.next()) {
+                    if let (Ok(queue), Ok(cpu)) = (queue.parse(), cpu.parse()) {
+                        load_shed::set_limits(queue, cpu);
+                    }
+                } else {
+                    res = load_shed::status();
+                }
+            }
+            Some("presence" | "pre") => {
+                res = presence::status();
+                res += &expiry::status();
+            }
+            Some("punch-stats" | "ps") => {
+                res = punch_stats::status();
+            }
+            Some("cooldown" | "cd") => match (fds.next(), fds.next(), fds.next()) {
+                (Some(id), Some("-"), _) => cooldown::reset(id),
+                (Some(id), Some(attempts), Some(minutes)) => {
+                    if let (Ok(attempts), Ok(minutes)) = (attempts.parse(), minutes.parse()) {
+                        cooldown::set_policy(id, attempts, minutes);
+                    }
+                }
+                _ => res = cooldown::status(),
+            },
+            Some("auth-failures" | "af") => match (fds.next(), fds.next()) {
+                (Some(ip), Some("-")) => auth_failures::unban(ip),
+                (n, _) => {
+                    res = auth_failures::status(n.and_then(|x| x.parse().ok()).unwrap_or(10));
+                }
+            },
+            Some("os-stats" | "os") => {
+                res = os_stats::status();
+            }
+            Some("socket-errors" | "se") => {
+                res = socket_errors::status();
+            }
+            Some("watchdog" | "wd") => {
+                res = watchdog::status();
+            }
+            Some("telemetry" | "tm") => {
+                res = telemetry::status();
+            }
+            Some("churn" | "ch") => {
+                res = churn::status(fds.next());
+            }
+            Some("dry-run" | "dr") => {
+                if let (Some(rule), Some(v)) = (fds.next(), fds.next()) {
+                    if !dry_run::set(rule, v.to_uppercase() == "Y") {
+                        res = format!("unknown rule {rule}\n");
+                    }
+                }
+                if res.is_empty() {
+                    res = dry_run::status();
+                }
+            }
+            Some("history" | "hi") => {
+                let metric = fds.next().unwrap_or_default();
+                let hourly = fds.next() == Some("hour");
+                let n = fds
+                    .next()
+                    .and_then(|x| x.parse::<usize>().ok())
+                    .unwrap_or(if hourly { 24 } else { 60 });
+                res = history::query(metric, hourly, n).await;
+            }
+            Some("relays" | "rl") => {
+                match (fds.next(), fds.next(), fds.next()) {
+                    (Some("add"), Some(addr), capacity) => {
+                        let capacity = capacity.and_then(|x| x.parse().ok()).unwrap_or(0);
+                        relay_registry::add(addr, capacity);
+                    }
+                    (Some("drain"), Some(addr), _) => relay_registry::set_draining(addr, true),
+                    (Some("undrain"), Some(addr), _) => relay_registry::set_draining(addr, false),
+                    (Some(addr), Some("-"), _) => {
+                        if !relay_registry::remove(addr) {
+                            res = "not found\n".to_owned();
+                        }
+                    }
+                    _ => {}
+                }
+                if res.is_empty() {
+                    res = relay_registry::status();
+                }
+            }
+            Some("federation" | "fed") => {
+                res = federation::status();
+                if let Some(id) = fds.next() {
+                    let _ = match federation::lookup(id).await {
+                        Some(upstream) => writeln!(res, "{} is online on {}", id, upstream),
+                        None => writeln!(res, "{} not found upstream", id),
+                    };
+                }
+            }
+            Some("export-peers" | "exp") => {
+                res = match (fds.next(), self.inner.sk.as_ref()) {
+                    (None, _) => "missing file\n".to_owned(),
+                    (_, None) => "no key pair to sign with\n".to_owned(),
+                    (Some(path), Some(sk)) => {
+                        let filter = fds.next().unwrap_or_default();
+                        match migration::export(&*self.pm.storage, sk, path, filter).await {
+                            Ok(n) => format!("exported {n} peers\n"),
+                            Err(err) => format!("{err}\n"),
+                        }
+                    }
+                };
+            }
+            Some("import-peers" | "imp") => {
+                res = match (fds.next(), fds.next()) {
+                    (Some(path), Some(pk)) => {
+                        match migration::import(&*self.pm.storage, path, pk).await {
+                            Ok((n, taken)) if taken.is_empty() => format!("imported {n} peers\n"),
+                            Ok((n, taken)) => {
+                                format!("imported {} peers, ids taken: {}\n", n, taken.join(","))
+                            }
+                            Err(err) => format!("{err}\n"),
+                        }
+                    }
+                    _ => "missing file or public key\n".to_owned(),
+                };
+            }
+            Some("healthz" | "hz") => {
+                res = health::get_status().1 + "\n";
+            }
             Some("test-geo" | "tg") => {
                 if let Some(rs) = fds.next() {
                     if let Ok(a) = rs.parse::<IpAddr>() {
                         if let Some(rs) = fds.next() {
                             if let Ok(b) = rs.parse::<IpAddr>() {
-                                res = format!("{:?}", self.get_relay_server(a, b));
+                                res = format!("{:?}", self.get_relay_server("", a, b));
                             }
                         } else {
-                            res = format!("{:?}", self.get_relay_server(a, a));
+                            res = format!("{:?}", self.get_relay_server("", a, a));
                         }
                     }
                 }
@@ -1109,8 +2524,11 @@ impl RendezvousServer {
                 let mut buffer = [0; 1024];
                 if let Ok(Ok(n)) = timeout(1000, stream.read(&mut buffer[..])).await {
                     if let Ok(data) = std::str::from_utf8(&buffer[..n]) {
-                        let res = rs.check_cmd(data).await;
-                        stream.write(res.as_bytes()).await.ok();
+                        let res = match console_auth::authorize(data) {
+                            Ok(cmd) => rs.check_cmd(cmd).await,
+                            Err(err) => err.to_owned(),
+                        };
+                        stream.write_all(res.as_bytes()).await.ok();
                     }
                 }
             });
@@ -1120,7 +2538,9 @@ impl RendezvousServer {
         tokio::spawn(async move {
             let mut stream = stream;
             if let Some(Ok(bytes)) = stream.next_timeout(30_000).await {
-                if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
+                if let Ok(mut msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
+                    id_norm::apply(&mut msg_in);
+                    alias::apply(&mut msg_in);
                     match msg_in.union {
                         Some(rendezvous_message::Union::TestNatRequest(_)) => {
                             let mut msg_out = RendezvousMessage::new();
@@ -1131,7 +2551,10 @@ impl RendezvousServer {
                             stream.send(&msg_out).await.ok();
                         }
                         Some(rendezvous_message::Union::OnlineRequest(or)) => {
-                            allow_err!(rs.handle_online_request(&mut stream, or.peers).await);
+                            errors::check(
+                                Site::Online,
+                                rs.handle_online_request(&mut stream, or.peers).await,
+                            );
                         }
                         _ => {}
                     }
@@ -1142,10 +2565,15 @@ impl RendezvousServer {
 
     async fn handle_listener(&self, stream: TcpStream, addr: SocketAddr, key: &str, ws: bool) {
         log::debug!("Tcp connection from {:?}, ws: {}", addr, ws);
+        if memory_budget::is_over() {
+            memory_budget::on_rejected();
+            log::debug!("Tcp connection from {:?} rejected, over memory budget", addr);
+            return;
+        }
         let mut rs = self.clone();
         let key = key.to_owned();
         tokio::spawn(async move {
-            allow_err!(rs.handle_listener_inner(stream, addr, &key, ws).await);
+            errors::check(Site::Connection, rs.handle_listener_inner(stream, addr, &key, ws).await);
         });
     }
 
@@ -1158,9 +2586,14 @@ impl RendezvousServer {
         ws: bool,
     ) -> ResultType<()> {
         let mut sink;
+        let token = SESSION_TOKEN.fetch_add(1, Ordering::Relaxed);
         if ws {
             use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
             let callback = |req: &Request, response: Response| {
+                // no proxy in front to set them
+                if tls::ws_acceptor().is_some() {
+                    return Ok(response);
+                }
                 let headers = req.headers();
                 // X-Real-IP / X-Forwarded-For are trusted as-is so that the real
                 // client IP is preserved when the WebSocket port runs behind a
@@ -1181,60 +2614,238 @@ impl RendezvousServer {
                     } else {
                         addr = format!("[{ip}]:0").parse().unwrap_or(addr);
                     }
+                    // the proxy hides the source port, so all clients behind the
+                    // same ip would share one address, give each connection its own
+                    if addr.port() == 0 {
+                        addr.set_port(session_port(token));
+                    }
                 }
                 Ok(response)
             };
+            let stream: Box<dyn Io> = match tls::ws_acceptor() {
+                Some(acceptor) => Box::new(timeout(30_000, acceptor.accept(stream)).await??),
+                None => Box::new(stream),
+            };
             let ws_stream = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
             let (a, mut b) = ws_stream.split();
             sink = Some(Sink::Ws(a));
             while let Ok(Some(Ok(msg))) = timeout(30_000, b.next()).await {
                 if let tungstenite::Message::Binary(bytes) = msg {
-                    if !self.handle_tcp(&bytes, &mut sink, addr, key, ws).await {
+                    if !self.handle_tcp(&bytes, &mut sink, addr, token, key, ws).await {
                         break;
                     }
                 }
             }
         } else {
+            let stream: Box<dyn Io> = match tls::tcp_acceptor() {
+                Some(acceptor) => Box::new(timeout(30_000, acceptor.accept(stream)).await??),
+                None => Box::new(stream),
+            };
             let (a, mut b) = Framed::new(stream, BytesCodec::new()).split();
             sink = Some(Sink::TcpStream(a));
             while let Ok(Some(Ok(bytes))) = timeout(30_000, b.next()).await {
-                if !self.handle_tcp(&bytes, &mut sink, addr, key, ws).await {
+                if !self.handle_tcp(&bytes, &mut sink, addr, token, key, ws).await {
                     break;
                 }
             }
         }
         if sink.is_none() {
-            self.tcp_punch.lock().await.remove(&try_into_v4(addr));
+            self.remove_tcp_session(addr, token).await;
+            self.remove_tcp_peer(addr, token).await;
         }
+        presence::unsubscribe(token);
         log::debug!("Tcp connection from {:?} closed", addr);
         Ok(())
     }
 
-    #[inline]
-    async fn get_pk(&mut self, version: &str, id: String) -> Bytes {
-        if version.is_empty() || self.inner.sk.is_none() {
-            Bytes::new()
-        } else {
-            match self.pm.get(&id).await {
-                Some(peer) => {
-                    let pk = peer.read().await.pk.clone();
-                    sign::sign(
-                        &hbb_common::message_proto::IdPk {
-                            id,
-                            pk,
-                            ..Default::default()
-                        }
-                        .write_to_bytes()
-                        .unwrap_or_default(),
-                        self.inner.sk.as_ref().unwrap(),
-                    )
-                    .into()
+    // Registrations and messages from the other nodes, see cluster.
+    async fn serve_cluster(self, socket: Arc<tokio::net::UdpSocket>) {
+        let mut buf = vec![0u8; cluster::MAX_DATAGRAM];
+        loop {
+            let (n, from) = match socket.recv_from(&mut buf).await {
+                Ok(x) => x,
+                Err(err) => {
+                    log::error!("cluster socket: {}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
                 }
-                _ => Bytes::new(),
+            };
+            match cluster::open(&buf[..n]) {
+                Some(cluster::Msg::Register {
+                    id,
+                    addr,
+                    pk,
+                    instance,
+                }) => {
+                    let Some(pk) = cluster::decode_pk(&pk) else {
+                        continue;
+                    };
+                    self.pm.set_remote(&id, addr, pk).await;
+                    cluster::on_remote_register(addr, from, instance);
+                }
+                Some(cluster::Msg::Peer { addr, msg }) => {
+                    if let Some(msg) = cluster::decode_msg(&msg) {
+                        self.tx.send(Data::Local(msg.into(), addr)).ok();
+                    }
+                }
+                Some(cluster::Msg::Session { addr, msg }) => {
+                    // only the node holding the connection answers
+                    let mut sink = self
+                        .tcp_punch
+                        .lock()
+                        .await
+                        .remove(&try_into_v4(addr))
+                        .map(|x| x.sink);
+                    if let (Some(msg), true) = (cluster::decode_msg(&msg), sink.is_some()) {
+                        Self::send_to_sink(&mut sink, msg).await;
+                    }
+                }
+                None => log::debug!("Dropped a cluster datagram from {}", from),
             }
         }
     }
 
+    async fn serve_dtls(
+        self,
+        listener: DtlsListener,
+        config: webrtc_dtls::config::Config,
+        key: String,
+    ) {
+        loop {
+            match listener.accept().await {
+                Ok((conn, addr)) => {
+                    if memory_budget::is_over() {
+                        memory_budget::on_rejected();
+                        conn.close().await.ok();
+                        continue;
+                    }
+                    let mut rs = self.clone();
+                    let config = config.clone();
+                    let key = key.clone();
+                    tokio::spawn(async move {
+                        let res = match dtls::accept(conn, config).await {
+                            Ok(conn) => rs.handle_dtls(conn, addr, &key).await,
+                            Err(err) => Err(err),
+                        };
+                        errors::check(Site::Connection, res);
+                    });
+                }
+                Err(err) => {
+                    log::error!("dtls listener accept: {}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    // The same handlers as the tcp listener, a datagram per message.
+    async fn handle_dtls(&mut self, conn: DtlsConn, addr: SocketAddr, key: &str) -> ResultType<()> {
+        let token = SESSION_TOKEN.fetch_add(1, Ordering::Relaxed);
+        let mut sink = Some(Sink::Dtls(conn.clone()));
+        let mut buf = vec![0u8; 64 * 1024];
+        while let Ok(Ok(n)) = timeout(30_000, conn.recv(&mut buf)).await {
+            if !self.handle_tcp(&buf[..n], &mut sink, addr, token, key, false).await {
+                break;
+            }
+        }
+        if sink.is_none() {
+            self.remove_tcp_session(addr, token).await;
+            self.remove_tcp_peer(addr, token).await;
+        }
+        presence::unsubscribe(token);
+        conn.close().await.ok();
+        Ok(())
+    }
+
+    async fn serve_json(self, listener: TcpListener, key: String) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let mut rs = self.clone();
+                    let key = key.clone();
+                    tokio::spawn(async move {
+                        errors::check(
+                            Site::Connection,
+                            rs.handle_json_listener(stream, addr, &key).await,
+                        );
+                    });
+                }
+                Err(err) => {
+                    log::error!("json debug listener accept: {}", err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    // The same handlers as the tcp listener, with each line translated from
+    // JSON and the answers back to it.
+    async fn handle_json_listener(
+        &mut self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        key: &str,
+    ) -> ResultType<()> {
+        let token = SESSION_TOKEN.fetch_add(1, Ordering::Relaxed);
+        let (a, mut b) =
+            Framed::new(stream, LinesCodec::new_with_max_length(json_wire::MAX_LINE)).split();
+        let mut sink = Some(Sink::Json(a));
+        while let Ok(Some(Ok(line))) = timeout(30_000, b.next()).await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let bytes = match json_wire::decode(&line) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    if let Some(Sink::Json(s)) = sink.as_mut() {
+                        errors::check(
+                            Site::Reply,
+                            s.send(json_wire::error(&err.to_string())).await,
+                        );
+                    }
+                    continue;
+                }
+            };
+            if !self.handle_tcp(&bytes, &mut sink, addr, token, key, false).await {
+                break;
+            }
+        }
+        if sink.is_none() {
+            self.remove_tcp_session(addr, token).await;
+            self.remove_tcp_peer(addr, token).await;
+        }
+        presence::unsubscribe(token);
+        Ok(())
+    }
+
+    // `to` is the requester, during key rotation it may only know the old key
+    #[inline]
+    async fn get_pk(&mut self, version: &str, id: String, to: SocketAddr) -> Bytes {
+        let ip = try_into_v4(to).ip().to_string();
+        let inner = self.inner.clone();
+        let sk = match inner.keys.get_sk(&ip).or(inner.sk.as_ref()) {
+            Some(sk) if !version.is_empty() => sk,
+            _ => return Bytes::new(),
+        };
+        match self.pm.get(&id).await {
+            Some(peer) => {
+                let pk = peer.read().await.pk.clone();
+                sign::sign(
+                    &hbb_common::message_proto::IdPk {
+                        id,
+                        pk,
+                        ..Default::default()
+                    }
+                    .write_to_bytes()
+                    .unwrap_or_default(),
+                    sk,
+                )
+                .into()
+            }
+            _ => Bytes::new(),
+        }
+    }
+
     #[inline]
     fn get_server_sk(key: &str) -> (String, Option<sign::SecretKey>) {
         let mut out_sk = None;
@@ -1282,30 +2893,43 @@ impl RendezvousServer {
     }
 }
 
+// Only the healthy relays are handed out, all of them if none is, see
+// relay_health; the registry skips its relays which are down itself.
 async fn check_relay_servers(rs0: Arc<RelayServers>, tx: Sender) {
-    let mut futs = Vec::new();
-    let rs = Arc::new(Mutex::new(Vec::new()));
-    for x in rs0.iter() {
-        let mut host = x.to_owned();
-        if !host.contains(':') {
-            host = format!("{}:{}", host, config::RELAY_PORT);
-        }
-        let rs = rs.clone();
-        let x = x.clone();
-        futs.push(tokio::spawn(async move {
-            if FramedStream::new(&host, None, CHECK_RELAY_TIMEOUT)
-                .await
-                .is_ok()
-            {
-                rs.lock().await.push(x);
-            }
-        }));
+    let mut relays = rs0.to_vec();
+    relays.extend(relay_registry::addrs());
+    if !relay_health::check(relays).await {
+        return;
     }
-    join_all(futs).await;
     log::debug!("check_relay_servers");
-    let rs = std::mem::take(&mut *rs.lock().await);
+    let rs: Vec<String> = rs0
+        .iter()
+        .filter(|x| !relay_health::is_down(x))
+        .cloned()
+        .collect();
     if !rs.is_empty() {
         tx.send(Data::RelayServers(rs)).ok();
+    } else if !rs0.is_empty() {
+        log::error!("All relay servers are down, handing out all of them");
+        tx.send(Data::RelayServers(rs0.to_vec())).ok();
+    }
+}
+
+async fn resolve_relay_servers(relay_servers: String, tx: Sender) {
+    let n = relay_servers.split(',').filter(|x| !x.is_empty()).count();
+    let mut timer = interval(Duration::from_secs(30));
+    timer.tick().await;
+    for _ in 0..120 {
+        timer.tick().await;
+        let rs = relay_servers.clone();
+        let resolved = tokio::task::spawn_blocking(move || get_servers(&rs, "relay-servers").len())
+            .await
+            .unwrap_or_default();
+        if resolved >= n {
+            log::info!("All relay servers resolved");
+            tx.send(Data::RelayServers0(relay_servers)).ok();
+            return;
+        }
     }
 }
 
@@ -1358,6 +2982,7 @@ async fn send_rk_res(
         result: res.into(),
         ..Default::default()
     });
+    udp_sign::apply(&mut msg_out);
     socket.send(&msg_out, addr).await
 }
 
@@ -1366,6 +2991,9 @@ async fn create_udp_listener(
     port: i32,
     rmem: usize,
 ) -> ResultType<FramedSocket> {
+    if let Some(socket) = systemd::udp_socket(port) {
+        return socket;
+    }
     if let Some(bind_addr) = bind_addr {
         let addr = SocketAddr::new(bind_addr, port as _);
         return FramedSocket::new_reuse(&addr, true, rmem).await;
@@ -1383,11 +3011,28 @@ async fn create_udp_listener(
 
 #[inline]
 async fn create_tcp_listener(bind_addr: Option<IpAddr>, port: i32) -> ResultType<TcpListener> {
+    if let Some(listener) = systemd::tcp_listener(port) {
+        return listener;
+    }
     let s = listen_tcp(bind_addr, port as _).await?;
     log::debug!("listen on tcp {:?}", s.local_addr());
     Ok(s)
 }
 
+// The port set in `name`, or `default`, the usual offset from PORT.
+fn listen_port(name: &str, default: i32) -> i32 {
+    match get_arg(name).parse::<u16>() {
+        Ok(port) if port > 0 => port as _,
+        _ => default,
+    }
+}
+
+// Stand-in source port for a connection whose real port is unknown, never 0
+#[inline]
+fn session_port(token: u64) -> u16 {
+    (token % u16::MAX as u64) as u16 + 1
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1398,4 +3043,12 @@ mod tests {
         let socket = create_udp_listener(Some(bind_addr), 0, 0).await.unwrap();
         assert_eq!(socket.local_addr().unwrap().ip(), bind_addr);
     }
+
+    #[test]
+    fn session_port_is_never_zero() {
+        assert_eq!(session_port(0), 1);
+        assert_eq!(session_port(u16::MAX as u64 - 1), u16::MAX);
+        assert_eq!(session_port(u16::MAX as u64), 1);
+        assert_ne!(session_port(1), session_port(2));
+    }
 }
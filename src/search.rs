@@ -0,0 +1,349 @@
+use crate::{
+    common::{get_arg, now},
+    connection_log::Outcome,
+    database::Database,
+    geoip, id_norm, ip_filter,
+};
+use hbb_common::{log, tokio, ResultType};
+use ipnetwork::IpNetwork;
+use once_cell::sync::OnceCell;
+use std::{
+    fmt::Write as _,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const PURGE_INTERVAL: u64 = 3600; // in seconds
+const MAX_PENDING: usize = 100_000;
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 10_000;
+const PAGE_SIZE: usize = 10_000; // rows read at once for a query by networks
+
+static DB: OnceCell<Database> = OnceCell::new();
+static STORED: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    // (unix time, id, requester ip, outcome) not stored yet
+    static ref PENDING: Mutex<Vec<(i64, String, String, Outcome)>> = Default::default();
+}
+
+/// `CONNECTION_HISTORY_DAYS` keeps that many days of connection attempts in
+/// the `connection_attempt` table, indexed by time, id and ip, to search
+/// them later, e.g. all attempts at a group of devices from outside a
+/// country's networks last month. 0 is off.
+pub(crate) async fn init(db: Database) {
+    let days = get_arg("CONNECTION_HISTORY_DAYS").parse::<u64>().unwrap_or(0);
+    if days == 0 {
+        return;
+    }
+    if let Err(err) = db.create_attempt_table().await {
+        log::error!("Failed to create connection attempt table: {}", err);
+        return;
+    }
+    log::info!("CONNECTION_HISTORY_DAYS={}", days);
+    DB.set(db.clone()).ok();
+    // batched, one transaction instead of one per attempt
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        let mut purged = 0;
+        loop {
+            interval.tick().await;
+            flush(&db).await;
+            let now = now();
+            if now >= purged + PURGE_INTERVAL {
+                purged = now;
+                let before = now.saturating_sub(days * 24 * 3600);
+                if let Err(err) = db.delete_attempts(before as _).await {
+                    log::error!("Failed to purge connection attempts: {}", err);
+                }
+            }
+        }
+    });
+}
+
+#[inline]
+pub(crate) fn enabled() -> bool {
+    DB.get().is_some()
+}
+
+/// A connection to `id` was requested from `ip`, see connection_log.
+pub(crate) fn record(id: &str, ip: &str, outcome: Outcome) {
+    if !enabled() {
+        return;
+    }
+    let Ok(mut pending) = PENDING.lock() else {
+        return;
+    };
+    if pending.len() >= MAX_PENDING {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    pending.push((now() as _, id.to_owned(), ip.to_owned(), outcome));
+}
+
+async fn flush(db: &Database) {
+    let attempts = match PENDING.lock() {
+        Ok(mut pending) => std::mem::take(&mut *pending),
+        Err(_) => return,
+    };
+    if attempts.is_empty() {
+        return;
+    }
+    let attempts: Vec<_> = attempts
+        .into_iter()
+        .map(|(tm, id, ip, outcome)| (tm, id, ip, outcome.as_str()))
+        .collect();
+    match db.insert_attempts(&attempts).await {
+        Ok(()) => {
+            STORED.fetch_add(attempts.len(), Ordering::Relaxed);
+        }
+        Err(err) => {
+            DROPPED.fetch_add(attempts.len(), Ordering::Relaxed);
+            log::error!("Failed to store {} connection attempts: {}", attempts.len(), err);
+        }
+    }
+}
+
+/// What to search for, from `<key>=<value>` pairs: `id`, comma separated ids
+/// or `<prefix>*`, `ip` and `not_ip`, comma separated addresses or networks,
+/// `country` and `not_country`, comma separated ISO country codes, `outcome`,
+/// `since` and `until`, unix times or e.g. `30d`, `12h` or `15m` ago, and
+/// `limit`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Query {
+    ids: Vec<String>,
+    ips: Vec<IpNetwork>,
+    not_ips: Vec<IpNetwork>,
+    countries: Vec<String>,
+    not_countries: Vec<String>,
+    outcome: Option<String>,
+    since: i64,
+    until: i64,
+    limit: usize,
+}
+
+impl Query {
+    pub(crate) fn parse<'a>(
+        pairs: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Self, String> {
+        let now = now() as i64;
+        let mut query = Query {
+            until: i64::MAX,
+            limit: DEFAULT_LIMIT,
+            ..Default::default()
+        };
+        for (key, value) in pairs {
+            match key {
+                "id" => {
+                    query.ids = value
+                        .split(',')
+                        .map(|x| id_norm::normalize(x.trim()))
+                        .filter(|x| !x.is_empty())
+                        .collect();
+                }
+                "ip" => query.ips = ip_filter::parse(value)?,
+                "not_ip" => query.not_ips = ip_filter::parse(value)?,
+                "country" => query.countries = parse_countries(value)?,
+                "not_country" => query.not_countries = parse_countries(value)?,
+                "outcome" => {
+                    let outcome = Outcome::ALL.iter().find(|x| x.as_str() == value);
+                    let Some(outcome) = outcome else {
+                        return Err(format!("unknown outcome {value}"));
+                    };
+                    query.outcome = Some(outcome.as_str().to_owned());
+                }
+                "since" => query.since = parse_time(value, now)?,
+                "until" => query.until = parse_time(value, now)?,
+                "limit" => {
+                    query.limit = value
+                        .parse::<usize>()
+                        .map_err(|_| format!("{value} isn't a number"))?
+                        .min(MAX_LIMIT);
+                }
+                _ => return Err(format!("unknown {key}")),
+            }
+        }
+        Ok(query)
+    }
+
+    #[inline]
+    fn by_country(&self) -> bool {
+        !self.countries.is_empty() || !self.not_countries.is_empty()
+    }
+
+    // what the database can't filter
+    fn matches(&self, ip: &str) -> bool {
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            return self.ips.is_empty() && self.not_ips.is_empty() && !self.by_country();
+        };
+        if !(self.ips.is_empty() || self.ips.iter().any(|x| x.contains(ip)))
+            || self.not_ips.iter().any(|x| x.contains(ip))
+        {
+            return false;
+        }
+        if !self.by_country() {
+            return true;
+        }
+        // an address of unknown country is in none
+        let Some(country) = geoip::country(ip) else {
+            return self.countries.is_empty();
+        };
+        (self.countries.is_empty() || self.countries.contains(&country))
+            && !self.not_countries.contains(&country)
+    }
+}
+
+// comma separated ISO country codes, upper case
+fn parse_countries(v: &str) -> Result<Vec<String>, String> {
+    v.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            if x.len() == 2 && x.chars().all(|c| c.is_ascii_alphabetic()) {
+                Ok(x.to_uppercase())
+            } else {
+                Err(format!("{x} isn't a country code like DE"))
+            }
+        })
+        .collect()
+}
+
+// a unix time, or a time ago with a d, h or m suffix
+fn parse_time(v: &str, now: i64) -> Result<i64, String> {
+    let (n, unit) = match v.char_indices().last() {
+        Some((i, 'd')) => (&v[..i], 24 * 3600),
+        Some((i, 'h')) => (&v[..i], 3600),
+        Some((i, 'm')) => (&v[..i], 60),
+        _ => (v, 0),
+    };
+    let n = n
+        .parse::<i64>()
+        .map_err(|_| format!("{v} isn't a unix time or a time ago like 30d"))?;
+    if unit == 0 {
+        return Ok(n);
+    }
+    n.checked_mul(unit)
+        .and_then(|x| now.checked_sub(x))
+        .ok_or_else(|| format!("{v} is too long ago"))
+}
+
+/// (time, id, ip, outcome) of the latest attempts matching `query`, newest
+/// first.
+pub(crate) async fn search(query: &Query) -> ResultType<Vec<(i64, String, String, String)>> {
+    let Some(db) = DB.get() else {
+        hbb_common::bail!("off, set CONNECTION_HISTORY_DAYS");
+    };
+    if query.by_country() && !geoip::enabled() {
+        hbb_common::bail!("country needs GEOIP_DB");
+    }
+    // single addresses go to the index, networks and countries are checked
+    // on the rows read
+    let (ips, by_net) = if query.ips.iter().all(|x| x.prefix() == max_prefix(x)) {
+        let ips = query.ips.iter().map(|x| x.ip().to_string()).collect();
+        (ips, !query.not_ips.is_empty() || query.by_country())
+    } else {
+        (Vec::new(), true)
+    };
+    let page = if by_net { PAGE_SIZE } else { query.limit };
+    let mut res = Vec::new();
+    let mut before = None;
+    // page by (time, rowid) until enough rows match or none are left
+    while res.len() < query.limit {
+        let rows = db
+            .search_attempts(
+                query.since,
+                query.until,
+                &query.ids,
+                &ips,
+                query.outcome.as_deref(),
+                before,
+                page as _,
+            )
+            .await?;
+        let n = rows.len();
+        before = rows.last().map(|x| (x.1, x.0));
+        res.extend(
+            rows.into_iter()
+                .map(|(_, tm, id, ip, outcome)| (tm, id, ip, outcome))
+                .filter(|x| query.matches(&x.2)),
+        );
+        if n < page {
+            break;
+        }
+    }
+    res.truncate(query.limit);
+    Ok(res)
+}
+
+#[inline]
+fn max_prefix(net: &IpNetwork) -> u8 {
+    match net {
+        IpNetwork::V4(_) => 32,
+        IpNetwork::V6(_) => 128,
+    }
+}
+
+pub(crate) fn status() -> String {
+    if !enabled() {
+        return "off, set CONNECTION_HISTORY_DAYS\n".to_owned();
+    }
+    format!(
+        "stored: {}\npending: {}\ndropped: {}\n",
+        STORED.load(Ordering::Relaxed),
+        PENDING.lock().map_or(0, |x| x.len()),
+        DROPPED.load(Ordering::Relaxed)
+    )
+}
+
+/// The result of a console search, as csv.
+pub(crate) async fn to_csv(query: &Query) -> String {
+    match search(query).await {
+        Ok(rows) => {
+            let mut res = "time,id,ip,outcome\n".to_owned();
+            for (tm, id, ip, outcome) in rows {
+                let _ = writeln!(res, "{},{},{},{}", tm, id, ip, outcome);
+            }
+            res
+        }
+        Err(err) => format!("{err}\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_queries() {
+        let query = Query::parse([
+            ("id", "kiosk-*,123456789"),
+            ("not_ip", "10.0.0.0/8"),
+            ("since", "30d"),
+            ("outcome", "refused"),
+        ])
+        .unwrap();
+        assert_eq!(query.ids, ["kiosk-*", "123456789"]);
+        assert!(query.since > 0 && query.since <= now() as i64 - 30 * 24 * 3600);
+        assert_eq!(query.outcome.as_deref(), Some("refused"));
+        assert!(query.matches("192.168.1.1"));
+        assert!(!query.matches("10.1.2.3"));
+        assert_eq!(parse_time("1700000000", 0), Ok(1700000000));
+        assert_eq!(parse_time("15m", 1000), Ok(100));
+        assert!(parse_time("9223372036854775807d", 0).is_err());
+        assert!(parse_time("-9223372036854775807m", 0).is_err());
+        let query = Query::parse([("country", "de, at"), ("not_country", "CH")]).unwrap();
+        assert_eq!(query.countries, ["DE", "AT"]);
+        assert_eq!(query.not_countries, ["CH"]);
+        // without GEOIP_DB no address is in a country
+        assert!(!query.matches("192.0.2.1"));
+        assert!(Query::parse([("not_country", "CH")]).unwrap().matches("192.0.2.1"));
+        assert!(Query::parse([("country", "Germany")]).is_err());
+        assert!(Query::parse([("outcome", "lost")]).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{
+    common::{get_arg, now_ms},
+    expiry,
+    peer::PeerMap,
+};
+use hbb_common::{anyhow, bail, log, tokio, ResultType};
+use serde_derive::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+const FETCH_TIMEOUT: u64 = 30; // in seconds
+
+/// The online peers at shutdown, or for the instance taking over.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    time: u64, // in ms since the epoch
+    peers: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) id: String,
+    pub(crate) addr: SocketAddr,
+    pub(crate) age: i64, // in ms since the last registration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) v6: Option<(SocketAddr, i64)>, // with its age
+}
+
+/// `PEER_SNAPSHOT` is a file the online peers' addresses are written to on
+/// SIGTERM, SIGINT or SIGQUIT and read back on start, so a restart doesn't
+/// take them offline until they register again. Off if empty.
+fn path() -> Option<String> {
+    Some(get_arg("PEER_SNAPSHOT")).filter(|x| !x.is_empty())
+}
+
+/// Write the snapshot on shutdown, after waiting a little for database
+/// writes still in flight.
+pub(crate) async fn save(pm: &PeerMap) {
+    if !pm.db.wait_idle(std::time::Duration::from_secs(5)).await {
+        log::warn!("Shutting down with database writes in flight");
+    }
+    let Some(path) = path() else {
+        return;
+    };
+    let snapshot = Snapshot {
+        time: now_ms(),
+        peers: pm.snapshot().await,
+    };
+    match write(&path, &snapshot) {
+        Ok(()) => log::info!("{} online peers saved to {}", snapshot.peers.len(), path),
+        Err(err) => log::error!("Failed to save the peers to {}: {}", path, err),
+    }
+}
+
+// through a temporary file, a crash halfway leaves the old snapshot
+fn write(path: &str, snapshot: &Snapshot) -> ResultType<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_vec(snapshot)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Bring back the peers of the snapshot which would still be online, once
+/// on start. The file is removed, a later crash mustn't restore it again.
+pub(crate) async fn restore(pm: &PeerMap) {
+    let Some(path) = path() else {
+        return;
+    };
+    let Ok(data) = std::fs::read(&path) else {
+        return;
+    };
+    std::fs::remove_file(&path).ok();
+    let snapshot = match serde_json::from_slice::<Snapshot>(&data) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            log::error!("Ignored the malformed snapshot {}: {}", path, err);
+            return;
+        }
+    };
+    let (n, downtime) = apply(pm, snapshot).await;
+    log::info!("{} online peers restored from {}, {}ms down", n, path, downtime);
+}
+
+// the peers not online here already, aged by the time since the snapshot
+async fn apply(pm: &PeerMap, snapshot: Snapshot) -> (usize, i64) {
+    let downtime = now_ms().saturating_sub(snapshot.time) as i64;
+    let mut n = 0;
+    for mut entry in snapshot.peers {
+        if expiry::is_online(&entry.id) {
+            continue;
+        }
+        entry.age = entry.age.saturating_add(downtime);
+        if let Some(v6) = entry.v6.as_mut() {
+            v6.1 = v6.1.saturating_add(downtime);
+        }
+        if pm.restore(entry).await {
+            n += 1;
+        }
+    }
+    (n, downtime)
+}
+
+/// The online peers now, served as `GET /presence` by the admin API.
+pub(crate) async fn export(pm: &PeerMap) -> Snapshot {
+    Snapshot {
+        time: now_ms(),
+        peers: pm.snapshot().await,
+    }
+}
+
+/// `WARM_FROM` is the admin API of the instance this one replaces, e.g.
+/// `http://10.0.0.1:21120` in a blue/green deploy, whose online peers are
+/// taken over once on start instead of waiting for them to register here.
+/// `WARM_FROM_TOKEN` is its `ADMIN_API_TOKEN`, if any.
+pub(crate) async fn warm(pm: &PeerMap) {
+    let url = get_arg("WARM_FROM");
+    if url.is_empty() {
+        return;
+    }
+    let url = format!("{}/presence", url.trim_end_matches('/'));
+    let token = get_arg("WARM_FROM_TOKEN");
+    let res = {
+        let url = url.clone();
+        tokio::task::spawn_blocking(move || fetch(&url, &token)).await
+    };
+    match res.map_err(anyhow::Error::from).and_then(|x| x) {
+        Ok(snapshot) => {
+            let (n, age) = apply(pm, snapshot).await;
+            log::info!("{} online peers taken over from {}, {}ms old", n, url, age);
+        }
+        Err(err) => log::error!("Failed to take over the peers of {}: {}", url, err),
+    }
+}
+
+fn fetch(url: &str, token: &str) -> ResultType<Snapshot> {
+    let mut req = minreq::get(url).with_timeout(FETCH_TIMEOUT);
+    if !token.is_empty() {
+        req = req.with_header("Authorization", format!("Bearer {token}"));
+    }
+    let res = req.send()?;
+    if res.status_code != 200 {
+        bail!("{} {}", res.status_code, res.reason_phrase);
+    }
+    Ok(serde_json::from_slice(res.as_bytes())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_and_reads_back() {
+        let path = std::env::temp_dir().join("hbbs_peer_snapshot_test.json");
+        let path = path.to_string_lossy().to_string();
+        let snapshot = Snapshot {
+            time: 1000,
+            peers: vec![Entry {
+                id: "123456789".to_owned(),
+                addr: "10.0.0.1:1".parse().unwrap(),
+                age: 5000,
+                v6: None,
+            }],
+        };
+        write(&path, &snapshot).unwrap();
+        let data = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert!(!data.contains("v6"));
+        let read: Snapshot = serde_json::from_str(&data).unwrap();
+        assert_eq!(read.peers[0].id, "123456789");
+        assert_eq!(read.peers[0].age, 5000);
+    }
+}
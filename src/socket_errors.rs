@@ -0,0 +1,131 @@
+use crate::common::get_arg_or;
+use hbb_common::{log, ResultType};
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+const DEFAULT_REBUILD_ERRORS: usize = 100;
+
+/// The sockets of the main loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Udp = 0,
+    Tcp = 1,
+    NatTest = 2,
+    Ws = 3,
+}
+
+const KINDS: [Kind; 4] = [Kind::Udp, Kind::Tcp, Kind::NatTest, Kind::Ws];
+
+static REBUILD_ERRORS: AtomicUsize = AtomicUsize::new(DEFAULT_REBUILD_ERRORS);
+static ERRORS: [AtomicUsize; 4] = [const { AtomicUsize::new(0) }; 4];
+// errors without a success in between
+static CONSECUTIVE: [AtomicUsize; 4] = [const { AtomicUsize::new(0) }; 4];
+static REBUILDS: [AtomicUsize; 4] = [const { AtomicUsize::new(0) }; 4];
+
+lazy_static::lazy_static! {
+    static ref LAST_ERROR: Mutex<[String; 4]> = Default::default();
+}
+
+pub(crate) fn init() {
+    let n = get_arg_or("SOCKET_REBUILD_ERRORS", DEFAULT_REBUILD_ERRORS.to_string())
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_REBUILD_ERRORS)
+        .max(1);
+    REBUILD_ERRORS.store(n, Ordering::SeqCst);
+    log::info!("SOCKET_REBUILD_ERRORS={}", n);
+}
+
+/// Record the result of a udp send, e.g. EPERM from a firewall or a full
+/// buffer. Returns true if the socket kept failing and should be rebuilt.
+#[inline]
+pub(crate) fn on_udp_send(res: &ResultType<()>, addr: SocketAddr) -> bool {
+    match res {
+        Ok(()) => {
+            on_ok(Kind::Udp);
+            false
+        }
+        Err(err) => on_error(Kind::Udp, &format!("send to {addr}: {err}")),
+    }
+}
+
+#[inline]
+pub(crate) fn on_ok(kind: Kind) {
+    CONSECUTIVE[kind as usize].store(0, Ordering::Relaxed);
+}
+
+/// Returns true if the socket kept failing and should be rebuilt.
+pub(crate) fn on_error(kind: Kind, err: &str) -> bool {
+    ERRORS[kind as usize].fetch_add(1, Ordering::Relaxed);
+    let n = CONSECUTIVE[kind as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    if let Ok(mut last) = LAST_ERROR.lock() {
+        last[kind as usize] = err.to_owned();
+    }
+    // the first of a series, not each of them
+    if n == 1 {
+        log::warn!("{:?} socket error: {}", kind, err);
+    }
+    if n >= REBUILD_ERRORS.load(Ordering::Relaxed) {
+        log::error!("{:?} socket failed {} times in a row, rebuilding it: {}", kind, n, err);
+        CONSECUTIVE[kind as usize].store(0, Ordering::Relaxed);
+        return true;
+    }
+    false
+}
+
+/// A terminal error, e.g. the udp stream ended, the socket is rebuilt right away.
+pub(crate) fn on_fatal(kind: Kind, err: &str) {
+    ERRORS[kind as usize].fetch_add(1, Ordering::Relaxed);
+    CONSECUTIVE[kind as usize].store(0, Ordering::Relaxed);
+    if let Ok(mut last) = LAST_ERROR.lock() {
+        last[kind as usize] = err.to_owned();
+    }
+    log::error!("{:?} socket failed, rebuilding it: {}", kind, err);
+}
+
+#[inline]
+pub(crate) fn on_rebuild(kind: Kind) {
+    REBUILDS[kind as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn status() -> String {
+    let last = LAST_ERROR.lock().map(|x| x.clone()).unwrap_or_default();
+    let mut res = format!("rebuild after: {}\n", REBUILD_ERRORS.load(Ordering::SeqCst));
+    for kind in KINDS {
+        let i = kind as usize;
+        let _ = writeln!(
+            res,
+            "{:?}: errors={} consecutive={} rebuilds={} last={}",
+            kind,
+            ERRORS[i].load(Ordering::Relaxed),
+            CONSECUTIVE[i].load(Ordering::Relaxed),
+            REBUILDS[i].load(Ordering::Relaxed),
+            last[i]
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebuilds_after_consecutive_errors() {
+        let n = REBUILD_ERRORS.load(Ordering::SeqCst);
+        for _ in 1..n {
+            assert!(!on_error(Kind::Ws, "EMFILE"));
+        }
+        on_ok(Kind::Ws);
+        for _ in 1..n {
+            assert!(!on_error(Kind::Ws, "EMFILE"));
+        }
+        assert!(on_error(Kind::Ws, "EMFILE"));
+        assert!(status().contains("last=EMFILE"));
+    }
+}
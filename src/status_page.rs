@@ -0,0 +1,125 @@
+use crate::common::{get_arg, get_arg_or};
+use hbb_common::log;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+
+const DEFAULT_TITLE: &str = "RustDesk Server";
+const DEFAULT_COLOR: &str = "#024eff";
+// built-in english, overridden by the language pack
+const STRINGS: &[(&str, &str)] = &[
+    ("lang", "en"),
+    ("operational", "All systems operational"),
+    ("unavailable", "Service unavailable"),
+    ("details", "Details"),
+];
+
+struct Branding {
+    title: String,
+    logo: String,
+    color: String,
+    strings: HashMap<String, String>,
+}
+
+static BRANDING: OnceCell<Branding> = OnceCell::new();
+
+/// Branding of the public status page: STATUS_PAGE_TITLE, STATUS_PAGE_LOGO (an
+/// image url), STATUS_PAGE_COLOR and STATUS_PAGE_LANG, a language pack file
+/// with `key=value` lines for the texts in STRINGS.
+pub(crate) fn init() {
+    let mut color = get_arg_or("STATUS_PAGE_COLOR", DEFAULT_COLOR.to_owned());
+    if !is_valid_color(&color) {
+        log::error!("Invalid STATUS_PAGE_COLOR {}, using {}", color, DEFAULT_COLOR);
+        color = DEFAULT_COLOR.to_owned();
+    }
+    let mut strings: HashMap<String, String> = STRINGS
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let lang = get_arg("STATUS_PAGE_LANG");
+    if !lang.is_empty() {
+        match std::fs::read_to_string(&lang) {
+            Ok(contents) => strings.extend(parse_lang(&contents)),
+            Err(err) => log::error!("Failed to read language pack {}: {}", lang, err),
+        }
+    }
+    BRANDING
+        .set(Branding {
+            title: get_arg_or("STATUS_PAGE_TITLE", DEFAULT_TITLE.to_owned()),
+            logo: get_arg("STATUS_PAGE_LOGO"),
+            color,
+            strings,
+        })
+        .ok();
+}
+
+fn parse_lang(contents: &str) -> Vec<(String, String)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .filter_map(|x| x.split_once('='))
+        .map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned()))
+        .collect()
+}
+
+// only what can't break out of the style attribute: #hex or a color name
+fn is_valid_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => !color.is_empty() && color.chars().all(|c| c.is_ascii_alphabetic()),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub(crate) fn render(ready: bool, status: &str) -> String {
+    let Some(b) = BRANDING.get() else {
+        return "".to_owned();
+    };
+    let text = |key: &str| escape(b.strings.get(key).map(|x| x.as_str()).unwrap_or(key));
+    let logo = if b.logo.is_empty() {
+        "".to_owned()
+    } else {
+        format!("<img src=\"{}\" alt=\"\" height=\"48\">", escape(&b.logo))
+    };
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"{}\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1.0\">\n\
+         <title>{}</title>\n</head>\n\
+         <body style=\"font-family: sans-serif; margin: 2em;\">\n\
+         <header style=\"border-bottom: 4px solid {}; padding-bottom: 1em;\">{} <h1>{}</h1></header>\n\
+         <h2 style=\"color: {};\">{}</h2>\n<p>{}: {}</p>\n</body>\n</html>\n",
+        text("lang"),
+        escape(&b.title),
+        b.color,
+        logo,
+        escape(&b.title),
+        if ready { b.color.as_str() } else { "#d32f2f" },
+        text(if ready { "operational" } else { "unavailable" }),
+        text("details"),
+        escape(status)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_branding_input() {
+        assert!(is_valid_color("#fff"));
+        assert!(is_valid_color("#024eff"));
+        assert!(is_valid_color("teal"));
+        assert!(!is_valid_color("red;background:url(x)"));
+        assert!(!is_valid_color("#12345"));
+        assert_eq!(escape("<b>\"&"), "&lt;b&gt;&quot;&amp;");
+        let lang = parse_lang("# german\nlang = de\noperational=Alles in Ordnung\nbad");
+        assert_eq!(lang.len(), 2);
+        assert_eq!(lang[1], ("operational".to_owned(), "Alles in Ordnung".to_owned()));
+    }
+}
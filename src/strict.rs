@@ -0,0 +1,30 @@
+use crate::common::get_arg;
+use hbb_common::{bail, log, ResultType};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// `STRICT=Y` (`--strict`) locks down a private server: a key is required,
+/// relay requests have to present it as punch hole requests do, and the
+/// endpoints without authentication are off: the JSON debug port, the admin
+/// API without a token and all but the probe on HEALTHZ_PORT.
+pub(crate) fn init(key: &str) -> ResultType<()> {
+    let strict = get_arg("STRICT").to_uppercase() == "Y";
+    STRICT.store(strict, Ordering::SeqCst);
+    if !strict {
+        return Ok(());
+    }
+    if key.is_empty() {
+        bail!("STRICT requires a key");
+    }
+    if get_arg("CONSOLE_TOKENS").is_empty() {
+        log::warn!("STRICT without CONSOLE_TOKENS, any local user can use the console");
+    }
+    log::info!("STRICT=Y");
+    Ok(())
+}
+
+#[inline]
+pub(crate) fn is_on() -> bool {
+    STRICT.load(Ordering::Relaxed)
+}
@@ -0,0 +1,136 @@
+use crate::{
+    common::{get_arg, get_arg_or, now},
+    refusal::{self, Reason},
+};
+use hbb_common::log;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// `PeerDiscovery.cmd` of the notice sent over udp to a device whose incoming
+/// connection attempts were refused by a ban or cooldown. `misc` is a json
+/// summary since the last notice: `{"since", "refused", "sources", "reasons"}`,
+/// the reasons as counts by name, e.g. `{"COOLDOWN": 12}`.
+pub(crate) const CMD: &str = "attempts-suppressed";
+const DEFAULT_INTERVAL: u64 = 10; // in minutes
+const MAX_TARGETS: usize = 100_000;
+const MAX_SOURCES: usize = 1_000; // counted per notice
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static INTERVAL: AtomicU64 = AtomicU64::new(DEFAULT_INTERVAL);
+static SENT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+struct Target {
+    since: u64, // unix time of the first refusal not notified yet
+    refused: HashMap<&'static str, usize>,
+    sources: HashSet<String>,
+    notified: Option<Instant>,
+}
+
+lazy_static::lazy_static! {
+    static ref TARGETS: Mutex<HashMap<String, Target>> = Default::default();
+}
+
+/// `SUPPRESSED_NOTICE=Y` notifies devices, at most every
+/// `SUPPRESSED_NOTICE_INTERVAL` minutes each.
+pub(crate) fn init() {
+    ENABLED.store(
+        get_arg("SUPPRESSED_NOTICE").to_uppercase() == "Y",
+        Ordering::SeqCst,
+    );
+    INTERVAL.store(
+        get_arg_or("SUPPRESSED_NOTICE_INTERVAL", DEFAULT_INTERVAL.to_string())
+            .parse()
+            .unwrap_or(DEFAULT_INTERVAL),
+        Ordering::SeqCst,
+    );
+    if ENABLED.load(Ordering::SeqCst) {
+        log::info!(
+            "SUPPRESSED_NOTICE=Y, SUPPRESSED_NOTICE_INTERVAL={}",
+            INTERVAL.load(Ordering::SeqCst)
+        );
+    }
+}
+
+/// An attempt at `id` from `ip` was refused, returns the notice's `misc` if
+/// one is due.
+pub(crate) fn on_refused(id: &str, ip: &str, reason: Reason) -> Option<String> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let interval = Duration::from_secs(INTERVAL.load(Ordering::Relaxed) * 60);
+    let mut targets = TARGETS.lock().ok()?;
+    if targets.len() >= MAX_TARGETS && !targets.contains_key(id) {
+        targets.retain(|_, t| t.notified.is_some_and(|x| x.elapsed() < interval));
+        if targets.len() >= MAX_TARGETS {
+            return None;
+        }
+    }
+    let target = targets.entry(id.to_owned()).or_default();
+    if target.refused.is_empty() {
+        target.since = now();
+    }
+    *target.refused.entry(refusal::name(reason)).or_default() += 1;
+    if target.sources.len() < MAX_SOURCES {
+        target.sources.insert(ip.to_owned());
+    }
+    if target.notified.is_some_and(|x| x.elapsed() < interval) {
+        return None;
+    }
+    target.notified = Some(Instant::now());
+    SENT.fetch_add(1, Ordering::Relaxed);
+    Some(summary(target))
+}
+
+// Also resets what was summed up.
+fn summary(target: &mut Target) -> String {
+    let refused = std::mem::take(&mut target.refused);
+    let sources = std::mem::take(&mut target.sources);
+    serde_json::json!({
+        "since": target.since,
+        "refused": refused.values().sum::<usize>(),
+        "sources": sources.len(),
+        "reasons": refused,
+    })
+    .to_string()
+}
+
+pub(crate) fn status() -> String {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return "off, set SUPPRESSED_NOTICE=Y\n".to_owned();
+    }
+    format!(
+        "notices sent: {}\ndevices: {}\n",
+        SENT.load(Ordering::Relaxed),
+        TARGETS.lock().map(|x| x.len()).unwrap_or(0)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_up_refusals_between_notices() {
+        ENABLED.store(true, Ordering::SeqCst);
+        let first = on_refused("dev", "10.0.0.1", Reason::Cooldown).unwrap();
+        assert!(first.contains(r#""refused":1"#));
+        assert!(on_refused("dev", "10.0.0.2", Reason::Ban).is_none());
+        assert!(on_refused("dev", "10.0.0.2", Reason::Cooldown).is_none());
+        if let Ok(mut targets) = TARGETS.lock() {
+            let target = targets.get_mut("dev").unwrap();
+            target.notified = None;
+        }
+        let v: serde_json::Value =
+            serde_json::from_str(&on_refused("dev", "10.0.0.3", Reason::Ban).unwrap()).unwrap();
+        assert_eq!(v["refused"], 3);
+        assert_eq!(v["sources"], 2);
+        assert_eq!(v["reasons"]["BAN"], 2);
+    }
+}
@@ -0,0 +1,186 @@
+use hbb_common::{
+    bytes_codec::BytesCodec,
+    log,
+    tokio::net::{TcpListener, UdpSocket},
+    tokio_util::udp::UdpFramed,
+    udp::FramedSocket,
+    ResultType,
+};
+use std::sync::Mutex;
+
+const FIRST_FD: i32 = 3; // SD_LISTEN_FDS_START
+// what the sockets passed by systemd are, in the order of the socket unit:
+// TCP_PORT, PORT, NAT_TEST_PORT, WS_PORT and NAT_TEST_PORT for NAT_TEST_UDP
+const KINDS: [bool; 5] = [true, false, true, true, false]; // true for tcp
+
+lazy_static::lazy_static! {
+    // each taken once, later rebuilds bind as usual
+    static ref FDS: Mutex<Vec<Option<i32>>> = Default::default();
+}
+
+/// Take the sockets systemd passed in `LISTEN_FDS`, for socket activation.
+/// Unset those variables, processes we start mustn't take them too.
+pub(crate) fn init() {
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|x| x.parse::<u32>().ok());
+    let n = std::env::var("LISTEN_FDS").ok().and_then(|x| x.parse::<i32>().ok());
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    let (Some(pid), Some(n)) = (pid, n) else {
+        return;
+    };
+    if pid != std::process::id() || n <= 0 {
+        return;
+    }
+    if n as usize > KINDS.len() {
+        log::warn!("{} sockets from systemd, only the first {} are used", n, KINDS.len());
+    }
+    log::info!("{} sockets from systemd", n);
+    if let Ok(mut fds) = FDS.lock() {
+        *fds = (FIRST_FD..FIRST_FD + n).map(Some).collect();
+    }
+}
+
+// the socket of its kind systemd passed for `port`, if any
+fn take(tcp: bool, port: i32) -> Option<i32> {
+    let mut fds = FDS.lock().ok()?;
+    fds.iter_mut()
+        .zip(KINDS)
+        .filter(|(fd, kind)| fd.is_some() && *kind == tcp)
+        .find(|(fd, _)| fd.and_then(local_port) == Some(port))
+        .and_then(|(fd, _)| fd.take())
+}
+
+#[cfg(unix)]
+fn local_port(fd: i32) -> Option<i32> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    // SAFETY: fd is one systemd passed, borrowed only to read its address
+    let socket = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    let port = socket.local_addr().ok().map(|x| x.port() as i32);
+    let _ = socket.into_raw_fd();
+    port
+}
+
+#[cfg(not(unix))]
+fn local_port(_fd: i32) -> Option<i32> {
+    None
+}
+
+/// The tcp listener systemd passed for `port`.
+pub(crate) fn tcp_listener(port: i32) -> Option<ResultType<TcpListener>> {
+    let fd = take(true, port)?;
+    Some(to_tcp_listener(fd))
+}
+
+/// The udp socket systemd passed for `port`.
+pub(crate) fn udp_socket(port: i32) -> Option<ResultType<FramedSocket>> {
+    let fd = take(false, port)?;
+    Some(to_udp_socket(fd))
+}
+
+#[cfg(unix)]
+fn to_tcp_listener(fd: i32) -> ResultType<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+    // SAFETY: fd is a listening socket systemd passed, owned from here on
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
+#[cfg(unix)]
+fn to_udp_socket(fd: i32) -> ResultType<FramedSocket> {
+    use std::os::unix::io::FromRawFd;
+    // SAFETY: fd is a datagram socket systemd passed, owned from here on
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    socket.set_nonblocking(true)?;
+    Ok(FramedSocket::Direct(UdpFramed::new(
+        UdpSocket::from_std(socket)?,
+        BytesCodec::new(),
+    )))
+}
+
+#[cfg(not(unix))]
+fn to_tcp_listener(_fd: i32) -> ResultType<TcpListener> {
+    hbb_common::bail!("socket activation is unix only")
+}
+
+#[cfg(not(unix))]
+fn to_udp_socket(_fd: i32) -> ResultType<FramedSocket> {
+    hbb_common::bail!("socket activation is unix only")
+}
+
+/// Tell systemd about our state, e.g. `READY=1`, if it runs us with
+/// `Type=notify`.
+pub(crate) fn notify(state: &str) {
+    #[cfg(target_os = "linux")]
+    if let Err(err) = send_notify(state) {
+        log::warn!("Failed to notify systemd of {}: {}", state, err);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = state;
+}
+
+#[cfg(target_os = "linux")]
+fn send_notify(state: &str) -> ResultType<()> {
+    use std::os::{linux::net::SocketAddrExt, unix::net};
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => net::SocketAddr::from_abstract_name(name)?,
+        None => net::SocketAddr::from_pathname(&path)?,
+    };
+    let socket = net::UnixDatagram::unbound()?;
+    if socket.send_to_addr(state.as_bytes(), &addr)? != state.len() {
+        hbb_common::bail!("short write");
+    }
+    Ok(())
+}
+
+/// Ping the systemd watchdog at half its `WATCHDOG_USEC`, while the main loop
+/// is alive, see watchdog.
+pub(crate) fn start_watchdog() {
+    let pid = std::env::var("WATCHDOG_PID").ok().and_then(|x| x.parse::<u32>().ok());
+    if pid.is_some_and(|x| x != std::process::id()) {
+        return;
+    }
+    let Some(usec) = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+        .filter(|x| *x > 0)
+    else {
+        return;
+    };
+    log::info!("systemd watchdog every {}ms", usec / 1000);
+    let interval = std::time::Duration::from_micros(usec / 2);
+    let res = std::thread::Builder::new()
+        .name("sd-watchdog".to_owned())
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            if crate::watchdog::is_alive(usec / 1000) {
+                notify("WATCHDOG=1");
+            }
+        });
+    if let Err(err) = res {
+        log::error!("Failed to start the systemd watchdog: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn takes_a_socket_once() {
+        use std::os::unix::io::AsRawFd;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as i32;
+        if let Ok(mut fds) = FDS.lock() {
+            *fds = vec![None, None, Some(listener.as_raw_fd())];
+        }
+        assert_eq!(take(false, port), None);
+        assert_eq!(take(true, port), Some(listener.as_raw_fd()));
+        assert_eq!(take(true, port), None);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{common::get_arg, peer::PeerMap};
+use hbb_common::{log, tokio, ResultType};
+use std::{sync::Mutex, time::Duration};
+
+const FIRST_REPORT: u64 = 600; // in seconds, skip short test runs
+const REPORT_INTERVAL: u64 = 24 * 3600; // in seconds
+const PEER_RANGES: &[usize] = &[10, 100, 1_000, 10_000, 100_000];
+
+lazy_static::lazy_static! {
+    static ref LAST_REPORT: Mutex<String> = Default::default();
+}
+
+/// Off unless TELEMETRY_URL is set. Only the version, platform and a range of
+/// the peer count are sent, no ids, addresses or keys.
+pub(crate) fn start(pm: PeerMap) {
+    let url = get_arg("TELEMETRY_URL");
+    if url.is_empty() {
+        return;
+    }
+    log::info!("TELEMETRY_URL={}", url);
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(FIRST_REPORT)).await;
+        loop {
+            let report = build_report(pm.len().await);
+            if let Ok(mut last) = LAST_REPORT.lock() {
+                *last = report.to_string();
+            }
+            if let Err(err) = send(&url, &report).await {
+                log::debug!("Failed to send usage statistics to {}: {}", url, err);
+            }
+            tokio::time::sleep(Duration::from_secs(REPORT_INTERVAL)).await;
+        }
+    });
+}
+
+async fn send(url: &str, report: &serde_json::Value) -> ResultType<()> {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()?
+        .post(url)
+        .json(report)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn build_report(peers: usize) -> serde_json::Value {
+    serde_json::json!({
+        "version": crate::version::VERSION,
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "peers": peer_range(peers),
+    })
+}
+
+fn peer_range(n: usize) -> String {
+    let mut lower = 0;
+    for &upper in PEER_RANGES {
+        if n <= upper {
+            return format!("{lower}-{upper}");
+        }
+        lower = upper + 1;
+    }
+    format!(">{}", lower - 1)
+}
+
+pub(crate) fn status() -> String {
+    let url = get_arg("TELEMETRY_URL");
+    if url.is_empty() {
+        return "off\n".to_owned();
+    }
+    let last = LAST_REPORT.lock().map(|x| x.clone()).unwrap_or_default();
+    if last.is_empty() {
+        format!("url: {url}\nnothing sent yet, example: {}\n", build_report(0))
+    } else {
+        format!("url: {url}\nlast report: {last}\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_count_is_a_range() {
+        assert_eq!(peer_range(0), "0-10");
+        assert_eq!(peer_range(11), "11-100");
+        assert_eq!(peer_range(5_000), "1001-10000");
+        assert_eq!(peer_range(1_000_000), ">100000");
+        let report = build_report(42).to_string();
+        assert!(report.contains("\"peers\":\"11-100\""));
+        assert!(!report.contains("42"));
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant, SystemTime};
+
+const EXPIRED_AGE: Duration = Duration::from_secs(3600);
+
+/// A point in time for presence bookkeeping. `Instant` may not advance while
+/// the host is suspended (e.g. a self-hosted server on a laptop), which would
+/// keep peers online for the whole sleep after resume. So elapsed is the larger
+/// of the monotonic and the wall clock durations; the wall clock is ignored
+/// when it went backwards.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Stamp {
+    mono: Instant,
+    wall: SystemTime,
+}
+
+impl Stamp {
+    #[inline]
+    pub(crate) fn now() -> Self {
+        Self {
+            mono: Instant::now(),
+            wall: SystemTime::now(),
+        }
+    }
+
+    /// A stamp which is already expired for any timeout we use.
+    pub(crate) fn expired() -> Self {
+        let now = Self::now();
+        Self {
+            mono: now.mono.checked_sub(EXPIRED_AGE).unwrap_or(now.mono),
+            wall: now.wall.checked_sub(EXPIRED_AGE).unwrap_or(SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    /// A stamp `age` before now, e.g. of a registration before a restart.
+    pub(crate) fn ago(age: Duration) -> Self {
+        let now = Self::now();
+        Self {
+            mono: now.mono.checked_sub(age).unwrap_or(now.mono),
+            wall: now.wall.checked_sub(age).unwrap_or(SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.elapsed_at(&Self::now())
+    }
+
+    /// Elapsed milliseconds, saturating instead of wrapping for huge values.
+    #[inline]
+    pub(crate) fn elapsed_ms(&self) -> i64 {
+        to_ms(self.elapsed())
+    }
+
+    fn elapsed_at(&self, now: &Stamp) -> Duration {
+        let mono = now.mono.saturating_duration_since(self.mono);
+        match now.wall.duration_since(self.wall) {
+            Ok(wall) => mono.max(wall),
+            Err(_) => mono,
+        }
+    }
+}
+
+impl PartialEq for Stamp {
+    fn eq(&self, other: &Self) -> bool {
+        self.mono == other.mono
+    }
+}
+
+impl Eq for Stamp {}
+
+impl PartialOrd for Stamp {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Stamp {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.mono.cmp(&other.mono)
+    }
+}
+
+#[inline]
+fn to_ms(d: Duration) -> i64 {
+    i64::try_from(d.as_millis()).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suspend_counts_as_elapsed() {
+        // after resume the monotonic clock only moved 1s but 2 hours passed
+        let before = Stamp::now();
+        let after = Stamp {
+            mono: before.mono + Duration::from_secs(1),
+            wall: before.wall + Duration::from_secs(7200),
+        };
+        assert_eq!(before.elapsed_at(&after), Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn wall_clock_going_backwards_is_ignored() {
+        let before = Stamp::now();
+        let after = Stamp {
+            mono: before.mono + Duration::from_secs(5),
+            wall: before.wall - Duration::from_secs(3600),
+        };
+        assert_eq!(before.elapsed_at(&after), Duration::from_secs(5));
+        // and a stamp from the future never underflows
+        let future = Stamp {
+            mono: before.mono + Duration::from_secs(5),
+            wall: before.wall + Duration::from_secs(5),
+        };
+        assert_eq!(future.elapsed_at(&before), Duration::ZERO);
+    }
+
+    #[test]
+    fn huge_elapsed_values_saturate() {
+        assert!(Stamp::expired().elapsed_ms() >= EXPIRED_AGE.as_millis() as i64);
+        assert_eq!(to_ms(Duration::MAX), i64::MAX);
+        assert!(Stamp::expired() <= Stamp::now());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::common::get_arg;
+use hbb_common::{bail, log, ResultType};
+use once_cell::sync::OnceCell;
+use std::{fs::File, io::BufReader, sync::Arc};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+static WS_ACCEPTOR: OnceCell<TlsAcceptor> = OnceCell::new();
+static TCP_ACCEPTOR: OnceCell<TlsAcceptor> = OnceCell::new();
+
+/// `WS_TLS_CERT` and `WS_TLS_KEY`, PEM files of a certificate chain and its
+/// private key, make the WebSocket listener serve wss itself, for web clients
+/// and networks only allowing HTTPS, without a reverse proxy in front.
+/// `TLS_CERT` and `TLS_KEY` wrap the tcp listener likewise, so registrations
+/// and punch hole requests over tcp can't be read on the way. Plaintext
+/// unless set. Read once, a renewed certificate needs a restart.
+pub(crate) fn init() -> ResultType<()> {
+    if let Some(acceptor) = load("WS_TLS_CERT", "WS_TLS_KEY")? {
+        WS_ACCEPTOR.set(acceptor).ok();
+        log::info!("WS_TLS_CERT={}, serving wss", get_arg("WS_TLS_CERT"));
+    }
+    if let Some(acceptor) = load("TLS_CERT", "TLS_KEY")? {
+        TCP_ACCEPTOR.set(acceptor).ok();
+        log::info!("TLS_CERT={}, serving tls over tcp", get_arg("TLS_CERT"));
+    }
+    Ok(())
+}
+
+fn load(cert_name: &str, key_name: &str) -> ResultType<Option<TlsAcceptor>> {
+    let (cert, key) = (get_arg(cert_name), get_arg(key_name));
+    if cert.is_empty() && key.is_empty() {
+        return Ok(None);
+    }
+    if cert.is_empty() || key.is_empty() {
+        bail!("{} and {} go together", cert_name, key_name);
+    }
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(&cert)?, load_key(&key)?)?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+fn load_certs(path: &str) -> ResultType<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        bail!("no certificate in {}", path);
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> ResultType<PrivateKey> {
+    for item in rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    bail!("no private key in {}", path)
+}
+
+/// The acceptor of wss connections, None for plain ws.
+#[inline]
+pub(crate) fn ws_acceptor() -> Option<&'static TlsAcceptor> {
+    WS_ACCEPTOR.get()
+}
+
+/// The acceptor of tls connections on the tcp listener, None for plaintext.
+#[inline]
+pub(crate) fn tcp_acceptor() -> Option<&'static TlsAcceptor> {
+    TCP_ACCEPTOR.get()
+}
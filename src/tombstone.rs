@@ -0,0 +1,116 @@
+use crate::{
+    common::{get_arg, now},
+    database::Database,
+};
+use hbb_common::log;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+const MAX_BLOCKED: usize = 100_000;
+const MAX_LISTED: usize = 100;
+
+static BLOCK: AtomicU64 = AtomicU64::new(0); // in minutes
+
+lazy_static::lazy_static! {
+    // id -> unix time it may register again
+    static ref BLOCKED: Mutex<HashMap<String, u64>> = Default::default();
+}
+
+/// `TOMBSTONE_BLOCK` is how many minutes an id deleted by an operator can't
+/// be registered again, 0 is off.
+pub(crate) fn init() {
+    let block = get_arg("TOMBSTONE_BLOCK").parse().unwrap_or(0);
+    BLOCK.store(block, Ordering::SeqCst);
+    if block > 0 {
+        log::info!("TOMBSTONE_BLOCK={}", block);
+    }
+}
+
+/// Pick up the blocks still running from before a restart.
+pub(crate) async fn load(db: &Database) {
+    match db.get_tombstones(None, now() as i64).await {
+        Ok(tombstones) => {
+            for (id, _, _, _, until) in tombstones {
+                on_removed(&id, until as u64);
+            }
+        }
+        Err(err) => log::error!("Failed to load tombstones: {}", err),
+    }
+}
+
+/// Until when an id deleted now can't register again, 0 for not blocked.
+pub(crate) fn block_until() -> u64 {
+    match BLOCK.load(Ordering::Relaxed) {
+        0 => 0,
+        block => now() + block * 60,
+    }
+}
+
+pub(crate) fn on_removed(id: &str, until: u64) {
+    let now = now();
+    if until <= now {
+        return;
+    }
+    let Ok(mut blocked) = BLOCKED.lock() else {
+        return;
+    };
+    if blocked.len() >= MAX_BLOCKED && !blocked.contains_key(id) {
+        blocked.retain(|_, x| *x > now);
+        if blocked.len() >= MAX_BLOCKED {
+            return;
+        }
+    }
+    blocked.insert(id.to_owned(), until);
+}
+
+pub(crate) fn is_blocked(id: &str) -> bool {
+    let Ok(mut blocked) = BLOCKED.lock() else {
+        return false;
+    };
+    match blocked.get(id) {
+        Some(until) if *until > now() => true,
+        Some(_) => {
+            blocked.remove(id);
+            false
+        }
+        None => false,
+    }
+}
+
+/// The latest tombstones, of `id` or all, as csv.
+pub(crate) async fn status(db: &Database, id: Option<&str>) -> String {
+    let tombstones = match db.get_tombstones(id, 0).await {
+        Ok(x) => x,
+        Err(err) => return format!("{err}\n"),
+    };
+    let mut res = "time,id,reason,operator,blocked_until\n".to_owned();
+    for (id, reason, operator, tm, until) in tombstones.iter().take(MAX_LISTED) {
+        let _ = writeln!(res, "{},{},{},{},{}", tm, id, reason, operator, until);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_until_expired() {
+        let now = now();
+        on_removed("deleted", now + 60);
+        on_removed("past", now - 1);
+        assert!(is_blocked("deleted"));
+        assert!(!is_blocked("past"));
+        assert!(!is_blocked("other"));
+        if let Ok(mut blocked) = BLOCKED.lock() {
+            blocked.insert("deleted".to_owned(), now - 1);
+        }
+        assert!(!is_blocked("deleted"));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::common::TRACE_PREFIX;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+const EXPIRE: u64 = 60; // in seconds, longer than any exchange
+const MAX_TRACES: usize = 100_000;
+
+static NEXT: AtomicU32 = AtomicU32::new(0);
+
+lazy_static::lazy_static! {
+    // requester's address -> the trace of its exchange
+    static ref TRACES: Mutex<HashMap<SocketAddr, (u32, Instant)>> = Default::default();
+}
+
+/// The correlation id of a punch hole exchange, shared by the log lines of
+/// A's request, the forward to B and the response, over udp and tcp alike.
+/// Displayed as `trace=<hex> `, the start of such a line, which
+/// `LOG_FORMAT=json` puts in a field of its own.
+#[derive(Clone, Copy)]
+pub(crate) struct Trace(Option<u32>);
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, "{}{:08x} ", TRACE_PREFIX, id),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Start the exchange of a request from `addr_a`, replacing an earlier one.
+pub(crate) fn start(addr_a: SocketAddr) -> Trace {
+    let mut id = NEXT.fetch_add(1, Ordering::Relaxed);
+    if id == 0 {
+        // random, so ids of different runs don't collide in the logs
+        id = u32::from_le_bytes(
+            sodiumoxide::randombytes::randombytes(4)
+                .try_into()
+                .unwrap_or_default(),
+        );
+        NEXT.store(id.wrapping_add(1), Ordering::Relaxed);
+    }
+    if let Ok(mut traces) = TRACES.lock() {
+        if traces.len() >= MAX_TRACES {
+            traces.retain(|_, x| x.1.elapsed().as_secs() < EXPIRE);
+        }
+        if traces.len() < MAX_TRACES || traces.contains_key(&addr_a) {
+            traces.insert(addr_a, (id, Instant::now()));
+        }
+    }
+    Trace(Some(id))
+}
+
+/// The exchange of `addr_a`, if any, e.g. for its relay request.
+pub(crate) fn get(addr_a: SocketAddr) -> Trace {
+    Trace(TRACES.lock().ok().and_then(|x| x.get(&addr_a).map(|x| x.0)))
+}
+
+/// The exchange of `addr_a` got its response.
+pub(crate) fn finish(addr_a: SocketAddr) -> Trace {
+    Trace(TRACES.lock().ok().and_then(|mut x| x.remove(&addr_a)).map(|x| x.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_request_and_response() {
+        let addr: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let request = start(addr).to_string();
+        assert!(request.starts_with(TRACE_PREFIX) && request.ends_with(' '));
+        assert_eq!(get(addr).to_string(), request);
+        assert_eq!(finish(addr).to_string(), request);
+        assert_eq!(get(addr).to_string(), "");
+    }
+}
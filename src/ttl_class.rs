@@ -0,0 +1,230 @@
+use crate::{
+    common::{get_arg, get_arg_or, now},
+    peer::PeerMap,
+};
+use hbb_common::{log, tokio};
+use ipnetwork::IpNetwork;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+const PURGE_INTERVAL: u64 = 24 * 3600; // in seconds
+const EVICT_INTERVAL: u64 = 3600; // in seconds
+const DEFAULT_IDLE: u64 = 24; // in hours
+
+static TTL: AtomicU64 = AtomicU64::new(0); // in days, 0 keeps records forever
+static IDLE: AtomicU64 = AtomicU64::new(DEFAULT_IDLE); // in hours, 0 keeps peers in memory
+static PURGED: AtomicUsize = AtomicUsize::new(0);
+static EVICTED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Class {
+    /// Kept in memory only.
+    Ephemeral,
+    /// Purged `PEER_TTL` days after it was last seen.
+    Standard,
+    /// Never purged.
+    Pinned,
+}
+
+impl Class {
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ephemeral" => Some(Class::Ephemeral),
+            "standard" => Some(Class::Standard),
+            "pinned" => Some(Class::Pinned),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Class::Ephemeral => "ephemeral",
+            Class::Standard => "standard",
+            Class::Pinned => "pinned",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Rules {
+    ids: HashMap<String, Class>,
+    prefixes: Vec<(String, Class)>, // longest first
+    nets: Vec<(IpNetwork, Class)>,  // most specific first
+}
+
+lazy_static::lazy_static! {
+    static ref RULES: Mutex<Rules> = Default::default();
+}
+
+/// `PEER_CLASSES` is a comma separated list of `<id>=<class>`,
+/// `<prefix>*=<class>` or `<cidr>=<class>`, matched against the registering
+/// IP, class is `ephemeral`, `standard` or `pinned`. Peers matching none
+/// are standard, purged `PEER_TTL` days after they were last seen.
+pub(crate) fn init() {
+    let v = get_arg("PEER_CLASSES");
+    for x in v.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let rule = x
+            .split_once('=')
+            .and_then(|(target, class)| Some((target.trim(), Class::parse(class.trim())?)));
+        match rule {
+            Some((target, class)) => set(target, class),
+            None => log::error!(
+                "Invalid peer class {}, expected <id|prefix*|cidr>=<ephemeral|standard|pinned>",
+                x
+            ),
+        }
+    }
+    let ttl = get_arg("PEER_TTL").parse().unwrap_or(0);
+    TTL.store(ttl, Ordering::SeqCst);
+    let idle = get_arg_or("PEER_IDLE_HOURS", DEFAULT_IDLE.to_string())
+        .parse()
+        .unwrap_or(DEFAULT_IDLE);
+    IDLE.store(idle, Ordering::SeqCst);
+    log::info!(
+        "PEER_CLASSES={}, PEER_TTL={}, PEER_IDLE_HOURS={}",
+        v,
+        ttl,
+        idle
+    );
+}
+
+pub(crate) fn set(target: &str, class: Class) {
+    let Ok(mut rules) = RULES.lock() else {
+        return;
+    };
+    if let Some(prefix) = target.strip_suffix('*') {
+        rules.prefixes.retain(|x| x.0 != prefix);
+        rules.prefixes.push((prefix.to_owned(), class));
+        rules.prefixes.sort_by_key(|x| std::cmp::Reverse(x.0.len()));
+    } else if let Ok(net) = target.parse::<IpNetwork>() {
+        rules.nets.retain(|x| x.0 != net);
+        rules.nets.push((net, class));
+        rules.nets.sort_by_key(|x| std::cmp::Reverse(x.0.prefix()));
+    } else {
+        rules.ids.insert(target.to_owned(), class);
+    }
+}
+
+pub(crate) fn remove(target: &str) {
+    let Ok(mut rules) = RULES.lock() else {
+        return;
+    };
+    if let Some(prefix) = target.strip_suffix('*') {
+        rules.prefixes.retain(|x| x.0 != prefix);
+    } else if let Ok(net) = target.parse::<IpNetwork>() {
+        rules.nets.retain(|x| x.0 != net);
+    } else {
+        rules.ids.remove(target);
+    }
+}
+
+/// The class of `id` registering from `ip`: by id, then prefix, then network.
+pub(crate) fn get(id: &str, ip: Option<IpAddr>) -> Class {
+    let Ok(rules) = RULES.lock() else {
+        return Class::Standard;
+    };
+    if let Some(class) = rules.ids.get(id) {
+        return *class;
+    }
+    if let Some(x) = rules.prefixes.iter().find(|x| id.starts_with(&x.0)) {
+        return x.1;
+    }
+    ip.and_then(|ip| rules.nets.iter().find(|x| x.0.contains(ip.to_canonical())))
+        .map(|x| x.1)
+        .unwrap_or(Class::Standard)
+}
+
+/// Every hour, drop the peers not registered for `PEER_IDLE_HOURS` from
+/// memory. Once a day, record when online peers were seen and purge
+/// standard peers not seen for `PEER_TTL` days from the database.
+pub(crate) fn start(pm: PeerMap) {
+    if IDLE.load(Ordering::SeqCst) > 0 {
+        let pm = pm.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(EVICT_INTERVAL)).await;
+                let idle = Duration::from_secs(IDLE.load(Ordering::Relaxed) * 3600);
+                let n = pm.evict_idle(idle).await;
+                EVICTED.fetch_add(n, Ordering::Relaxed);
+                if n > 0 {
+                    log::info!("Dropped {} idle peers from memory", n);
+                }
+            }
+        });
+    }
+    if TTL.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(PURGE_INTERVAL)).await;
+            let now = now();
+            if let Err(err) = pm.touch_online(now).await {
+                log::error!("Failed to record when peers were seen: {}", err);
+                continue;
+            }
+            let before = now.saturating_sub(TTL.load(Ordering::Relaxed) * 24 * 3600);
+            match pm.purge_unseen(before).await {
+                Ok(n) => {
+                    PURGED.fetch_add(n, Ordering::Relaxed);
+                    log::info!(
+                        "Purged {} peers not seen for {} days",
+                        n,
+                        TTL.load(Ordering::Relaxed)
+                    );
+                }
+                Err(err) => log::error!("Failed to purge peers: {}", err),
+            }
+        }
+    });
+}
+
+pub(crate) fn status() -> String {
+    let mut res = format!(
+        "ttl: {} days\npurged: {}\nidle: {} hours\nevicted: {}\n",
+        TTL.load(Ordering::Relaxed),
+        PURGED.load(Ordering::Relaxed),
+        IDLE.load(Ordering::Relaxed),
+        EVICTED.load(Ordering::Relaxed)
+    );
+    if let Ok(rules) = RULES.lock() {
+        for (id, class) in rules.ids.iter() {
+            let _ = writeln!(res, "{} {}", id, class.as_str());
+        }
+        for (prefix, class) in rules.prefixes.iter() {
+            let _ = writeln!(res, "{}* {}", prefix, class.as_str());
+        }
+        for (net, class) in rules.nets.iter() {
+            let _ = writeln!(res, "{} {}", net, class.as_str());
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes_by_id_prefix_then_network() {
+        set("kiosk-*", Class::Ephemeral);
+        set("kiosk-lobby", Class::Pinned);
+        set("192.168.0.0/16", Class::Pinned);
+        let ip = |x: &str| x.parse::<IpAddr>().ok();
+        assert_eq!(get("kiosk-lobby", ip("8.8.8.8")), Class::Pinned);
+        assert_eq!(get("kiosk-7", ip("192.168.1.1")), Class::Ephemeral);
+        assert_eq!(get("123456789", ip("192.168.1.1")), Class::Pinned);
+        assert_eq!(get("123456789", ip("8.8.8.8")), Class::Standard);
+        assert_eq!(get("123456789", None), Class::Standard);
+        remove("kiosk-*");
+        assert_eq!(get("kiosk-7", None), Class::Standard);
+    }
+}
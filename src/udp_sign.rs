@@ -0,0 +1,90 @@
+use crate::common::{get_arg, now_ms};
+use hbb_common::{log, protobuf::Message as _, rendezvous_proto::RendezvousMessage};
+use once_cell::sync::OnceCell;
+use sodiumoxide::crypto::sign;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of the field the signature is appended as, unknown to clients that
+/// don't check it, so they skip it.
+pub(crate) const FIELD: u32 = 1000;
+
+static SK: OnceCell<sign::SecretKey> = OnceCell::new();
+static SIGNED: AtomicUsize = AtomicUsize::new(0);
+
+/// `UDP_SIGN=Y` signs the UDP answers to registrations and punch holes, and
+/// what's passed on to peers over UDP, with the server's key, so a client on
+/// a hostile network can tell them from datagrams forged by someone off the
+/// path. The signature is a last field of number `FIELD`: the milliseconds
+/// since the epoch, 8 bytes big endian, then the ed25519 signature of the
+/// datagram before the field followed by those 8 bytes.
+pub(crate) fn init(sk: Option<&sign::SecretKey>) {
+    if get_arg("UDP_SIGN").to_uppercase() != "Y" {
+        return;
+    }
+    let Some(sk) = sk else {
+        log::error!("UDP_SIGN needs the key pair of the server, not signing");
+        return;
+    };
+    log::info!("UDP_SIGN=Y");
+    SK.set(sk.clone()).ok();
+}
+
+/// Append the signature to `msg` if on, right before it's sent over UDP.
+pub(crate) fn apply(msg: &mut RendezvousMessage) {
+    let Some(sk) = SK.get() else {
+        return;
+    };
+    let Ok(bytes) = msg.write_to_bytes() else {
+        return;
+    };
+    let ms = now_ms();
+    msg.mut_unknown_fields()
+        .add_length_delimited(FIELD, seal(&bytes, ms, sk));
+    SIGNED.fetch_add(1, Ordering::Relaxed);
+}
+
+fn seal(bytes: &[u8], ms: u64, sk: &sign::SecretKey) -> Vec<u8> {
+    let mut data = bytes.to_vec();
+    data.extend_from_slice(&ms.to_be_bytes());
+    let mut res = ms.to_be_bytes().to_vec();
+    res.extend_from_slice(sign::sign_detached(&data, sk).as_ref());
+    res
+}
+
+pub(crate) fn status() -> String {
+    if SK.get().is_none() {
+        return "off, set UDP_SIGN=Y\n".to_owned();
+    }
+    format!("signed: {}\n", SIGNED.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::rendezvous_proto::RegisterPeerResponse;
+
+    #[test]
+    fn signature_is_a_trailing_field() {
+        let (pk, sk) = sign::gen_keypair();
+        let mut msg = RendezvousMessage::new();
+        msg.set_register_peer_response(RegisterPeerResponse {
+            request_pk: true,
+            ..Default::default()
+        });
+        let plain = msg.write_to_bytes().unwrap();
+        SK.set(sk).ok();
+        apply(&mut msg);
+        let signed = msg.write_to_bytes().unwrap();
+        // tag of field 1000 length delimited, length 72, 8 + 64 bytes
+        assert_eq!(&signed[..plain.len()], &plain[..]);
+        assert_eq!(&signed[plain.len()..plain.len() + 3], &[0xc2, 0x3e, 72]);
+        let (ms, sig) = signed[plain.len() + 3..].split_at(8);
+        let mut data = plain.clone();
+        data.extend_from_slice(ms);
+        let sig = sign::Signature::try_from(sig).unwrap();
+        assert!(sign::verify_detached(&sig, &data, &pk));
+        // clients not checking it still read the message
+        let parsed = RendezvousMessage::parse_from_bytes(&signed).unwrap();
+        assert!(parsed.register_peer_response().request_pk);
+    }
+}
@@ -1,8 +1,10 @@
 use dns_lookup::{lookup_addr, lookup_host};
 use hbb_common::{bail, ResultType};
+use hbbs::{client_config, common::gen_sk, pk_attest};
 use sodiumoxide::crypto::sign;
 use std::{
     env,
+    io::{self, BufRead, Write},
     net::{IpAddr, TcpStream},
     process, str,
 };
@@ -12,8 +14,12 @@ fn print_help() {
         "Usage:
     rustdesk-utils [command]\n
 Available Commands:
+    init                                         Set up a new server in the current directory, interactively
     genkeypair                                   Generate a new keypair
     validatekeypair [public key] [secret key]    Validate an existing keypair
+    rotatekey                                    Replace the hbbs keypair in the current directory, keeping the old one for a grace period
+    certifyca [CA secret key] [public key]       Certify an intermediate CA for PK_CA
+    certifypk [CA secret key] [id] [pk]          Certify the pk of a device for PK_CA, append it to the certificate of the CA
     doctor [rustdesk-server]                     Check for server connection problems"
     );
     process::exit(0x0001);
@@ -72,6 +78,124 @@ fn validate_keypair(pk: &str, sk: &str) -> ResultType<()> {
     Ok(())
 }
 
+fn rotate_key() -> ResultType<()> {
+    const SK_FILE: &str = "id_ed25519";
+    const OLD_SK_FILE: &str = "id_ed25519.old";
+    if !std::path::Path::new(SK_FILE).exists() {
+        bail!("No {SK_FILE} in the current directory, run it in the hbbs working directory");
+    }
+    if std::path::Path::new(OLD_SK_FILE).exists() {
+        bail!("{OLD_SK_FILE} exists, a rotation is already in progress. Remove it once hbbs reports the old key is ready to retire");
+    }
+    // same as hbbs, avoid characters which break the client config string
+    let (mut pk, mut sk) = sign::gen_keypair();
+    for _ in 0..300 {
+        let s = base64::encode(&pk);
+        if !s.contains('/') && !s.contains(':') {
+            break;
+        }
+        (pk, sk) = sign::gen_keypair();
+    }
+    std::fs::rename(SK_FILE, OLD_SK_FILE)?;
+    std::fs::rename(format!("{SK_FILE}.pub"), format!("{OLD_SK_FILE}.pub")).ok();
+    std::fs::write(SK_FILE, base64::encode(&sk))?;
+    std::fs::write(format!("{SK_FILE}.pub"), base64::encode(&pk))?;
+    println!("New Public Key:  {}", base64::encode(&pk));
+    println!("The old key pair was moved to {OLD_SK_FILE}(.pub).");
+    println!("Restart hbbs, it accepts both keys for KEY_ROTATION_GRACE days (default 30).");
+    println!("Distribute the new public key to clients, then check progress with:");
+    println!("    printf 'key-rotation' | nc 127.0.0.1 21115");
+    println!("Remove {OLD_SK_FILE} and restart hbbs once it reports ready to retire.");
+    Ok(())
+}
+
+// prints one link of a PK_CA certificate
+fn certify(sk: &str, statement: &[u8]) -> ResultType<()> {
+    let Some(sk) = base64::decode(sk)
+        .ok()
+        .and_then(|x| sign::SecretKey::from_slice(&x))
+    else {
+        bail!("Invalid secret key");
+    };
+    println!("{}", base64::encode(sign::sign(statement, &sk)));
+    Ok(())
+}
+
+fn ask(question: &str, default: &str) -> ResultType<String> {
+    if default.is_empty() {
+        print!("{question}: ");
+    } else {
+        print!("{question} [{default}]: ");
+    }
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_owned())
+}
+
+fn init() -> ResultType<()> {
+    println!("Setting up hbbs and hbbr in the current directory, enter accepts [defaults].\n");
+    let host = ask("Host name or IP address clients reach this server at", "")?;
+    if host.is_empty() {
+        bail!("A host name or IP address is required");
+    }
+    let relay = ask("Relay server, if hbbr runs on another host", &host)?;
+    let relay_key = ask("Refuse clients without the key on the relay too (y/n)", "y")?;
+    // loads id_ed25519 if there is one, like hbbs does on start
+    let (pk, _) = gen_sk(0);
+    if pk.is_empty() {
+        bail!("Failed to write id_ed25519, is the current directory writable?");
+    }
+    println!("\nPublic key (id_ed25519.pub): {pk}");
+    let relay = if relay == host { "" } else { relay.as_str() };
+    let write_env = !std::path::Path::new(".env").exists()
+        || ask(".env exists, overwrite it (y/n)", "n")?.eq_ignore_ascii_case("y");
+    if write_env {
+        let mut env = "# Written by rustdesk-utils init, all options are described in\n\
+                       # docs/environment-variables.md of rustdesk-server.\n"
+            .to_owned();
+        if relay.is_empty() {
+            env += "# Relay handed to clients, only needed if hbbr runs on another host\n";
+            env += "# RELAY_SERVERS=\n";
+        } else {
+            env += &format!("RELAY_SERVERS={relay}\n");
+        }
+        if relay_key.eq_ignore_ascii_case("y") {
+            env += "# hbbr refuses clients without the key, hbbs always does\nKEY=_\n";
+        }
+        std::fs::write(".env", env)?;
+        println!("Configuration written to .env");
+    }
+    println!(
+        "
+Open these ports to clients, e.g. with ufw:
+    sudo ufw allow 21115:21119/tcp
+    sudo ufw allow 21116/udp
+or firewalld:
+    sudo firewall-cmd --permanent --add-port=21115-21119/tcp --add-port=21116/udp
+    sudo firewall-cmd --reload
+21115 is also the loopback console, hbbs only answers it from 127.0.0.1.
+
+Start hbbs and hbbr in this directory, then configure clients with:"
+    );
+    let config = client_config::encode(&host, relay, &pk);
+    println!("    ID server: {host}");
+    if !relay.is_empty() {
+        println!("    Relay server: {relay}");
+    }
+    println!("    Key: {pk}");
+    println!("or import this string in Settings > Network > ID/Relay server:");
+    println!("    {config}");
+    println!("or run rustdesk --config {config}");
+    println!("or open {}", client_config::deep_link(&config));
+    let payload = client_config::qr_payload(&config);
+    std::fs::write("client-config.png", client_config::qr_png(&payload)?)?;
+    println!("or scan client-config.png with the mobile client, it encodes:");
+    println!("    {payload}");
+    Ok(())
+}
+
 fn doctor_tcp(address: std::net::IpAddr, port: &str, desc: &str) {
     let start = std::time::Instant::now();
     let conn = format!("{address}:{port}");
@@ -147,6 +271,12 @@ fn main() {
 
     let command = args[1].to_lowercase();
     match command.as_str() {
+        "init" => {
+            if let Err(e) = init() {
+                println!("{e}");
+                process::exit(0x0001);
+            }
+        }
         "genkeypair" => gen_keypair(),
         "validatekeypair" => {
             if args.len() <= 3 {
@@ -159,6 +289,38 @@ fn main() {
             }
             println!("Key pair is VALID");
         }
+        "rotatekey" => {
+            if let Err(e) = rotate_key() {
+                println!("{e}");
+                process::exit(0x0001);
+            }
+        }
+        "certifyca" => {
+            if args.len() <= 3 {
+                error_then_help("You must supply the CA secret key and the public key");
+            }
+            let res = match base64::decode(&args[3]) {
+                Ok(pk) => certify(&args[2], &pk_attest::intermediate(&pk)),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = res {
+                println!("{e}");
+                process::exit(0x0001);
+            }
+        }
+        "certifypk" => {
+            if args.len() <= 4 {
+                error_then_help("You must supply the CA secret key, the id and the pk");
+            }
+            let res = match base64::decode(&args[4]) {
+                Ok(pk) => certify(&args[2], &pk_attest::leaf(&args[3], &pk)),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = res {
+                println!("{e}");
+                process::exit(0x0001);
+            }
+        }
         "doctor" => {
             if args.len() <= 2 {
                 error_then_help("You must supply the rustdesk-server address");
@@ -0,0 +1,115 @@
+use crate::{
+    common::{get_arg, get_arg_or, now},
+    database::Database,
+};
+use hbb_common::{log, tokio};
+use once_cell::sync::OnceCell;
+use std::{sync::Mutex, time::Duration};
+
+const CHECK_INTERVAL: u64 = 600; // in seconds
+const DEFAULT_MIN_FREE: i64 = 10; // in percent of the file
+
+static DB: OnceCell<Database> = OnceCell::new();
+
+lazy_static::lazy_static! {
+    static ref LAST: Mutex<String> = Mutex::new("never".to_owned());
+}
+
+/// `DB_VACUUM_WINDOW` is a range of UTC hours, e.g. `2-5`, in which the
+/// database is compacted at most once a day, when at least
+/// `DB_VACUUM_MIN_FREE` percent of it is free pages. Compacting blocks
+/// other queries while it runs, so it's kept to low-traffic hours.
+pub(crate) fn start(db: Database) {
+    DB.set(db.clone()).ok();
+    let v = get_arg("DB_VACUUM_WINDOW");
+    if v.is_empty() {
+        return;
+    }
+    let Some(window) = parse_window(&v) else {
+        log::error!("Invalid DB_VACUUM_WINDOW {}, expected <hour>-<hour> in UTC", v);
+        return;
+    };
+    let min_free = get_arg_or("DB_VACUUM_MIN_FREE", DEFAULT_MIN_FREE.to_string())
+        .parse()
+        .unwrap_or(DEFAULT_MIN_FREE);
+    log::info!("DB_VACUUM_WINDOW={}, DB_VACUUM_MIN_FREE={}%", v, min_free);
+    tokio::spawn(async move {
+        let mut last_day = 0;
+        loop {
+            tokio::time::sleep(Duration::from_secs(CHECK_INTERVAL)).await;
+            let now = now();
+            let day = now / 86400;
+            if day == last_day || !in_window((now / 3600) % 24, window) {
+                continue;
+            }
+            last_day = day;
+            let (size, free) = match db.get_size().await {
+                Ok(x) => x,
+                Err(err) => {
+                    log::error!("Failed to get the database size: {}", err);
+                    continue;
+                }
+            };
+            if free * 100 < size * min_free {
+                continue;
+            }
+            log::info!("Compacting the database, {} of {} bytes free", free, size);
+            let res = match db.vacuum().await {
+                Ok(_) => format!("{} reclaimed {} bytes", now, free),
+                Err(err) => {
+                    log::error!("Failed to compact the database: {}", err);
+                    format!("{} failed: {}", now, err)
+                }
+            };
+            if let Ok(mut last) = LAST.lock() {
+                *last = res;
+            }
+        }
+    });
+}
+
+fn parse_window(v: &str) -> Option<(u64, u64)> {
+    let (start, end) = v.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    (start < 24 && end <= 24 && start != end).then_some((start, end))
+}
+
+// end exclusive, may wrap around midnight
+fn in_window(hour: u64, (start, end): (u64, u64)) -> bool {
+    if start < end {
+        hour >= start && hour < end
+    } else {
+        hour >= start || hour < end
+    }
+}
+
+pub(crate) async fn status() -> String {
+    let Some(db) = DB.get() else {
+        return "".to_owned();
+    };
+    let size = match db.get_size().await {
+        Ok((size, free)) => format!("size: {} bytes\nfree: {} bytes\n", size, free),
+        Err(err) => format!("{err}\n"),
+    };
+    format!(
+        "{}last vacuum: {}\n",
+        size,
+        LAST.lock().map(|x| x.clone()).unwrap_or_default()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_may_wrap_around_midnight() {
+        assert_eq!(parse_window("2-5"), Some((2, 5)));
+        assert_eq!(parse_window("22-24"), Some((22, 24)));
+        assert_eq!(parse_window("5-5"), None);
+        assert_eq!(parse_window("25-3"), None);
+        assert!(in_window(2, (2, 5)) && !in_window(5, (2, 5)));
+        assert!(in_window(23, (22, 4)) && in_window(1, (22, 4)));
+        assert!(!in_window(12, (22, 4)));
+    }
+}
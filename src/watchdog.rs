@@ -0,0 +1,172 @@
+use crate::common::{get_arg, get_arg_or};
+use hbb_common::log;
+use std::{
+    fmt::Write as _,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_TIMEOUT: u64 = 30; // in seconds
+pub(crate) const HEARTBEAT_INTERVAL: u64 = 1_000; // in ms
+
+/// What the main loop is doing, reported when it stalls.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Stage {
+    Timer = 0,
+    Queue = 1,
+    Udp = 2,
+    Tcp = 3,
+    NatTest = 4,
+    Ws = 5,
+}
+
+const STAGES: [Stage; 6] = [
+    Stage::Timer,
+    Stage::Queue,
+    Stage::Udp,
+    Stage::Tcp,
+    Stage::NatTest,
+    Stage::Ws,
+];
+
+static LAST_BEAT: AtomicU64 = AtomicU64::new(0); // in ms since STARTED
+static STAGE: AtomicUsize = AtomicUsize::new(0);
+static STALLS: AtomicUsize = AtomicUsize::new(0);
+static STALLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+}
+
+/// Called by the main loop on every event, and at least every HEARTBEAT_INTERVAL.
+#[inline]
+pub(crate) fn beat(stage: Stage) {
+    LAST_BEAT.store(now_ms(), Ordering::Relaxed);
+    STAGE.store(stage as usize, Ordering::Relaxed);
+}
+
+#[inline]
+fn now_ms() -> u64 {
+    STARTED.elapsed().as_millis() as u64
+}
+
+/// Watch the main loop from an os thread, which keeps running when the async
+/// runtime is blocked, e.g. by a std Mutex held across a slow call.
+pub(crate) fn start() {
+    let timeout = get_arg_or("WATCHDOG_TIMEOUT", DEFAULT_TIMEOUT.to_string())
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_TIMEOUT);
+    if timeout == 0 {
+        return;
+    }
+    let abort = get_arg("WATCHDOG_ABORT").to_uppercase() == "Y";
+    log::info!("WATCHDOG_TIMEOUT={}s, WATCHDOG_ABORT={}", timeout, abort);
+    beat(Stage::Timer);
+    let res = std::thread::Builder::new()
+        .name("watchdog".to_owned())
+        .spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(HEARTBEAT_INTERVAL));
+            check(timeout * 1000, abort);
+        });
+    if let Err(err) = res {
+        log::error!("Failed to start watchdog: {}", err);
+    }
+}
+
+fn check(timeout: u64, abort: bool) {
+    let elapsed = now_ms().saturating_sub(LAST_BEAT.load(Ordering::Relaxed));
+    if !is_stalled(elapsed, timeout) {
+        if STALLED.swap(false, Ordering::Relaxed) {
+            log::warn!("Main loop recovered");
+        }
+        return;
+    }
+    // once per stall
+    if STALLED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    STALLS.fetch_add(1, Ordering::Relaxed);
+    log::error!(
+        "Main loop stalled for {}ms in {:?}\n{}",
+        elapsed,
+        STAGES[STAGE.load(Ordering::Relaxed)],
+        dump_threads()
+    );
+    if abort {
+        log::error!("Aborting so that the supervisor restarts hbbs");
+        log::logger().flush();
+        std::process::abort();
+    }
+}
+
+/// Whether the main loop did something within `timeout` ms.
+pub(crate) fn is_alive(timeout: u64) -> bool {
+    !is_stalled(now_ms().saturating_sub(LAST_BEAT.load(Ordering::Relaxed)), timeout)
+}
+
+#[inline]
+fn is_stalled(elapsed: u64, timeout: u64) -> bool {
+    elapsed > timeout
+}
+
+// Name, state and kernel wait channel of every thread, as tokio has no stable task dump.
+#[cfg(target_os = "linux")]
+fn dump_threads() -> String {
+    let mut res = String::new();
+    let Ok(dir) = std::fs::read_dir("/proc/self/task") else {
+        return res;
+    };
+    for entry in dir.flatten() {
+        let path = entry.path();
+        let read = |name: &str| {
+            std::fs::read_to_string(path.join(name))
+                .unwrap_or_default()
+                .trim()
+                .to_owned()
+        };
+        let stat = read("stat");
+        // the state follows the parenthesized name
+        let state = stat
+            .rsplit_once(')')
+            .and_then(|x| x.1.split_whitespace().next())
+            .unwrap_or("?")
+            .to_owned();
+        let _ = writeln!(
+            res,
+            "thread {} {}: state={} wchan={}",
+            entry.file_name().to_string_lossy(),
+            read("comm"),
+            state,
+            read("wchan")
+        );
+    }
+    res
+}
+
+#[cfg(not(target_os = "linux"))]
+fn dump_threads() -> String {
+    "".to_owned()
+}
+
+pub(crate) fn status() -> String {
+    format!(
+        "last beat: {}ms ago in {:?}\nstalls: {}\n",
+        now_ms().saturating_sub(LAST_BEAT.load(Ordering::Relaxed)),
+        STAGES[STAGE.load(Ordering::Relaxed)],
+        STALLS.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_map_back() {
+        for (i, stage) in STAGES.iter().enumerate() {
+            assert_eq!(*stage as usize, i);
+        }
+        assert!(!is_stalled(30_000, 30_000));
+        assert!(is_stalled(30_001, 30_000));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{
+    common::{get_arg, now},
+    instance, relay_report,
+};
+use hbb_common::log;
+use once_cell::sync::OnceCell;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender},
+    },
+    time::Duration,
+};
+
+const MAX_QUEUE: usize = 10_000;
+const MAX_ATTEMPTS: u32 = 4;
+const FIRST_RETRY: Duration = Duration::from_secs(1); // doubled each retry
+const TIMEOUT: u64 = 10; // in seconds, of a post
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Event {
+    PeerOnline = 0,
+    PeerOffline = 1,
+    PunchHoleFailed = 2,
+    PkMismatch = 3,
+}
+
+const EVENTS: [&str; 4] = ["peer_online", "peer_offline", "punch_hole_failed", "pk_mismatch"];
+
+struct Webhook {
+    tx: SyncSender<String>,
+    events: [bool; 4],
+}
+
+static WEBHOOK: OnceCell<Webhook> = OnceCell::new();
+static SENT: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+/// `WEBHOOK_URL` gets a POST of a json event, e.g. for a helpdesk to learn
+/// that a device it manages went offline without polling: `peer_online`,
+/// `peer_offline`, `punch_hole_failed` and `pk_mismatch`, or those in
+/// `WEBHOOK_EVENTS`. With `WEBHOOK_SECRET` the body's base64 HMAC-SHA256 is
+/// in `X-Signature`. Posted in order from a queue, retried a few times, and
+/// dropped when the queue is full.
+pub(crate) fn init() {
+    let url = get_arg("WEBHOOK_URL");
+    if url.is_empty() {
+        return;
+    }
+    let events = match parse_events(&get_arg("WEBHOOK_EVENTS")) {
+        Ok(events) => events,
+        Err(err) => {
+            log::error!("WEBHOOK_EVENTS: {}, webhook off", err);
+            return;
+        }
+    };
+    log::info!("WEBHOOK_URL={}", url);
+    let secret = get_arg("WEBHOOK_SECRET");
+    let (tx, rx) = sync_channel(MAX_QUEUE);
+    let res = std::thread::Builder::new()
+        .name("webhook".to_owned())
+        .spawn(move || post_all(rx, &url, &secret));
+    if let Err(err) = res {
+        log::error!("Failed to start the webhook: {}", err);
+        return;
+    }
+    WEBHOOK.set(Webhook { tx, events }).ok();
+}
+
+// all events if empty
+fn parse_events(v: &str) -> Result<[bool; 4], String> {
+    let mut events = [v.trim().is_empty(); 4];
+    for x in v.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        let Some(i) = EVENTS.iter().position(|e| *e == x) else {
+            return Err(format!("unknown event {x}"));
+        };
+        events[i] = true;
+    }
+    Ok(events)
+}
+
+/// Queue `event` of `id`, with the ip it concerns and why, either may be
+/// empty. Never blocks.
+pub(crate) fn emit(event: Event, id: &str, ip: &str, reason: &str) {
+    let Some(w) = WEBHOOK.get() else {
+        return;
+    };
+    if !w.events[event as usize] {
+        return;
+    }
+    let body = to_json(event, id, ip, reason, now()).to_string();
+    // before the worker can take it
+    QUEUED.fetch_add(1, Ordering::Relaxed);
+    if w.tx.try_send(body).is_err() {
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn to_json(event: Event, id: &str, ip: &str, reason: &str, time: u64) -> serde_json::Value {
+    let mut res = serde_json::json!({
+        "event": EVENTS[event as usize],
+        "time": time,
+        "id": id,
+        "instance": instance::id(),
+    });
+    if !ip.is_empty() {
+        res["ip"] = ip.into();
+    }
+    if !reason.is_empty() {
+        res["reason"] = reason.into();
+    }
+    res
+}
+
+fn post_all(rx: Receiver<String>, url: &str, secret: &str) {
+    for body in rx {
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+        let mut retry = FIRST_RETRY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match post(url, secret, &body) {
+                Ok(()) => {
+                    SENT.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                Err(err) if attempt == MAX_ATTEMPTS => {
+                    FAILED.fetch_add(1, Ordering::Relaxed);
+                    log::warn!("Webhook failed {} times, dropped: {}", attempt, err);
+                }
+                Err(err) => {
+                    log::debug!("Webhook failed, retrying in {:?}: {}", retry, err);
+                    std::thread::sleep(retry);
+                    retry *= 2;
+                }
+            }
+        }
+    }
+}
+
+fn post(url: &str, secret: &str, body: &str) -> Result<(), String> {
+    let mut req = minreq::post(url)
+        .with_timeout(TIMEOUT)
+        .with_header("Content-Type", "application/json")
+        .with_body(body);
+    if !secret.is_empty() {
+        req = req.with_header("X-Signature", relay_report::mac(body, secret));
+    }
+    let res = req.send().map_err(|err| err.to_string())?;
+    if !(200..300).contains(&res.status_code) {
+        return Err(format!("{} {}", res.status_code, res.reason_phrase));
+    }
+    Ok(())
+}
+
+pub(crate) fn status() -> String {
+    let Some(w) = WEBHOOK.get() else {
+        return "off, set WEBHOOK_URL\n".to_owned();
+    };
+    let events: Vec<_> = EVENTS
+        .iter()
+        .zip(w.events)
+        .filter(|x| x.1)
+        .map(|x| *x.0)
+        .collect();
+    format!(
+        "events: {}\nqueued: {}\nsent: {}\nfailed: {}\ndropped: {}\n",
+        events.join(","),
+        QUEUED.load(Ordering::Relaxed),
+        SENT.load(Ordering::Relaxed),
+        FAILED.load(Ordering::Relaxed),
+        DROPPED.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_events() {
+        assert_eq!(parse_events(""), Ok([true; 4]));
+        assert_eq!(parse_events("peer_offline, pk_mismatch"), Ok([false, true, false, true]));
+        assert!(parse_events("peer_gone").is_err());
+        let json = to_json(Event::PunchHoleFailed, "123456789", "10.0.0.1", "OFFLINE", 1000);
+        assert_eq!(json["event"], "punch_hole_failed");
+        assert_eq!(json["reason"], "OFFLINE");
+        assert!(to_json(Event::PeerOffline, "123456789", "", "", 1000).get("ip").is_none());
+    }
+}